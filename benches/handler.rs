@@ -0,0 +1,140 @@
+//! Time spent by the handler on a query, from the received bytes to the response,
+//! with the answer already in the cache and with a cache miss.
+//!
+//! donos being a binary without library, the modules are included here the same
+//! way `main.rs` declares them. The commands aren't used, nor the imports of the
+//! tests, which are compiled without their functions.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/api/mod.rs"]
+mod api;
+#[path = "../src/blocklist/mod.rs"]
+mod blocklist;
+#[path = "../src/cache/mod.rs"]
+mod cache;
+#[path = "../src/capture/mod.rs"]
+mod capture;
+#[path = "../src/client/mod.rs"]
+mod client;
+#[path = "../src/common/mod.rs"]
+mod common;
+#[path = "../src/config/mod.rs"]
+mod config;
+#[path = "../src/dns/mod.rs"]
+mod dns;
+#[path = "../src/domain/mod.rs"]
+mod domain;
+#[path = "../src/healthcheck/mod.rs"]
+mod healthcheck;
+#[path = "../src/query/mod.rs"]
+mod query;
+#[path = "../src/repository/mod.rs"]
+mod repository;
+#[path = "../src/service/mod.rs"]
+mod service;
+#[path = "../src/stats/mod.rs"]
+mod stats;
+
+use common::source::QuerySource;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use dns::handler::DnsHandler;
+use donos_parser::packet::header::Header;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use donos_server::prelude::{Message, Transport};
+use donos_server::Handler;
+use repository::blocklist::BlocklistService;
+use repository::lookup::LookupService;
+use std::error::Error;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Blocks nothing, the blocklist being checked without touching a database
+struct EmptyBlocklist;
+
+#[async_trait::async_trait]
+impl BlocklistService for EmptyBlocklist {
+    async fn is_blocked(
+        &self,
+        _origin: &SocketAddr,
+        _domain: &str,
+    ) -> Result<bool, Box<dyn Error>> {
+        Ok(false)
+    }
+
+    async fn import(&self) -> Result<(u64, u64), Box<dyn Error>> {
+        Ok((0, 0))
+    }
+}
+
+/// Answers every name right away, for the time of the network not to be measured
+struct InstantLookup;
+
+#[async_trait::async_trait]
+impl LookupService for InstantLookup {
+    async fn lookup(
+        &self,
+        qname: &str,
+        _qtype: QueryType,
+        _source: QuerySource,
+    ) -> std::io::Result<DnsPacket> {
+        Ok(DnsPacket::new(Header::response(0)).with_answer(Record::A {
+            domain: qname.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 3600,
+        }))
+    }
+}
+
+fn message(id: u16, qname: &str) -> Message {
+    let buffer = DnsPacket::new(Header::question(id))
+        .with_question(Question::new(qname.into(), QueryType::A))
+        .create_buffer()
+        .unwrap();
+    Message {
+        address: SocketAddr::from((Ipv4Addr::new(192, 168, 1, 12), 4242)),
+        listener: SocketAddr::from((Ipv4Addr::LOCALHOST, 53)),
+        transport: Transport::Udp,
+        size: buffer.pos,
+        buffer: buffer.buf,
+    }
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let cache = runtime
+        .block_on(repository::cache::Config::default().build())
+        .unwrap();
+    let handler = DnsHandler::new(
+        Arc::new(EmptyBlocklist),
+        Arc::new(cache),
+        Arc::new(InstantLookup),
+    );
+
+    runtime.block_on(handler.handle(message(1, "www.perdu.com")));
+    c.bench_function("handling query with warm cache", |b| {
+        b.iter_batched(
+            || message(1, "www.perdu.com"),
+            |input| runtime.block_on(handler.handle(input)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    // a name never queried before for each iteration
+    let index = AtomicU64::new(0);
+    c.bench_function("handling query with cache miss", |b| {
+        b.iter_batched(
+            || {
+                let index = index.fetch_add(1, Ordering::Relaxed);
+                message(index as u16, &format!("host-{index}.perdu.com"))
+            },
+            |input| runtime.block_on(handler.handle(input)).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
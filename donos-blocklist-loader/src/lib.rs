@@ -1,43 +1,196 @@
 use sha2::{Digest, Sha256};
 use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
 
-#[derive(Clone, Copy, Debug)]
+/// Number of domains handed at once by a stream
+const BATCH_SIZE: usize = 4096;
+/// Number of bytes read at once from a file
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
 pub enum BlocklistKind {
     EtcHosts,
     NoIp,
+    /// Adblock Plus filter list, like EasyList or the AdGuard DNS filter, of which
+    /// only the rules blocking whole domains are kept, with their subdomains
+    #[cfg_attr(feature = "serde", serde(alias = "adguard"))]
+    AdblockFilter,
+    /// One domain per line, with `#` comments
+    DomainList,
+    /// dnsmasq configuration, like `address=/example.com/0.0.0.0` or `server=/example.com/`,
+    /// blocking the domains with their subdomains like dnsmasq does
+    Dnsmasq,
 }
 
 impl BlocklistKind {
-    fn parse(self, input: &str) -> HashSet<String> {
+    /// Adds the domains blocked by a line of the list, the domains that the list
+    /// excepts being added to `exceptions`
+    fn parse_line(self, line: &str, domains: &mut Vec<String>, exceptions: &mut HashSet<String>) {
         match self {
-            Self::EtcHosts => parse_etchosts(input),
-            Self::NoIp => parse_noip(input),
+            Self::EtcHosts => domains.extend(parse_etchosts_line(line)),
+            Self::NoIp => domains.extend(parse_noip_line(line)),
+            Self::AdblockFilter => match parse_adblock_rule(line.trim()) {
+                Some(AdblockRule::Block(domain)) => {
+                    domains.extend(normalize(domain).into_iter().flat_map(with_subdomains))
+                }
+                Some(AdblockRule::Allow(domain)) => exceptions.extend(normalize(domain)),
+                None => {}
+            },
+            Self::DomainList => domains.extend(parse_domain_list_line(line)),
+            Self::Dnsmasq => domains.extend(parse_dnsmasq_line(line)),
+        }
+    }
+
+    fn parse(self, input: &str) -> HashSet<String> {
+        let mut domains = Vec::new();
+        let mut exceptions = HashSet::new();
+        for line in input.lines() {
+            self.parse_line(line, &mut domains, &mut exceptions);
         }
+        domains
+            .into_iter()
+            .filter(|domain| !is_excepted(&exceptions, domain))
+            .collect()
+    }
+}
+
+/// Whether the domain, or one of its parents, is in the exceptions of a list
+pub fn is_excepted(exceptions: &HashSet<String>, domain: &str) -> bool {
+    if exceptions.is_empty() {
+        return false;
+    }
+    let mut rest = domain;
+    loop {
+        if exceptions.contains(rest) {
+            return true;
+        }
+        match rest.split_once('.') {
+            Some((_, parent)) => rest = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Entries blocking the domain and its subdomains, the latter as a wildcard like `*.example.com`
+fn with_subdomains(domain: String) -> [String; 2] {
+    let wildcard = format!("*.{domain}");
+    [domain, wildcard]
+}
+
+fn parse_etchosts_line(line: &str) -> impl Iterator<Item = String> + '_ {
+    line.split_whitespace()
+        .take_while(|item| !item.starts_with('#'))
+        .skip(1)
+        .filter_map(normalize)
+}
+
+fn parse_noip_line(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        None
+    } else {
+        normalize(line)
     }
 }
 
-fn parse_etchosts(input: &str) -> HashSet<String> {
-    input
-        .split('\n')
-        .flat_map(|line| {
-            line.split_whitespace()
-                .take_while(|item| !item.starts_with('#'))
-                .enumerate()
-                .filter_map(|(idx, item)| if idx > 0 { Some(item) } else { None })
-                .map(|item| item.to_string())
-        })
-        .collect()
+fn parse_domain_list_line(line: &str) -> Option<String> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    // a line with several words isn't a domain
+    if line.contains(char::is_whitespace) {
+        None
+    } else {
+        normalize(line)
+    }
+}
+
+/// Targets of the dnsmasq `address` option that prevent the domains from resolving
+const DNSMASQ_SINKHOLES: &[&str] = &["", "#", "0.0.0.0", "::", "127.0.0.1", "::1"];
+
+/// Keeps the domains answered with a sinkhole address or resolved by no server, along
+/// with their subdomains. The options redirecting a domain to an actual address or
+/// server are ignored.
+fn parse_dnsmasq_line(line: &str) -> impl Iterator<Item = String> + '_ {
+    let domains = line.trim().split_once('=').and_then(|(option, value)| {
+        let value = value.strip_prefix('/')?;
+        let (domains, target) = value.rsplit_once('/')?;
+        let blocking = match option.trim() {
+            "address" => DNSMASQ_SINKHOLES.contains(&target.trim()),
+            "server" | "local" => target.trim().is_empty(),
+            _ => false,
+        };
+        blocking.then_some(domains)
+    });
+    domains
+        .into_iter()
+        .flat_map(|domains| domains.split('/').filter_map(normalize))
+        .flat_map(with_subdomains)
+}
+
+/// Options that don't restrict a rule to some kinds of requests, that a DNS server can't tell apart
+const ADBLOCK_OPTIONS: &[&str] = &["important", "all", "document"];
+
+/// Rule of an adblock filter list applying to a whole domain. Comments, cosmetic
+/// filters and the rules on urls are ignored.
+#[derive(Debug, PartialEq, Eq)]
+enum AdblockRule<'a> {
+    /// Rule like `||example.com^`, blocking the domain and its subdomains
+    Block(&'a str),
+    /// Exception, `@@||example.com^`, allowing the domain and its subdomains
+    Allow(&'a str),
+}
+
+fn parse_adblock_rule(line: &str) -> Option<AdblockRule<'_>> {
+    let (rule, exception) = match line.strip_prefix("@@") {
+        Some(rest) => (rest, true),
+        None => (line, false),
+    };
+    let (pattern, options) = match rule.split_once('$') {
+        Some((pattern, options)) => (pattern, Some(options)),
+        None => (rule, None),
+    };
+    if let Some(options) = options {
+        if !options
+            .split(',')
+            .all(|option| ADBLOCK_OPTIONS.contains(&option.trim()))
+        {
+            return None;
+        }
+    }
+    let domain = pattern.strip_prefix("||")?;
+    let domain = domain
+        .strip_suffix("^|")
+        .or_else(|| domain.strip_suffix('^'))?;
+    if domain.is_empty()
+        || !domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+    {
+        return None;
+    }
+    Some(if exception {
+        AdblockRule::Allow(domain)
+    } else {
+        AdblockRule::Block(domain)
+    })
 }
 
-fn parse_noip(input: &str) -> HashSet<String> {
-    input
-        .split('\n')
-        .map(|line| line.trim())
-        .filter(|line| !line.starts_with('#'))
-        .map(String::from)
-        .collect()
+/// Removes the trailing dot of fully qualified names and ignores empty entries,
+/// that would otherwise block the root domain.
+///
+/// The internationalized names are stored in their punycode form, the one of the queries.
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    if domain.is_empty() {
+        None
+    } else if domain.is_ascii() {
+        Some(domain.to_ascii_lowercase())
+    } else {
+        Some(idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_lowercase()))
+    }
 }
 
 fn hash(input: &str) -> String {
@@ -60,26 +213,175 @@ impl Blocklist {
     }
 }
 
+#[derive(Debug)]
+pub enum LoadError {
+    Http(reqwest::Error),
+    File(std::io::Error),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(inner) => write!(f, "unable to download blocklist: {inner}"),
+            Self::File(inner) => write!(f, "unable to read blocklist file: {inner}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Http(inner) => Some(inner),
+            Self::File(inner) => Some(inner),
+        }
+    }
+}
+
+/// Path of the file when the blocklist isn't served over http, like
+/// `/etc/donos/blocklist.txt` or `file:///etc/donos/blocklist.txt`
+fn local_path(url: &str) -> Option<&Path> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(Path::new(path))
+    } else if url.contains("://") {
+        None
+    } else {
+        Some(Path::new(url))
+    }
+}
+
+enum Source {
+    Http(reqwest::Response),
+    File(tokio::fs::File),
+}
+
+impl Source {
+    /// Appends the next bytes to the buffer, returns false once everything has been read
+    async fn read_into(&mut self, buffer: &mut Vec<u8>) -> Result<bool, LoadError> {
+        match self {
+            Self::Http(response) => match response.chunk().await.map_err(LoadError::Http)? {
+                Some(chunk) => {
+                    buffer.extend_from_slice(&chunk);
+                    Ok(true)
+                }
+                None => Ok(false),
+            },
+            Self::File(file) => {
+                buffer.reserve(READ_SIZE);
+                let read = file
+                    .take(READ_SIZE as u64)
+                    .read_to_end(buffer)
+                    .await
+                    .map_err(LoadError::File)?;
+                Ok(read > 0)
+            }
+        }
+    }
+}
+
+/// Blocklist read line by line, so that the very large ones are never entirely in memory
+pub struct BlocklistStream {
+    kind: BlocklistKind,
+    source: Source,
+    hasher: Sha256,
+    /// Bytes read after the last complete line
+    pending: Vec<u8>,
+    exceptions: HashSet<String>,
+    finished: bool,
+}
+
+impl BlocklistStream {
+    fn new(kind: BlocklistKind, source: Source) -> Self {
+        Self {
+            kind,
+            source,
+            hasher: Sha256::new(),
+            pending: Vec::new(),
+            exceptions: HashSet::new(),
+            finished: false,
+        }
+    }
+
+    /// Next domains of the list, `None` once it has been entirely read. The domains
+    /// can be repeated and include the ones excepted by the list.
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<String>>, LoadError> {
+        let mut domains = Vec::new();
+        while !self.finished && domains.len() < BATCH_SIZE {
+            let start = self.pending.len();
+            let more = self.source.read_into(&mut self.pending).await?;
+            self.hasher.update(&self.pending[start..]);
+            let end = if more {
+                match self.pending.iter().rposition(|byte| *byte == b'\n') {
+                    Some(position) => position + 1,
+                    None => continue,
+                }
+            } else {
+                self.finished = true;
+                self.pending.len()
+            };
+            let rest = self.pending.split_off(end);
+            let lines = std::mem::replace(&mut self.pending, rest);
+            for line in String::from_utf8_lossy(&lines).lines() {
+                self.kind
+                    .parse_line(line, &mut domains, &mut self.exceptions);
+            }
+        }
+        if domains.is_empty() && self.finished {
+            Ok(None)
+        } else {
+            Ok(Some(domains))
+        }
+    }
+
+    /// Hash of the whole list and the domains it excepts, once it has been read
+    pub fn finish(self) -> (String, HashSet<String>) {
+        let hash = base16ct::lower::encode_string(&self.hasher.finalize());
+        (hash, self.exceptions)
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct BlocklistLoader;
 
 impl BlocklistLoader {
-    pub async fn load(&self, url: &str, kind: BlocklistKind) -> Result<Blocklist, reqwest::Error> {
+    /// Opens the blocklist, to be read by batches of domains
+    pub async fn stream(
+        &self,
+        url: &str,
+        kind: BlocklistKind,
+    ) -> Result<BlocklistStream, LoadError> {
         tracing::debug!("loading {url:?}");
-        let req = reqwest::get(url).await?;
-        let text = req.text().await?;
-        Ok(Blocklist::from_file(&text, kind))
+        let source = match local_path(url) {
+            Some(path) => Source::File(tokio::fs::File::open(path).await.map_err(LoadError::File)?),
+            None => Source::Http(
+                reqwest::get(url)
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(LoadError::Http)?,
+            ),
+        };
+        Ok(BlocklistStream::new(kind, source))
+    }
+
+    pub async fn load(&self, url: &str, kind: BlocklistKind) -> Result<Blocklist, LoadError> {
+        let mut stream = self.stream(url, kind).await?;
+        let mut entries = HashSet::new();
+        while let Some(domains) = stream.next_batch().await? {
+            entries.extend(domains);
+        }
+        let (hash, exceptions) = stream.finish();
+        entries.retain(|domain: &String| !is_excepted(&exceptions, domain));
+        Ok(Blocklist { hash, entries })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{hash, parse_etchosts, parse_noip, Blocklist, BlocklistKind};
+    use super::{hash, parse_adblock_rule, AdblockRule, Blocklist, BlocklistKind, BlocklistLoader};
 
     #[test]
     fn parse_ads_etchosts() {
         let data = include_str!("../data/ads.txt");
-        let result = parse_etchosts(data);
+        let result = BlocklistKind::EtcHosts.parse(data);
         assert!(result.contains("0.r.msn.com"));
         assert!(result.contains("207.net"));
         assert!(!result.contains("#"));
@@ -89,16 +391,33 @@ mod tests {
     #[test]
     fn parse_ads_noip() {
         let data = include_str!("../data/ads-noip.txt");
-        let result = parse_noip(data);
+        let result = BlocklistKind::NoIp.parse(data);
         assert!(result.contains("0.r.msn.com"));
         assert!(result.contains("207.net"));
         assert!(!result.contains("#"));
+        assert!(!result.contains(""));
+    }
+
+    #[test]
+    fn parse_fully_qualified_noip() {
+        let result = BlocklistKind::NoIp.parse("perdu.com.\n\nFacebook.com\n");
+        assert!(result.contains("perdu.com"));
+        assert!(result.contains("facebook.com"));
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn parse_internationalized_noip() {
+        let result = BlocklistKind::NoIp.parse("пример.рф\nXN--80AK6AA92E.com\n");
+        assert!(result.contains("xn--e1afmkfd.xn--p1ai"));
+        assert!(result.contains("xn--80ak6aa92e.com"));
+        assert_eq!(result.len(), 2);
     }
 
     #[test]
     fn parse_basic_hostfile() {
         let data = include_str!("../data/basic.txt");
-        let result = parse_etchosts(data);
+        let result = BlocklistKind::EtcHosts.parse(data);
         assert!(result.contains("0-app.com"));
         assert!(!result.contains("#"));
         assert!(!result.contains("0.0.0.0"));
@@ -130,4 +449,148 @@ mod tests {
             "52139cfb54f4ca549444fe7cf31b30a6f71174dc39eeaf2df631ebd34b91950d"
         );
     }
+
+    #[test]
+    fn parse_adblock_rules() {
+        assert_eq!(
+            parse_adblock_rule("||ads.example.com^"),
+            Some(AdblockRule::Block("ads.example.com"))
+        );
+        assert_eq!(
+            parse_adblock_rule("||ads.example.com^$important"),
+            Some(AdblockRule::Block("ads.example.com"))
+        );
+        assert_eq!(
+            parse_adblock_rule("@@||cdn.example.com^|"),
+            Some(AdblockRule::Allow("cdn.example.com"))
+        );
+        assert_eq!(parse_adblock_rule("||example.com^$third-party"), None);
+        assert_eq!(parse_adblock_rule("||example.com/ads/*"), None);
+        assert_eq!(parse_adblock_rule("||ads*.example.com^"), None);
+        assert_eq!(parse_adblock_rule("||example.com"), None);
+        assert_eq!(parse_adblock_rule("example.com##.banner"), None);
+        assert_eq!(parse_adblock_rule("/banner\\d+/"), None);
+    }
+
+    #[test]
+    fn parse_adblock_filter() {
+        let result = BlocklistKind::AdblockFilter.parse(
+            r#"[Adblock Plus 2.0]
+! Title: test filter
+||Tracker.com^
+||ads.example.com^
+||cdn.ads.example.com^
+||metrics.example.org^$important
+@@||ads.example.com^
+example.com##.banner
+||example.net/ads.js
+"#,
+        );
+        assert!(result.contains("tracker.com"));
+        assert!(result.contains("*.tracker.com"));
+        assert!(result.contains("metrics.example.org"));
+        assert!(result.contains("*.metrics.example.org"));
+        assert!(!result.contains("ads.example.com"));
+        assert!(!result.contains("*.ads.example.com"));
+        assert!(!result.contains("cdn.ads.example.com"));
+        assert_eq!(result.len(), 4);
+    }
+
+    #[test]
+    fn parse_domain_list_with_comments() {
+        let result = BlocklistKind::DomainList.parse(
+            "# title\nads.example.com\n  Tracker.com.  # inline comment\n\n0.0.0.0 hosts.line\n",
+        );
+        assert!(result.contains("ads.example.com"));
+        assert!(result.contains("tracker.com"));
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn parse_dnsmasq_options() {
+        let result = BlocklistKind::Dnsmasq.parse(
+            r#"# dnsmasq blocklist
+address=/ads.example.com/0.0.0.0
+address=/one.com/two.com/::
+address=/nxdomain.com/
+server=/tracker.com/
+local=/local.example.org/
+address=/router.lan/192.168.1.1
+server=/corp.example.com/10.0.0.1
+cache-size=1000
+"#,
+        );
+        for domain in [
+            "ads.example.com",
+            "one.com",
+            "two.com",
+            "nxdomain.com",
+            "tracker.com",
+            "local.example.org",
+        ] {
+            assert!(result.contains(domain), "{domain}");
+            assert!(result.contains(&format!("*.{domain}")), "{domain}");
+        }
+        assert_eq!(result.len(), 12);
+    }
+
+    #[test]
+    fn should_detect_local_paths() {
+        use std::path::Path;
+
+        assert_eq!(
+            super::local_path("file:///etc/donos/list.txt"),
+            Some(Path::new("/etc/donos/list.txt"))
+        );
+        assert_eq!(
+            super::local_path("/etc/donos/list.txt"),
+            Some(Path::new("/etc/donos/list.txt"))
+        );
+        assert_eq!(
+            super::local_path("lists/mine.txt"),
+            Some(Path::new("lists/mine.txt"))
+        );
+        assert_eq!(super::local_path("https://example.com/list.txt"), None);
+    }
+
+    #[tokio::test]
+    async fn load_local_file() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/data/basic.txt");
+        let result = BlocklistLoader
+            .load(&format!("file://{path}"), BlocklistKind::EtcHosts)
+            .await
+            .unwrap();
+        assert!(result.entries.contains("0-app.com"));
+        assert_eq!(
+            result.hash,
+            "c0d1929bb2584c045eece5cf9d46ae913fc524e960893ab469f8a93a88fe6e94"
+        );
+        assert!(BlocklistLoader
+            .load("/does/not/exist.txt", BlocklistKind::EtcHosts)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_local_file_by_batches() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/data/ads.txt");
+        let mut stream = BlocklistLoader
+            .stream(path, BlocklistKind::EtcHosts)
+            .await
+            .unwrap();
+        let mut entries = std::collections::HashSet::new();
+        let mut batches = 0;
+        while let Some(domains) = stream.next_batch().await.unwrap() {
+            assert!(domains.len() < 2 * super::BATCH_SIZE);
+            entries.extend(domains);
+            batches += 1;
+        }
+        let (hash, _) = stream.finish();
+
+        let data = include_str!("../data/ads.txt");
+        let expected = Blocklist::from_file(data, BlocklistKind::EtcHosts);
+        assert!(batches > 1);
+        assert_eq!(hash, expected.hash);
+        assert_eq!(entries, expected.entries);
+    }
 }
@@ -20,6 +20,13 @@ fn encoding(packet: DnsPacket) {
     let _buffer = packet.create_buffer().unwrap();
 }
 
+fn encoding_into(packet: DnsPacket, buffer: Vec<u8>) -> Vec<u8> {
+    packet
+        .write_into(BytePacketBuffer::reuse(buffer))
+        .unwrap()
+        .buf
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("encoding query packet", |b| {
         let packet = prepare(QUERY_PACKET);
@@ -29,6 +36,11 @@ fn criterion_benchmark(c: &mut Criterion) {
         let packet = prepare(RESPONSE_PACKET);
         b.iter(|| encoding(black_box(packet.clone())))
     });
+    c.bench_function("encoding response packet into a reused buffer", |b| {
+        let packet = prepare(RESPONSE_PACKET);
+        let mut buffer = Vec::new();
+        b.iter(|| buffer = encoding_into(black_box(packet.clone()), std::mem::take(&mut buffer)))
+    });
 }
 
 criterion_group!(benches, criterion_benchmark);
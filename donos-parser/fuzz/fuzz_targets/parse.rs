@@ -1,4 +1,6 @@
 #![no_main]
+//! The packets of `../data`, including the malformed ones, make a good starting corpus:
+//! `cargo fuzz run parse corpus/parse ../data`
 
 use donos_parser::{buffer::BytePacketBuffer, packet::DnsPacket};
 use std::convert::TryFrom;
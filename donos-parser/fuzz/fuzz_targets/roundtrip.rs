@@ -0,0 +1,19 @@
+#![no_main]
+//! A packet that can be read must be written, then read again, without change
+
+use donos_parser::{buffer::BytePacketBuffer, packet::DnsPacket};
+use std::convert::TryFrom;
+
+libfuzzer_sys::fuzz_target!(|buffer: BytePacketBuffer| {
+    let Ok(packet) = DnsPacket::try_from(buffer) else {
+        return;
+    };
+    // the invalid UTF-8 of the names is replaced when read, which can make them
+    // too long to be written back
+    let Ok(written) = packet.create_buffer() else {
+        return;
+    };
+    let read = DnsPacket::try_from(BytePacketBuffer::new(&written.buf[..written.pos]))
+        .expect("written packet should be readable");
+    assert_eq!(packet, read);
+});
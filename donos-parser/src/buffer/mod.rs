@@ -1,15 +1,75 @@
-use std::collections::HashMap;
+use arrayvec::ArrayVec;
+use std::borrow::Borrow;
 
 pub mod reader;
 pub mod writer;
 
-#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary, Debug, Clone))]
-#[cfg_attr(test, derive(Clone))]
+/// Maximum number of names memoized when writing a packet.
+///
+/// A regular response only repeats a handful of names, so this is enough to
+/// compress it while keeping the memory used bounded and on the stack.
+const MAX_MEMOIZED_LABELS: usize = 16;
+
+/// Maximum size of a message over UDP, without EDNS (RFC 1035 section 4.2.1)
+pub const UDP_PACKET_SIZE: usize = 512;
+/// UDP payload size advertised with EDNS, small enough to avoid IP fragmentation
+pub const EDNS_PACKET_SIZE: usize = 1232;
+/// Maximum size of a message, limited by the two bytes length prefix used over TCP
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+/// Fixed capacity association list used to memoize the names written in a packet.
+///
+/// Once full, new entries are ignored so that a crafted packet cannot make it grow.
+#[derive(Clone, Debug)]
+pub(crate) struct LabelCache<K, V> {
+    inner: ArrayVec<(K, V), MAX_MEMOIZED_LABELS>,
+}
+
+impl<K, V> Default for LabelCache<K, V> {
+    fn default() -> Self {
+        Self {
+            inner: ArrayVec::new(),
+        }
+    }
+}
+
+impl<K: PartialEq, V> LabelCache<K, V> {
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: PartialEq + ?Sized,
+    {
+        self.inner
+            .iter()
+            .find(|(k, _)| k.borrow() == key)
+            .map(|(_, v)| v)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if !self.inner.is_full() && self.get(&key).is_none() {
+            self.inner.push((key, value));
+        }
+    }
+}
+
+#[cfg_attr(feature = "fuzzing", derive(Debug))]
+#[cfg_attr(any(test, feature = "fuzzing"), derive(Clone))]
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    /// Content of the packet, growing when writing up to [`MAX_PACKET_SIZE`]
+    pub buf: Vec<u8>,
     pub pos: usize,
-    reading_labels: HashMap<usize, String>,
-    writing_labels: HashMap<String, usize>,
+    writing_labels: LabelCache<String, usize>,
+    /// Whether the names are written with pointers to the ones already written
+    compression: bool,
+    /// Whether the names are written in lowercase, whatever the case they were received with
+    lowercase: bool,
+}
+
+#[cfg(feature = "fuzzing")]
+impl<'a> arbitrary::Arbitrary<'a> for BytePacketBuffer {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Self::new(u.arbitrary::<[u8; UDP_PACKET_SIZE]>()?))
+    }
 }
 
 impl Default for BytePacketBuffer {
@@ -17,18 +77,49 @@ impl Default for BytePacketBuffer {
     /// field for keeping track of where we are.
     fn default() -> Self {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; UDP_PACKET_SIZE],
             pos: 0,
-            reading_labels: HashMap::default(),
-            writing_labels: HashMap::default(),
+            writing_labels: LabelCache::default(),
+            compression: true,
+            lowercase: false,
         }
     }
 }
 
 impl BytePacketBuffer {
-    pub fn new(buffer: [u8; 512]) -> Self {
+    pub fn new(buffer: impl Into<Vec<u8>>) -> Self {
+        Self {
+            buf: buffer.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Empty buffer able to receive a message of the given size
+    pub fn with_size(size: usize) -> Self {
+        Self::new(vec![0; size.min(MAX_PACKET_SIZE)])
+    }
+
+    /// Empty buffer writing into an existing allocation, like the one of the received
+    /// message, so that answering it doesn't allocate again
+    pub fn reuse(mut buffer: Vec<u8>) -> Self {
+        buffer.clear();
+        Self::new(buffer)
+    }
+
+    /// Empty buffer writing the names in full
+    pub fn without_compression() -> Self {
+        Self {
+            compression: false,
+            ..Default::default()
+        }
+    }
+
+    /// Empty buffer writing the names in full and in lowercase, like in the canonical
+    /// form of the records used by DNSSEC (RFC 4034 section 6.2)
+    pub fn canonical() -> Self {
         Self {
-            buf: buffer,
+            compression: false,
+            lowercase: true,
             ..Default::default()
         }
     }
@@ -38,3 +129,27 @@ impl BytePacketBuffer {
         self.pos
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{LabelCache, MAX_MEMOIZED_LABELS};
+
+    #[test]
+    fn label_cache_should_be_bounded() {
+        let mut cache = LabelCache::<usize, String>::default();
+        for idx in 0..(MAX_MEMOIZED_LABELS * 2) {
+            cache.insert(idx, idx.to_string());
+        }
+        assert_eq!(cache.inner.len(), MAX_MEMOIZED_LABELS);
+        assert_eq!(cache.get(&0).map(String::as_str), Some("0"));
+        assert!(cache.get(&MAX_MEMOIZED_LABELS).is_none());
+    }
+
+    #[test]
+    fn label_cache_should_borrow_keys() {
+        let mut cache = LabelCache::<String, usize>::default();
+        cache.insert("foo.bar".to_string(), 12);
+        cache.insert("foo.bar".to_string(), 42);
+        assert_eq!(cache.get("foo.bar"), Some(&12));
+    }
+}
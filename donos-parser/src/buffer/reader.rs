@@ -1,8 +1,11 @@
 use std::fmt::Display;
 
 use super::BytePacketBuffer;
+use arrayvec::ArrayVec;
 
 const MAX_JUMP: usize = 5;
+/// Maximum length of a name, once encoded, including the null label.
+pub(crate) const MAX_NAME_LENGTH: usize = 255;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ReaderError {
@@ -10,6 +13,12 @@ pub enum ReaderError {
     TooManyJumps(usize),
     InvalidResponseCode(u8),
     InvalidClass(u16),
+    InvalidLabelType(u8),
+    NameTooLong(usize),
+    InvalidDataLength(u16),
+    InvalidCharacter(u8),
+    /// Number of entries claimed by the header, more than the rest of the packet can hold
+    TooManyEntries(usize),
 }
 
 impl Display for ReaderError {
@@ -19,6 +28,15 @@ impl Display for ReaderError {
             Self::TooManyJumps(limit) => write!(f, "reached the limit of {limit} jumps"),
             Self::InvalidResponseCode(code) => write!(f, "invalid response code {code}"),
             Self::InvalidClass(code) => write!(f, "invalid class {code}"),
+            Self::InvalidLabelType(value) => write!(f, "invalid label type {value:#04x}"),
+            Self::NameTooLong(size) => write!(f, "name of {size} bytes is too long"),
+            Self::InvalidDataLength(size) => {
+                write!(f, "record data doesn't fit in its length of {size} bytes")
+            }
+            Self::InvalidCharacter(value) => write!(f, "invalid character {value:#04x} in name"),
+            Self::TooManyEntries(count) => {
+                write!(f, "{count} entries can't fit in the size of the packet")
+            }
         }
     }
 }
@@ -43,6 +61,26 @@ impl From<ReaderError> for std::io::Error {
                 std::io::ErrorKind::InvalidData,
                 format!("invalid class: {value}"),
             ),
+            ReaderError::InvalidLabelType(value) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid label type: {value:#04x}"),
+            ),
+            ReaderError::NameTooLong(size) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("name too long: {size}"),
+            ),
+            ReaderError::InvalidDataLength(size) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid record data length: {size}"),
+            ),
+            ReaderError::InvalidCharacter(value) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid character in name: {value:#04x}"),
+            ),
+            ReaderError::TooManyEntries(count) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("too many entries for the packet size: {count}"),
+            ),
         }
     }
 }
@@ -64,7 +102,7 @@ impl BytePacketBuffer {
 
     /// Read a single byte and move the position one step forward
     pub fn read(&mut self) -> Result<u8, ReaderError> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err(ReaderError::EndOfBuffer);
         }
         let res = self.buf[self.pos];
@@ -75,7 +113,7 @@ impl BytePacketBuffer {
 
     /// Get a single byte, without changing the buffer position
     fn get(&self, pos: usize) -> Result<u8, ReaderError> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err(ReaderError::EndOfBuffer);
         }
         Ok(self.buf[pos])
@@ -84,7 +122,7 @@ impl BytePacketBuffer {
     /// Get a range of bytes
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8], ReaderError> {
         let end = start + len;
-        if end >= 512 {
+        if end > self.buf.len() {
             return Err(ReaderError::EndOfBuffer);
         }
         Ok(&self.buf[start..end])
@@ -107,73 +145,101 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
-    fn recursive_read_qname(
-        &mut self,
-        position: usize,
-        jumps_count: usize,
-    ) -> Result<(String, usize), ReaderError> {
-        // Dns Packets are untrusted data, so we need to be paranoid.
-        // Someone can craft a packet with a cycle in the jump instructions.
-        // This guards against such packets.
-        if jumps_count > MAX_JUMP {
-            return Err(ReaderError::TooManyJumps(MAX_JUMP));
-        }
-
-        // At this point, we're always at the beginning of a label. Recall
-        // that labels start with a length byte.
-        let length = self.get(position)?;
-
-        // If `length` has the two most significant bit are set, it represents a
-        // jump to some other offset in the packet:
-        if (length & 0xC0) == 0xC0 {
-            // Read another byte, calculate offset and perform the jump by
-            // updating our local position variable
-            let b2 = self.get(position + 1)? as u16;
-            let offset = ((((length as u16) ^ 0xC0) << 8) | b2) as usize;
-
-            let label = if let Some(label) = self.reading_labels.get(&offset) {
-                label.to_owned()
-            } else {
-                let (label, _) = self.recursive_read_qname(offset, jumps_count + 1)?;
-                label
-            };
-            Ok((label, position + 2))
-        } else if length == 0 {
-            // Domain names are terminated by an empty label of length 0,
-            // so if the length is zero we're done.
-            Ok((String::new(), position + 1))
-        } else {
-            // The base scenario, where we're reading a single label and
-            // appending it to the output
-            let length = length as usize;
-            // Extract the actual ASCII bytes for this label and append them
-            // to the output buffer.
-            let str_buffer = self.get_range(position + 1, length)?;
-            let label = String::from_utf8_lossy(str_buffer).to_lowercase();
-
-            let next_position = position + 1 + length;
-            let (next_label, next_position) =
-                self.recursive_read_qname(next_position, jumps_count)?;
-
-            let label = if next_label.is_empty() {
-                label
-            } else {
-                format!("{label}.{next_label}")
-            };
-            self.reading_labels.insert(position, label.clone());
-            Ok((label, next_position))
-        }
-    }
-
     /// Read a qname
     ///
     /// The tricky part: Reading domain names, taking labels into consideration.
     /// Will take something like [3]www[6]google[3]com[0] and append
     /// www.google.com to outstr.
+    ///
+    /// The labels are gathered on the stack, following the jumps, so that the name
+    /// is allocated only once.
     pub fn read_qname(&mut self) -> Result<String, ReaderError> {
-        let (label, position) = self.recursive_read_qname(self.pos(), 0)?;
-        self.seek(position)?;
-        Ok(label)
+        self.read_name(false)
+    }
+
+    /// Read a qname sent by a client, also rejecting the labels containing control
+    /// characters and the names that are not valid UTF-8.
+    ///
+    /// The other printable characters stay allowed, like the spaces of the DNS-SD
+    /// instance names (RFC 6763 section 4.1.1).
+    pub fn read_strict_qname(&mut self) -> Result<String, ReaderError> {
+        self.read_name(true)
+    }
+
+    fn read_name(&mut self, strict: bool) -> Result<String, ReaderError> {
+        let mut output = ArrayVec::<u8, MAX_NAME_LENGTH>::new();
+        let mut position = self.pos();
+        // position after the name, once the first jump is done
+        let mut next_position = None;
+        let mut jumps_count = 0;
+        // each label is prefixed by its length, the dots take that place
+        // and the null label ends the name
+        let mut encoded_length = 1;
+
+        loop {
+            // At this point, we're always at the beginning of a label. Recall
+            // that labels start with a length byte.
+            let length = self.get(position)?;
+
+            // If `length` has the two most significant bit are set, it represents a
+            // jump to some other offset in the packet:
+            if (length & 0xC0) == 0xC0 {
+                // Dns Packets are untrusted data, so we need to be paranoid.
+                // Someone can craft a packet with a cycle in the jump instructions.
+                // This guards against such packets.
+                if jumps_count >= MAX_JUMP {
+                    return Err(ReaderError::TooManyJumps(MAX_JUMP));
+                }
+                // Read another byte, calculate offset and perform the jump by
+                // updating our local position variable
+                let b2 = self.get(position + 1)? as u16;
+                next_position.get_or_insert(position + 2);
+                position = ((((length as u16) ^ 0xC0) << 8) | b2) as usize;
+                jumps_count += 1;
+            } else if (length & 0xC0) != 0 {
+                // The 0x40 and 0x80 prefixes are reserved (RFC 1035 section 4.1.4)
+                return Err(ReaderError::InvalidLabelType(length));
+            } else if length == 0 {
+                // Domain names are terminated by an empty label of length 0,
+                // so if the length is zero we're done.
+                position += 1;
+                break;
+            } else {
+                // The base scenario, where we're reading a single label and
+                // appending it to the output
+                let length = length as usize;
+                encoded_length += 1 + length;
+                if encoded_length > MAX_NAME_LENGTH {
+                    return Err(ReaderError::NameTooLong(encoded_length));
+                }
+                if !output.is_empty() {
+                    output.push(b'.');
+                }
+                // Extract the actual bytes for this label and append them
+                // to the output buffer.
+                let label = self.get_range(position + 1, length)?;
+                // a dot within a label can't be told apart from the separators once read
+                if let Some(invalid) = label
+                    .iter()
+                    .find(|c| **c == b'.' || (strict && c.is_ascii_control()))
+                {
+                    return Err(ReaderError::InvalidCharacter(*invalid));
+                }
+                output
+                    .try_extend_from_slice(label)
+                    .expect("bounded name length");
+                position += 1 + length;
+            }
+        }
+
+        if strict {
+            if let Err(error) = std::str::from_utf8(&output) {
+                return Err(ReaderError::InvalidCharacter(output[error.valid_up_to()]));
+            }
+        }
+        self.seek(next_position.unwrap_or(position))?;
+        // the name is kept as received, with its case (RFC 4343)
+        Ok(String::from_utf8_lossy(&output).into_owned())
     }
 }
 
@@ -225,7 +291,6 @@ mod tests {
 
     #[test]
     fn should_read_qname_with_redirect() {
-        println!("{}", 0xC2);
         let mut buffer = crate::buffer::BytePacketBuffer::default();
         buffer.buf[0] = 1;
         buffer.buf[1] = b'b';
@@ -239,5 +304,25 @@ mod tests {
         buffer.pos = 5;
         let result = buffer.read_qname().unwrap();
         assert_eq!(result, "d.c");
+        // the name ends with the pointer, not with the labels it refers to
+        assert_eq!(buffer.pos, 9);
+    }
+
+    #[test]
+    fn should_fail_read_qname_with_reserved_label_type() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        buffer.buf[0] = 0x40;
+        let error = buffer.read_qname().unwrap_err();
+        assert_eq!(error, super::ReaderError::InvalidLabelType(0x40));
+    }
+
+    #[test]
+    fn should_get_range_until_end_of_buffer() {
+        let buffer = crate::buffer::BytePacketBuffer::default();
+        assert_eq!(buffer.get_range(508, 4).unwrap().len(), 4);
+        assert_eq!(
+            buffer.get_range(509, 4).unwrap_err(),
+            super::ReaderError::EndOfBuffer
+        );
     }
 }
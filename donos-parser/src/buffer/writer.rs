@@ -1,11 +1,16 @@
 use std::fmt::Display;
 
-use super::BytePacketBuffer;
+use super::{BytePacketBuffer, MAX_PACKET_SIZE};
+
+/// Compression pointers are 14 bits long, names after this offset can't be referenced
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
 
 #[derive(Debug)]
 pub enum WriterError {
     EndOfBuffer,
     SingleLabelLengh,
+    NameTooLong(usize),
+    StringTooLong(usize),
 }
 
 impl Display for WriterError {
@@ -13,6 +18,8 @@ impl Display for WriterError {
         match self {
             Self::EndOfBuffer => write!(f, "end of buffer"),
             Self::SingleLabelLengh => write!(f, "invalid label length"),
+            Self::NameTooLong(size) => write!(f, "name of {size} bytes is too long"),
+            Self::StringTooLong(size) => write!(f, "character string of {size} bytes is too long"),
         }
     }
 }
@@ -29,13 +36,24 @@ impl From<WriterError> for std::io::Error {
                 std::io::ErrorKind::InvalidData,
                 "single label too long when writing",
             ),
+            WriterError::NameTooLong(size) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("name too long when writing: {size}"),
+            ),
+            WriterError::StringTooLong(size) => std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("character string too long when writing: {size}"),
+            ),
         }
     }
 }
 
 impl BytePacketBuffer {
     fn set(&mut self, pos: usize, val: u8) -> Result<(), WriterError> {
-        self.buf[pos] = val;
+        let Some(item) = self.buf.get_mut(pos) else {
+            return Err(WriterError::EndOfBuffer);
+        };
+        *item = val;
 
         Ok(())
     }
@@ -48,10 +66,16 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<(), WriterError> {
-        if self.pos >= 512 {
+        if self.pos >= MAX_PACKET_SIZE {
             return Err(WriterError::EndOfBuffer);
         }
-        self.buf[self.pos] = val;
+        match self.buf.get_mut(self.pos) {
+            Some(item) => *item = val,
+            None => {
+                self.buf.resize(self.pos, 0);
+                self.buf.push(val);
+            }
+        }
         self.pos += 1;
         Ok(())
     }
@@ -79,13 +103,17 @@ impl BytePacketBuffer {
     }
 
     fn write_label(&mut self, label: &str) -> Result<(), WriterError> {
-        let len = label.len() as u8;
-        if len > 0x3f {
+        // an empty label would end the name early, like in `a..b`
+        if label.is_empty() || label.len() > 0x3f {
             return Err(WriterError::SingleLabelLengh);
         }
-        self.write_u8(len)?;
+        self.write_u8(label.len() as u8)?;
         for b in label.as_bytes() {
-            self.write_u8(*b)?;
+            self.write_u8(if self.lowercase {
+                b.to_ascii_lowercase()
+            } else {
+                *b
+            })?;
         }
         Ok(())
     }
@@ -95,7 +123,9 @@ impl BytePacketBuffer {
             self.write_u16(0xC000 | (*index as u16))?;
             Ok(true)
         } else {
-            self.writing_labels.insert(qname.to_string(), self.pos());
+            if self.pos() <= MAX_POINTER_OFFSET {
+                self.writing_labels.insert(qname.to_string(), self.pos());
+            }
             if let Some((head, tail)) = qname.split_once('.') {
                 self.write_label(head)?;
                 self.recursive_write_qname(tail)
@@ -106,13 +136,42 @@ impl BytePacketBuffer {
         }
     }
 
+    /// Write a qname
+    ///
+    /// A trailing dot is ignored, and the root domain (`""` or `"."`) is written
+    /// as a single null label.
     pub fn write_qname(&mut self, qname: &str) -> Result<(), WriterError> {
+        if !self.compression {
+            return self.write_uncompressed_qname(qname);
+        }
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+        if qname.is_empty() {
+            return self.write_u8(0);
+        }
+        let encoded_length = qname.len() + 2;
+        if encoded_length > super::reader::MAX_NAME_LENGTH {
+            return Err(WriterError::NameTooLong(encoded_length));
+        }
         if !self.recursive_write_qname(qname)? {
             self.write_u8(0)?;
         }
 
         Ok(())
     }
+
+    /// Write a qname without compression, for the record data where it's forbidden
+    /// like the target of the service bindings (RFC 9460 section 2.2)
+    pub fn write_uncompressed_qname(&mut self, qname: &str) -> Result<(), WriterError> {
+        let qname = qname.strip_suffix('.').unwrap_or(qname);
+        let encoded_length = qname.len() + 2;
+        if encoded_length > super::reader::MAX_NAME_LENGTH {
+            return Err(WriterError::NameTooLong(encoded_length));
+        }
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
+            self.write_label(label)?;
+        }
+        self.write_u8(0)
+    }
 }
 
 #[cfg(test)]
@@ -121,9 +180,30 @@ mod tests {
     fn should_write_empty_qname() {
         let mut buffer = crate::buffer::BytePacketBuffer::default();
         buffer.write_qname("").unwrap();
-        assert_eq!(buffer.pos, 2);
+        assert_eq!(buffer.pos, 1);
         assert_eq!(buffer.buf[0], 0);
-        assert_eq!(buffer.buf[1], 0);
+    }
+
+    #[test]
+    fn should_write_root_qname() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        buffer.write_qname(".").unwrap();
+        assert_eq!(buffer.pos, 1);
+        assert_eq!(buffer.buf[0], 0);
+    }
+
+    #[test]
+    fn should_write_qname_with_trailing_dot() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        buffer.write_qname("foo.bar.").unwrap();
+        buffer.write_qname("foo.bar").unwrap();
+        assert_eq!(buffer.buf[0], 3);
+        assert_eq!(buffer.buf[4], 3);
+        assert_eq!(buffer.buf[8], 0);
+        // the second one is a redirect to the first one
+        assert_eq!(buffer.buf[9], 0xC0);
+        assert_eq!(buffer.buf[10], 0x00);
+        assert_eq!(buffer.pos, 11);
     }
 
     #[test]
@@ -146,6 +226,38 @@ mod tests {
         assert_eq!(buffer.pos, 13);
     }
 
+    #[test]
+    fn should_grow_over_udp_size() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        for _ in 0..200 {
+            buffer.write_u32(42).unwrap();
+        }
+        assert_eq!(buffer.pos, 800);
+        assert_eq!(buffer.buf.len(), 800);
+        assert_eq!(&buffer.buf[796..800], &[0, 0, 0, 42]);
+    }
+
+    #[test]
+    fn should_fail_writing_over_max_size() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        buffer.step(crate::buffer::MAX_PACKET_SIZE - 1).unwrap();
+        buffer.write_u8(1).unwrap();
+        assert!(matches!(
+            buffer.write_u8(1).unwrap_err(),
+            super::WriterError::EndOfBuffer
+        ));
+    }
+
+    #[test]
+    fn should_not_redirect_after_pointer_range() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        buffer.step(0x4000).unwrap();
+        buffer.write_qname("foo.bar").unwrap();
+        buffer.write_qname("foo.bar").unwrap();
+        // written twice, without any pointer
+        assert_eq!(buffer.pos, 0x4000 + 9 * 2);
+    }
+
     #[test]
     fn should_write_qname_with_redirect() {
         let mut buffer = crate::buffer::BytePacketBuffer::default();
@@ -216,4 +216,49 @@ mod tests {
         let created = packet.create_buffer().unwrap();
         assert_eq!(buffer.buf, created.buf);
     }
+
+    #[test]
+    fn should_write_and_read_root_ns_query() {
+        let packet = crate::packet::DnsPacket::new(crate::packet::header::Header::question(42))
+            .with_question(crate::packet::question::Question::new(
+                ".".into(),
+                crate::packet::QueryType::NS,
+            ));
+        let buffer = packet.create_buffer().unwrap();
+        // header, then a single null label for the root, the type and the class
+        assert_eq!(buffer.pos, 12 + 1 + 2 + 2);
+        assert_eq!(&buffer.buf[12..17], &[0, 0, 2, 0, 1]);
+
+        let buffer = crate::buffer::BytePacketBuffer::new(buffer.buf);
+        let result = crate::packet::DnsPacket::try_from(buffer).unwrap();
+        assert_eq!(result.questions.len(), 1);
+        assert_eq!(result.questions[0].name, "");
+        assert_eq!(result.questions[0].qtype, crate::packet::QueryType::NS);
+    }
+
+    #[test]
+    fn should_write_response_in_query_buffer() {
+        let mut buffer = crate::buffer::BytePacketBuffer::default();
+        copy_to(
+            include_bytes!("../data/googlecom_query.bin"),
+            &mut buffer.buf,
+        );
+        let query = crate::packet::DnsPacket::read(&mut buffer).unwrap();
+
+        let mut response = crate::buffer::BytePacketBuffer::default();
+        copy_to(
+            include_bytes!("../data/googlecom_response.bin"),
+            &mut response.buf,
+        );
+        let response = crate::packet::DnsPacket::try_from(response).unwrap();
+        assert_eq!(query.header.id, response.header.id);
+
+        let capacity = buffer.buf.capacity();
+        let written = response
+            .write_into(crate::buffer::BytePacketBuffer::reuse(buffer.buf))
+            .unwrap();
+        let created = response.create_buffer().unwrap();
+        assert_eq!(written.buf.capacity(), capacity);
+        assert_eq!(&written.buf[..written.pos], &created.buf[..created.pos]);
+    }
 }
@@ -0,0 +1,576 @@
+//! Presentation format of the records, the one of the zone files (RFC 1035 section 5.1),
+//! and dig-like rendering of the whole packets.
+use super::header::{Header, ResponseCode};
+use super::question::{DnsClass, Question};
+use super::record::Record;
+use super::svcb::SvcParam;
+use super::{DnsPacket, QueryType};
+use std::fmt::{Display, Formatter, Result, Write};
+
+impl Display for QueryType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let name = match self {
+            Self::Unknown(num) => return write!(f, "TYPE{num}"),
+            Self::A => "A",
+            Self::NS => "NS",
+            Self::CNAME => "CNAME",
+            Self::SOA => "SOA",
+            Self::PTR => "PTR",
+            Self::MX => "MX",
+            Self::TXT => "TXT",
+            Self::AAAA => "AAAA",
+            Self::OPT => "OPT",
+            Self::DS => "DS",
+            Self::RRSIG => "RRSIG",
+            Self::NSEC => "NSEC",
+            Self::DNSKEY => "DNSKEY",
+            Self::NSEC3 => "NSEC3",
+            Self::SVCB => "SVCB",
+            Self::HTTPS => "HTTPS",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Display for DnsClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(match self {
+            Self::Internet => "IN",
+            Self::Csnet => "CS",
+            Self::Chaos => "CH",
+            Self::Hesiod => "HS",
+        })
+    }
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(match self {
+            Self::NoError => "NOERROR",
+            Self::FormatError => "FORMERR",
+            Self::ServerFailure => "SERVFAIL",
+            Self::NameError => "NXDOMAIN",
+            Self::NotImplemented => "NOTIMP",
+            Self::Refused => "REFUSED",
+        })
+    }
+}
+
+/// Absolute name, with its trailing dot and the special characters escaped
+struct Name<'a>(&'a str);
+
+impl Display for Name<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for byte in self.0.trim_end_matches('.').bytes() {
+            match byte {
+                b'"' | b'(' | b')' | b';' | b'@' | b'$' | b'\\' | b' ' => {
+                    write!(f, "\\{}", byte as char)?
+                }
+                0x21..=0x7e => f.write_char(byte as char)?,
+                other => write!(f, "\\{other:03}")?,
+            }
+        }
+        f.write_char('.')
+    }
+}
+
+/// Character string between quotes, the non printable characters as `\DDD`
+struct CharacterString<'a>(&'a [u8]);
+
+impl Display for CharacterString<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_char('"')?;
+        for byte in self.0 {
+            match byte {
+                b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+                0x20..=0x7e => f.write_char(*byte as char)?,
+                other => write!(f, "\\{other:03}")?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+struct Base64<'a>(&'a [u8]);
+
+impl Display for Base64<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        for chunk in self.0.chunks(3) {
+            let value = chunk.iter().enumerate().fold(0u32, |acc, (index, byte)| {
+                acc | (*byte as u32) << (16 - 8 * index)
+            });
+            for index in 0..4 {
+                if index <= chunk.len() {
+                    let sextet = (value >> (18 - 6 * index)) & 0x3f;
+                    f.write_char(ALPHABET[sextet as usize] as char)?;
+                } else {
+                    f.write_char('=')?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Base32 with the extended hex alphabet and without padding, for the hashed names of NSEC3
+struct Base32Hex<'a>(&'a [u8]);
+
+impl Display for Base32Hex<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+        for chunk in self.0.chunks(5) {
+            let value = chunk.iter().enumerate().fold(0u64, |acc, (index, byte)| {
+                acc | (*byte as u64) << (32 - 8 * index)
+            });
+            // each byte spreads over the next 5 bits character
+            for index in 0..(chunk.len() * 8).div_ceil(5) {
+                let quintet = (value >> (35 - 5 * index)) & 0x1f;
+                f.write_char(ALPHABET[quintet as usize] as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Types<'a>(&'a [u16]);
+
+impl Display for Types<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (index, kind) in self.0.iter().enumerate() {
+            if index > 0 {
+                f.write_char(' ')?;
+            }
+            write!(f, "{}", QueryType::from_num(*kind))?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of a key of the service parameters (RFC 9460 section 14.3.2)
+fn svc_key(f: &mut Formatter<'_>, key: u16) -> Result {
+    match key {
+        0 => f.write_str("mandatory"),
+        1 => f.write_str("alpn"),
+        2 => f.write_str("no-default-alpn"),
+        3 => f.write_str("port"),
+        4 => f.write_str("ipv4hint"),
+        5 => f.write_str("ech"),
+        6 => f.write_str("ipv6hint"),
+        other => write!(f, "key{other}"),
+    }
+}
+
+fn join<T: Display>(f: &mut Formatter<'_>, items: &[T]) -> Result {
+    for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+            f.write_char(',')?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+impl Display for SvcParam {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        svc_key(f, self.key())?;
+        match self {
+            Self::NoDefaultAlpn => Ok(()),
+            Self::Mandatory(keys) => {
+                f.write_char('=')?;
+                for (index, key) in keys.iter().enumerate() {
+                    if index > 0 {
+                        f.write_char(',')?;
+                    }
+                    svc_key(f, *key)?;
+                }
+                Ok(())
+            }
+            Self::Alpn(protocols) => {
+                f.write_char('=')?;
+                let protocols = protocols
+                    .iter()
+                    .map(|protocol| String::from_utf8_lossy(protocol))
+                    .collect::<Vec<_>>();
+                join(f, &protocols)
+            }
+            Self::Port(port) => write!(f, "={port}"),
+            Self::Ipv4Hint(addrs) => {
+                f.write_char('=')?;
+                join(f, addrs)
+            }
+            Self::Ech(value) => write!(f, "={}", Base64(value)),
+            Self::Ipv6Hint(addrs) => {
+                f.write_char('=')?;
+                join(f, addrs)
+            }
+            Self::Unknown { value, .. } => write!(f, "={}", CharacterString(value)),
+        }
+    }
+}
+
+/// Flags and extended response code held by the TTL of the OPT record (RFC 6891 section 6.1.3)
+fn edns(f: &mut Formatter<'_>, payload_size: u16, flags: u32) -> Result {
+    write!(f, "; EDNS: version: {}, flags:", (flags >> 16) & 0xff)?;
+    if flags & super::EDNS_DNSSEC_OK != 0 {
+        f.write_str(" do")?;
+    }
+    write!(f, "; udp: {payload_size}")
+}
+
+impl Display for Record {
+    /// Zone file line of the record, like `example.com. 300 IN A 1.2.3.4`.
+    ///
+    /// The OPT record, which doesn't belong to a zone, is rendered as a comment like dig does.
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if let Self::OPT {
+            payload_size,
+            flags,
+            ..
+        } = self
+        {
+            return edns(f, *payload_size, *flags);
+        }
+        write!(f, "{} {} IN ", Name(self.domain()), self.ttl())?;
+        match self {
+            Self::Unknown { qtype, data, .. } => {
+                // generic format of RFC 3597 section 5
+                write!(f, "TYPE{qtype} \\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " {}", Hex(data))?;
+                }
+                Ok(())
+            }
+            Self::A { addr, .. } => write!(f, "A {addr}"),
+            Self::NS { host, .. } => write!(f, "NS {}", Name(host)),
+            Self::CNAME { host, .. } => write!(f, "CNAME {}", Name(host)),
+            Self::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "SOA {} {} {serial} {refresh} {retry} {expire} {minimum}",
+                Name(mname),
+                Name(rname)
+            ),
+            Self::PTR { host, .. } => write!(f, "PTR {}", Name(host)),
+            Self::MX { priority, host, .. } => write!(f, "MX {priority} {}", Name(host)),
+            Self::TXT { data, .. } => {
+                f.write_str("TXT")?;
+                data.iter()
+                    .try_for_each(|item| write!(f, " {}", CharacterString(item)))
+            }
+            Self::AAAA { addr, .. } => write!(f, "AAAA {addr}"),
+            // written above
+            Self::OPT { .. } => Ok(()),
+            Self::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "DS {key_tag} {algorithm} {digest_type} {}",
+                Hex(digest)
+            ),
+            Self::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => write!(
+                f,
+                "RRSIG {} {algorithm} {labels} {original_ttl} {expiration} {inception} {key_tag} {} {}",
+                QueryType::from_num(*type_covered),
+                Name(signer),
+                Base64(signature)
+            ),
+            Self::NSEC { next, types, .. } => {
+                write!(f, "NSEC {} {}", Name(next), Types(types))
+            }
+            Self::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "DNSKEY {flags} {protocol} {algorithm} {}",
+                Base64(public_key)
+            ),
+            Self::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                write!(f, "NSEC3 {hash_algorithm} {flags} {iterations} ")?;
+                // an empty salt is written as a dash (RFC 5155 section 3.3)
+                if salt.is_empty() {
+                    f.write_char('-')?;
+                } else {
+                    write!(f, "{}", Hex(salt))?;
+                }
+                write!(f, " {} {}", Base32Hex(next_hashed), Types(types))
+            }
+            Self::SVCB {
+                priority,
+                target,
+                params,
+                ..
+            }
+            | Self::HTTPS {
+                priority,
+                target,
+                params,
+                ..
+            } => {
+                write!(f, "{} {priority} {}", self.qtype(), Name(target))?;
+                params.iter().try_for_each(|param| write!(f, " {param}"))
+            }
+        }
+    }
+}
+
+impl Display for Question {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{} {} {}", Name(&self.name), self.qclass, self.qtype)
+    }
+}
+
+fn opcode(f: &mut Formatter<'_>, value: u8) -> Result {
+    match value {
+        0 => f.write_str("QUERY"),
+        1 => f.write_str("IQUERY"),
+        2 => f.write_str("STATUS"),
+        4 => f.write_str("NOTIFY"),
+        5 => f.write_str("UPDATE"),
+        other => write!(f, "{other}"),
+    }
+}
+
+fn flags(f: &mut Formatter<'_>, header: &Header) -> Result {
+    f.write_str(";; flags:")?;
+    for (name, set) in [
+        ("qr", header.response),
+        ("aa", header.authoritative_answer),
+        ("tc", header.truncated_message),
+        ("rd", header.recursion_desired),
+        ("ra", header.recursion_available),
+        ("ad", header.authed_data),
+        ("cd", header.checking_disabled),
+    ] {
+        if set {
+            write!(f, " {name}")?;
+        }
+    }
+    Ok(())
+}
+
+fn section(f: &mut Formatter<'_>, name: &str, records: &[&Record]) -> Result {
+    if records.is_empty() {
+        return Ok(());
+    }
+    writeln!(f)?;
+    writeln!(f, ";; {name} SECTION:")?;
+    records
+        .iter()
+        .try_for_each(|record| writeln!(f, "{record}"))
+}
+
+impl Display for DnsPacket {
+    /// Header, flags and sections of the packet, the way dig prints them
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        f.write_str(";; ->>HEADER<<- opcode: ")?;
+        opcode(f, self.header.opcode)?;
+        writeln!(
+            f,
+            ", status: {}, id: {}",
+            self.header.response_code, self.header.id
+        )?;
+        flags(f, &self.header)?;
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        )?;
+        let (pseudo, resources): (Vec<_>, Vec<_>) = self
+            .resources
+            .iter()
+            .partition(|record| matches!(record, Record::OPT { .. }));
+        if !pseudo.is_empty() {
+            writeln!(f)?;
+            writeln!(f, ";; OPT PSEUDOSECTION:")?;
+            pseudo
+                .iter()
+                .try_for_each(|record| writeln!(f, "{record}"))?;
+        }
+        if !self.questions.is_empty() {
+            writeln!(f)?;
+            writeln!(f, ";; QUESTION SECTION:")?;
+            self.questions
+                .iter()
+                .try_for_each(|question| writeln!(f, ";{question}"))?;
+        }
+        section(f, "ANSWER", &self.answers.iter().collect::<Vec<_>>())?;
+        section(f, "AUTHORITY", &self.authorities.iter().collect::<Vec<_>>())?;
+        section(f, "ADDITIONAL", &resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::header::Header;
+    use crate::packet::question::Question;
+    use crate::packet::record::Record;
+    use crate::packet::svcb::SvcParam;
+    use crate::packet::{DnsPacket, QueryType, EDNS_DNSSEC_OK};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn should_display_records_in_zone_format() {
+        for (record, expected) in [
+            (
+                Record::A {
+                    domain: "example.com".into(),
+                    addr: Ipv4Addr::new(1, 2, 3, 4),
+                    ttl: 300,
+                },
+                "example.com. 300 IN A 1.2.3.4",
+            ),
+            (
+                Record::TXT {
+                    domain: "example.com".into(),
+                    data: vec![b"v=ok".to_vec(), b"say \"hi\"\n".to_vec()],
+                    ttl: 60,
+                },
+                r#"example.com. 60 IN TXT "v=ok" "say \"hi\"\010""#,
+            ),
+            (
+                Record::SOA {
+                    domain: String::new(),
+                    mname: "a.root-servers.net".into(),
+                    rname: "nstld.verisign-grs.com".into(),
+                    serial: 2024010100,
+                    refresh: 1800,
+                    retry: 900,
+                    expire: 604800,
+                    minimum: 86400,
+                    ttl: 86400,
+                },
+                ". 86400 IN SOA a.root-servers.net. nstld.verisign-grs.com. 2024010100 1800 900 604800 86400",
+            ),
+            (
+                Record::Unknown {
+                    domain: "example.com".into(),
+                    qtype: 99,
+                    data: vec![0xca, 0xfe],
+                    ttl: 10,
+                },
+                r"example.com. 10 IN TYPE99 \# 2 CAFE",
+            ),
+            (
+                Record::DNSKEY {
+                    domain: "example.com".into(),
+                    flags: 257,
+                    protocol: 3,
+                    algorithm: 13,
+                    public_key: b"hello".to_vec(),
+                    ttl: 3600,
+                },
+                "example.com. 3600 IN DNSKEY 257 3 13 aGVsbG8=",
+            ),
+            (
+                Record::NSEC3 {
+                    domain: "example.com".into(),
+                    hash_algorithm: 1,
+                    flags: 0,
+                    iterations: 0,
+                    salt: Vec::new(),
+                    next_hashed: b"foobar".to_vec(),
+                    types: vec![1, 46],
+                    ttl: 3600,
+                },
+                "example.com. 3600 IN NSEC3 1 0 0 - CPNMUOJ1E8 A RRSIG",
+            ),
+            (
+                Record::HTTPS {
+                    domain: "example.com".into(),
+                    priority: 1,
+                    target: String::new(),
+                    params: vec![
+                        SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]),
+                        SvcParam::Ipv4Hint(vec![Ipv4Addr::new(1, 2, 3, 4)]),
+                    ],
+                    ttl: 300,
+                },
+                "example.com. 300 IN HTTPS 1 . alpn=h2,h3 ipv4hint=1.2.3.4",
+            ),
+        ] {
+            assert_eq!(record.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn should_display_packet_like_dig() {
+        let header = Header::response(1234)
+            .with_recursion_desired(true)
+            .with_recursion_available(true);
+        let packet = DnsPacket::new(header)
+            .with_question(Question::new("example.com".into(), QueryType::A))
+            .with_answer(Record::A {
+                domain: "example.com".into(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 300,
+            })
+            .with_resource(Record::OPT {
+                payload_size: 1232,
+                flags: EDNS_DNSSEC_OK,
+                data: Vec::new(),
+            });
+        assert_eq!(
+            packet.to_string(),
+            r#";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 1234
+;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags: do; udp: 1232
+
+;; QUESTION SECTION:
+;example.com. IN A
+
+;; ANSWER SECTION:
+example.com. 300 IN A 1.2.3.4
+"#
+        );
+    }
+}
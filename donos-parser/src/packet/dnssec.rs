@@ -0,0 +1,128 @@
+//! Type bit maps of the NSEC and NSEC3 records (RFC 4034, section 4.1.2)
+use crate::buffer::reader::ReaderError;
+use crate::buffer::writer::WriterError;
+use crate::buffer::BytePacketBuffer;
+
+/// Reads the types listed by the bit maps, until the end of the record data
+pub(crate) fn read_types(
+    buffer: &mut BytePacketBuffer,
+    end: usize,
+    data_len: u16,
+) -> Result<Vec<u16>, ReaderError> {
+    let mut types = Vec::new();
+    let mut previous = None;
+    while buffer.pos() < end {
+        let window = buffer.read()? as u16;
+        let size = buffer.read()? as usize;
+        if size == 0 || size > 32 || buffer.pos() + size > end {
+            return Err(ReaderError::InvalidDataLength(data_len));
+        }
+        // the windows come in increasing order, each of them once (RFC 4034 section 4.1.2)
+        if previous.is_some_and(|previous| window <= previous) {
+            return Err(ReaderError::InvalidDataLength(data_len));
+        }
+        previous = Some(window);
+        let bitmap = buffer.get_range(buffer.pos(), size)?;
+        for (index, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push((window << 8) | (index as u16 * 8 + bit));
+                }
+            }
+        }
+        buffer.step(size)?;
+    }
+    if buffer.pos() != end {
+        return Err(ReaderError::InvalidDataLength(data_len));
+    }
+    Ok(types)
+}
+
+/// Writes the bit maps of the types, one per window holding at least one of them
+pub(crate) fn write_types(buffer: &mut BytePacketBuffer, types: &[u16]) -> Result<(), WriterError> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        let mut size = 0;
+        for qtype in window {
+            let low = (qtype & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            size = low / 8 + 1;
+        }
+        buffer.write_u8((window[0] >> 8) as u8)?;
+        buffer.write_u8(size as u8)?;
+        for b in &bitmap[..size] {
+            buffer.write_u8(*b)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the remaining bytes of the record data, like a key or a signature
+pub(crate) fn read_remaining(
+    buffer: &mut BytePacketBuffer,
+    end: usize,
+    data_len: u16,
+) -> Result<Vec<u8>, ReaderError> {
+    let size = end
+        .checked_sub(buffer.pos())
+        .ok_or(ReaderError::InvalidDataLength(data_len))?;
+    let data = buffer.get_range(buffer.pos(), size)?.to_vec();
+    buffer.step(size)?;
+    Ok(data)
+}
+
+/// Reads a string prefixed by its size, like the salt and the next hashed name of NSEC3
+pub(crate) fn read_sized(
+    buffer: &mut BytePacketBuffer,
+    end: usize,
+    data_len: u16,
+) -> Result<Vec<u8>, ReaderError> {
+    let size = buffer.read()? as usize;
+    if buffer.pos() + size > end {
+        return Err(ReaderError::InvalidDataLength(data_len));
+    }
+    let data = buffer.get_range(buffer.pos(), size)?.to_vec();
+    buffer.step(size)?;
+    Ok(data)
+}
+
+pub(crate) fn write_sized(buffer: &mut BytePacketBuffer, data: &[u8]) -> Result<(), WriterError> {
+    let size = u8::try_from(data.len()).map_err(|_| WriterError::StringTooLong(data.len()))?;
+    buffer.write_u8(size)?;
+    write_bytes(buffer, data)
+}
+
+pub(crate) fn write_bytes(buffer: &mut BytePacketBuffer, data: &[u8]) -> Result<(), WriterError> {
+    for b in data {
+        buffer.write_u8(*b)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_types, write_types};
+    use crate::buffer::BytePacketBuffer;
+
+    #[test]
+    fn should_write_back_the_types() {
+        // A, MX, RRSIG, NSEC and CAA, in two windows
+        let mut buffer = BytePacketBuffer::default();
+        write_types(&mut buffer, &[257, 1, 15, 46, 47, 1]).unwrap();
+        let end = buffer.pos();
+        assert_eq!(
+            &buffer.buf[..end],
+            &[0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x01, 0x40]
+        );
+
+        buffer.pos = 0;
+        assert_eq!(
+            read_types(&mut buffer, end, end as u16).unwrap(),
+            vec![1, 15, 46, 47, 257]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+//! Builders of unusual but legal messages, so that the way they are handled
+//! is specified by tests instead of being accidental.
+//!
+//! The messages are returned as raw bytes since some of them, like the EDNS
+//! ones, can't be represented by a [`DnsPacket`].
+use super::header::Header;
+use super::question::Question;
+use super::{DnsPacket, QueryType, EDNS_DNSSEC_OK};
+use crate::buffer::writer::WriterError;
+use crate::buffer::BytePacketBuffer;
+
+/// NOTIFY operation code (RFC 1996)
+pub const OPCODE_NOTIFY: u8 = 4;
+
+/// Position of the additional records count in the header
+const ARCOUNT_OFFSET: usize = 10;
+
+/// How closely a generated query follows what a regular stub resolver sends
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compliance {
+    /// Sets the RD bit
+    pub recursion_desired: bool,
+    /// Adds an OPT record advertising this UDP payload size (RFC 6891)
+    pub edns: Option<u16>,
+    /// Sets the DO bit of the OPT record, only used with `edns`
+    pub dnssec_ok: bool,
+}
+
+impl Default for Compliance {
+    fn default() -> Self {
+        Self {
+            recursion_desired: true,
+            edns: None,
+            dnssec_ok: false,
+        }
+    }
+}
+
+impl Compliance {
+    pub fn with_edns(mut self, udp_payload_size: u16) -> Self {
+        self.edns = Some(udp_payload_size);
+        self
+    }
+
+    pub fn with_dnssec_ok(mut self) -> Self {
+        self.dnssec_ok = true;
+        self
+    }
+
+    pub fn without_recursion(mut self) -> Self {
+        self.recursion_desired = false;
+        self
+    }
+}
+
+/// Query holding all the given questions, in order, possibly none
+pub fn query(
+    id: u16,
+    questions: impl IntoIterator<Item = Question>,
+    compliance: &Compliance,
+) -> Result<BytePacketBuffer, WriterError> {
+    let header = Header::question(id).with_recursion_desired(compliance.recursion_desired);
+    let packet = DnsPacket {
+        header,
+        questions: questions.into_iter().collect(),
+        ..Default::default()
+    };
+    let mut buffer = packet.create_buffer()?;
+    if let Some(size) = compliance.edns {
+        write_opt(&mut buffer, size, compliance.dnssec_ok)?;
+        // the OPT record is the only additional record
+        buffer.set_u16(ARCOUNT_OFFSET, 1)?;
+    }
+    Ok(buffer)
+}
+
+/// NOTIFY message without any question, like the ones sent by some
+/// primary servers to check that a secondary is alive
+pub fn empty_notify(id: u16) -> Result<BytePacketBuffer, WriterError> {
+    let header = Header::question(id)
+        .with_opcode(OPCODE_NOTIFY)
+        .with_authoritative_answer(true);
+    DnsPacket::new(header).create_buffer()
+}
+
+/// Query with no question and only an OPT record, like the ones used
+/// to probe the EDNS support of a server
+pub fn edns_only(id: u16, udp_payload_size: u16) -> Result<BytePacketBuffer, WriterError> {
+    query(id, [], &Compliance::default().with_edns(udp_payload_size))
+}
+
+fn write_opt(
+    buffer: &mut BytePacketBuffer,
+    udp_payload_size: u16,
+    dnssec_ok: bool,
+) -> Result<(), WriterError> {
+    // root name
+    buffer.write_u8(0)?;
+    buffer.write_u16(QueryType::OPT.into_num())?;
+    // the class holds the payload size
+    buffer.write_u16(udp_payload_size)?;
+    // extended rcode, version, then the flags with DO as the highest bit
+    buffer.write_u32(if dnssec_ok { EDNS_DNSSEC_OK } else { 0 })?;
+    // no option
+    buffer.write_u16(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Compliance;
+    use crate::buffer::BytePacketBuffer;
+    use crate::packet::question::Question;
+    use crate::packet::{DnsPacket, QueryType};
+
+    #[test]
+    fn should_write_opt_record() {
+        let buffer = super::query(
+            1,
+            [Question::new("perdu.com".into(), QueryType::A)],
+            &Compliance::default().with_edns(1232).with_dnssec_ok(),
+        )
+        .unwrap();
+        // arcount
+        assert_eq!(&buffer.buf[10..12], &[0x00, 0x01]);
+        assert_eq!(
+            &buffer.buf[buffer.pos - 11..buffer.pos],
+            &[0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00]
+        );
+
+        let packet = DnsPacket::try_from(BytePacketBuffer::new(buffer.buf)).unwrap();
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(packet.resources.len(), 1);
+        assert!(packet.dnssec_ok());
+    }
+}
@@ -3,6 +3,7 @@ use crate::buffer::writer::WriterError;
 use crate::buffer::BytePacketBuffer;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub enum ResponseCode {
     /// No error condition
     NoError = 0,
@@ -39,6 +40,7 @@ impl TryFrom<u8> for ResponseCode {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub struct Header {
     /// A 16 bit identifier assigned by the program that
     /// generates any kind of query.  This identifier is copied
@@ -119,6 +121,31 @@ impl Header {
         self.response_code = value;
         self
     }
+
+    pub fn with_opcode(mut self, value: u8) -> Self {
+        self.opcode = value;
+        self
+    }
+
+    pub fn with_recursion_desired(mut self, value: bool) -> Self {
+        self.recursion_desired = value;
+        self
+    }
+
+    pub fn with_recursion_available(mut self, value: bool) -> Self {
+        self.recursion_available = value;
+        self
+    }
+
+    pub fn with_authoritative_answer(mut self, value: bool) -> Self {
+        self.authoritative_answer = value;
+        self
+    }
+
+    pub fn with_truncated_message(mut self, value: bool) -> Self {
+        self.truncated_message = value;
+        self
+    }
 }
 
 impl Default for Header {
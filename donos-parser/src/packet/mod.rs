@@ -1,12 +1,24 @@
+mod display;
+pub mod dnssec;
+pub mod generate;
 pub mod header;
 pub mod question;
 pub mod record;
+pub mod svcb;
 
 use crate::buffer::reader::ReaderError;
 use crate::buffer::writer::WriterError;
 use crate::buffer::BytePacketBuffer;
 
+/// Smallest question, the root name followed by its type and class
+const MIN_QUESTION_SIZE: usize = 5;
+/// Smallest record, the root name followed by its type, class, ttl and data length
+const MIN_RECORD_SIZE: usize = 11;
+/// Entries allocated upfront in a section, the larger sections growing as they're read
+const MAX_PREALLOCATED_ENTRIES: usize = 16;
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[allow(clippy::upper_case_acronyms)]
 pub enum QueryType {
     Unknown(u16),
@@ -16,9 +28,30 @@ pub enum QueryType {
     NS, // 2
     /// the canonical name for an alias
     CNAME, // 5
+    /// marks the start of a zone of authority
+    SOA, // 6
+    PTR, // 12
     /// mail exchange
     MX, // 15
+    /// text strings
+    TXT, // 16
     AAAA, // 28
+    /// EDNS pseudo record
+    OPT, // 41
+    /// delegation signer
+    DS, // 43
+    /// signature of a set of records
+    RRSIG, // 46
+    /// next secure record, proving a name or type doesn't exist
+    NSEC, // 47
+    /// public key of a zone
+    DNSKEY, // 48
+    /// hashed next secure record
+    NSEC3, // 50
+    /// service binding
+    SVCB, // 64
+    /// service binding of an https origin
+    HTTPS, // 65
 }
 
 impl QueryType {
@@ -28,8 +61,19 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::OPT => 41,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
         }
     }
 
@@ -39,14 +83,73 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            41 => QueryType::OPT,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
             _ => QueryType::Unknown(num),
         }
     }
 }
 
+/// Name that is neither the mnemonic of a [`QueryType`] nor `TYPE<number>`
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidQueryType(pub String);
+
+impl std::fmt::Display for InvalidQueryType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown query type {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidQueryType {}
+
+impl std::str::FromStr for QueryType {
+    type Err = InvalidQueryType;
+
+    /// Reads a mnemonic like `AAAA`, in any case, or the generic `TYPE65` of RFC 3597
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let found = match value.to_ascii_uppercase().as_str() {
+            "A" => QueryType::A,
+            "NS" => QueryType::NS,
+            "CNAME" => QueryType::CNAME,
+            "SOA" => QueryType::SOA,
+            "PTR" => QueryType::PTR,
+            "MX" => QueryType::MX,
+            "TXT" => QueryType::TXT,
+            "AAAA" => QueryType::AAAA,
+            "OPT" => QueryType::OPT,
+            "DS" => QueryType::DS,
+            "RRSIG" => QueryType::RRSIG,
+            "NSEC" => QueryType::NSEC,
+            "DNSKEY" => QueryType::DNSKEY,
+            "NSEC3" => QueryType::NSEC3,
+            "SVCB" => QueryType::SVCB,
+            "HTTPS" => QueryType::HTTPS,
+            other => other
+                .strip_prefix("TYPE")
+                .and_then(|num| num.parse().ok())
+                .map(QueryType::from_num)
+                .ok_or_else(|| InvalidQueryType(value.to_string()))?,
+        };
+        Ok(found)
+    }
+}
+
+/// DO bit of the EDNS flags, set by the resolvers wanting the DNSSEC records (RFC 3225)
+pub const EDNS_DNSSEC_OK: u32 = 0x8000;
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub struct DnsPacket {
     pub header: header::Header,
     pub questions: Vec<question::Question>,
@@ -71,6 +174,16 @@ impl DnsPacket {
         }
     }
 
+    /// Query of a single question asking for recursion, the way stub resolvers send them
+    pub fn query(id: u16, question: question::Question) -> Self {
+        Self::new(header::Header::question(id).with_recursion_desired(true)).with_question(question)
+    }
+
+    pub fn with_response_code(mut self, value: header::ResponseCode) -> Self {
+        self.header.response_code = value;
+        self
+    }
+
     pub fn with_question(mut self, question: question::Question) -> Self {
         self.questions.push(question);
         self
@@ -95,37 +208,63 @@ impl DnsPacket {
         self.resources.push(record);
         self
     }
+
+    /// OPT pseudo record of the message, sent by the EDNS aware resolvers
+    pub fn edns(&self) -> Option<&record::Record> {
+        self.resources
+            .iter()
+            .find(|record| matches!(record, record::Record::OPT { .. }))
+    }
+
+    /// Whether the sender wants the DNSSEC records, with the DO bit of its OPT record
+    pub fn dnssec_ok(&self) -> bool {
+        matches!(self.edns(), Some(record::Record::OPT { flags, .. }) if flags & EDNS_DNSSEC_OK != 0)
+    }
 }
 
 impl TryFrom<BytePacketBuffer> for DnsPacket {
     type Error = ReaderError;
 
     fn try_from(mut buffer: BytePacketBuffer) -> Result<Self, Self::Error> {
-        let header = header::Header::read(&mut buffer)?;
+        Self::read(&mut buffer)
+    }
+}
+
+impl DnsPacket {
+    /// Reads the packet without taking the buffer, for it to be reused with [`DnsPacket::write_into`]
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Self, ReaderError> {
+        let header = header::Header::read(buffer)?;
 
         let question_count = buffer.read_u16()? as usize;
         let answer_count = buffer.read_u16()? as usize;
         let authority_count = buffer.read_u16()? as usize;
         let resource_count = buffer.read_u16()? as usize;
 
-        let mut questions = Vec::with_capacity(question_count);
+        // a crafted header can claim far more entries than the packet holds
+        let record_count = answer_count + authority_count + resource_count;
+        let remaining = buffer.buf.len().saturating_sub(buffer.pos);
+        if question_count * MIN_QUESTION_SIZE + record_count * MIN_RECORD_SIZE > remaining {
+            return Err(ReaderError::TooManyEntries(question_count + record_count));
+        }
+
+        let mut questions = Vec::with_capacity(question_count.min(MAX_PREALLOCATED_ENTRIES));
         for _ in 0..question_count {
-            questions.push(question::Question::read(&mut buffer)?);
+            questions.push(question::Question::read(buffer)?);
         }
 
-        let mut answers = Vec::with_capacity(answer_count);
+        let mut answers = Vec::with_capacity(answer_count.min(MAX_PREALLOCATED_ENTRIES));
         for _ in 0..answer_count {
-            answers.push(record::Record::read(&mut buffer)?);
+            answers.push(record::Record::read(buffer)?);
         }
 
-        let mut authorities = Vec::with_capacity(authority_count);
+        let mut authorities = Vec::with_capacity(authority_count.min(MAX_PREALLOCATED_ENTRIES));
         for _ in 0..authority_count {
-            authorities.push(record::Record::read(&mut buffer)?);
+            authorities.push(record::Record::read(buffer)?);
         }
 
-        let mut resources = Vec::with_capacity(resource_count);
+        let mut resources = Vec::with_capacity(resource_count.min(MAX_PREALLOCATED_ENTRIES));
         for _ in 0..resource_count {
-            resources.push(record::Record::read(&mut buffer)?);
+            resources.push(record::Record::read(buffer)?);
         }
 
         Ok(DnsPacket {
@@ -136,11 +275,16 @@ impl TryFrom<BytePacketBuffer> for DnsPacket {
             resources,
         })
     }
-}
 
-impl DnsPacket {
     pub fn create_buffer(&self) -> Result<BytePacketBuffer, WriterError> {
-        let mut buffer = BytePacketBuffer::default();
+        self.write_into(BytePacketBuffer::default())
+    }
+
+    /// Writes the packet at the position of the buffer, growing it when needed
+    pub fn write_into(
+        &self,
+        mut buffer: BytePacketBuffer,
+    ) -> Result<BytePacketBuffer, WriterError> {
         self.header.write(&mut buffer)?;
 
         buffer.write_u16(self.questions.len() as u16)?;
@@ -164,3 +308,19 @@ impl DnsPacket {
         Ok(buffer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{InvalidQueryType, QueryType};
+
+    #[test]
+    fn should_parse_query_types() {
+        assert_eq!("aaaa".parse::<QueryType>(), Ok(QueryType::AAAA));
+        assert_eq!("TYPE65".parse::<QueryType>(), Ok(QueryType::HTTPS));
+        assert_eq!("type257".parse::<QueryType>(), Ok(QueryType::Unknown(257)));
+        assert_eq!(
+            "AXFR2".parse::<QueryType>(),
+            Err(InvalidQueryType("AXFR2".into()))
+        );
+    }
+}
@@ -4,10 +4,12 @@ use crate::buffer::writer::WriterError;
 use crate::buffer::BytePacketBuffer;
 
 /// CLASS fields appear in resource records.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[repr(u8)]
 pub enum DnsClass {
     /// IN - the Internet
+    #[default]
     Internet = 1,
     /// CS - the CSNET class (Obsolete - used only for examples in some obsolete RFCs)
     Csnet = 2,
@@ -17,12 +19,6 @@ pub enum DnsClass {
     Hesiod = 4,
 }
 
-impl Default for DnsClass {
-    fn default() -> Self {
-        Self::Internet
-    }
-}
-
 impl TryFrom<u16> for DnsClass {
     type Error = ReaderError;
 
@@ -38,6 +34,7 @@ impl TryFrom<u16> for DnsClass {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 pub struct Question {
     /// QNAME a domain name represented as a sequence of labels,
     /// where each label consists of a length octet followed by that number of octets.
@@ -64,7 +61,11 @@ impl Default for Question {
 }
 
 impl Question {
-    pub fn new(name: String, qtype: QueryType) -> Self {
+    /// Names are stored without trailing dot, the root domain being an empty name.
+    pub fn new(mut name: String, qtype: QueryType) -> Self {
+        if name.ends_with('.') {
+            name.pop();
+        }
         Self {
             name,
             qtype,
@@ -73,7 +74,9 @@ impl Question {
     }
 
     pub fn read(buffer: &mut BytePacketBuffer) -> Result<Self, ReaderError> {
-        let name = buffer.read_qname()?;
+        // the name of the question ends up in the cache and the database, it's checked
+        // more strictly than the ones of the records
+        let name = buffer.read_strict_qname()?;
         let qtype = QueryType::from_num(buffer.read_u16()?); // qtype
         let qclass = DnsClass::try_from(buffer.read_u16()?)?; // class
 
@@ -1,3 +1,5 @@
+use super::dnssec;
+use super::svcb::{self, SvcParam};
 use super::QueryType;
 use crate::buffer::reader::ReaderError;
 use crate::buffer::writer::WriterError;
@@ -5,12 +7,16 @@ use crate::buffer::BytePacketBuffer;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Record {
+    /// Record of a type that isn't modeled, with its data kept as it is so that it can be
+    /// forwarded (RFC 3597). Names compressed in the data, only found in the oldest types,
+    /// would point to the wrong place once written back.
     Unknown {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -28,20 +34,177 @@ pub enum Record {
         host: String,
         ttl: u32,
     }, // 5
+    /// Start of a zone of authority, sent in the authority section of the negative answers
+    SOA {
+        domain: String,
+        /// Primary name server of the zone
+        mname: String,
+        /// Mailbox of the person responsible for the zone
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        /// TTL of the negative answers (RFC 2308)
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    /// Name pointed by a reverse lookup domain, like `4.3.2.1.in-addr.arpa`
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    /// One or more character strings, each of them up to 255 bytes.
+    ///
+    /// The strings are kept as bytes since nothing requires them to be valid UTF-8.
+    TXT {
+        domain: String,
+        data: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    /// EDNS pseudo record (RFC 6891), only found in the additional section and
+    /// describing the capabilities of the sender rather than a domain
+    OPT {
+        /// Size of the largest UDP message the sender can receive, held by the class
+        payload_size: u16,
+        /// Extended response code, version and flags like DO, held by the TTL
+        flags: u32,
+        /// Options, kept as they are
+        data: Vec<u8>,
+    }, // 41
+    /// Digest of a key of a child zone, delegating the trust to it
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    /// Signature of the records of the given type (RFC 4034)
+    RRSIG {
+        domain: String,
+        type_covered: u16,
+        algorithm: u8,
+        /// Number of labels of the owner name, without the wildcard
+        labels: u8,
+        original_ttl: u32,
+        /// Validity period of the signature, in seconds since the epoch modulo 2^32
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        /// Zone of the key, never compressed
+        signer: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    /// Next name of the zone and types of the owner name, proving the absence of the others
+    NSEC {
+        domain: String,
+        /// Never compressed
+        next: String,
+        types: Vec<u16>,
+        ttl: u32,
+    }, // 47
+    /// Public key of a zone
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    /// Same as NSEC with hashed owner names (RFC 5155), so that the zone can't be listed
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<u16>,
+        ttl: u32,
+    }, // 50
+    /// Service binding (RFC 9460), telling how to reach a service
+    SVCB {
+        domain: String,
+        /// 0 in alias mode, the order of preference of the endpoints otherwise
+        priority: u16,
+        /// Name of the endpoint, the root one meaning the owner name
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    }, // 64
+    /// Service binding of an HTTPS origin, with the same format as SVCB
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<SvcParam>,
+        ttl: u32,
+    }, // 65
 }
 
 impl Record {
+    pub fn domain(&self) -> &str {
+        match self {
+            Self::A { domain, .. }
+            | Self::AAAA { domain, .. }
+            | Self::CNAME { domain, .. }
+            | Self::MX { domain, .. }
+            | Self::NS { domain, .. }
+            | Self::PTR { domain, .. }
+            | Self::SOA { domain, .. }
+            | Self::TXT { domain, .. }
+            | Self::SVCB { domain, .. }
+            | Self::HTTPS { domain, .. }
+            | Self::DS { domain, .. }
+            | Self::RRSIG { domain, .. }
+            | Self::NSEC { domain, .. }
+            | Self::DNSKEY { domain, .. }
+            | Self::NSEC3 { domain, .. }
+            | Self::Unknown { domain, .. } => domain.as_str(),
+            // the owner of the OPT record is always the root
+            Self::OPT { .. } => "",
+        }
+    }
+
+    /// Changes the owner of the record, like to echo the case of the question
+    pub fn set_domain(&mut self, name: &str) {
+        match self {
+            Self::A { domain, .. }
+            | Self::AAAA { domain, .. }
+            | Self::CNAME { domain, .. }
+            | Self::MX { domain, .. }
+            | Self::NS { domain, .. }
+            | Self::PTR { domain, .. }
+            | Self::SOA { domain, .. }
+            | Self::TXT { domain, .. }
+            | Self::SVCB { domain, .. }
+            | Self::HTTPS { domain, .. }
+            | Self::DS { domain, .. }
+            | Self::RRSIG { domain, .. }
+            | Self::NSEC { domain, .. }
+            | Self::DNSKEY { domain, .. }
+            | Self::NSEC3 { domain, .. }
+            | Self::Unknown { domain, .. } => name.clone_into(domain),
+            Self::OPT { .. } => {}
+        }
+    }
+
     pub fn ttl(&self) -> u32 {
         match self {
             Self::A { ttl, .. } => *ttl,
@@ -49,10 +212,82 @@ impl Record {
             Self::CNAME { ttl, .. } => *ttl,
             Self::MX { ttl, .. } => *ttl,
             Self::NS { ttl, .. } => *ttl,
+            Self::PTR { ttl, .. } => *ttl,
+            Self::SOA { ttl, .. } => *ttl,
+            Self::TXT { ttl, .. } => *ttl,
+            Self::SVCB { ttl, .. } => *ttl,
+            Self::HTTPS { ttl, .. } => *ttl,
+            Self::DS { ttl, .. } => *ttl,
+            Self::RRSIG { ttl, .. } => *ttl,
+            Self::NSEC { ttl, .. } => *ttl,
+            Self::DNSKEY { ttl, .. } => *ttl,
+            Self::NSEC3 { ttl, .. } => *ttl,
             Self::Unknown { ttl, .. } => *ttl,
+            // the TTL field holds the flags, the record is never cached
+            Self::OPT { .. } => 0,
+        }
+    }
+
+    /// Changes the TTL of the record, the one of the OPT record holding its flags instead
+    pub fn set_ttl(&mut self, value: u32) {
+        match self {
+            Self::A { ttl, .. }
+            | Self::AAAA { ttl, .. }
+            | Self::CNAME { ttl, .. }
+            | Self::MX { ttl, .. }
+            | Self::NS { ttl, .. }
+            | Self::PTR { ttl, .. }
+            | Self::SOA { ttl, .. }
+            | Self::TXT { ttl, .. }
+            | Self::SVCB { ttl, .. }
+            | Self::HTTPS { ttl, .. }
+            | Self::DS { ttl, .. }
+            | Self::RRSIG { ttl, .. }
+            | Self::NSEC { ttl, .. }
+            | Self::DNSKEY { ttl, .. }
+            | Self::NSEC3 { ttl, .. }
+            | Self::Unknown { ttl, .. } => *ttl = value,
+            Self::OPT { .. } => {}
         }
     }
 
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            Self::Unknown { qtype, .. } => QueryType::from_num(*qtype),
+            Self::A { .. } => QueryType::A,
+            Self::NS { .. } => QueryType::NS,
+            Self::CNAME { .. } => QueryType::CNAME,
+            Self::SOA { .. } => QueryType::SOA,
+            Self::PTR { .. } => QueryType::PTR,
+            Self::MX { .. } => QueryType::MX,
+            Self::TXT { .. } => QueryType::TXT,
+            Self::AAAA { .. } => QueryType::AAAA,
+            Self::OPT { .. } => QueryType::OPT,
+            Self::DS { .. } => QueryType::DS,
+            Self::RRSIG { .. } => QueryType::RRSIG,
+            Self::NSEC { .. } => QueryType::NSEC,
+            Self::DNSKEY { .. } => QueryType::DNSKEY,
+            Self::NSEC3 { .. } => QueryType::NSEC3,
+            Self::SVCB { .. } => QueryType::SVCB,
+            Self::HTTPS { .. } => QueryType::HTTPS,
+        }
+    }
+
+    /// Data of the record in the canonical form used to sign it, with the names
+    /// written in full and in lowercase (RFC 4034 section 6.2)
+    pub fn canonical_data(&self) -> Result<Vec<u8>, WriterError> {
+        let mut buffer = BytePacketBuffer::canonical();
+        self.write(&mut buffer)?;
+        // the owner name, then the type, class, ttl and size of the data
+        let domain = self.domain().strip_suffix('.').unwrap_or(self.domain());
+        let start = if domain.is_empty() {
+            1
+        } else {
+            domain.len() + 2
+        } + 10;
+        Ok(buffer.buf[start..buffer.pos].to_vec())
+    }
+
     pub fn delayed_ttl(&self, ttl: u32) -> Self {
         match self {
             Self::A { domain, addr, .. } => Self::A {
@@ -86,15 +321,159 @@ impl Record {
                 host: host.clone(),
                 ttl,
             },
+            Self::PTR { domain, host, .. } => Self::PTR {
+                domain: domain.clone(),
+                host: host.clone(),
+                ttl,
+            },
+            Self::SOA {
+                domain,
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => Self::SOA {
+                domain: domain.clone(),
+                mname: mname.clone(),
+                rname: rname.clone(),
+                serial: *serial,
+                refresh: *refresh,
+                retry: *retry,
+                expire: *expire,
+                minimum: *minimum,
+                ttl,
+            },
+            Self::TXT { domain, data, .. } => Self::TXT {
+                domain: domain.clone(),
+                data: data.clone(),
+                ttl,
+            },
+            Self::SVCB {
+                domain,
+                priority,
+                target,
+                params,
+                ..
+            } => Self::SVCB {
+                domain: domain.clone(),
+                priority: *priority,
+                target: target.clone(),
+                params: params.clone(),
+                ttl,
+            },
+            Self::HTTPS {
+                domain,
+                priority,
+                target,
+                params,
+                ..
+            } => Self::HTTPS {
+                domain: domain.clone(),
+                priority: *priority,
+                target: target.clone(),
+                params: params.clone(),
+                ttl,
+            },
+            Self::DS {
+                domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => Self::DS {
+                domain: domain.clone(),
+                key_tag: *key_tag,
+                algorithm: *algorithm,
+                digest_type: *digest_type,
+                digest: digest.clone(),
+                ttl,
+            },
+            Self::RRSIG {
+                domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => Self::RRSIG {
+                domain: domain.clone(),
+                type_covered: *type_covered,
+                algorithm: *algorithm,
+                labels: *labels,
+                original_ttl: *original_ttl,
+                expiration: *expiration,
+                inception: *inception,
+                key_tag: *key_tag,
+                signer: signer.clone(),
+                signature: signature.clone(),
+                ttl,
+            },
+            Self::NSEC {
+                domain,
+                next,
+                types,
+                ..
+            } => Self::NSEC {
+                domain: domain.clone(),
+                next: next.clone(),
+                types: types.clone(),
+                ttl,
+            },
+            Self::DNSKEY {
+                domain,
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => Self::DNSKEY {
+                domain: domain.clone(),
+                flags: *flags,
+                protocol: *protocol,
+                algorithm: *algorithm,
+                public_key: public_key.clone(),
+                ttl,
+            },
+            Self::NSEC3 {
+                domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => Self::NSEC3 {
+                domain: domain.clone(),
+                hash_algorithm: *hash_algorithm,
+                flags: *flags,
+                iterations: *iterations,
+                salt: salt.clone(),
+                next_hashed: next_hashed.clone(),
+                types: types.clone(),
+                ttl,
+            },
+            // the TTL field holds the flags
+            Self::OPT { .. } => self.clone(),
             Self::Unknown {
                 domain,
                 qtype,
-                data_len,
+                data,
                 ..
             } => Self::Unknown {
                 domain: domain.clone(),
                 qtype: *qtype,
-                data_len: *data_len,
+                data: data.clone(),
                 ttl,
             },
         }
@@ -110,7 +489,7 @@ impl Record {
         let qtype = QueryType::from_num(qtype_num);
 
         // CLASS two octets which specify the class of the data in the RDATA field.
-        let _qclass = buffer.read_u16()?;
+        let qclass = buffer.read_u16()?;
 
         // TTL a 32 bit unsigned integer that specifies the time interval (in seconds)
         // that the resource record may be cached before it should be discarded.
@@ -161,6 +540,27 @@ impl Record {
 
                 Ok(Record::CNAME { domain, host, ttl })
             }
+            QueryType::SOA => {
+                let mname = buffer.read_qname()?;
+                let rname = buffer.read_qname()?;
+
+                Ok(Record::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let host = buffer.read_qname()?;
+
+                Ok(Record::PTR { domain, host, ttl })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let host = buffer.read_qname()?;
@@ -172,13 +572,126 @@ impl Record {
                     ttl,
                 })
             }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+                while buffer.pos() < end {
+                    let size = buffer.read()? as usize;
+                    if buffer.pos() + size > end {
+                        return Err(ReaderError::InvalidDataLength(data_len));
+                    }
+                    data.push(buffer.get_range(buffer.pos(), size)?.to_vec());
+                    buffer.step(size)?;
+                }
+                // an empty data is read as the single empty string it's written with
+                if data.is_empty() {
+                    data.push(Vec::new());
+                }
+
+                Ok(Record::TXT { domain, data, ttl })
+            }
+            QueryType::SVCB | QueryType::HTTPS => {
+                let end = buffer.pos() + data_len as usize;
+                let priority = buffer.read_u16()?;
+                let target = buffer.read_qname()?;
+                let params = svcb::read_params(buffer, end, data_len)?;
+
+                if qtype == QueryType::SVCB {
+                    Ok(Record::SVCB {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
+                } else {
+                    Ok(Record::HTTPS {
+                        domain,
+                        priority,
+                        target,
+                        params,
+                        ttl,
+                    })
+                }
+            }
+            QueryType::OPT => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
+                buffer.step(data_len as usize)?;
+
+                Ok(Record::OPT {
+                    payload_size: qclass,
+                    flags: ttl,
+                    data,
+                })
+            }
+            QueryType::DS => {
+                let end = buffer.pos() + data_len as usize;
+                Ok(Record::DS {
+                    domain,
+                    key_tag: buffer.read_u16()?,
+                    algorithm: buffer.read()?,
+                    digest_type: buffer.read()?,
+                    digest: dnssec::read_remaining(buffer, end, data_len)?,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let end = buffer.pos() + data_len as usize;
+                Ok(Record::RRSIG {
+                    domain,
+                    type_covered: buffer.read_u16()?,
+                    algorithm: buffer.read()?,
+                    labels: buffer.read()?,
+                    original_ttl: buffer.read_u32()?,
+                    expiration: buffer.read_u32()?,
+                    inception: buffer.read_u32()?,
+                    key_tag: buffer.read_u16()?,
+                    signer: buffer.read_qname()?,
+                    signature: dnssec::read_remaining(buffer, end, data_len)?,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let end = buffer.pos() + data_len as usize;
+                Ok(Record::NSEC {
+                    domain,
+                    next: buffer.read_qname()?,
+                    types: dnssec::read_types(buffer, end, data_len)?,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let end = buffer.pos() + data_len as usize;
+                Ok(Record::DNSKEY {
+                    domain,
+                    flags: buffer.read_u16()?,
+                    protocol: buffer.read()?,
+                    algorithm: buffer.read()?,
+                    public_key: dnssec::read_remaining(buffer, end, data_len)?,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let end = buffer.pos() + data_len as usize;
+                Ok(Record::NSEC3 {
+                    domain,
+                    hash_algorithm: buffer.read()?,
+                    flags: buffer.read()?,
+                    iterations: buffer.read_u16()?,
+                    salt: dnssec::read_sized(buffer, end, data_len)?,
+                    next_hashed: dnssec::read_sized(buffer, end, data_len)?,
+                    types: dnssec::read_types(buffer, end, data_len)?,
+                    ttl,
+                })
+            }
             QueryType::Unknown(_) => {
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(Record::Unknown {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
@@ -242,6 +755,54 @@ impl Record {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            Record::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             Record::MX {
                 ref domain,
                 priority,
@@ -277,8 +838,229 @@ impl Record {
                     buffer.write_u16(*octet)?;
                 }
             }
-            Record::Unknown { .. } => {
-                println!("Skipping record: {:?}", self);
+            Record::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // the rdata holds at least one string, even an empty one
+                if data.is_empty() {
+                    buffer.write_u8(0)?;
+                }
+                for item in data {
+                    let size = u8::try_from(item.len())
+                        .map_err(|_| WriterError::StringTooLong(item.len()))?;
+                    buffer.write_u8(size)?;
+                    for b in item {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::SVCB {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            }
+            | Record::HTTPS {
+                ref domain,
+                priority,
+                ref target,
+                ref params,
+                ttl,
+            } => {
+                let qtype = if matches!(self, Record::SVCB { .. }) {
+                    QueryType::SVCB
+                } else {
+                    QueryType::HTTPS
+                };
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_uncompressed_qname(target)?;
+                svcb::write_params(buffer, params)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::OPT {
+                payload_size,
+                flags,
+                ref data,
+            } => {
+                let size = u16::try_from(data.len()).map_err(|_| WriterError::EndOfBuffer)?;
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.into_num())?;
+                buffer.write_u16(payload_size)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(size)?;
+
+                dnssec::write_bytes(buffer, data)?;
+            }
+            Record::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                dnssec::write_bytes(buffer, digest)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer,
+                ref signature,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_uncompressed_qname(signer)?;
+                dnssec::write_bytes(buffer, signature)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::NSEC {
+                ref domain,
+                ref next,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_uncompressed_qname(next)?;
+                dnssec::write_types(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                dnssec::write_bytes(buffer, public_key)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.into_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                dnssec::write_sized(buffer, salt)?;
+                dnssec::write_sized(buffer, next_hashed)?;
+                dnssec::write_types(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            Record::Unknown {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                let size = u16::try_from(data.len()).map_err(|_| WriterError::EndOfBuffer)?;
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(size)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
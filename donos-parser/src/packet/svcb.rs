@@ -0,0 +1,190 @@
+//! Parameters of the service binding records, SVCB and HTTPS (RFC 9460)
+use crate::buffer::reader::ReaderError;
+use crate::buffer::writer::WriterError;
+use crate::buffer::BytePacketBuffer;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Key and value of a service parameter.
+///
+/// The values that can't be decoded, like the ones of the keys defined after RFC 9460,
+/// are kept as they are so that the record can be written back unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub enum SvcParam {
+    /// Keys of the parameters the client must support to use the record
+    Mandatory(Vec<u16>), // 0
+    /// Protocols supported by the service, like `h2` or `h3`
+    Alpn(Vec<Vec<u8>>), // 1
+    /// The default protocol (`http/1.1` for HTTPS) isn't supported
+    NoDefaultAlpn, // 2
+    /// Port of the service, when not the default one
+    Port(u16), // 3
+    /// Addresses of the endpoint, until its A records are resolved
+    Ipv4Hint(Vec<Ipv4Addr>), // 4
+    /// Encrypted ClientHello configuration
+    Ech(Vec<u8>), // 5
+    /// Addresses of the endpoint, until its AAAA records are resolved
+    Ipv6Hint(Vec<Ipv6Addr>), // 6
+    Unknown {
+        key: u16,
+        value: Vec<u8>,
+    },
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            Self::Mandatory(_) => 0,
+            Self::Alpn(_) => 1,
+            Self::NoDefaultAlpn => 2,
+            Self::Port(_) => 3,
+            Self::Ipv4Hint(_) => 4,
+            Self::Ech(_) => 5,
+            Self::Ipv6Hint(_) => 6,
+            Self::Unknown { key, .. } => *key,
+        }
+    }
+
+    fn decode(key: u16, value: &[u8]) -> Option<Self> {
+        match key {
+            0 if !value.is_empty() && value.len().is_multiple_of(2) => Some(Self::Mandatory(
+                value
+                    .chunks_exact(2)
+                    .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]))
+                    .collect(),
+            )),
+            1 => {
+                let mut protocols = Vec::new();
+                let mut rest = value;
+                while let Some((size, tail)) = rest.split_first() {
+                    let size = *size as usize;
+                    if size == 0 || size > tail.len() {
+                        return None;
+                    }
+                    protocols.push(tail[..size].to_vec());
+                    rest = &tail[size..];
+                }
+                (!protocols.is_empty()).then_some(Self::Alpn(protocols))
+            }
+            2 if value.is_empty() => Some(Self::NoDefaultAlpn),
+            3 => Some(Self::Port(u16::from_be_bytes(value.try_into().ok()?))),
+            4 if !value.is_empty() && value.len().is_multiple_of(4) => Some(Self::Ipv4Hint(
+                value
+                    .chunks_exact(4)
+                    .map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+                    .collect(),
+            )),
+            5 => Some(Self::Ech(value.to_vec())),
+            6 if !value.is_empty() && value.len().is_multiple_of(16) => Some(Self::Ipv6Hint(
+                value
+                    .chunks_exact(16)
+                    .map(|chunk| Ipv6Addr::from(<[u8; 16]>::try_from(chunk).unwrap()))
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+
+    fn write_value(&self, buffer: &mut BytePacketBuffer) -> Result<(), WriterError> {
+        match self {
+            Self::Mandatory(keys) => {
+                for key in keys {
+                    buffer.write_u16(*key)?;
+                }
+            }
+            Self::Alpn(protocols) => {
+                for protocol in protocols {
+                    let size = u8::try_from(protocol.len())
+                        .map_err(|_| WriterError::StringTooLong(protocol.len()))?;
+                    buffer.write_u8(size)?;
+                    for b in protocol {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+            }
+            Self::NoDefaultAlpn => {}
+            Self::Port(port) => buffer.write_u16(*port)?,
+            Self::Ipv4Hint(addrs) => {
+                for addr in addrs {
+                    for b in addr.octets() {
+                        buffer.write_u8(b)?;
+                    }
+                }
+            }
+            Self::Ipv6Hint(addrs) => {
+                for addr in addrs {
+                    for b in addr.octets() {
+                        buffer.write_u8(b)?;
+                    }
+                }
+            }
+            Self::Ech(value) | Self::Unknown { value, .. } => {
+                for b in value {
+                    buffer.write_u8(*b)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the parameters until the end of the record data
+pub(crate) fn read_params(
+    buffer: &mut BytePacketBuffer,
+    end: usize,
+    data_len: u16,
+) -> Result<Vec<SvcParam>, ReaderError> {
+    let mut params = Vec::new();
+    while buffer.pos() < end {
+        let key = buffer.read_u16()?;
+        let size = buffer.read_u16()? as usize;
+        if buffer.pos() + size > end {
+            return Err(ReaderError::InvalidDataLength(data_len));
+        }
+        let value = buffer.get_range(buffer.pos(), size)?;
+        params.push(
+            SvcParam::decode(key, value).unwrap_or_else(|| SvcParam::Unknown {
+                key,
+                value: value.to_vec(),
+            }),
+        );
+        buffer.step(size)?;
+    }
+    if buffer.pos() != end {
+        return Err(ReaderError::InvalidDataLength(data_len));
+    }
+    Ok(params)
+}
+
+pub(crate) fn write_params(
+    buffer: &mut BytePacketBuffer,
+    params: &[SvcParam],
+) -> Result<(), WriterError> {
+    for param in params {
+        buffer.write_u16(param.key())?;
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        param.write_value(buffer)?;
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SvcParam;
+
+    #[test]
+    fn should_keep_undecodable_values() {
+        assert_eq!(SvcParam::decode(3, &[0x01]), None);
+        assert_eq!(SvcParam::decode(1, &[0x03, b'h', b'2']), None);
+        assert_eq!(SvcParam::decode(2, &[0x00]), None);
+        assert_eq!(
+            SvcParam::decode(1, &[0x02, b'h', b'2', 0x02, b'h', b'3']),
+            Some(SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]))
+        );
+    }
+}
@@ -0,0 +1,531 @@
+//! Conformance tests based on hand crafted messages following RFC 1035,
+//! checking the parser and the writer byte for byte.
+use donos_parser::buffer::reader::ReaderError;
+use donos_parser::buffer::writer::WriterError;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::generate::{self, Compliance};
+use donos_parser::packet::header::{Header, ResponseCode};
+use donos_parser::packet::question::{DnsClass, Question};
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::Ipv4Addr;
+
+/// Query for the A record of SRI-NIC.ARPA (section 6.2.1), with recursion desired
+const SRI_NIC_QUERY: &[u8] = &[
+    0x04, 0xd2, // id
+    0x01, 0x00, // flags: RD
+    0x00, 0x01, // qdcount
+    0x00, 0x00, // ancount
+    0x00, 0x00, // nscount
+    0x00, 0x00, // arcount
+    0x07, b's', b'r', b'i', b'-', b'n', b'i', b'c', 0x04, b'a', b'r', b'p', b'a',
+    0x00, // qname
+    0x00, 0x01, // qtype: A
+    0x00, 0x01, // qclass: IN
+];
+
+/// Authoritative answer to the above query, the owner names are compressed
+/// with a pointer to the question (section 4.1.4)
+const SRI_NIC_RESPONSE: &[u8] = &[
+    0x04, 0xd2, // id
+    0x85, 0x80, // flags: QR, AA, RD, RA
+    0x00, 0x01, // qdcount
+    0x00, 0x02, // ancount
+    0x00, 0x00, // nscount
+    0x00, 0x00, // arcount
+    0x07, b's', b'r', b'i', b'-', b'n', b'i', b'c', 0x04, b'a', b'r', b'p', b'a',
+    0x00, // qname
+    0x00, 0x01, // qtype: A
+    0x00, 0x01, // qclass: IN
+    0xc0, 0x0c, // name: pointer to the question
+    0x00, 0x01, // type: A
+    0x00, 0x01, // class: IN
+    0x00, 0x01, 0x51, 0x80, // ttl: 86400
+    0x00, 0x04, // rdlength
+    26, 0, 0, 73, // rdata
+    0xc0, 0x0c, // name: pointer to the question
+    0x00, 0x01, // type: A
+    0x00, 0x01, // class: IN
+    0x00, 0x01, 0x51, 0x80, // ttl: 86400
+    0x00, 0x04, // rdlength
+    10, 0, 0, 51, // rdata
+];
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[..bytes.len()].copy_from_slice(bytes);
+    buffer
+}
+
+fn written_bytes(packet: &DnsPacket) -> Vec<u8> {
+    let buffer = packet.create_buffer().unwrap();
+    buffer.buf[..buffer.pos].to_vec()
+}
+
+fn label(size: usize) -> String {
+    "a".repeat(size)
+}
+
+#[test]
+fn should_read_and_write_query() {
+    let packet = DnsPacket::try_from(buffer_from(SRI_NIC_QUERY)).unwrap();
+    assert_eq!(packet.header.id, 1234);
+    assert!(!packet.header.response);
+    assert!(packet.header.recursion_desired);
+    assert_eq!(packet.questions.len(), 1);
+    assert_eq!(packet.questions[0].name, "sri-nic.arpa");
+    assert_eq!(packet.questions[0].qtype, QueryType::A);
+    assert_eq!(packet.questions[0].qclass, DnsClass::Internet);
+
+    assert_eq!(written_bytes(&packet), SRI_NIC_QUERY);
+}
+
+#[test]
+fn should_build_query() {
+    let packet = DnsPacket::query(1234, Question::new("sri-nic.arpa.".into(), QueryType::A));
+
+    assert_eq!(written_bytes(&packet), SRI_NIC_QUERY);
+}
+
+#[test]
+fn should_build_response() {
+    let request = DnsPacket::try_from(buffer_from(SRI_NIC_QUERY)).unwrap();
+    let header = Header::response_from(&request.header)
+        .with_authoritative_answer(true)
+        .with_recursion_available(true);
+    let response = DnsPacket::new(header)
+        .with_question(request.questions[0].clone())
+        .with_answer(Record::A {
+            domain: "sri-nic.arpa".into(),
+            addr: Ipv4Addr::new(26, 0, 0, 73),
+            ttl: 86400,
+        })
+        .with_answer(Record::A {
+            domain: "sri-nic.arpa".into(),
+            addr: Ipv4Addr::new(10, 0, 0, 51),
+            ttl: 86400,
+        });
+
+    assert_eq!(written_bytes(&response), SRI_NIC_RESPONSE);
+}
+
+#[test]
+fn should_read_and_write_compressed_response() {
+    let packet = DnsPacket::try_from(buffer_from(SRI_NIC_RESPONSE)).unwrap();
+    assert!(packet.header.response);
+    assert!(packet.header.authoritative_answer);
+    assert!(packet.header.recursion_available);
+    assert_eq!(packet.header.response_code, ResponseCode::NoError);
+    assert_eq!(
+        packet.answers,
+        vec![
+            Record::A {
+                domain: "sri-nic.arpa".into(),
+                addr: Ipv4Addr::new(26, 0, 0, 73),
+                ttl: 86400,
+            },
+            Record::A {
+                domain: "sri-nic.arpa".into(),
+                addr: Ipv4Addr::new(10, 0, 0, 51),
+                ttl: 86400,
+            },
+        ]
+    );
+
+    assert_eq!(written_bytes(&packet), SRI_NIC_RESPONSE);
+}
+
+#[test]
+fn should_keep_the_case_of_names() {
+    // the case is preserved so that it can be echoed (RFC 4343 section 4.1),
+    // the comparisons being case insensitive
+    let mut query = SRI_NIC_QUERY.to_vec();
+    query[13..20].copy_from_slice(b"SRI-NIC");
+    query[21..25].copy_from_slice(b"ARPA");
+
+    let packet = DnsPacket::try_from(buffer_from(&query)).unwrap();
+    assert_eq!(packet.questions[0].name, "SRI-NIC.ARPA");
+    assert_eq!(written_bytes(&packet), query);
+}
+
+#[test]
+fn should_keep_zero_ttl() {
+    let packet = DnsPacket::new(Header::response(1)).with_answer(Record::A {
+        domain: "perdu.com".into(),
+        addr: Ipv4Addr::new(1, 2, 3, 4),
+        ttl: 0,
+    });
+    let bytes = written_bytes(&packet);
+    // the ttl is right after the compressed name, the type and the class
+    assert_eq!(&bytes[bytes.len() - 10..bytes.len() - 6], &[0, 0, 0, 0]);
+
+    let read = DnsPacket::try_from(buffer_from(&bytes)).unwrap();
+    assert_eq!(read.answers[0].ttl(), 0);
+}
+
+#[test]
+fn should_handle_maximum_label_length() {
+    let name = format!("{}.com", label(63));
+    let mut buffer = BytePacketBuffer::default();
+    buffer.write_qname(&name).unwrap();
+    assert_eq!(buffer.buf[0], 63);
+    assert_eq!(buffer.pos, 1 + 63 + 1 + 3 + 1);
+
+    let mut buffer = BytePacketBuffer::new(buffer.buf);
+    assert_eq!(buffer.read_qname().unwrap(), name);
+
+    let mut buffer = BytePacketBuffer::default();
+    let error = buffer
+        .write_qname(&format!("{}.com", label(64)))
+        .unwrap_err();
+    assert!(matches!(error, WriterError::SingleLabelLengh));
+}
+
+#[test]
+fn should_handle_maximum_name_length() {
+    // 3 labels of 63 and one of 61 makes 255 bytes once encoded
+    let name = [label(63), label(63), label(63), label(61)].join(".");
+    let mut buffer = BytePacketBuffer::default();
+    buffer.write_qname(&name).unwrap();
+    assert_eq!(buffer.pos, 255);
+
+    let mut buffer = BytePacketBuffer::new(buffer.buf);
+    assert_eq!(buffer.read_qname().unwrap(), name);
+
+    let name = [label(63), label(63), label(63), label(62)].join(".");
+    let mut buffer = BytePacketBuffer::default();
+    let error = buffer.write_qname(&name).unwrap_err();
+    assert!(matches!(error, WriterError::NameTooLong(256)));
+}
+
+#[test]
+fn should_reject_too_long_name() {
+    let mut bytes = Vec::new();
+    for _ in 0..4 {
+        bytes.push(63);
+        bytes.extend(label(63).bytes());
+    }
+    bytes.push(0);
+
+    let mut buffer = buffer_from(&bytes);
+    assert_eq!(
+        buffer.read_qname().unwrap_err(),
+        ReaderError::NameTooLong(257)
+    );
+}
+
+#[test]
+fn should_reject_malformed_question_names() {
+    for (bytes, expected) in [
+        (
+            include_bytes!("../data/malformed_label_too_long_query.bin").as_slice(),
+            ReaderError::InvalidLabelType(0x40),
+        ),
+        (
+            include_bytes!("../data/malformed_name_too_long_query.bin").as_slice(),
+            ReaderError::NameTooLong(257),
+        ),
+        (
+            include_bytes!("../data/malformed_dot_in_label_query.bin").as_slice(),
+            ReaderError::InvalidCharacter(b'.'),
+        ),
+        (
+            include_bytes!("../data/malformed_control_character_query.bin").as_slice(),
+            ReaderError::InvalidCharacter(0),
+        ),
+        (
+            include_bytes!("../data/malformed_utf8_query.bin").as_slice(),
+            ReaderError::InvalidCharacter(0xff),
+        ),
+    ] {
+        assert_eq!(DnsPacket::try_from(buffer_from(bytes)), Err(expected));
+    }
+
+    // the names of the records can hold any character but the dot
+    let mut buffer = buffer_from(include_bytes!(
+        "../data/malformed_control_character_query.bin"
+    ));
+    buffer.pos = 12;
+    assert_eq!(buffer.read_qname().unwrap(), "perdu\0.com");
+    let mut buffer = buffer_from(include_bytes!("../data/malformed_dot_in_label_query.bin"));
+    buffer.pos = 12;
+    assert_eq!(
+        buffer.read_qname().unwrap_err(),
+        ReaderError::InvalidCharacter(b'.')
+    );
+}
+
+#[test]
+fn should_not_write_empty_labels() {
+    for name in ["perdu..com", ".perdu.com"] {
+        let mut buffer = BytePacketBuffer::default();
+        let error = buffer.write_qname(name).unwrap_err();
+        assert!(matches!(error, WriterError::SingleLabelLengh), "{name}");
+    }
+}
+
+#[test]
+fn should_reject_pointer_at_end_of_buffer() {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[511] = 0xc0;
+    buffer.pos = 511;
+    assert_eq!(buffer.read_qname().unwrap_err(), ReaderError::EndOfBuffer);
+}
+
+#[test]
+fn should_reject_pointer_outside_of_buffer() {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[0] = 0xff;
+    buffer.buf[1] = 0xff;
+    assert_eq!(buffer.read_qname().unwrap_err(), ReaderError::EndOfBuffer);
+}
+
+#[test]
+fn should_reject_truncated_question() {
+    // the qclass is missing at the end of the buffer
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[505] = 3;
+    buffer.buf[506..509].copy_from_slice(b"com");
+    buffer.buf[509] = 0;
+    buffer.buf[510..512].copy_from_slice(&[0x00, 0x01]);
+    buffer.pos = 505;
+    assert_eq!(
+        Question::read(&mut buffer).unwrap_err(),
+        ReaderError::EndOfBuffer
+    );
+}
+
+#[test]
+fn should_reject_counts_larger_than_packet() {
+    let mut bytes = SRI_NIC_RESPONSE.to_vec();
+    bytes[6..8].copy_from_slice(&[0xff, 0xff]);
+    assert_eq!(
+        DnsPacket::try_from(BytePacketBuffer::new(bytes.clone())),
+        Err(ReaderError::TooManyEntries(65536))
+    );
+    // five answers of 11 bytes at least can't fit in the remaining 49 bytes
+    bytes[6..8].copy_from_slice(&[0x00, 0x05]);
+    assert_eq!(
+        DnsPacket::try_from(BytePacketBuffer::new(bytes.clone())),
+        Err(ReaderError::TooManyEntries(6))
+    );
+    // three could, the third one is missing though
+    bytes[6..8].copy_from_slice(&[0x00, 0x03]);
+    assert_eq!(
+        DnsPacket::try_from(BytePacketBuffer::new(bytes)),
+        Err(ReaderError::EndOfBuffer)
+    );
+}
+
+/// TXT record of example.com with two character strings (section 3.3.14)
+const TXT_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x10, // type: TXT
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x0a, // rdlength
+    0x04, b'v', b'=', b'o', b'k', // first string
+    0x00, // empty string
+    0x03, 0xff, 0x00, b'a', // binary string
+];
+
+#[test]
+fn should_read_and_write_txt_record() {
+    let mut buffer = buffer_from(TXT_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, TXT_RECORD.len());
+    assert_eq!(
+        record,
+        Record::TXT {
+            domain: "example.com".into(),
+            data: vec![b"v=ok".to_vec(), Vec::new(), vec![0xff, 0x00, b'a']],
+            ttl: 3600,
+        }
+    );
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], TXT_RECORD);
+}
+
+#[test]
+fn should_reject_txt_string_longer_than_rdata() {
+    let mut bytes = TXT_RECORD.to_vec();
+    // the last string claims more bytes than the rdlength allows
+    bytes[TXT_RECORD.len() - 4] = 0x04;
+    assert_eq!(
+        Record::read(&mut buffer_from(&bytes)).unwrap_err(),
+        ReaderError::InvalidDataLength(10)
+    );
+}
+
+#[test]
+fn should_reject_txt_string_too_long() {
+    let record = Record::TXT {
+        domain: "example.com".into(),
+        data: vec![vec![b'a'; 256]],
+        ttl: 3600,
+    };
+    let mut buffer = BytePacketBuffer::default();
+    assert!(matches!(
+        record.write(&mut buffer).unwrap_err(),
+        WriterError::StringTooLong(256)
+    ));
+}
+
+#[test]
+fn should_write_empty_txt_record_with_one_string() {
+    let record = Record::TXT {
+        domain: "example.com".into(),
+        data: Vec::new(),
+        ttl: 3600,
+    };
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    // rdlength of one, then the empty string
+    assert_eq!(&buffer.buf[buffer.pos - 3..buffer.pos], &[0x00, 0x01, 0x00]);
+}
+
+#[test]
+fn should_read_empty_txt_record_as_one_string() {
+    // an empty rdata, that is then written with an empty string
+    let mut bytes = TXT_RECORD[..TXT_RECORD.len() - 10].to_vec();
+    bytes[22] = 0x00; // rdlength
+    let record = Record::read(&mut buffer_from(&bytes)).unwrap();
+    assert_eq!(
+        record,
+        Record::TXT {
+            domain: "example.com".into(),
+            data: vec![Vec::new()],
+            ttl: 3600,
+        }
+    );
+}
+
+/// NXDOMAIN for nope.example.com, with the SOA of the zone in the authority
+/// section (section 3.3.13 and RFC 2308)
+const NXDOMAIN_RESPONSE: &[u8] = &[
+    0x00, 0x2a, // id
+    0x81, 0x83, // flags: QR, RD, RA, NXDOMAIN
+    0x00, 0x01, // qdcount
+    0x00, 0x00, // ancount
+    0x00, 0x01, // nscount
+    0x00, 0x00, // arcount
+    0x04, b'n', b'o', b'p', b'e', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o',
+    b'm', 0x00, // qname
+    0x00, 0x01, // qtype: A
+    0x00, 0x01, // qclass: IN
+    0xc0, 0x11, // name: pointer to example.com
+    0x00, 0x06, // type: SOA
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x03, 0x84, // ttl: 900
+    0x00, 0x21, // rdlength
+    0x02, b'n', b's', 0xc0, 0x11, // mname
+    0x05, b'a', b'd', b'm', b'i', b'n', 0xc0, 0x11, // rname
+    0x78, 0x49, 0x7a, 0x2c, // serial
+    0x00, 0x00, 0x1c, 0x20, // refresh: 7200
+    0x00, 0x00, 0x0e, 0x10, // retry: 3600
+    0x00, 0x12, 0x75, 0x00, // expire: 1209600
+    0x00, 0x00, 0x01, 0x2c, // minimum: 300
+];
+
+#[test]
+fn should_read_and_write_soa_in_authority() {
+    let packet = DnsPacket::try_from(buffer_from(NXDOMAIN_RESPONSE)).unwrap();
+    assert_eq!(packet.header.response_code, ResponseCode::NameError);
+    assert!(packet.answers.is_empty());
+    assert_eq!(
+        packet.authorities,
+        vec![Record::SOA {
+            domain: "example.com".into(),
+            mname: "ns.example.com".into(),
+            rname: "admin.example.com".into(),
+            serial: 2018081324,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 300,
+            ttl: 900,
+        }]
+    );
+
+    assert_eq!(written_bytes(&packet), NXDOMAIN_RESPONSE);
+}
+
+/// PTR record of 4.3.2.1.in-addr.arpa (section 3.3.12)
+const PTR_RECORD: &[u8] = &[
+    0x01, b'4', 0x01, b'3', 0x01, b'2', 0x01, b'1', 0x07, b'i', b'n', b'-', b'a', b'd', b'd', b'r',
+    0x04, b'a', b'r', b'p', b'a', 0x00, // name
+    0x00, 0x0c, // type: PTR
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x0a, // rdlength
+    0x04, b'h', b'o', b's', b't', 0x03, b'l', b'a', b'n', 0x00, // ptrdname
+];
+
+#[test]
+fn should_read_and_write_ptr_record() {
+    let mut buffer = buffer_from(PTR_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, PTR_RECORD.len());
+    assert_eq!(
+        record,
+        Record::PTR {
+            domain: "4.3.2.1.in-addr.arpa".into(),
+            host: "host.lan".into(),
+            ttl: 3600,
+        }
+    );
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], PTR_RECORD);
+}
+
+#[test]
+fn should_read_multiple_questions() {
+    let buffer = generate::query(
+        7,
+        [
+            Question::new("example.com".into(), QueryType::A),
+            Question::new("example.com".into(), QueryType::AAAA),
+        ],
+        &Compliance::default(),
+    )
+    .unwrap();
+    // the second name is a pointer to the first one
+    assert_eq!(buffer.pos, 12 + 13 + 4 + 2 + 4);
+
+    let packet = DnsPacket::try_from(buffer_from(&buffer.buf[..buffer.pos])).unwrap();
+    assert_eq!(packet.questions.len(), 2);
+    assert_eq!(packet.questions[1].qtype, QueryType::AAAA);
+    assert_eq!(written_bytes(&packet), &buffer.buf[..buffer.pos]);
+}
+
+#[test]
+fn should_read_notify_without_question() {
+    let buffer = generate::empty_notify(7).unwrap();
+    assert_eq!(
+        &buffer.buf[..buffer.pos],
+        &[0x00, 0x07, 0x24, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+
+    let packet = DnsPacket::try_from(buffer_from(&buffer.buf[..buffer.pos])).unwrap();
+    assert_eq!(packet.header.opcode, generate::OPCODE_NOTIFY);
+    assert!(packet.questions.is_empty());
+}
+
+#[test]
+fn should_read_edns_only_query() {
+    let buffer = generate::edns_only(7, 4096).unwrap();
+    let packet = DnsPacket::try_from(buffer_from(&buffer.buf[..buffer.pos])).unwrap();
+    assert!(packet.questions.is_empty());
+    assert_eq!(
+        packet.resources,
+        vec![Record::OPT {
+            payload_size: 4096,
+            flags: 0,
+            data: Vec::new(),
+        }]
+    );
+}
@@ -0,0 +1,59 @@
+//! Conformance tests of the records of unknown types following RFC 3597,
+//! checking they are written back byte for byte.
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::header::Header;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+
+/// CAA record of example.com, a type donos doesn't model
+const CAA_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x01, 0x01, // type: CAA
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x08, // rdlength
+    0x00, // flags
+    0x05, b'i', b's', b's', b'u', b'e', // tag
+    b';', // value: no issuer allowed
+];
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[..bytes.len()].copy_from_slice(bytes);
+    buffer
+}
+
+#[test]
+fn should_read_and_write_unknown_record() {
+    let mut buffer = buffer_from(CAA_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, CAA_RECORD.len());
+    assert_eq!(
+        record,
+        Record::Unknown {
+            domain: "example.com".into(),
+            qtype: 257,
+            data: vec![0x00, 0x05, b'i', b's', b's', b'u', b'e', b';'],
+            ttl: 3600,
+        }
+    );
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], CAA_RECORD);
+}
+
+#[test]
+fn should_forward_unknown_records_in_responses() {
+    let mut buffer = buffer_from(CAA_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    let packet = DnsPacket::new(Header::response(42))
+        .with_question(Question::new("example.com".into(), QueryType::Unknown(257)))
+        .with_answer(record.clone())
+        .with_answer(record);
+
+    let buffer = packet.create_buffer().unwrap();
+    let result = DnsPacket::try_from(BytePacketBuffer::new(buffer.buf)).unwrap();
+    assert_eq!(result, packet);
+}
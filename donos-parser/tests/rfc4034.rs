@@ -0,0 +1,202 @@
+//! Conformance tests of the DNSSEC records following RFC 4034,
+//! checking the parser and the writer byte for byte.
+use donos_parser::buffer::reader::ReaderError;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::header::Header;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+
+/// Signature of the A records of example.com
+const RRSIG_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x2e, // type: RRSIG
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x23, // rdlength
+    0x00, 0x01, // type covered: A
+    0x0d, // algorithm: ECDSA P-256
+    0x02, // labels
+    0x00, 0x00, 0x0e, 0x10, // original ttl: 3600
+    0x67, 0x89, 0xab, 0xcd, // expiration
+    0x67, 0x70, 0x00, 0x00, // inception
+    0x30, 0x39, // key tag: 12345
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // signer
+    0xde, 0xad, 0xbe, 0xef, // signature
+];
+
+/// Key signing key of example.com
+const DNSKEY_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x30, // type: DNSKEY
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x08, // rdlength
+    0x01, 0x01, // flags: zone key, secure entry point
+    0x03, // protocol
+    0x0d, // algorithm: ECDSA P-256
+    0xde, 0xad, 0xbe, 0xef, // public key
+];
+
+/// Digest of the key signing key of example.com, found in the com zone
+const DS_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x2b, // type: DS
+    0x00, 0x01, // class: IN
+    0x00, 0x01, 0x51, 0x80, // ttl: 86400
+    0x00, 0x08, // rdlength
+    0x30, 0x39, // key tag: 12345
+    0x0d, // algorithm: ECDSA P-256
+    0x02, // digest type: SHA-256
+    0xca, 0xfe, 0xba, 0xbe, // digest
+];
+
+/// Next name after example.com, with the types of example.com
+const NSEC_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x2f, // type: NSEC
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x1a, // rdlength
+    0x03, b'w', b'w', b'w', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm',
+    0x00, // next name
+    0x00, 0x07, 0x62, 0x00, 0x00, 0x00, 0x00, 0x03, 0x80, // types: A NS SOA RRSIG NSEC DNSKEY
+];
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[..bytes.len()].copy_from_slice(bytes);
+    buffer
+}
+
+fn roundtrip(bytes: &[u8]) -> Record {
+    let mut buffer = buffer_from(bytes);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, bytes.len());
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], bytes);
+    record
+}
+
+#[test]
+fn should_read_and_write_rrsig_record() {
+    assert_eq!(
+        roundtrip(RRSIG_RECORD),
+        Record::RRSIG {
+            domain: "example.com".into(),
+            type_covered: 1,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: 3600,
+            expiration: 0x6789abcd,
+            inception: 0x67700000,
+            key_tag: 12345,
+            signer: "example.com".into(),
+            signature: vec![0xde, 0xad, 0xbe, 0xef],
+            ttl: 3600,
+        }
+    );
+}
+
+#[test]
+fn should_read_and_write_dnskey_record() {
+    assert_eq!(
+        roundtrip(DNSKEY_RECORD),
+        Record::DNSKEY {
+            domain: "example.com".into(),
+            flags: 257,
+            protocol: 3,
+            algorithm: 13,
+            public_key: vec![0xde, 0xad, 0xbe, 0xef],
+            ttl: 3600,
+        }
+    );
+}
+
+#[test]
+fn should_read_and_write_ds_record() {
+    assert_eq!(
+        roundtrip(DS_RECORD),
+        Record::DS {
+            domain: "example.com".into(),
+            key_tag: 12345,
+            algorithm: 13,
+            digest_type: 2,
+            digest: vec![0xca, 0xfe, 0xba, 0xbe],
+            ttl: 86400,
+        }
+    );
+}
+
+#[test]
+fn should_read_and_write_nsec_record() {
+    assert_eq!(
+        roundtrip(NSEC_RECORD),
+        Record::NSEC {
+            domain: "example.com".into(),
+            next: "www.example.com".into(),
+            types: vec![1, 2, 6, 46, 47, 48],
+            ttl: 3600,
+        }
+    );
+}
+
+#[test]
+fn should_reject_nsec_windows_out_of_order() {
+    // the window 0 repeated after itself
+    let mut bytes = NSEC_RECORD.to_vec();
+    bytes[22] += 3;
+    bytes.extend([0x00, 0x01, 0x40]);
+    assert_eq!(
+        Record::read(&mut buffer_from(&bytes)).unwrap_err(),
+        ReaderError::InvalidDataLength(0x1d)
+    );
+}
+
+#[test]
+fn should_write_signer_without_compression() {
+    let signature = Record::read(&mut buffer_from(RRSIG_RECORD)).unwrap();
+    let packet = DnsPacket::new(Header::response(42))
+        .with_question(Question::new("example.com".into(), QueryType::A))
+        .with_answer(Record::A {
+            domain: "example.com".into(),
+            addr: [93, 184, 215, 14].into(),
+            ttl: 3600,
+        })
+        .with_answer(signature.clone());
+
+    let buffer = packet.create_buffer().unwrap();
+    // the owner name is compressed but the signer is written in full
+    let end = buffer.pos;
+    assert_eq!(
+        &buffer.buf[end - 17..end - 4],
+        &[0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00]
+    );
+
+    let result = DnsPacket::try_from(BytePacketBuffer::new(buffer.buf)).unwrap();
+    assert_eq!(result, packet);
+    assert_eq!(result.answers[1], signature);
+}
+
+#[test]
+fn should_write_canonical_data_in_full_and_in_lowercase() {
+    let record = Record::SOA {
+        domain: "example.com".into(),
+        mname: "ns.example.com".into(),
+        rname: "HostMaster.Example.com".into(),
+        serial: 1,
+        refresh: 2,
+        retry: 3,
+        expire: 4,
+        minimum: 5,
+        ttl: 3600,
+    };
+    let data = record.canonical_data().unwrap();
+    assert_eq!(data.len(), 16 + 24 + 20);
+    assert_eq!(
+        &data[16..40],
+        b"\x0ahostmaster\x07example\x03com\x00".as_slice()
+    );
+}
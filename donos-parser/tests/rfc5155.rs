@@ -0,0 +1,62 @@
+//! Conformance tests of the NSEC3 records following RFC 5155,
+//! checking the parser and the writer byte for byte.
+use donos_parser::buffer::reader::ReaderError;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::record::Record;
+
+/// Hashed next name after the hashed owner name, with the types of the owner
+const NSEC3_RECORD: &[u8] = &[
+    0x04, b'q', b'3', b'f', b'1', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o',
+    b'm', 0x00, // name
+    0x00, 0x32, // type: NSEC3
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x14, // rdlength
+    0x01, // hash algorithm: SHA-1
+    0x00, // flags
+    0x00, 0x00, // iterations
+    0x02, 0xab, 0xcd, // salt
+    0x04, 0x12, 0x34, 0x56, 0x78, // next hashed owner name
+    0x00, 0x06, 0x40, 0x00, 0x00, 0x00, 0x00, 0x02, // types: A RRSIG
+];
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[..bytes.len()].copy_from_slice(bytes);
+    buffer
+}
+
+#[test]
+fn should_read_and_write_nsec3_record() {
+    let mut buffer = buffer_from(NSEC3_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, NSEC3_RECORD.len());
+    assert_eq!(
+        record,
+        Record::NSEC3 {
+            domain: "q3f1.example.com".into(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: vec![0xab, 0xcd],
+            next_hashed: vec![0x12, 0x34, 0x56, 0x78],
+            types: vec![1, 46],
+            ttl: 3600,
+        }
+    );
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], NSEC3_RECORD);
+}
+
+#[test]
+fn should_reject_salt_longer_than_rdata() {
+    let mut bytes = NSEC3_RECORD.to_vec();
+    // the next hashed name claims more bytes than the rdlength allows
+    bytes[35] = 0x20;
+    assert_eq!(
+        Record::read(&mut buffer_from(&bytes)).unwrap_err(),
+        ReaderError::InvalidDataLength(0x14)
+    );
+}
@@ -0,0 +1,105 @@
+//! Conformance tests of the service binding records following RFC 9460,
+//! checking the parser and the writer byte for byte.
+use donos_parser::buffer::reader::ReaderError;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::svcb::SvcParam;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// HTTPS record of example.com served on the same name, with most of the parameters
+const HTTPS_RECORD: &[u8] = &[
+    0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, // name
+    0x00, 0x41, // type: HTTPS
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x01, 0x2c, // ttl: 300
+    0x00, 0x38, // rdlength
+    0x00, 0x01, // priority
+    0x00, // target: root, the owner name
+    0x00, 0x01, 0x00, 0x06, 0x02, b'h', b'2', 0x02, b'h', b'3', // alpn
+    0x00, 0x03, 0x00, 0x02, 0x20, 0xfb, // port: 8443
+    0x00, 0x04, 0x00, 0x04, 192, 0, 2, 1, // ipv4hint
+    0x00, 0x06, 0x00, 0x10, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0x01, // ipv6hint
+    0x02, 0x9b, 0x00, 0x05, b'h', b'e', b'l', b'l', b'o', // key667, unknown
+];
+
+/// SVCB record in alias mode, pointing to another name without parameters
+const SVCB_ALIAS_RECORD: &[u8] = &[
+    0x04, b'_', b'd', b'n', b's', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o',
+    b'm', 0x00, // name
+    0x00, 0x40, // type: SVCB
+    0x00, 0x01, // class: IN
+    0x00, 0x00, 0x0e, 0x10, // ttl: 3600
+    0x00, 0x13, // rdlength
+    0x00, 0x00, // priority: alias mode
+    0x03, b'd', b'n', b's', 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm',
+    0x00, // target
+];
+
+fn buffer_from(bytes: &[u8]) -> BytePacketBuffer {
+    let mut buffer = BytePacketBuffer::default();
+    buffer.buf[..bytes.len()].copy_from_slice(bytes);
+    buffer
+}
+
+#[test]
+fn should_read_and_write_https_record() {
+    let mut buffer = buffer_from(HTTPS_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(buffer.pos, HTTPS_RECORD.len());
+    assert_eq!(
+        record,
+        Record::HTTPS {
+            domain: "example.com".into(),
+            priority: 1,
+            target: String::new(),
+            params: vec![
+                SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]),
+                SvcParam::Port(8443),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                SvcParam::Ipv6Hint(vec![Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)]),
+                SvcParam::Unknown {
+                    key: 667,
+                    value: b"hello".to_vec(),
+                },
+            ],
+            ttl: 300,
+        }
+    );
+
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], HTTPS_RECORD);
+}
+
+#[test]
+fn should_write_svcb_target_without_compression() {
+    let mut buffer = buffer_from(SVCB_ALIAS_RECORD);
+    let record = Record::read(&mut buffer).unwrap();
+    assert_eq!(
+        record,
+        Record::SVCB {
+            domain: "_dns.example.com".into(),
+            priority: 0,
+            target: "dns.example.com".into(),
+            params: Vec::new(),
+            ttl: 3600,
+        }
+    );
+
+    // example.com is already written in the owner name, but the target is not compressed
+    let mut buffer = BytePacketBuffer::default();
+    record.write(&mut buffer).unwrap();
+    assert_eq!(&buffer.buf[..buffer.pos], SVCB_ALIAS_RECORD);
+}
+
+#[test]
+fn should_reject_param_longer_than_rdata() {
+    let mut bytes = HTTPS_RECORD.to_vec();
+    // the unknown parameter claims more bytes than the rdlength allows
+    bytes[HTTPS_RECORD.len() - 6] = 0x06;
+    assert_eq!(
+        Record::read(&mut buffer_from(&bytes)).unwrap_err(),
+        ReaderError::InvalidDataLength(0x38)
+    );
+}
@@ -0,0 +1,43 @@
+//! Packets serialized to JSON, for them to be logged or exported.
+#![cfg(feature = "serde")]
+use donos_parser::packet::header::Header;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::Ipv4Addr;
+
+#[test]
+fn should_serialize_and_deserialize_packet() {
+    let packet = DnsPacket::new(Header::response(1234))
+        .with_question(Question::new("perdu.com".into(), QueryType::A))
+        .with_answer(Record::A {
+            domain: "perdu.com".into(),
+            addr: Ipv4Addr::new(208, 97, 177, 124),
+            ttl: 3600,
+        });
+
+    let value = serde_json::to_value(&packet).unwrap();
+    assert_eq!(
+        value,
+        serde_json::json!({
+            "header": {
+                "id": 1234,
+                "recursion_desired": false,
+                "truncated_message": false,
+                "authoritative_answer": false,
+                "opcode": 0,
+                "response": true,
+                "response_code": "NoError",
+                "checking_disabled": false,
+                "authed_data": false,
+                "z": false,
+                "recursion_available": false,
+            },
+            "questions": [{ "name": "perdu.com", "qtype": "A", "qclass": "Internet" }],
+            "answers": [{ "A": { "domain": "perdu.com", "addr": "208.97.177.124", "ttl": 3600 } }],
+            "authorities": [],
+            "resources": [],
+        })
+    );
+    assert_eq!(serde_json::from_value::<DnsPacket>(value).unwrap(), packet);
+}
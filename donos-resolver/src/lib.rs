@@ -1,8 +1,15 @@
 #[cfg(feature = "mock")]
 pub mod mock;
 pub mod prelude;
+pub mod recursive;
+pub mod trace;
 
+use donos_parser::packet::record::Record;
 use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::IpAddr;
+
+/// Maximum number of CNAME followed before giving up
+const MAX_CNAME_CHAIN: usize = 8;
 
 #[derive(Clone, Debug)]
 pub enum ManagerBuilderError {
@@ -37,6 +44,20 @@ impl ManagerBuilder {
 #[derive(Clone, Debug)]
 pub enum ManagerError {
     Failed(Vec<prelude::ResolverError>),
+    CnameChainTooLong(String),
+}
+
+fn same_name(left: &str, right: &str) -> bool {
+    let left = left.strip_suffix('.').unwrap_or(left);
+    let right = right.strip_suffix('.').unwrap_or(right);
+    left.eq_ignore_ascii_case(right)
+}
+
+fn cname_target<'r>(records: &'r [Record], name: &str) -> Option<&'r str> {
+    records.iter().find_map(|record| match record {
+        Record::CNAME { domain, host, .. } if same_name(domain, name) => Some(host.as_str()),
+        _ => None,
+    })
 }
 
 #[derive(Debug)]
@@ -59,10 +80,132 @@ impl Manager {
         }
         Err(ManagerError::Failed(errors))
     }
+
+    /// Same as `resolve` but records every step taken by the resolvers
+    pub async fn resolve_traced(
+        &self,
+        kind: QueryType,
+        hostname: &str,
+        trace: &mut trace::Trace,
+    ) -> Result<DnsPacket, ManagerError> {
+        let mut errors = Vec::new();
+        for resolver in self.resolvers.iter() {
+            match resolver.resolve_traced(kind, hostname, trace).await {
+                Ok(found) => return Ok(found),
+                Err(err) => errors.push(err),
+            };
+        }
+        Err(ManagerError::Failed(errors))
+    }
+}
+
+impl Manager {
+    /// Resolves the hostname, following the CNAME records, and extracts the values
+    /// from the records of the final name.
+    async fn resolve_chased<T, F>(
+        &self,
+        kind: QueryType,
+        hostname: &str,
+        extract: F,
+    ) -> Result<Vec<T>, ManagerError>
+    where
+        F: Fn(&Record) -> Option<T>,
+    {
+        let mut name = hostname.strip_suffix('.').unwrap_or(hostname).to_string();
+        let mut chain = 0;
+        loop {
+            let (packet, _) = self.resolve(kind, &name).await?;
+            // the upstream server usually gives the whole chain in the same response
+            let mut current = name.as_str();
+            loop {
+                let values: Vec<T> = packet
+                    .answers
+                    .iter()
+                    .filter(|record| same_name(record.domain(), current))
+                    .filter_map(&extract)
+                    .collect();
+                if !values.is_empty() {
+                    return Ok(values);
+                }
+                match cname_target(&packet.answers, current) {
+                    Some(target) if chain < MAX_CNAME_CHAIN => {
+                        chain += 1;
+                        current = target;
+                    }
+                    Some(_) => return Err(ManagerError::CnameChainTooLong(hostname.to_string())),
+                    None => break,
+                }
+            }
+            if current == name {
+                return Ok(Vec::new());
+            }
+            // the chain ends on a name that wasn't resolved, asking for it
+            name = current.to_string();
+        }
+    }
+
+    /// Resolves the IPv4 and IPv6 addresses of the hostname
+    pub async fn resolve_ips(&self, hostname: &str) -> Result<Vec<IpAddr>, ManagerError> {
+        let mut result = self
+            .resolve_chased(QueryType::A, hostname, |record| match record {
+                Record::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                _ => None,
+            })
+            .await?;
+        result.extend(
+            self.resolve_chased(QueryType::AAAA, hostname, |record| match record {
+                Record::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .await?,
+        );
+        Ok(result)
+    }
+
+    /// Resolves the mail servers of the domain, ordered by priority
+    pub async fn resolve_mx(
+        &self,
+        hostname: &str,
+    ) -> Result<Vec<prelude::MailExchanger>, ManagerError> {
+        let mut result = self
+            .resolve_chased(QueryType::MX, hostname, |record| match record {
+                Record::MX { priority, host, .. } => Some(prelude::MailExchanger {
+                    priority: *priority,
+                    host: host.clone(),
+                }),
+                _ => None,
+            })
+            .await?;
+        result.sort_by_key(|item| item.priority);
+        Ok(result)
+    }
+
+    /// Resolves the text records of the domain, the strings of each record being
+    /// joined like for SPF (RFC 7208 section 3.3)
+    pub async fn resolve_txt(&self, hostname: &str) -> Result<Vec<String>, ManagerError> {
+        self.resolve_chased(QueryType::TXT, hostname, |record| match record {
+            Record::TXT { data, .. } => Some(String::from_utf8_lossy(&data.concat()).into_owned()),
+            _ => None,
+        })
+        .await
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::DnsPacket;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn cname(domain: &str, host: &str) -> Record {
+        Record::CNAME {
+            domain: domain.into(),
+            host: host.into(),
+            ttl: 60,
+        }
+    }
+
     #[test]
     fn manager_builder_should_error_if_no_resolver() {
         let builder = super::ManagerBuilder::default().build();
@@ -80,4 +223,147 @@ mod tests {
             .await
             .unwrap_err();
     }
+
+    #[tokio::test]
+    async fn manager_should_trace_resolvers() {
+        let manager = super::ManagerBuilder::default()
+            .with_resolver(Box::new(crate::mock::MockResolver::new("first")))
+            .with_resolver(Box::new(crate::mock::MockResolver::new("second")))
+            .build()
+            .unwrap();
+        let mut trace = crate::trace::Trace::default();
+        let _ = manager
+            .resolve_traced(super::QueryType::A, "foo.bar", &mut trace)
+            .await
+            .unwrap_err();
+        let steps: Vec<String> = trace
+            .steps()
+            .iter()
+            .map(|step| step.event.to_string())
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                "mock-resolver/first: started",
+                "mock-resolver/first: failed with Unknown",
+                "mock-resolver/second: started",
+                "mock-resolver/second: failed with Unknown",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn manager_should_resolve_ips_following_cnames() {
+        let resolver = crate::mock::MockResolver::new("first")
+            .with_response(
+                super::QueryType::A,
+                "www.perdu.com",
+                DnsPacket::new(Header::response(1))
+                    .with_answer(cname("www.perdu.com", "perdu.com"))
+                    .with_answer(Record::A {
+                        domain: "perdu.com".into(),
+                        addr: Ipv4Addr::new(1, 2, 3, 4),
+                        ttl: 60,
+                    }),
+            )
+            .with_response(
+                super::QueryType::AAAA,
+                "www.perdu.com",
+                DnsPacket::new(Header::response(2))
+                    .with_answer(cname("www.perdu.com", "perdu.com")),
+            )
+            .with_response(
+                super::QueryType::AAAA,
+                "perdu.com",
+                DnsPacket::new(Header::response(3)).with_answer(Record::AAAA {
+                    domain: "perdu.com".into(),
+                    addr: Ipv6Addr::LOCALHOST,
+                    ttl: 60,
+                }),
+            );
+        let manager = super::ManagerBuilder::default()
+            .with_resolver(Box::new(resolver))
+            .build()
+            .unwrap();
+        let ips = manager.resolve_ips("www.perdu.com").await.unwrap();
+        assert_eq!(
+            ips,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn manager_should_stop_on_cname_loop() {
+        let resolver = crate::mock::MockResolver::new("first").with_response(
+            super::QueryType::MX,
+            "perdu.com",
+            DnsPacket::new(Header::response(1))
+                .with_answer(cname("perdu.com", "www.perdu.com"))
+                .with_answer(cname("www.perdu.com", "perdu.com")),
+        );
+        let manager = super::ManagerBuilder::default()
+            .with_resolver(Box::new(resolver))
+            .build()
+            .unwrap();
+        let error = manager.resolve_mx("perdu.com").await.unwrap_err();
+        assert!(matches!(error, super::ManagerError::CnameChainTooLong(_)));
+    }
+
+    #[tokio::test]
+    async fn manager_should_sort_mail_exchangers() {
+        let mx = |priority: u16, host: &str| Record::MX {
+            domain: "perdu.com".into(),
+            priority,
+            host: host.into(),
+            ttl: 60,
+        };
+        let resolver = crate::mock::MockResolver::new("first").with_response(
+            super::QueryType::MX,
+            "perdu.com",
+            DnsPacket::new(Header::response(1))
+                .with_answer(mx(20, "backup.perdu.com"))
+                .with_answer(mx(10, "mail.perdu.com")),
+        );
+        let manager = super::ManagerBuilder::default()
+            .with_resolver(Box::new(resolver))
+            .build()
+            .unwrap();
+        let hosts: Vec<String> = manager
+            .resolve_mx("perdu.com.")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|item| item.host)
+            .collect();
+        assert_eq!(hosts, vec!["mail.perdu.com", "backup.perdu.com"]);
+    }
+
+    #[tokio::test]
+    async fn manager_should_resolve_txt_following_cnames() {
+        let resolver = crate::mock::MockResolver::new("first").with_response(
+            super::QueryType::TXT,
+            "perdu.com",
+            DnsPacket::new(Header::response(1))
+                .with_answer(cname("perdu.com", "spf.perdu.com"))
+                .with_answer(Record::TXT {
+                    domain: "spf.perdu.com".into(),
+                    data: vec![b"v=spf1 ".to_vec(), b"-all".to_vec()],
+                    ttl: 60,
+                })
+                .with_answer(Record::TXT {
+                    domain: "other.perdu.com".into(),
+                    data: vec![b"ignored".to_vec()],
+                    ttl: 60,
+                }),
+        );
+        let manager = super::ManagerBuilder::default()
+            .with_resolver(Box::new(resolver))
+            .build()
+            .unwrap();
+        let texts = manager.resolve_txt("perdu.com").await.unwrap();
+        assert_eq!(texts, vec!["v=spf1 -all"]);
+    }
 }
@@ -16,6 +16,16 @@ impl MockResolver {
             responses: Default::default(),
         }
     }
+
+    pub fn with_response(
+        mut self,
+        kind: QueryType,
+        hostname: &'static str,
+        packet: DnsPacket,
+    ) -> Self {
+        self.responses.insert((kind, hostname), packet);
+        self
+    }
 }
 
 #[async_trait::async_trait]
@@ -1,14 +1,65 @@
+use crate::trace::{Trace, TraceEvent};
 use donos_parser::packet::{DnsPacket, QueryType};
 
+/// Mail server of a domain, as returned by `Manager::resolve_mx`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MailExchanger {
+    pub priority: u16,
+    pub host: String,
+}
+
 #[derive(Clone, Debug)]
 pub enum ResolverError {
     Unknown,
+    /// The server didn't answer in time
+    Timeout,
+    /// The query couldn't be sent or its response received
+    Network(std::io::ErrorKind),
+    /// The response couldn't be read
+    Malformed,
+    /// None of the name servers of the zone could be reached
+    NoServer,
+    /// The resolution went through too many referrals or aliases
+    TooDeep,
+}
+
+impl From<std::io::Error> for ResolverError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Network(value.kind())
+    }
 }
 
 #[async_trait::async_trait]
-pub trait Resolver: std::fmt::Debug {
+pub trait Resolver: std::fmt::Debug + Send + Sync {
     fn kind(&self) -> &'static str;
     fn identifier(&self) -> &str;
 
     async fn resolve(&self, kind: QueryType, hostname: &str) -> Result<DnsPacket, ResolverError>;
+
+    /// Resolves the query while recording the steps taken in the trace.
+    ///
+    /// Resolvers following referrals should override it to record each of them.
+    async fn resolve_traced(
+        &self,
+        kind: QueryType,
+        hostname: &str,
+        trace: &mut Trace,
+    ) -> Result<DnsPacket, ResolverError> {
+        let resolver = format!("{}/{}", self.kind(), self.identifier());
+        trace.push(TraceEvent::Started {
+            resolver: resolver.clone(),
+        });
+        let result = self.resolve(kind, hostname).await;
+        match result {
+            Ok(ref packet) => trace.push(TraceEvent::Answered {
+                resolver,
+                answers: packet.answers.len(),
+            }),
+            Err(ref error) => trace.push(TraceEvent::Failed {
+                resolver,
+                error: error.clone(),
+            }),
+        };
+        result
+    }
 }
@@ -0,0 +1,741 @@
+//! Resolution starting from the root servers and following the referrals,
+//! without relying on any upstream resolver.
+use crate::prelude::{Resolver, ResolverError};
+use crate::trace::{Trace, TraceEvent};
+use crate::{cname_target, same_name, MAX_CNAME_CHAIN};
+use donos_parser::buffer::{BytePacketBuffer, EDNS_PACKET_SIZE};
+use donos_parser::packet::header::{Header, ResponseCode};
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType, EDNS_DNSSEC_OK};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+/// Addresses of the root servers, from a.root-servers.net to m.root-servers.net
+pub const ROOT_SERVERS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+/// Maximum number of referrals followed for a single name
+const MAX_REFERRALS: usize = 16;
+/// Maximum number of nested resolutions, for the aliases and the name servers without glue
+const MAX_DEPTH: usize = 8;
+/// Maximum number of zones whose name servers are remembered
+const MAX_DELEGATIONS: usize = 10_000;
+/// Longest time the name servers of a zone are remembered
+const MAX_DELEGATION_TTL: u32 = 86_400;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends a query to a name server and waits for its response
+#[async_trait::async_trait]
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        packet: &DnsPacket,
+    ) -> Result<DnsPacket, ResolverError>;
+}
+
+/// Sends the queries over UDP, from a new socket each time so that the responses
+/// can't be guessed, and retries over TCP when the response is truncated.
+#[derive(Debug)]
+pub struct UdpTransport {
+    timeout: Duration,
+}
+
+impl Default for UdpTransport {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(2))
+    }
+}
+
+impl UdpTransport {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+
+    async fn exchange_udp(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+        packet: &DnsPacket,
+    ) -> Result<DnsPacket, ResolverError> {
+        let bind = match server {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(server).await?;
+        socket.send(query).await?;
+
+        let mut buffer = BytePacketBuffer::with_size(EDNS_PACKET_SIZE);
+        loop {
+            let size = socket.recv(&mut buffer.buf).await?;
+            let Ok(response) = DnsPacket::try_from(BytePacketBuffer::new(&buffer.buf[..size]))
+            else {
+                continue;
+            };
+            // anything that doesn't match the query is ignored, like a spoofed response
+            if is_response_to(&response, packet) {
+                return Ok(response);
+            }
+        }
+    }
+
+    async fn exchange_tcp(
+        &self,
+        server: SocketAddr,
+        query: &[u8],
+    ) -> Result<DnsPacket, ResolverError> {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_u16(query.len() as u16).await?;
+        stream.write_all(query).await?;
+        let size = stream.read_u16().await? as usize;
+        let mut buffer = vec![0; size];
+        stream.read_exact(&mut buffer).await?;
+        DnsPacket::try_from(BytePacketBuffer::new(buffer)).map_err(|_| ResolverError::Malformed)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for UdpTransport {
+    async fn exchange(
+        &self,
+        server: SocketAddr,
+        packet: &DnsPacket,
+    ) -> Result<DnsPacket, ResolverError> {
+        let buffer = packet
+            .create_buffer()
+            .map_err(|_| ResolverError::Malformed)?;
+        let query = &buffer.buf[..buffer.pos];
+        let response = tokio::time::timeout(self.timeout, async {
+            let response = self.exchange_udp(server, query, packet).await?;
+            if response.header.truncated_message {
+                self.exchange_tcp(server, query).await
+            } else {
+                Ok(response)
+            }
+        });
+        response.await.map_err(|_| ResolverError::Timeout)?
+    }
+}
+
+fn is_response_to(response: &DnsPacket, query: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(left, right)| left.qtype == right.qtype && same_name(&left.name, &right.name))
+}
+
+/// Lower case name, without the trailing dot, the root being empty
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether the normalized name is the zone or one of its subdomains
+fn is_within(name: &str, zone: &str) -> bool {
+    zone.is_empty()
+        || name == zone
+        || name
+            .strip_suffix(zone)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+fn random_id() -> u16 {
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u16
+}
+
+/// Zone the response delegates the name to, closer than the current one,
+/// with the names of its servers and how long they can be remembered
+fn referral(response: &DnsPacket, zone: &str, name: &str) -> Option<(String, Vec<String>, u32)> {
+    let mut child: Option<String> = None;
+    let mut hosts = Vec::new();
+    let mut ttl = MAX_DELEGATION_TTL;
+    for record in response.authorities.iter() {
+        let Record::NS {
+            domain,
+            host,
+            ttl: record_ttl,
+        } = record
+        else {
+            continue;
+        };
+        let domain = normalize(domain);
+        if domain == zone || !is_within(&domain, zone) || !is_within(name, &domain) {
+            continue;
+        }
+        if child.as_ref().is_some_and(|child| *child != domain) {
+            continue;
+        }
+        child = Some(domain);
+        hosts.push(host.clone());
+        ttl = ttl.min(*record_ttl);
+    }
+    child.map(|child| (child, hosts, ttl))
+}
+
+/// Addresses of the name servers given along with the referral.
+///
+/// Only the ones inside the zone of the responding server are trusted, a server
+/// can't tell the addresses of names it isn't authoritative for.
+fn glue(response: &DnsPacket, hosts: &[String], zone: &str) -> Vec<IpAddr> {
+    response
+        .resources
+        .iter()
+        .filter_map(|record| match record {
+            Record::A { domain, addr, .. }
+                if is_within(&normalize(domain), zone)
+                    && hosts.iter().any(|host| same_name(host, domain)) =>
+            {
+                Some(IpAddr::V4(*addr))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug)]
+struct Delegation {
+    servers: Vec<IpAddr>,
+    expires: Instant,
+}
+
+/// Resolver iterating from the root servers, the way an ISP resolver does.
+///
+/// The name servers of the zones are remembered so that the next queries
+/// don't go through the root and top level domain servers again.
+#[derive(Debug)]
+pub struct RecursiveResolver {
+    transport: Box<dyn Transport>,
+    roots: Vec<IpAddr>,
+    dnssec: bool,
+    delegations: Mutex<HashMap<String, Delegation>>,
+}
+
+impl Default for RecursiveResolver {
+    fn default() -> Self {
+        Self::new(Box::<UdpTransport>::default())
+    }
+}
+
+impl RecursiveResolver {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            roots: ROOT_SERVERS.iter().copied().map(IpAddr::V4).collect(),
+            dnssec: false,
+            delegations: Default::default(),
+        }
+    }
+
+    pub fn with_root_servers(mut self, value: Vec<IpAddr>) -> Self {
+        self.roots = value;
+        self
+    }
+
+    /// Requests the DNSSEC records along with the answers
+    pub fn with_dnssec(mut self, value: bool) -> Self {
+        self.dnssec = value;
+        self
+    }
+
+    fn name(&self) -> String {
+        format!("{}/{}", self.kind(), self.identifier())
+    }
+
+    /// Deepest known zone containing the name, with its servers
+    fn closest(&self, name: &str) -> (String, Vec<IpAddr>) {
+        let now = Instant::now();
+        let delegations = self.delegations.lock().unwrap();
+        let mut current = name;
+        while !current.is_empty() {
+            if let Some(found) = delegations.get(current).filter(|item| item.expires > now) {
+                return (current.to_string(), found.servers.clone());
+            }
+            current = current.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        (String::new(), self.roots.clone())
+    }
+
+    fn remember(&self, zone: &str, servers: &[IpAddr], ttl: u32) {
+        let now = Instant::now();
+        let mut delegations = self.delegations.lock().unwrap();
+        if delegations.len() >= MAX_DELEGATIONS {
+            delegations.retain(|_, item| item.expires > now);
+        }
+        if delegations.len() < MAX_DELEGATIONS {
+            delegations.insert(
+                zone.to_string(),
+                Delegation {
+                    servers: servers.to_vec(),
+                    expires: now + Duration::from_secs(ttl.into()),
+                },
+            );
+        }
+    }
+
+    /// Asks the servers one after the other until one of them answers
+    async fn query(
+        &self,
+        servers: &[IpAddr],
+        kind: QueryType,
+        name: &str,
+    ) -> Result<DnsPacket, ResolverError> {
+        // iterative query, the servers being asked one after the other
+        let mut packet = DnsPacket::new(Header::question(random_id()))
+            .with_question(Question::new(name.to_string(), kind));
+        if self.dnssec {
+            packet.resources.push(Record::OPT {
+                payload_size: EDNS_PACKET_SIZE as u16,
+                flags: EDNS_DNSSEC_OK,
+                data: Vec::new(),
+            });
+        }
+
+        let mut last = Err(ResolverError::NoServer);
+        for server in servers {
+            match self
+                .transport
+                .exchange(SocketAddr::new(*server, 53), &packet)
+                .await
+            {
+                // the other servers of the zone might be working
+                Ok(response)
+                    if !matches!(
+                        response.header.response_code,
+                        ResponseCode::NoError | ResponseCode::NameError
+                    ) =>
+                {
+                    last = Ok(response);
+                }
+                Ok(response) => return Ok(response),
+                Err(error) => last = Err(error),
+            }
+        }
+        last
+    }
+
+    /// Resolves the addresses of a name server given without glue
+    async fn resolve_server(&self, host: &str, depth: usize, trace: &mut Trace) -> Vec<IpAddr> {
+        match self.iterate(QueryType::A, host, depth + 1, trace).await {
+            Ok(response) => response
+                .answers
+                .iter()
+                .filter_map(|record| match record {
+                    Record::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                    _ => None,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn iterate<'a>(
+        &'a self,
+        kind: QueryType,
+        name: &'a str,
+        depth: usize,
+        trace: &'a mut Trace,
+    ) -> BoxFuture<'a, Result<DnsPacket, ResolverError>> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(ResolverError::TooDeep);
+            }
+            let target = normalize(name);
+            let (mut zone, mut servers) = self.closest(&target);
+            for _ in 0..MAX_REFERRALS {
+                let response = self.query(&servers, kind, name).await?;
+                if !response.answers.is_empty() {
+                    return self
+                        .follow_aliases(kind, name, response, depth, trace)
+                        .await;
+                }
+                let Some((child, hosts, ttl)) = referral(&response, &zone, &target) else {
+                    // the final answer, an error or a name without records of this type
+                    return Ok(response);
+                };
+                trace.push(TraceEvent::Referral {
+                    resolver: self.name(),
+                    servers: hosts.clone(),
+                });
+                let mut addresses = glue(&response, &hosts, &zone);
+                for host in hosts.iter() {
+                    if !addresses.is_empty() {
+                        break;
+                    }
+                    // a server inside the zone can't be reached without glue
+                    if !is_within(&normalize(host), &child) {
+                        addresses = self.resolve_server(host, depth, trace).await;
+                    }
+                }
+                if addresses.is_empty() {
+                    return Err(ResolverError::NoServer);
+                }
+                self.remember(&child, &addresses, ttl);
+                zone = child;
+                servers = addresses;
+            }
+            Err(ResolverError::TooDeep)
+        })
+    }
+
+    /// Resolves the target of the aliases when their zone didn't provide its records
+    async fn follow_aliases(
+        &self,
+        kind: QueryType,
+        name: &str,
+        mut response: DnsPacket,
+        depth: usize,
+        trace: &mut Trace,
+    ) -> Result<DnsPacket, ResolverError> {
+        if kind == QueryType::CNAME {
+            return Ok(response);
+        }
+        let mut current = name.to_string();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let answered = response
+                .answers
+                .iter()
+                .any(|record| record.qtype() == kind && same_name(record.domain(), &current));
+            if answered {
+                return Ok(response);
+            }
+            match cname_target(&response.answers, &current) {
+                Some(target) => current = target.to_string(),
+                None => break,
+            }
+        }
+        if same_name(&current, name) {
+            return Ok(response);
+        }
+        let followed = self.iterate(kind, &current, depth + 1, trace).await?;
+        response.header.response_code = followed.header.response_code;
+        response.answers.extend(followed.answers);
+        response.authorities = followed.authorities;
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolver for RecursiveResolver {
+    fn kind(&self) -> &'static str {
+        "recursive-resolver"
+    }
+
+    fn identifier(&self) -> &str {
+        "root"
+    }
+
+    async fn resolve(&self, kind: QueryType, hostname: &str) -> Result<DnsPacket, ResolverError> {
+        self.iterate(kind, hostname, 0, &mut Trace::default()).await
+    }
+
+    async fn resolve_traced(
+        &self,
+        kind: QueryType,
+        hostname: &str,
+        trace: &mut Trace,
+    ) -> Result<DnsPacket, ResolverError> {
+        trace.push(TraceEvent::Started {
+            resolver: self.name(),
+        });
+        let result = self.iterate(kind, hostname, 0, trace).await;
+        match result {
+            Ok(ref packet) => trace.push(TraceEvent::Answered {
+                resolver: self.name(),
+                answers: packet.answers.len(),
+            }),
+            Err(ref error) => trace.push(TraceEvent::Failed {
+                resolver: self.name(),
+                error: error.clone(),
+            }),
+        };
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecursiveResolver, Transport};
+    use crate::prelude::{Resolver, ResolverError};
+    use crate::trace::Trace;
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    const ROOT: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const TLD: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    const ZONE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+
+    #[derive(Debug, Default)]
+    struct MockTransport {
+        responses: HashMap<(IpAddr, &'static str, QueryType), DnsPacket>,
+        queried: Arc<Mutex<Vec<IpAddr>>>,
+    }
+
+    impl MockTransport {
+        fn with_response(
+            mut self,
+            server: IpAddr,
+            name: &'static str,
+            kind: QueryType,
+            packet: DnsPacket,
+        ) -> Self {
+            self.responses.insert((server, name, kind), packet);
+            self
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for MockTransport {
+        async fn exchange(
+            &self,
+            server: SocketAddr,
+            packet: &DnsPacket,
+        ) -> Result<DnsPacket, ResolverError> {
+            self.queried.lock().unwrap().push(server.ip());
+            let question = &packet.questions[0];
+            self.responses
+                .iter()
+                .find(|((ip, name, kind), _)| {
+                    *ip == server.ip() && *name == question.name && *kind == question.qtype
+                })
+                .map(|(_, response)| response.clone())
+                .ok_or(ResolverError::Timeout)
+        }
+    }
+
+    fn ns(domain: &str, host: &str) -> Record {
+        Record::NS {
+            domain: domain.into(),
+            host: host.into(),
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str, addr: IpAddr) -> Record {
+        let IpAddr::V4(addr) = addr else {
+            unreachable!()
+        };
+        Record::A {
+            domain: domain.into(),
+            addr,
+            ttl: 3600,
+        }
+    }
+
+    fn referral(domain: &str, host: &str, glue: Option<IpAddr>) -> DnsPacket {
+        let packet = DnsPacket::new(Header::response(0)).with_authority(ns(domain, host));
+        match glue {
+            Some(addr) => packet.with_resource(a(host, addr)),
+            None => packet,
+        }
+    }
+
+    fn resolver(transport: MockTransport) -> RecursiveResolver {
+        RecursiveResolver::new(Box::new(transport)).with_root_servers(vec![ROOT])
+    }
+
+    #[tokio::test]
+    async fn should_follow_referrals_with_glue() {
+        let transport = MockTransport::default()
+            .with_response(
+                ROOT,
+                "www.perdu.com",
+                QueryType::A,
+                referral("com", "a.gtld-servers.net", Some(TLD)),
+            )
+            .with_response(
+                TLD,
+                "www.perdu.com",
+                QueryType::A,
+                referral("perdu.com", "ns1.perdu.com", Some(ZONE)),
+            )
+            .with_response(
+                ZONE,
+                "www.perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(a("www.perdu.com", ZONE)),
+            );
+        let queried = transport.queried.clone();
+        let resolver = resolver(transport);
+
+        let mut trace = Trace::default();
+        let response = resolver
+            .resolve_traced(QueryType::A, "www.perdu.com", &mut trace)
+            .await
+            .unwrap();
+        assert_eq!(response.answers, vec![a("www.perdu.com", ZONE)]);
+        let steps: Vec<String> = trace
+            .steps()
+            .iter()
+            .map(|step| step.event.to_string())
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                "recursive-resolver/root: started",
+                "recursive-resolver/root: referred to a.gtld-servers.net",
+                "recursive-resolver/root: referred to ns1.perdu.com",
+                "recursive-resolver/root: answered with 1 records",
+            ]
+        );
+        assert_eq!(*queried.lock().unwrap(), vec![ROOT, TLD, ZONE]);
+
+        // the name servers of the zone are remembered
+        queried.lock().unwrap().clear();
+        resolver
+            .resolve(QueryType::A, "www.perdu.com")
+            .await
+            .unwrap();
+        assert_eq!(*queried.lock().unwrap(), vec![ZONE]);
+    }
+
+    #[tokio::test]
+    async fn should_resolve_name_servers_without_glue() {
+        let transport = MockTransport::default()
+            .with_response(
+                ROOT,
+                "perdu.com",
+                QueryType::MX,
+                referral("perdu.com", "ns.hosting.net", None),
+            )
+            .with_response(
+                ROOT,
+                "ns.hosting.net",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(a("ns.hosting.net", ZONE)),
+            )
+            .with_response(
+                ZONE,
+                "perdu.com",
+                QueryType::MX,
+                DnsPacket::new(Header::response(0)).with_answer(Record::MX {
+                    domain: "perdu.com".into(),
+                    priority: 10,
+                    host: "mail.perdu.com".into(),
+                    ttl: 3600,
+                }),
+            );
+        let response = resolver(transport)
+            .resolve(QueryType::MX, "perdu.com")
+            .await
+            .unwrap();
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_ignore_glue_outside_the_zone_of_the_server() {
+        let evil = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 66));
+        let transport = MockTransport::default()
+            .with_response(
+                ROOT,
+                "www.perdu.com",
+                QueryType::A,
+                referral("com", "a.gtld-servers.net", Some(TLD)),
+            )
+            .with_response(
+                TLD,
+                "www.perdu.com",
+                QueryType::A,
+                referral("perdu.com", "ns.hosting.net", Some(evil)),
+            )
+            .with_response(
+                ROOT,
+                "ns.hosting.net",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(a("ns.hosting.net", ZONE)),
+            )
+            .with_response(
+                ZONE,
+                "www.perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(a("www.perdu.com", ZONE)),
+            );
+        let queried = transport.queried.clone();
+        let response = resolver(transport)
+            .resolve(QueryType::A, "www.perdu.com")
+            .await
+            .unwrap();
+        assert_eq!(response.answers, vec![a("www.perdu.com", ZONE)]);
+        // the servers of com can't tell the address of a name in net
+        assert_eq!(*queried.lock().unwrap(), vec![ROOT, TLD, ROOT, ZONE]);
+    }
+
+    #[tokio::test]
+    async fn should_follow_aliases_to_other_zones() {
+        let transport = MockTransport::default()
+            .with_response(
+                ROOT,
+                "www.perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(Record::CNAME {
+                    domain: "www.perdu.com".into(),
+                    host: "perdu.cdn.net".into(),
+                    ttl: 3600,
+                }),
+            )
+            .with_response(
+                ROOT,
+                "perdu.cdn.net",
+                QueryType::A,
+                referral("cdn.net", "ns.cdn.net", Some(ZONE)),
+            )
+            .with_response(
+                ZONE,
+                "perdu.cdn.net",
+                QueryType::A,
+                DnsPacket::new(Header::response(0)).with_answer(a("perdu.cdn.net", ZONE)),
+            );
+        let response = resolver(transport)
+            .resolve(QueryType::A, "www.perdu.com")
+            .await
+            .unwrap();
+        assert_eq!(response.answers.len(), 2);
+        assert_eq!(response.answers[1], a("perdu.cdn.net", ZONE));
+    }
+
+    #[tokio::test]
+    async fn should_fail_without_reachable_server() {
+        let transport = MockTransport::default().with_response(
+            ROOT,
+            "www.perdu.com",
+            QueryType::A,
+            referral("perdu.com", "ns1.perdu.com", None),
+        );
+        let error = resolver(transport)
+            .resolve(QueryType::A, "www.perdu.com")
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResolverError::NoServer));
+    }
+}
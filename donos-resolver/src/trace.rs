@@ -0,0 +1,136 @@
+use crate::prelude::ResolverError;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// Something that happened while resolving a query
+#[derive(Clone, Debug)]
+pub enum TraceEvent {
+    /// The resolver starts handling the query
+    Started { resolver: String },
+    /// The resolver has been redirected to other name servers
+    Referral {
+        resolver: String,
+        servers: Vec<String>,
+    },
+    /// The resolver found an answer
+    Answered { resolver: String, answers: usize },
+    /// The resolver failed, the next one will be used
+    Failed {
+        resolver: String,
+        error: ResolverError,
+    },
+}
+
+impl Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Started { resolver } => write!(f, "{resolver}: started"),
+            Self::Referral { resolver, servers } => {
+                write!(f, "{resolver}: referred to {}", servers.join(", "))
+            }
+            Self::Answered { resolver, answers } => {
+                write!(f, "{resolver}: answered with {answers} records")
+            }
+            Self::Failed { resolver, error } => write!(f, "{resolver}: failed with {error:?}"),
+        }
+    }
+}
+
+/// Event with the time elapsed since the beginning of the resolution
+#[derive(Clone, Debug)]
+pub struct TraceStep {
+    pub elapsed: Duration,
+    pub event: TraceEvent,
+}
+
+impl Display for TraceStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{:>8.3}ms] {}",
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.event
+        )
+    }
+}
+
+type Listener = Box<dyn Fn(&TraceStep) + Send + Sync>;
+
+/// Records all the steps taken to resolve a query, similar to `dig +trace`.
+///
+/// A listener can be attached to get the steps as soon as they happen.
+pub struct Trace {
+    start: Instant,
+    steps: Vec<TraceStep>,
+    listener: Option<Listener>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            steps: Vec::new(),
+            listener: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for Trace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Trace")
+            .field("start", &self.start)
+            .field("steps", &self.steps)
+            .finish()
+    }
+}
+
+impl Trace {
+    pub fn with_listener<F: Fn(&TraceStep) + Send + Sync + 'static>(mut self, listener: F) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    pub fn push(&mut self, event: TraceEvent) {
+        let step = TraceStep {
+            elapsed: self.start.elapsed(),
+            event,
+        };
+        if let Some(ref listener) = self.listener {
+            listener(&step);
+        }
+        self.steps.push(step);
+    }
+
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Trace, TraceEvent};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn should_notify_listener() {
+        let counter = Arc::new(AtomicUsize::new(0));
+        let cloned = counter.clone();
+        let mut trace = Trace::default().with_listener(move |_| {
+            cloned.fetch_add(1, Ordering::SeqCst);
+        });
+        trace.push(TraceEvent::Started {
+            resolver: "first".into(),
+        });
+        trace.push(TraceEvent::Answered {
+            resolver: "first".into(),
+            answers: 2,
+        });
+        assert_eq!(trace.steps().len(), 2);
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            trace.steps()[1].event.to_string(),
+            "first: answered with 2 records"
+        );
+    }
+}
@@ -1,12 +1,17 @@
-use futures::stream::StreamExt;
 use prelude::Message;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 
+mod pool;
 pub mod prelude;
 pub mod receiver;
 pub mod sender;
+pub mod socket;
+pub mod tcp;
+pub mod workers;
+
+pub use tcp::TcpServer;
 
 #[async_trait::async_trait]
 pub trait Handler {
@@ -14,34 +19,123 @@ pub trait Handler {
 }
 
 pub struct UdpServer<H> {
-    address: SocketAddr,
+    socket: Arc<UdpSocket>,
     handler: H,
+    workers: workers::WorkerOptions,
 }
 
 impl<H: Handler> UdpServer<H> {
-    pub fn new(address: SocketAddr, handler: H) -> Self {
-        Self { address, handler }
+    /// Binds the socket right away so that errors (port already used, permission denied)
+    /// can be reported before starting to handle messages.
+    pub async fn bind(address: SocketAddr, handler: H) -> std::io::Result<Self> {
+        Self::bind_with(address, socket::BindOptions::default(), handler)
     }
 
-    pub async fn run(&self) -> std::io::Result<()> {
-        let socket = UdpSocket::bind(self.address).await?;
-        let socket = Arc::new(socket);
+    /// Same as `bind`, with the options applied to the socket before binding it
+    pub fn bind_with(
+        address: SocketAddr,
+        options: socket::BindOptions,
+        handler: H,
+    ) -> std::io::Result<Self> {
+        let socket = socket::bind_udp(address, options)?;
+        Ok(Self {
+            socket: Arc::new(UdpSocket::from_std(socket)?),
+            handler,
+            workers: Default::default(),
+        })
+    }
+
+    /// Uses a socket that is already bound, inherited from another process for example
+    pub fn from_std(socket: std::net::UdpSocket, handler: H) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Arc::new(UdpSocket::from_std(socket)?),
+            handler,
+            workers: Default::default(),
+        })
+    }
+
+    /// Sets how many messages are handled at the same time and how many can wait
+    pub fn with_workers(mut self, options: workers::WorkerOptions) -> Self {
+        self.workers = options;
+        self
+    }
 
-        let receiver = receiver::Receiver::new(socket.clone());
-        let sender = sender::Sender::new(socket);
+    /// Address the socket is actually bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
 
-        let stream = receiver
-            .into_stream()
-            .filter_map(|item| async { self.handler.handle(item).await });
+    pub async fn run(&self) -> std::io::Result<()> {
+        self.run_until(futures::future::pending()).await
+    }
 
-        tokio::pin!(stream);
+    /// Handles the messages until the shutdown future completes.
+    ///
+    /// The messages received before it completes are still answered,
+    /// so that no query is dropped when handing the socket over to another process.
+    pub async fn run_until<F>(&self, shutdown: F) -> std::io::Result<()>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let concurrency = self.workers.concurrency.max(1);
+        // at most `concurrency` messages are answered at the same time, so as many buffers
+        // are enough for the following ones
+        let pool = Arc::new(pool::BufferPool::new(concurrency));
+        let receiver = receiver::Receiver::new(self.socket.clone()).with_pool(pool.clone());
+        let sender = sender::Sender::new(self.socket.clone());
+        let queue = workers::Queue::new(self.workers.queue_size, self.workers.overflow);
 
-        while let Some(item) = stream.next().await {
-            if let Err(error) = sender.send(&item).await {
-                tracing::error!("couldn't send message to {:?}: {error:?}", item.address);
+        let intake = async {
+            tokio::pin!(shutdown);
+            let result = loop {
+                let message = tokio::select! {
+                    biased;
+                    _ = &mut shutdown => break Ok(()),
+                    received = receiver.receive() => match received {
+                        Ok(found) => found,
+                        Err(error) => break Err(error),
+                    },
+                };
+                tracing::debug!("received message from {:?}", message.address);
+                queue.push(message);
+            };
+            queue.close();
+            result
+        };
+        let workers =
+            futures::future::join_all((0..concurrency).map(|_| self.work(&queue, &sender, &pool)));
+        let (result, _) = tokio::join!(intake, workers);
+        result
+    }
+
+    async fn work(&self, queue: &workers::Queue, sender: &sender::Sender, pool: &pool::BufferPool) {
+        while let Some(message) = queue.pop().await {
+            let address = message.address;
+            let handled = self.handler.handle(message);
+            let response = match self.workers.timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, handled).await {
+                    Ok(found) => found,
+                    Err(_) => {
+                        tracing::warn!("message from {address:?} not handled in time, dropped");
+                        continue;
+                    }
+                },
+                None => handled.await,
+            };
+            if let Some(item) = response {
+                if let Err(error) = sender.send(&item).await {
+                    tracing::error!("couldn't send message to {:?}: {error:?}", item.address);
+                }
+                pool.give(item.buffer);
             }
         }
+    }
+}
 
-        Ok(())
+#[cfg(unix)]
+impl<H> std::os::fd::AsRawFd for UdpServer<H> {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.socket.as_raw_fd()
     }
 }
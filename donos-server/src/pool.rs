@@ -0,0 +1,68 @@
+//! Buffers of the answered messages, kept to receive the next ones without allocating
+use std::sync::Mutex;
+
+/// Buffers growing over this size, like the ones of large responses, are not kept
+const MAX_KEPT_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    /// Number of buffers kept, the ones given back once full are dropped
+    capacity: usize,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Buffer of `size` zeroed bytes, from the pool when one is available
+    pub fn take(&self, size: usize) -> Vec<u8> {
+        let found = self.buffers.lock().unwrap().pop();
+        let mut buffer = found.unwrap_or_default();
+        buffer.clear();
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    pub fn give(&self, buffer: Vec<u8>) {
+        if buffer.capacity() > MAX_KEPT_CAPACITY {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, MAX_KEPT_CAPACITY};
+
+    #[test]
+    fn should_reuse_given_buffers() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.take(512);
+        assert_eq!(buffer.len(), 512);
+        buffer.fill(42);
+        let pointer = buffer.as_ptr();
+        pool.give(buffer);
+        // the pool is full, this one is dropped
+        pool.give(Vec::with_capacity(512));
+
+        let buffer = pool.take(12);
+        assert_eq!(buffer.len(), 12);
+        assert_eq!(buffer.as_ptr(), pointer);
+        // nothing is left of the previous message
+        assert!(buffer.iter().all(|byte| *byte == 0));
+        assert_eq!(pool.take(0).capacity(), 0);
+
+        // the large ones are never kept
+        pool.give(Vec::with_capacity(MAX_KEPT_CAPACITY + 1));
+        assert_eq!(pool.take(0).capacity(), 0);
+    }
+}
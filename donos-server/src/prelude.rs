@@ -1,7 +1,35 @@
+use std::fmt::Display;
 use std::net::SocketAddr;
 
+/// Protocol used to receive a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Message {
     pub address: SocketAddr,
-    pub buffer: [u8; 512],
+    /// Local address of the socket that received the message
+    pub listener: SocketAddr,
+    pub transport: Transport,
+    /// Content of the message, only the first `size` bytes are meaningful
+    pub buffer: Vec<u8>,
     pub size: usize,
 }
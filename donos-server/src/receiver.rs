@@ -1,4 +1,5 @@
-use crate::prelude::Message;
+use crate::pool::BufferPool;
+use crate::prelude::{Message, Transport};
 use async_stream::stream;
 use futures_core::stream::Stream;
 use std::sync::Arc;
@@ -7,18 +8,30 @@ use tokio::net::UdpSocket;
 #[derive(Debug)]
 pub struct Receiver {
     socket: Arc<UdpSocket>,
+    pool: Arc<BufferPool>,
 }
 
 impl Receiver {
     pub fn new(socket: Arc<UdpSocket>) -> Self {
-        Self { socket }
+        Self {
+            socket,
+            pool: Arc::new(BufferPool::new(0)),
+        }
+    }
+
+    /// Receives the messages in the buffers given back to the pool once answered
+    pub(crate) fn with_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.pool = pool;
+        self
     }
 
-    async fn receive(&self) -> std::io::Result<Message> {
-        let mut buffer = [0u8; 512];
+    pub async fn receive(&self) -> std::io::Result<Message> {
+        let mut buffer = self.pool.take(512);
         let (size, address) = self.socket.recv_from(&mut buffer).await?;
         Ok(Message {
-            address,
+            address: crate::socket::canonical(address),
+            listener: self.socket.local_addr()?,
+            transport: Transport::Udp,
             buffer,
             size,
         })
@@ -17,9 +17,11 @@ impl Sender {
             address,
             buffer,
             size,
+            ..
         } = message;
         tracing::debug!("sending message to {:?}", address);
-        self.socket.send_to(&buffer[0..*size], address).await?;
+        let destination = crate::socket::destination(*address, self.socket.local_addr()?);
+        self.socket.send_to(&buffer[0..*size], destination).await?;
         Ok(())
     }
 }
@@ -0,0 +1,117 @@
+//! Sockets bound with options that can't be given to the tokio constructors
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+
+/// Options applied to the sockets before binding them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    /// On an IPv6 address, only accept the IPv6 clients. Otherwise the IPv4 ones
+    /// are accepted too, as IPv4-mapped addresses.
+    pub ipv6_only: bool,
+    /// Allow several UDP sockets on the same address, the kernel spreading the messages
+    /// between them on Linux. Not applied to the TCP listeners.
+    pub reuse_port: bool,
+}
+
+fn socket(
+    address: SocketAddr,
+    kind: Type,
+    protocol: Protocol,
+    options: BindOptions,
+) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), kind, Some(protocol))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(options.ipv6_only)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Binds a non blocking UDP socket
+pub fn bind_udp(address: SocketAddr, options: BindOptions) -> std::io::Result<std::net::UdpSocket> {
+    let socket = socket(address, Type::DGRAM, Protocol::UDP, options)?;
+    #[cfg(unix)]
+    if options.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+/// Binds a non blocking TCP listener
+pub fn bind_tcp(
+    address: SocketAddr,
+    options: BindOptions,
+) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket(address, Type::STREAM, Protocol::TCP, options)?;
+    // like tokio, so that the port can be bound again right after a restart
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Address of the client as it would be seen on an IPv4 socket,
+/// unwrapping the IPv4-mapped addresses of the dual-stack sockets
+pub fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Address to send a message to from a socket bound to the local address,
+/// the IPv4 destinations being mapped for the IPv6 sockets
+pub fn destination(address: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (address.ip(), local.ip()) {
+        (IpAddr::V4(ip), IpAddr::V6(_)) => {
+            SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), address.port())
+        }
+        _ => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_udp, canonical, destination, BindOptions};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn should_map_ipv4_addresses_on_ipv6_sockets() {
+        let client: SocketAddr = "[::ffff:192.168.1.10]:1234".parse().unwrap();
+        assert_eq!(canonical(client), "192.168.1.10:1234".parse().unwrap());
+        let local: SocketAddr = "[::]:53".parse().unwrap();
+        assert_eq!(destination(canonical(client), local), client);
+        let local: SocketAddr = "0.0.0.0:53".parse().unwrap();
+        assert_eq!(destination(canonical(client), local), canonical(client));
+    }
+
+    #[test]
+    fn should_share_address_with_reuse_port() {
+        let options = BindOptions {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind_udp("127.0.0.1:0".parse().unwrap(), options).unwrap();
+        let address = first.local_addr().unwrap();
+        let second = bind_udp(address, options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), address);
+        assert!(bind_udp(address, BindOptions::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn should_receive_ipv4_on_dual_stack_socket() {
+        let Ok(socket) = bind_udp("[::]:0".parse().unwrap(), BindOptions::default()) else {
+            // ipv6 is disabled on this machine
+            return;
+        };
+        let socket = tokio::net::UdpSocket::from_std(socket).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&[1, 2, 3], ("127.0.0.1", port))
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 3];
+        let (_, address) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(canonical(address), client.local_addr().unwrap());
+    }
+}
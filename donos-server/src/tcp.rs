@@ -0,0 +1,258 @@
+use crate::prelude::{Message, Transport};
+use crate::Handler;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+/// Time a client can stay connected without sending a message
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of the queries handled, bigger ones close the connection.
+///
+/// Responses can use the whole range allowed by the length prefix.
+const MAX_MESSAGE_SIZE: usize = 4096;
+/// Number of connections served at the same time by default
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// Server accepting the queries over TCP, each message being prefixed by its length
+/// on two bytes (RFC 1035 section 4.2.2).
+///
+/// Clients usually query over TCP when the UDP response was truncated.
+pub struct TcpServer<H> {
+    listener: TcpListener,
+    handler: Arc<H>,
+    /// One permit by connection served, the ones over the limit being closed right away
+    connections: Arc<Semaphore>,
+}
+
+impl<H: Handler + Send + Sync + 'static> TcpServer<H> {
+    pub async fn bind(address: SocketAddr, handler: H) -> std::io::Result<Self> {
+        Self::bind_with(address, crate::socket::BindOptions::default(), handler)
+    }
+
+    /// Same as `bind`, with the options applied to the listener before binding it
+    pub fn bind_with(
+        address: SocketAddr,
+        options: crate::socket::BindOptions,
+        handler: H,
+    ) -> std::io::Result<Self> {
+        let listener = crate::socket::bind_tcp(address, options)?;
+        Ok(Self {
+            listener: TcpListener::from_std(listener)?,
+            handler: Arc::new(handler),
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+        })
+    }
+
+    /// Uses a listener that is already bound, inherited from another process for example
+    pub fn from_std(listener: std::net::TcpListener, handler: H) -> std::io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener: TcpListener::from_std(listener)?,
+            handler: Arc::new(handler),
+            connections: Arc::new(Semaphore::new(DEFAULT_MAX_CONNECTIONS)),
+        })
+    }
+
+    /// Limits the number of connections served at the same time, for idle clients
+    /// not to hold all the file descriptors
+    pub fn with_max_connections(mut self, count: usize) -> Self {
+        self.connections = Arc::new(Semaphore::new(count.max(1)));
+        self
+    }
+
+    /// Address the listener is actually bound to
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(&self) -> std::io::Result<()> {
+        self.run_until(futures::future::pending()).await
+    }
+
+    /// Accepts connections until the shutdown future completes.
+    ///
+    /// The connections already accepted keep being served in their own task.
+    pub async fn run_until<F>(&self, shutdown: F) -> std::io::Result<()>
+    where
+        F: std::future::Future<Output = ()>,
+    {
+        let local = self.listener.local_addr()?;
+
+        tokio::pin!(shutdown);
+
+        loop {
+            let (stream, address) = tokio::select! {
+                biased;
+                _ = &mut shutdown => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok(found) => found,
+                    Err(error) => {
+                        // running out of file descriptors shouldn't stop the server
+                        tracing::warn!("couldn't accept connection: {error:?}");
+                        continue;
+                    }
+                },
+            };
+            let Ok(permit) = self.connections.clone().try_acquire_owned() else {
+                tracing::debug!("too many connections, closing the one from {address:?}");
+                continue;
+            };
+            tracing::debug!("accepted connection from {address:?}");
+            let handler = self.handler.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(error) = serve_connection(stream, address, local, handler).await {
+                    tracing::debug!("connection with {address:?} closed: {error:?}");
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl<H> std::os::fd::AsRawFd for TcpServer<H> {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+async fn serve_connection<H: Handler>(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    listener: SocketAddr,
+    handler: Arc<H>,
+) -> std::io::Result<()> {
+    loop {
+        let size = match tokio::time::timeout(IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(size)) => size as usize,
+            // the client closed the connection or stayed idle too long
+            Ok(Err(error)) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(error)) => return Err(error),
+            Err(_) => return Ok(()),
+        };
+        if size > MAX_MESSAGE_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("message of {size} bytes is too large"),
+            ));
+        }
+        let mut buffer = vec![0u8; size];
+        tokio::time::timeout(IDLE_TIMEOUT, stream.read_exact(&mut buffer))
+            .await
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+
+        let message = Message {
+            address: crate::socket::canonical(address),
+            listener,
+            transport: Transport::Tcp,
+            buffer,
+            size,
+        };
+        if let Some(response) = handler.handle(message).await {
+            let Ok(length) = u16::try_from(response.size) else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("response of {} bytes is too large", response.size),
+                ));
+            };
+            tracing::debug!("sending message to {:?}", address);
+            let mut output = Vec::with_capacity(response.size + 2);
+            output.extend_from_slice(&length.to_be_bytes());
+            output.extend_from_slice(&response.buffer[..response.size]);
+            stream.write_all(&output).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::{Message, Transport};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers the message reversed
+    struct ReverseHandler;
+
+    #[async_trait::async_trait]
+    impl crate::Handler for ReverseHandler {
+        async fn handle(&self, mut message: Message) -> Option<Message> {
+            assert_eq!(message.transport, Transport::Tcp);
+            message.buffer[..message.size].reverse();
+            Some(message)
+        }
+    }
+
+    #[tokio::test]
+    async fn should_handle_length_prefixed_messages() {
+        let server = super::TcpServer::bind("127.0.0.1:0".parse().unwrap(), ReverseHandler)
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        // two messages in a row on the same connection
+        stream
+            .write_all(&[0, 3, 1, 2, 3, 0, 2, 4, 5])
+            .await
+            .unwrap();
+
+        let mut response = [0u8; 9];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 3, 3, 2, 1, 0, 2, 5, 4]);
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_over_the_limit() {
+        let server = super::TcpServer::bind("127.0.0.1:0".parse().unwrap(), ReverseHandler)
+            .await
+            .unwrap()
+            .with_max_connections(1);
+        let address = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut first = tokio::net::TcpStream::connect(address).await.unwrap();
+        first.write_all(&[0, 2, 1, 2]).await.unwrap();
+        let mut response = [0u8; 4];
+        first.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0, 2, 2, 1]);
+
+        let mut second = tokio::net::TcpStream::connect(address).await.unwrap();
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        // the permit is given back once the first connection is closed
+        drop(first);
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            stream.write_all(&[0, 1, 7]).await.unwrap();
+            let mut response = [0u8; 3];
+            if stream.read_exact(&mut response).await.is_ok() {
+                assert_eq!(response, [0, 1, 7]);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_close_on_too_large_message() {
+        let server = super::TcpServer::bind("127.0.0.1:0".parse().unwrap(), ReverseHandler)
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(&[0x20, 0]).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+}
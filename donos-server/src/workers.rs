@@ -0,0 +1,178 @@
+//! Messages received over UDP waiting to be handled, by a fixed number of workers
+use crate::prelude::Message;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Message dropped when the queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// The received one, the queued messages being answered first
+    #[default]
+    DropNewest,
+    /// The one waiting for the longest time, its client being the most likely to have given up
+    DropOldest,
+}
+
+/// How the received messages are handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WorkerOptions {
+    /// Number of messages handled at the same time
+    pub concurrency: usize,
+    /// Time after which a message stops being handled and gets no answer
+    pub timeout: Option<Duration>,
+    /// Number of received messages waiting for a worker
+    pub queue_size: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 64,
+            timeout: None,
+            queue_size: 1024,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    items: VecDeque<Message>,
+    closed: bool,
+    /// Set when a message is dropped, to warn once until the queue is empty again
+    overflowing: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Queue {
+    state: Mutex<State>,
+    notify: Notify,
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Queue {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            state: Mutex::default(),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+
+    pub fn push(&self, message: Message) {
+        let mut state = self.state.lock().unwrap();
+        if state.items.len() >= self.capacity {
+            let dropped = match self.overflow {
+                Overflow::DropNewest => message,
+                Overflow::DropOldest => {
+                    let oldest = state.items.pop_front().unwrap();
+                    state.items.push_back(message);
+                    oldest
+                }
+            };
+            if !state.overflowing {
+                state.overflowing = true;
+                tracing::warn!(
+                    "{} messages waiting to be handled, dropping the {}",
+                    self.capacity,
+                    match self.overflow {
+                        Overflow::DropNewest => "new ones",
+                        Overflow::DropOldest => "oldest ones",
+                    }
+                );
+            }
+            tracing::debug!("dropped message from {:?}", dropped.address);
+            return;
+        }
+        state.items.push_back(message);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Next message to handle, `None` once the queue is closed and empty
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            // created before looking at the queue, so that no notification is missed
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(message) = state.items.pop_front() {
+                    if state.items.is_empty() {
+                        state.overflowing = false;
+                    } else {
+                        self.notify.notify_one();
+                    }
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Stops the workers once the queued messages are handled
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overflow, Queue};
+    use crate::prelude::{Message, Transport};
+
+    fn message(port: u16) -> Message {
+        Message {
+            address: ([127, 0, 0, 1], port).into(),
+            listener: ([127, 0, 0, 1], 53).into(),
+            transport: Transport::Udp,
+            buffer: Vec::new(),
+            size: 0,
+        }
+    }
+
+    async fn drain(queue: &Queue) -> Vec<u16> {
+        let mut result = Vec::new();
+        while let Some(found) = queue.pop().await {
+            result.push(found.address.port());
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn should_drop_messages_when_full() {
+        for (overflow, expected) in [
+            (Overflow::DropNewest, vec![1, 2]),
+            (Overflow::DropOldest, vec![2, 3]),
+        ] {
+            let queue = Queue::new(2, overflow);
+            for port in 1..=3 {
+                queue.push(message(port));
+            }
+            queue.close();
+            assert_eq!(drain(&queue).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_wake_workers_until_closed() {
+        let queue = std::sync::Arc::new(Queue::new(8, Overflow::default()));
+        let worker = tokio::spawn({
+            let queue = queue.clone();
+            async move { drain(&queue).await }
+        });
+        tokio::task::yield_now().await;
+        queue.push(message(1));
+        queue.push(message(2));
+        queue.close();
+        assert_eq!(worker.await.unwrap(), vec![1, 2]);
+    }
+}
@@ -0,0 +1 @@
+drop table clients;
\ No newline at end of file
@@ -0,0 +1,6 @@
+create table clients (
+    id INTEGER NOT NULL PRIMARY KEY,
+    address TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1 @@
+drop table allowed_domains;
\ No newline at end of file
@@ -0,0 +1,5 @@
+create table allowed_domains (
+    id INTEGER NOT NULL PRIMARY KEY,
+    pattern TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+create table queries (
+    id INTEGER NOT NULL PRIMARY KEY,
+    client TEXT NOT NULL,
+    domain TEXT NOT NULL,
+    qtype INTEGER NOT NULL,
+    provenance TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+create index queries_created_at_idx on queries (created_at);
@@ -0,0 +1 @@
+alter table blocklists drop column enabled;
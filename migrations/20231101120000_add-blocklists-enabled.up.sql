@@ -0,0 +1 @@
+alter table blocklists add column enabled BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1 @@
+alter table queries drop column client_name;
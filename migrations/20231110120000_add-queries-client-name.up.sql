@@ -0,0 +1 @@
+alter table queries add column client_name TEXT;
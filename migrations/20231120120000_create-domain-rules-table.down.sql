@@ -0,0 +1 @@
+drop table domain_rules;
\ No newline at end of file
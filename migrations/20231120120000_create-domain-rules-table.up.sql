@@ -0,0 +1,6 @@
+create table domain_rules (
+    id INTEGER NOT NULL PRIMARY KEY,
+    domain TEXT NOT NULL UNIQUE,
+    blocked BOOLEAN NOT NULL,
+    created_at INTEGER NOT NULL
+);
\ No newline at end of file
@@ -0,0 +1,454 @@
+//! HTTP API to look at what the server is doing and manage it,
+//! without touching the database directly.
+use crate::dns::metrics::{Metrics, Provenance};
+use crate::dns::pipeline::blocklist::BlockingSwitch;
+use crate::repository::blocklist::{AllowPattern, BlocklistItem, DatabaseBlocklistService};
+use crate::repository::cache::CacheService;
+use crate::repository::query::{unix_now, DatabaseQueryLogService};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Number of domains and clients listed in the tops of the stats
+const TOP_SIZE: u32 = 10;
+
+/// Page showing the stats and recent queries, built on top of the API
+const DASHBOARD: &[u8] = include_bytes!("dashboard.html");
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Address the API listens to, only reachable from the host by default
+    #[serde(default = "Config::default_address")]
+    pub address: SocketAddr,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: Self::default_address(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_address() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 5380))
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub blocklist: Arc<DatabaseBlocklistService>,
+    pub blocking: Arc<BlockingSwitch>,
+    pub cache: Arc<dyn CacheService + Send + Sync>,
+    pub queries: DatabaseQueryLogService,
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<crate::healthcheck::Probe>,
+}
+
+/// Error returned as a status code with a message
+#[derive(Debug)]
+pub(crate) struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn internal(error: impl std::fmt::Debug) -> Self {
+        tracing::warn!("api request failed: {error:?}");
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{error:?}"))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "message": self.1 }))).into_response()
+    }
+}
+
+type ApiResult<T> = Result<T, ApiError>;
+
+pub(crate) fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/", get(dashboard))
+        .route("/health", get(health))
+        .route("/api/stats", get(stats))
+        .route("/api/queries", get(queries))
+        .route(
+            "/api/blocklists",
+            get(list_blocklists)
+                .post(add_blocklist)
+                .delete(remove_blocklist),
+        )
+        .route("/api/blocklists/reload", post(reload_blocklists))
+        .route("/api/blocking", get(blocking_status))
+        .route("/api/blocking/disable", post(disable_blocking))
+        .route("/api/blocking/enable", post(enable_blocking))
+        .route("/api/allowlist", get(list_allowed))
+        .route("/api/allowlist/:pattern", put(allow).delete(disallow))
+        .route("/api/cache", get(list_cache))
+        .route("/api/cache/flush", post(flush_cache))
+        .route("/api/cache/:name", delete(evict_cache))
+        .with_state(state)
+}
+
+/// Serves the API on the listener until the process stops
+pub(crate) async fn serve(
+    listener: std::net::TcpListener,
+    state: ApiState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let address = listener.local_addr()?;
+    let server = axum::Server::from_tcp(listener)?;
+    tracing::info!("api listening on {address}");
+    server.serve(router(state).into_make_service()).await?;
+    Ok(())
+}
+
+/// Resolves the domain of the healthcheck, answering 503 when it fails
+async fn health(State(state): State<ApiState>) -> Response {
+    match state.health.check().await {
+        Ok(status) => Json(status).into_response(),
+        Err(message) => ApiError(StatusCode::SERVICE_UNAVAILABLE, message).into_response(),
+    }
+}
+
+async fn dashboard() -> impl IntoResponse {
+    (
+        [(axum::http::header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        DASHBOARD,
+    )
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatsParams {
+    #[serde(default = "StatsParams::default_hours")]
+    hours: u64,
+}
+
+impl StatsParams {
+    fn default_hours() -> u64 {
+        24
+    }
+}
+
+async fn stats(
+    State(state): State<ApiState>,
+    Query(params): Query<StatsParams>,
+) -> ApiResult<Json<serde_json::Value>> {
+    let since = unix_now().saturating_sub(params.hours * 3600);
+    let log = state
+        .queries
+        .stats(since, TOP_SIZE)
+        .await
+        .map_err(ApiError::internal)?;
+    let responses: BTreeMap<&str, u64> = Provenance::ALL
+        .iter()
+        .map(|provenance| (provenance.as_str(), state.metrics.responses(*provenance)))
+        .collect();
+    Ok(Json(serde_json::json!({
+        "hours": params.hours,
+        "log": log,
+        "since_start": {
+            "responses": responses,
+            "internal": state.metrics.internal(),
+            "aaaa_filtered": state.metrics.aaaa_filtered(),
+            "cache": state.cache.stats(),
+        },
+    })))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct QueriesParams {
+    #[serde(default = "QueriesParams::default_limit")]
+    limit: u32,
+}
+
+impl QueriesParams {
+    fn default_limit() -> u32 {
+        100
+    }
+}
+
+async fn queries(
+    State(state): State<ApiState>,
+    Query(params): Query<QueriesParams>,
+) -> ApiResult<impl IntoResponse> {
+    let found = state
+        .queries
+        .recent(params.limit)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(Json(found))
+}
+
+async fn list_blocklists(State(state): State<ApiState>) -> ApiResult<impl IntoResponse> {
+    let found = state.blocklist.list().await.map_err(ApiError::internal)?;
+    Ok(Json(found))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NewBlocklist {
+    name: String,
+    #[serde(flatten)]
+    item: BlocklistItem,
+}
+
+/// Imports a blocklist that is not in the configuration, it isn't refreshed by the import command
+async fn add_blocklist(
+    State(state): State<ApiState>,
+    Json(payload): Json<NewBlocklist>,
+) -> ApiResult<impl IntoResponse> {
+    let (inserted, deleted) = state
+        .blocklist
+        .add(&payload.name, &payload.item)
+        .await
+        .map_err(|error| ApiError(StatusCode::BAD_GATEWAY, error.to_string()))?;
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "inserted": inserted, "deleted": deleted })),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BlocklistParams {
+    url: String,
+}
+
+async fn remove_blocklist(
+    State(state): State<ApiState>,
+    Query(params): Query<BlocklistParams>,
+) -> ApiResult<StatusCode> {
+    match state.blocklist.remove(&params.url).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(error) => Err(ApiError::internal(error)),
+    }
+}
+
+fn blocking_json(switch: &BlockingSwitch) -> Json<serde_json::Value> {
+    let remaining = switch.remaining();
+    Json(serde_json::json!({
+        "enabled": remaining.is_none(),
+        "remaining": remaining.map(|value| value.as_secs()),
+    }))
+}
+
+async fn blocking_status(State(state): State<ApiState>) -> Json<serde_json::Value> {
+    blocking_json(&state.blocking)
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DisableBlocking {
+    minutes: u64,
+}
+
+/// Stops blocking for the given number of minutes, blocking again afterwards
+async fn disable_blocking(
+    State(state): State<ApiState>,
+    Json(payload): Json<DisableBlocking>,
+) -> Json<serde_json::Value> {
+    state
+        .blocking
+        .disable(Duration::from_secs(payload.minutes * 60));
+    blocking_json(&state.blocking)
+}
+
+async fn enable_blocking(State(state): State<ApiState>) -> Json<serde_json::Value> {
+    state.blocking.enable();
+    blocking_json(&state.blocking)
+}
+
+/// Applies right away the changes made by another process, like the import command
+async fn reload_blocklists(State(state): State<ApiState>) -> ApiResult<StatusCode> {
+    state.blocklist.reload().await.map_err(ApiError::internal)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_allowed(State(state): State<ApiState>) -> ApiResult<impl IntoResponse> {
+    let found = state
+        .blocklist
+        .allowed()
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(Json(found))
+}
+
+fn parse_pattern(pattern: &str) -> ApiResult<AllowPattern> {
+    pattern
+        .parse()
+        .map_err(|error: crate::repository::blocklist::InvalidAllowPattern| {
+            ApiError(StatusCode::BAD_REQUEST, error.to_string())
+        })
+}
+
+async fn allow(
+    State(state): State<ApiState>,
+    Path(pattern): Path<String>,
+) -> ApiResult<StatusCode> {
+    let pattern = parse_pattern(&pattern)?;
+    match state.blocklist.allow(&pattern).await {
+        Ok(true) => Ok(StatusCode::CREATED),
+        Ok(false) => Ok(StatusCode::OK),
+        Err(error) => Err(ApiError::internal(error)),
+    }
+}
+
+async fn disallow(
+    State(state): State<ApiState>,
+    Path(pattern): Path<String>,
+) -> ApiResult<StatusCode> {
+    let pattern = parse_pattern(&pattern)?;
+    match state.blocklist.disallow(&pattern).await {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(error) => Err(ApiError::internal(error)),
+    }
+}
+
+async fn list_cache(State(state): State<ApiState>) -> ApiResult<impl IntoResponse> {
+    let found = state.cache.entries().await.map_err(ApiError::internal)?;
+    Ok(Json(found))
+}
+
+async fn evict_cache(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> ApiResult<StatusCode> {
+    let name = crate::common::domain::normalize(&name);
+    match state.cache.evict(&name).await.map_err(ApiError::internal)? {
+        0 => Ok(StatusCode::NOT_FOUND),
+        count => {
+            tracing::info!("{count} cache entries of {name} evicted through the api");
+            Ok(StatusCode::NO_CONTENT)
+        }
+    }
+}
+
+async fn flush_cache(State(state): State<ApiState>) -> ApiResult<StatusCode> {
+    state.cache.flush().await.map_err(ApiError::internal)?;
+    tracing::info!("cache flushed through the api");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        allow, dashboard, disallow, evict_cache, flush_cache, health, list_allowed, ApiState,
+    };
+    use crate::dns::handler::DnsHandler;
+    use crate::repository::blocklist::{Config as BlocklistConfig, MemoryBlocklistService};
+    use crate::repository::cache::{CacheService, MemoryCacheService};
+    use crate::repository::lookup::MockLookupService;
+    use crate::repository::query::DatabaseQueryLogService;
+    use axum::extract::{Path, State};
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::QueryType;
+    use std::sync::Arc;
+
+    async fn state() -> (ApiState, Arc<MemoryCacheService>) {
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+        let cache = Arc::new(MemoryCacheService::new(10));
+        let state = ApiState {
+            blocklist: Arc::new(BlocklistConfig::default().build(database.clone())),
+            blocking: Arc::default(),
+            cache: cache.clone(),
+            queries: DatabaseQueryLogService::new(database),
+            metrics: Arc::default(),
+            health: Arc::new(crate::healthcheck::Probe::new(
+                DnsHandler::new(
+                    Arc::new(MemoryBlocklistService::default()),
+                    cache.clone(),
+                    Arc::new(MockLookupService::default()),
+                ),
+                &Default::default(),
+            )),
+        };
+        (state, cache)
+    }
+
+    #[tokio::test]
+    async fn should_manage_allowlist() {
+        let (state, _) = state().await;
+        let created = allow(State(state.clone()), Path("*.perdu.com".into())).await;
+        assert_eq!(created.unwrap(), StatusCode::CREATED);
+        let again = allow(State(state.clone()), Path("*.perdu.com".into())).await;
+        assert_eq!(again.unwrap(), StatusCode::OK);
+        let invalid = allow(State(state.clone()), Path("*".into())).await;
+        assert_eq!(invalid.unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        let listed = list_allowed(State(state.clone())).await.unwrap();
+        assert_eq!(listed.into_response().status(), StatusCode::OK);
+        assert_eq!(
+            state.blocklist.allowed().await.unwrap(),
+            vec!["*.perdu.com"]
+        );
+
+        let removed = disallow(State(state.clone()), Path("*.perdu.com".into())).await;
+        assert_eq!(removed.unwrap(), StatusCode::NO_CONTENT);
+        let missing = disallow(State(state), Path("*.perdu.com".into())).await;
+        assert_eq!(missing.unwrap(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn should_flush_cache() {
+        let (state, cache) = state().await;
+        cache
+            .persist_negative("perdu.com", QueryType::A, ResponseCode::NoError, None, 60)
+            .await
+            .unwrap();
+        assert_eq!(
+            flush_cache(State(state)).await.unwrap(),
+            StatusCode::NO_CONTENT
+        );
+        assert!(cache
+            .request("perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn should_evict_cache_entries() {
+        let (state, cache) = state().await;
+        for qtype in [QueryType::A, QueryType::AAAA] {
+            cache
+                .persist_negative("perdu.com", qtype, ResponseCode::NoError, None, 60)
+                .await
+                .unwrap();
+        }
+        let evicted = evict_cache(State(state.clone()), Path("Perdu.com.".into())).await;
+        assert_eq!(evicted.unwrap(), StatusCode::NO_CONTENT);
+        assert!(cache.entries().await.unwrap().is_empty());
+        let missing = evict_cache(State(state), Path("perdu.com".into())).await;
+        assert_eq!(missing.unwrap(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn should_be_unhealthy_when_upstream_fails() {
+        let (state, _) = state().await;
+        let response = health(State(state)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn should_serve_dashboard() {
+        let response = dashboard().await.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[axum::http::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+    }
+}
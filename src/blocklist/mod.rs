@@ -1,10 +1,109 @@
-use clap::Args;
+use clap::{Args, Subcommand};
+use std::net::IpAddr;
 
-use crate::repository::blocklist::BlocklistService;
+use crate::common::domain::normalize;
+use crate::repository::blocklist::{
+    AllowPattern, BlocklistService, DatabaseBlocklistService, DomainCheck,
+};
 
 /// Handle the blocklist in database
 #[derive(Args, Debug)]
-pub struct Command {}
+pub struct Command {
+    /// Imports the blocklists when not specified
+    #[command(subcommand)]
+    inner: Option<Action>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Import the configured blocklists
+    Import,
+    /// Show what importing the configured blocklists would change, without importing them
+    Diff {
+        /// Number of added and removed domains printed for each blocklist
+        #[arg(long, default_value_t = 0)]
+        samples: u32,
+    },
+    /// Tell whether a domain is blocked, by which blocklists and which allowlist patterns
+    Check {
+        domain: String,
+        /// Address of the client, to only consider the blocklists of its group
+        #[arg(long)]
+        client: Option<IpAddr>,
+    },
+    /// Block again the domains of a disabled blocklist
+    Enable {
+        /// Name of the blocklist in the configuration
+        name: String,
+    },
+    /// Stop blocking the domains of a blocklist, without removing them
+    Disable {
+        /// Name of the blocklist in the configuration
+        name: String,
+    },
+    /// Domains that are never blocked, even when in a blocklist
+    Allow {
+        #[command(subcommand)]
+        inner: AllowAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AllowAction {
+    /// Allow a domain, or its subdomains with a pattern like `*.example.com`
+    Add { pattern: AllowPattern },
+    /// Remove a domain from the allowlist
+    Remove { pattern: AllowPattern },
+    /// List the allowed domains
+    List,
+}
+
+fn print_check(domain: &str, check: &DomainCheck, allowed_by_policy: bool) {
+    if check.is_blocked() && !allowed_by_policy {
+        println!("{domain} is blocked");
+    } else {
+        println!("{domain} is not blocked");
+    }
+    if check.blocklists.is_empty() {
+        println!("  in no blocklist");
+    }
+    for found in check.blocklists.iter() {
+        let url = found.url.as_deref().unwrap_or("unknown source");
+        let mut line = match found.name {
+            Some(ref name) => format!("  in blocklist {name} ({url})"),
+            None => format!("  in blocklist {url}, not in the configuration"),
+        };
+        if !found.enabled {
+            line.push_str(", disabled");
+        }
+        if !found.applied {
+            line.push_str(", not applied to the client");
+        }
+        println!("{line}");
+    }
+    for pattern in check.allowed_by.iter() {
+        println!("  allowed by the allowlist pattern {pattern}");
+    }
+    if let Some((ref domain, rule)) = check.rule {
+        println!("  {rule} rule of {domain}, set with the domain command");
+    }
+    if allowed_by_policy {
+        println!("  allowed by the policy of the configuration");
+    }
+}
+
+async fn set_enabled(blocklist: &DatabaseBlocklistService, name: &str, enabled: bool) {
+    let Some(url) = blocklist.url(name) else {
+        tracing::error!("unknown blocklist {name:?}, it's not in the configuration");
+        return;
+    };
+    let state = if enabled { "enabled" } else { "disabled" };
+    match blocklist.set_enabled(&url, enabled).await {
+        Ok(true) => tracing::info!("blocklist {name:?} {state}"),
+        Ok(false) => tracing::warn!("blocklist {name:?} has not been imported yet"),
+        Err(err) => tracing::error!("couldn't update blocklist {name:?}: {err:?}"),
+    }
+}
 
 impl Command {
     pub async fn run(self, config: crate::config::Config) {
@@ -17,16 +116,71 @@ impl Command {
             .await
             .expect("unable to migrate the database");
 
-        let blocklist = config.blocklists.build(database);
-        match blocklist.import().await {
-            Ok((inserted, deleted)) => {
-                tracing::info!(
-                    "inserted {inserted} new domains and deleted {deleted} existing domains"
-                );
-            }
-            Err(err) => {
-                tracing::error!("couldn't import blocklists: {err:?}");
+        let policy = config.policy.build();
+        let blocklist = config.blocklists.build(database).with_groups(config.groups);
+        match self.inner.unwrap_or(Action::Import) {
+            Action::Import => match blocklist.import().await {
+                Ok((inserted, deleted)) => {
+                    tracing::info!(
+                        "inserted {inserted} new domains and deleted {deleted} existing domains"
+                    );
+                }
+                Err(err) => {
+                    tracing::error!("couldn't import blocklists: {err:?}");
+                }
+            },
+            Action::Diff { samples } => match blocklist.diff(samples).await {
+                Ok(diffs) => {
+                    for (name, diff) in diffs {
+                        match diff {
+                            Ok(diff) => {
+                                println!("{name}\t+{}\t-{}", diff.added, diff.removed);
+                                for domain in diff.added_samples {
+                                    println!("  + {domain}");
+                                }
+                                for domain in diff.removed_samples {
+                                    println!("  - {domain}");
+                                }
+                            }
+                            Err(err) => tracing::error!("couldn't load blocklist {name:?}: {err}"),
+                        }
+                    }
+                }
+                Err(err) => tracing::error!("couldn't compare blocklists: {err:?}"),
+            },
+            Action::Check { domain, client } => {
+                let domain = normalize(&domain);
+                match blocklist.check(&domain, client).await {
+                    Ok(check) => print_check(&domain, &check, policy.is_allowed(&domain)),
+                    Err(err) => tracing::error!("couldn't check domain: {err:?}"),
+                }
             }
+            Action::Enable { name } => set_enabled(&blocklist, &name, true).await,
+            Action::Disable { name } => set_enabled(&blocklist, &name, false).await,
+            Action::Allow {
+                inner: AllowAction::Add { pattern },
+            } => match blocklist.allow(&pattern).await {
+                Ok(true) => tracing::info!("{pattern} allowed"),
+                Ok(false) => tracing::warn!("{pattern} was already allowed"),
+                Err(err) => tracing::error!("couldn't allow domain: {err:?}"),
+            },
+            Action::Allow {
+                inner: AllowAction::Remove { pattern },
+            } => match blocklist.disallow(&pattern).await {
+                Ok(true) => tracing::info!("{pattern} removed from the allowlist"),
+                Ok(false) => tracing::warn!("{pattern} is not in the allowlist"),
+                Err(err) => tracing::error!("couldn't remove domain: {err:?}"),
+            },
+            Action::Allow {
+                inner: AllowAction::List,
+            } => match blocklist.allowed().await {
+                Ok(list) => {
+                    for pattern in list {
+                        println!("{pattern}");
+                    }
+                }
+                Err(err) => tracing::error!("couldn't list allowed domains: {err:?}"),
+            },
         }
     }
 }
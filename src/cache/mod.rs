@@ -0,0 +1,104 @@
+use clap::{Args, Subcommand};
+use reqwest::{Method, StatusCode};
+use std::net::SocketAddr;
+
+use crate::repository::cache::CacheEntry;
+
+/// Look at the cache of the running server, or remove some of its entries,
+/// through its API that must be enabled
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Address of the API, defaults to the one in the configuration
+    #[arg(long)]
+    api: Option<SocketAddr>,
+    #[command(subcommand)]
+    inner: Action,
+}
+
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// List the entries with their remaining ttl, the negative ones in brackets
+    List {
+        /// Only list the entries of this name
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Remove all the entries
+    Flush,
+    /// Remove the entries of a name, whatever their type
+    Evict { name: String },
+}
+
+fn exit_with<E: std::fmt::Display>(message: &str, error: E) -> ! {
+    tracing::error!("{message}: {error}");
+    std::process::exit(1)
+}
+
+impl Command {
+    /// Address to reach the API, the local one when it listens on all the interfaces
+    fn base_url(&self, config: &crate::api::Config) -> String {
+        let mut address = self.api.unwrap_or(config.address);
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        format!("http://{address}")
+    }
+
+    pub async fn run(self, config: crate::config::Config) {
+        if !config.api.enabled && self.api.is_none() {
+            tracing::warn!("the api is disabled in the configuration, the server might not answer");
+        }
+        let base = self.base_url(&config.api);
+        let client = reqwest::Client::new();
+        let (method, path) = match self.inner {
+            Action::List { .. } => (Method::GET, String::from("/api/cache")),
+            Action::Flush => (Method::POST, String::from("/api/cache/flush")),
+            Action::Evict { ref name } => (Method::DELETE, format!("/api/cache/{name}")),
+        };
+        let response = match client.request(method, format!("{base}{path}")).send().await {
+            Ok(found) => found,
+            Err(error) => exit_with(&format!("unable to reach the api on {base}"), error),
+        };
+        let status = response.status();
+        match self.inner {
+            Action::List { name } if status.is_success() => {
+                let body = match response.bytes().await {
+                    Ok(found) => found,
+                    Err(error) => exit_with("unable to read the cache entries", error),
+                };
+                let entries: Vec<CacheEntry> = match serde_json::from_slice(&body) {
+                    Ok(found) => found,
+                    Err(error) => exit_with("unable to read the cache entries", error),
+                };
+                let name = name.map(|name| crate::common::domain::normalize(&name).into_owned());
+                for entry in entries
+                    .into_iter()
+                    .filter(|entry| name.as_ref().is_none_or(|name| *name == entry.domain))
+                {
+                    let ttl = if entry.ttl < 0 {
+                        format!("expired {}s ago", -entry.ttl)
+                    } else {
+                        format!("{}s", entry.ttl)
+                    };
+                    if entry.records == 0 {
+                        println!("{}\t{}\t{ttl}\t[{}]", entry.domain, entry.qtype, entry.code);
+                    } else {
+                        println!(
+                            "{}\t{}\t{ttl}\t{} records",
+                            entry.domain, entry.qtype, entry.records
+                        );
+                    }
+                }
+            }
+            Action::Flush if status.is_success() => tracing::info!("cache flushed"),
+            Action::Evict { name } if status == StatusCode::NOT_FOUND => {
+                tracing::warn!("{name} is not in cache")
+            }
+            Action::Evict { name } if status.is_success() => tracing::info!("{name} evicted"),
+            _ => exit_with("the api refused the request", status),
+        }
+    }
+}
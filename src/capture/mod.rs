@@ -0,0 +1,63 @@
+use clap::Args;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::DnsPacket;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+fn describe(bytes: &[u8], size: usize) -> String {
+    if bytes.is_empty() {
+        return String::from("-");
+    }
+    if bytes.len() < size {
+        return format!("{size} bytes, too large to be kept whole");
+    }
+    match DnsPacket::try_from(BytePacketBuffer::new(bytes.to_vec())) {
+        Ok(packet) => {
+            let question = packet
+                .questions
+                .first()
+                .map(|question| format!("{} {:?}", question.name, question.qtype))
+                .unwrap_or_default();
+            format!(
+                "id={} {question} rcode={:?} answers={}",
+                packet.header.id,
+                packet.header.response_code,
+                packet.answers.len()
+            )
+        }
+        Err(error) => format!("unreadable packet: {error}"),
+    }
+}
+
+/// Print the packets kept in the capture ring buffer
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Path to the capture file, defaults to the one in the configuration
+    #[arg(long)]
+    path: Option<PathBuf>,
+}
+
+impl Command {
+    pub async fn run(self, config: crate::config::Config) {
+        let path = self.path.unwrap_or(config.dns.capture.path);
+        let exchanges = match crate::dns::capture::read(&path) {
+            Ok(found) => found,
+            Err(error) => {
+                tracing::error!("unable to read capture file {path:?}: {error}");
+                std::process::exit(1);
+            }
+        };
+        for exchange in exchanges {
+            let millis = exchange
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            println!(
+                "{millis}\tquery: {}\tresponse: {}",
+                describe(&exchange.query, exchange.query_size),
+                describe(&exchange.response, exchange.response_size)
+            );
+        }
+    }
+}
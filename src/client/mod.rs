@@ -0,0 +1,57 @@
+use clap::{Args, Subcommand};
+
+use crate::repository::client::{ClientAddress, DatabaseClientService};
+
+/// Give human names to the clients, used in the logs instead of their addresses
+#[derive(Args, Debug)]
+pub struct Command {
+    #[command(subcommand)]
+    inner: Action,
+}
+
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Name a client by its ip or mac address
+    Set {
+        address: ClientAddress,
+        name: String,
+    },
+    /// Remove the name of a client
+    Remove { address: ClientAddress },
+    /// List the named clients
+    List,
+}
+
+impl Command {
+    pub async fn run(self, config: crate::config::Config) {
+        let database = config
+            .database
+            .build()
+            .await
+            .expect("unable to connect to database");
+        crate::service::database::migrate(&database)
+            .await
+            .expect("unable to migrate the database");
+
+        let clients = DatabaseClientService::new(database);
+        match self.inner {
+            Action::Set { address, name } => match clients.set(address, &name).await {
+                Ok(_) => tracing::info!("client {address} named {name:?}"),
+                Err(err) => tracing::error!("couldn't name client: {err:?}"),
+            },
+            Action::Remove { address } => match clients.remove(address).await {
+                Ok(true) => tracing::info!("client {address} removed"),
+                Ok(false) => tracing::warn!("client {address} has no name"),
+                Err(err) => tracing::error!("couldn't remove client: {err:?}"),
+            },
+            Action::List => match clients.list().await {
+                Ok(list) => {
+                    for client in list {
+                        println!("{}\t{}", client.address, client.name);
+                    }
+                }
+                Err(err) => tracing::error!("couldn't list clients: {err:?}"),
+            },
+        }
+    }
+}
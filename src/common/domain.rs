@@ -0,0 +1,85 @@
+use std::borrow::Cow;
+
+/// Lowercases the domain and removes the leading and trailing dots,
+/// the root domain being represented by an empty string.
+///
+/// The internationalized names are converted to their punycode form (RFC 5891),
+/// so that `пример.рф` and `xn--e1afmkfd.xn--p1ai` are the same domain.
+///
+/// Only allocates when the domain is not already normalized.
+pub fn normalize(domain: &str) -> Cow<'_, str> {
+    let trimmed = domain.trim_matches('.');
+    if !trimmed.is_ascii() {
+        Cow::Owned(idna::domain_to_ascii(trimmed).unwrap_or_else(|_| trimmed.to_lowercase()))
+    } else if trimmed.bytes().any(|c| c.is_ascii_uppercase()) {
+        Cow::Owned(trimmed.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(trimmed)
+    }
+}
+
+/// Compares two domains as `normalize` would, the names being kept with the case sent by
+/// the clients (RFC 4343).
+///
+/// Doesn't allocate, unlike comparing the normalized domains.
+pub fn equals(left: &str, right: &str) -> bool {
+    left.trim_matches('.')
+        .eq_ignore_ascii_case(right.trim_matches('.'))
+}
+
+/// Checks if the domain is the suffix itself or one of its subdomains.
+///
+/// `matches_suffix("foo.lan", "lan")` is true, `matches_suffix("foolan", "lan")` is not.
+pub fn matches_suffix(domain: &str, suffix: &str) -> bool {
+    match domain.strip_suffix(suffix) {
+        Some(rest) => rest.is_empty() || rest.ends_with('.'),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{equals, matches_suffix, normalize};
+    use std::borrow::Cow;
+
+    #[test]
+    fn should_normalize() {
+        assert_eq!(normalize("."), "");
+        assert_eq!(normalize("Perdu.com."), "perdu.com");
+        assert_eq!(normalize(".lan"), "lan");
+        assert_eq!(normalize("Пример.рф"), "xn--e1afmkfd.xn--p1ai");
+        assert_eq!(normalize("*.пример.рф"), "*.xn--e1afmkfd.xn--p1ai");
+    }
+
+    #[test]
+    fn should_not_allocate_when_normalized() {
+        assert!(matches!(normalize("perdu.com"), Cow::Borrowed("perdu.com")));
+        assert!(matches!(
+            normalize("perdu.com."),
+            Cow::Borrowed("perdu.com")
+        ));
+        assert!(matches!(normalize("Perdu.com"), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn should_compare_whatever_the_case() {
+        assert!(equals("WwW.PerDu.CoM", "www.perdu.com."));
+        assert!(equals(".", ""));
+        assert!(!equals("www.perdu.com", "perdu.com"));
+    }
+
+    #[test]
+    fn should_match_suffix() {
+        assert!(matches_suffix("lan", "lan"));
+        assert!(matches_suffix("printer.lan", "lan"));
+        assert!(matches_suffix("a.b.localdomain", "localdomain"));
+        assert!(matches_suffix("www.perdu.com", "perdu.com"));
+    }
+
+    #[test]
+    fn should_not_match_suffix() {
+        assert!(!matches_suffix("foolan", "lan"));
+        assert!(!matches_suffix("lan.com", "lan"));
+        assert!(!matches_suffix("perdu.com", "www.perdu.com"));
+    }
+}
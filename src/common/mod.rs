@@ -0,0 +1,3 @@
+pub mod domain;
+pub mod schedule;
+pub mod source;
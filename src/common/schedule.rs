@@ -0,0 +1,248 @@
+//! Time windows during which a rule applies, like `mon-fri 09:00-17:00`.
+//!
+//! The days follow the weekday field of cron: `*`, names or numbers from 0 for sunday
+//! to 7 for sunday again, lists like `sat,sun` and ranges like `mon-fri`.
+use std::fmt::Display;
+use std::str::FromStr;
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const ALL_DAYS: u8 = 0b111_1111;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// Day of the week and minute of the day, in the local time of the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalTime {
+    /// From 0 for sunday to 6 for saturday
+    pub weekday: u8,
+    pub minute: u16,
+}
+
+impl LocalTime {
+    pub fn new(weekday: u8, hour: u16, minute: u16) -> Self {
+        Self {
+            weekday: weekday % 7,
+            minute: hour * 60 + minute,
+        }
+    }
+}
+
+/// Source of the current time, replaced in the tests
+pub trait Clock {
+    fn now(&self) -> LocalTime;
+    /// Changes every minute and is cheaper to get than `now`, for the values
+    /// depending on the local time to be computed again only when it changes
+    fn minute(&self) -> u64;
+}
+
+/// Local time of the system, following its timezone
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> LocalTime {
+        // SAFETY: time accepts a null pointer, the result being returned instead
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        // SAFETY: an all zero tm is valid, it's only read after being filled
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        // SAFETY: both pointers are valid during the call
+        unsafe { libc::localtime_r(&now, &mut tm) };
+        LocalTime::new(tm.tm_wday as u8, tm.tm_hour as u16, tm.tm_min as u16)
+    }
+
+    fn minute(&self) -> u64 {
+        // the offsets of the timezones are whole minutes, the local minutes change at the same time
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() / 60)
+    }
+}
+
+/// Days of the week and range of time, the range going over midnight when it ends
+/// before it starts, like `22:00-06:00`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    /// One bit per day, from sunday
+    days: u8,
+    start: u16,
+    end: u16,
+}
+
+impl Window {
+    fn has_day(&self, weekday: u8) -> bool {
+        self.days & (1 << (weekday % 7)) != 0
+    }
+
+    pub fn contains(&self, time: LocalTime) -> bool {
+        if self.start <= self.end {
+            return self.has_day(time.weekday)
+                && self.start <= time.minute
+                && time.minute < self.end;
+        }
+        // the night started the day before
+        (self.has_day(time.weekday) && self.start <= time.minute)
+            || (self.has_day(time.weekday + 6) && time.minute < self.end)
+    }
+}
+
+/// Whether one of the windows contains the time, always when there is no window
+pub fn is_active(windows: &[Window], time: LocalTime) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(time))
+}
+
+#[derive(Debug)]
+pub struct InvalidWindow(String);
+
+impl Display for InvalidWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} is not a time window like \"mon-fri 09:00-17:00\"",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidWindow {}
+
+fn parse_day(value: &str) -> Option<u8> {
+    match value.parse::<u8>() {
+        Ok(number) if number <= 7 => Some(number % 7),
+        Ok(_) => None,
+        Err(_) => DAY_NAMES
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value))
+            .map(|index| index as u8),
+    }
+}
+
+fn parse_days(value: &str) -> Option<u8> {
+    if value == "*" {
+        return Some(ALL_DAYS);
+    }
+    value.split(',').try_fold(0, |days, item| {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (parse_day(first)?, parse_day(last)?),
+            None => (parse_day(item)?, parse_day(item)?),
+        };
+        // a range like fri-mon goes over the weekend
+        let count = (last + 7 - first) % 7 + 1;
+        Some((0..count).fold(days, |days, offset| days | 1 << ((first + offset) % 7)))
+    })
+}
+
+fn parse_time(value: &str) -> Option<u16> {
+    let (hour, minute) = value.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?);
+    let result = hour * 60 + minute;
+    (minute < 60 && result <= MINUTES_PER_DAY).then_some(result)
+}
+
+impl FromStr for Window {
+    type Err = InvalidWindow;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidWindow(value.to_string());
+        let mut window = Window {
+            days: ALL_DAYS,
+            start: 0,
+            end: MINUTES_PER_DAY,
+        };
+        let mut parts = value.split_whitespace();
+        let mut next = parts.next().ok_or_else(invalid)?;
+        if !next.contains(':') {
+            window.days = parse_days(next).ok_or_else(invalid)?;
+            next = match parts.next() {
+                Some(found) => found,
+                None => return Ok(window),
+            };
+        }
+        let (start, end) = next.split_once('-').ok_or_else(invalid)?;
+        window.start = parse_time(start).ok_or_else(invalid)?;
+        window.end = parse_time(end).ok_or_else(invalid)?;
+        if parts.next().is_some() || window.start == window.end {
+            return Err(invalid());
+        }
+        Ok(window)
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = InvalidWindow;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.days == ALL_DAYS {
+            f.write_str("*")?;
+        } else {
+            let days: Vec<_> = (0..7u8)
+                .filter(|day| self.has_day(*day))
+                .map(|day| DAY_NAMES[day as usize])
+                .collect();
+            f.write_str(&days.join(","))?;
+        }
+        write!(
+            f,
+            " {:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl From<Window> for String {
+    fn from(value: Window) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_active, LocalTime, Window};
+
+    #[test]
+    fn should_parse_windows() {
+        let window: Window = "mon-fri 09:00-17:30".parse().unwrap();
+        assert_eq!(window.to_string(), "mon,tue,wed,thu,fri 09:00-17:30");
+        assert_eq!(
+            "Sat,0".parse::<Window>().unwrap().to_string(),
+            "sun,sat 00:00-24:00"
+        );
+        assert_eq!(
+            "fri-1 22:00-06:00".parse::<Window>().unwrap().to_string(),
+            "sun,mon,fri,sat 22:00-06:00"
+        );
+        assert_eq!(
+            "22:00-06:00".parse::<Window>().unwrap().to_string(),
+            "* 22:00-06:00"
+        );
+        for invalid in ["", "monday", "mon 9-17", "mon 09:00-09:00", "* 10:00-25:00"] {
+            assert!(invalid.parse::<Window>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn should_check_the_time() {
+        let office: Window = "mon-fri 09:00-17:00".parse().unwrap();
+        assert!(office.contains(LocalTime::new(1, 9, 0)));
+        assert!(!office.contains(LocalTime::new(1, 17, 0)));
+        assert!(!office.contains(LocalTime::new(6, 12, 0)));
+
+        // the night of friday goes on saturday morning
+        let night: Window = "fri 22:00-06:00".parse().unwrap();
+        assert!(night.contains(LocalTime::new(5, 23, 0)));
+        assert!(night.contains(LocalTime::new(6, 5, 59)));
+        assert!(!night.contains(LocalTime::new(5, 5, 0)));
+        assert!(!night.contains(LocalTime::new(6, 23, 0)));
+
+        assert!(is_active(&[], LocalTime::new(0, 0, 0)));
+        assert!(!is_active(&[office, night], LocalTime::new(0, 12, 0)));
+    }
+}
@@ -0,0 +1,41 @@
+use std::fmt::Display;
+use std::net::SocketAddr;
+
+/// Reason for donos to send a query by itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InternalReason {
+    /// Refreshing a cached entry before it expires
+    Prefetch,
+    /// Checking that the upstream servers answer
+    HealthCheck,
+    /// Following the keys of a zone to validate a signed answer
+    Validation,
+}
+
+impl InternalReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Prefetch => "prefetch",
+            Self::HealthCheck => "health-check",
+            Self::Validation => "validation",
+        }
+    }
+}
+
+/// Origin of a query, a client or donos itself.
+///
+/// Internal queries are never blocked and are counted apart from the client traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuerySource {
+    Client(SocketAddr),
+    Internal(InternalReason),
+}
+
+impl Display for QuerySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Client(address) => write!(f, "client/{address}"),
+            Self::Internal(reason) => write!(f, "internal/{}", reason.as_str()),
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Checks of the configuration that deserializing it doesn't cover, like the urls of the
+//! blocklists or the access to the database, for them to fail before the server starts.
+use super::Config;
+use crate::repository::lookup::{split_address, Mode, ServerConfig};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Problems found in the configuration, empty when it's valid
+pub(crate) fn check(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.lookup.mode == Mode::Forward && config.lookup.servers.is_empty() {
+        problems.push("lookup.servers: no upstream server to forward the queries".to_string());
+    }
+    for server in config.lookup.servers.iter() {
+        check_server("lookup.servers", server, &mut problems);
+    }
+    for zone in config.lookup.forward.iter() {
+        let key = format!("lookup.forward {:?}", zone.domain);
+        if zone.servers.is_empty() {
+            problems.push(format!("{key}: no server to forward the queries"));
+        }
+        for server in zone.servers.iter() {
+            check_server(&key, server, &mut problems);
+        }
+    }
+    if config.lookup.validation.enabled {
+        for anchor in config.lookup.validation.trust_anchors.iter() {
+            if crate::repository::validator::parse_anchor(anchor).is_none() {
+                problems.push(format!(
+                    "lookup.validation.trust_anchors: invalid anchor {anchor:?}"
+                ));
+            }
+        }
+    }
+
+    for (name, item) in config.blocklists.inner.iter() {
+        if let Err(error) = check_blocklist_url(&item.url) {
+            problems.push(format!("blocklists.{name}.url: {error}"));
+        }
+    }
+    for (name, group) in config.groups.iter() {
+        for list in group.blocklists.iter() {
+            if !config.blocklists.inner.contains_key(list) {
+                problems.push(format!(
+                    "groups.{name}.blocklists: unknown blocklist {list:?}"
+                ));
+            }
+        }
+    }
+
+    if let Err(error) = check_database_url(&config.database.url) {
+        problems.push(format!("database.url: {error}"));
+    }
+    if let (Some(min), Some(max)) = (config.dns.ttl.min, config.dns.ttl.max) {
+        if min > max {
+            problems.push(format!(
+                "dns.ttl: the min of {min} seconds is above the max of {max} seconds"
+            ));
+        }
+    }
+    if config.dns.leases.enabled && !config.dns.leases.path.exists() {
+        problems.push(format!(
+            "dns.leases.path: {} doesn't exist",
+            config.dns.leases.path.display()
+        ));
+    }
+
+    problems
+}
+
+/// Keys of the file that don't end up in the configuration, most likely typos
+pub(crate) fn unknown_keys(raw: &toml::Value, effective: &toml::Value) -> Vec<String> {
+    let mut result = Vec::new();
+    collect_unknown_keys("", raw, effective, &mut result);
+    result
+}
+
+fn collect_unknown_keys(
+    prefix: &str,
+    raw: &toml::Value,
+    effective: &toml::Value,
+    result: &mut Vec<String>,
+) {
+    match (raw, effective) {
+        (toml::Value::Table(raw), toml::Value::Table(effective)) => {
+            for (key, value) in raw.iter() {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                // the keys are lowercased when loading the file
+                match effective.get(&key.to_lowercase()) {
+                    Some(found) => collect_unknown_keys(&path, value, found, result),
+                    None => result.push(path),
+                }
+            }
+        }
+        (toml::Value::Array(raw), toml::Value::Array(effective)) => {
+            for (index, (value, found)) in raw.iter().zip(effective.iter()).enumerate() {
+                collect_unknown_keys(&format!("{prefix}[{index}]"), value, found, result);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_server(key: &str, server: &ServerConfig, problems: &mut Vec<String>) {
+    let (host, _) = split_address(server.address(), 53);
+    let valid = host.parse::<std::net::IpAddr>().is_ok()
+        || (!host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.'));
+    if !valid {
+        problems.push(format!("{key}: invalid address {:?}", server.address()));
+    }
+}
+
+/// Blocklists are downloaded over http(s) or read from a local file
+fn check_blocklist_url(url: &str) -> Result<(), String> {
+    if !url.contains("://") {
+        return check_exists(Path::new(url));
+    }
+    let parsed =
+        reqwest::Url::parse(url).map_err(|error| format!("invalid url {url:?}: {error}"))?;
+    match parsed.scheme() {
+        "http" | "https" if parsed.host().is_some() => Ok(()),
+        "http" | "https" => Err(format!("missing host in {url:?}")),
+        "file" => check_exists(Path::new(parsed.path())),
+        other => Err(format!("unsupported scheme {other:?} in {url:?}")),
+    }
+}
+
+fn check_exists(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        Ok(())
+    } else {
+        Err(format!("{} doesn't exist", path.display()))
+    }
+}
+
+/// The database is created when missing, its directory must be writable then
+fn check_database_url(url: &str) -> Result<(), String> {
+    let path = url
+        .strip_prefix("sqlite://")
+        .or_else(|| url.strip_prefix("sqlite:"))
+        .unwrap_or(url);
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    if path.is_empty() || path == ":memory:" {
+        return Ok(());
+    }
+    let path = Path::new(path);
+    let target = if path.exists() {
+        path
+    } else {
+        match path.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) if parent.exists() => parent,
+            _ => return Err(format!("the directory of {} doesn't exist", path.display())),
+        }
+    };
+    if is_writable(target) {
+        Ok(())
+    } else {
+        Err(format!("{} is not writable", target.display()))
+    }
+}
+
+fn is_writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, unknown_keys};
+    use crate::config::Config;
+
+    fn parse(content: &str) -> Config {
+        ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                content,
+                ::config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    #[test]
+    fn should_accept_default_config() {
+        let mut config = parse("");
+        config.database.url = ":memory:".into();
+        assert_eq!(check(&config), Vec::<String>::new());
+    }
+
+    #[test]
+    fn should_report_unknown_keys() {
+        let content = r#"
+[lookup]
+adress = "0.0.0.0:43210"
+servers = ["1.1.1.1"]
+
+[[lookup.forward]]
+domain = "corp.example"
+servers = ["10.0.0.1"]
+server = "10.0.0.2"
+
+[dns.blocking]
+mode = "nxdomain"
+ttl = 60
+"#;
+        let config = parse(content);
+        let raw: toml::Value = toml::from_str(content).unwrap();
+        let effective = toml::Value::try_from(&config).unwrap();
+        assert_eq!(
+            unknown_keys(&raw, &effective),
+            vec![
+                "dns.blocking.ttl",
+                "lookup.adress",
+                "lookup.forward[0].server"
+            ]
+        );
+    }
+
+    #[test]
+    fn should_report_invalid_sections() {
+        let directory = std::env::temp_dir().join("donos-config-check");
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = parse(&format!(
+            r#"
+[database]
+url = "sqlite://{}/missing/donos.db"
+
+[lookup]
+servers = []
+
+[[lookup.forward]]
+domain = "corp.example"
+servers = ["10.0.0.1:5x3"]
+
+[blocklists.ads]
+url = "htps://example.com/ads.txt"
+kind = "etc-hosts"
+
+[blocklists.malware]
+url = "https://example.com/list.txt"
+kind = "etc-hosts"
+
+[groups.kids]
+blocklists = ["ads", "adult"]
+"#,
+            directory.display()
+        ));
+        let problems = check(&config);
+        assert_eq!(problems.len(), 5, "{problems:#?}");
+        assert_eq!(
+            problems[0],
+            "lookup.servers: no upstream server to forward the queries"
+        );
+        assert_eq!(
+            problems[1],
+            "lookup.forward \"corp.example\": invalid address \"10.0.0.1:5x3\""
+        );
+        assert_eq!(
+            problems[2],
+            "blocklists.ads.url: unsupported scheme \"htps\" in \"htps://example.com/ads.txt\""
+        );
+        assert_eq!(
+            problems[3],
+            "groups.kids.blocklists: unknown blocklist \"adult\""
+        );
+        assert!(problems[4].starts_with("database.url: the directory of"));
+    }
+}
@@ -0,0 +1,217 @@
+use clap::{Args, Subcommand};
+use std::path::{Path, PathBuf};
+
+mod check;
+
+/// Example configuration, with every option commented out along with its default value
+const EXAMPLE: &str = include_str!("../../donos.toml");
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub database: crate::service::database::Config,
+    #[serde(default)]
+    pub cache: crate::repository::cache::Config,
+    #[serde(default)]
+    pub lookup: crate::repository::lookup::Config,
+    #[serde(default)]
+    pub blocklists: crate::repository::blocklist::Config,
+    /// History of the client queries
+    #[serde(default)]
+    pub query_log: crate::repository::query::Config,
+    /// Records answered by donos, like the addresses of the hosts of the local network
+    #[serde(default)]
+    pub records: crate::dns::pipeline::local::Config,
+    /// Names of the clients, displayed in the logs and the stats instead of their addresses
+    #[serde(default)]
+    pub clients: std::collections::BTreeMap<std::net::IpAddr, String>,
+    /// Groups of clients with their own blocklists
+    #[serde(default)]
+    pub groups: std::collections::BTreeMap<String, crate::repository::blocklist::ClientGroup>,
+    #[serde(default)]
+    pub dns: crate::dns::config::Config,
+    #[serde(default)]
+    pub api: crate::api::Config,
+    #[serde(default)]
+    pub policy: crate::dns::policy::Config,
+    /// Query sent by the healthcheck command and the `/health` endpoint of the api
+    #[serde(default)]
+    pub healthcheck: crate::healthcheck::Config,
+}
+
+impl Config {
+    fn source(path: &Path) -> Result<::config::Config, ::config::ConfigError> {
+        ::config::Config::builder()
+            .add_source(::config::File::from(path).required(true))
+            .add_source(::config::Environment::default().separator("_"))
+            .build()
+    }
+
+    pub fn load(path: &Path) -> Self {
+        Self::source(path)
+            .expect("unable to locate configuration file")
+            .try_deserialize()
+            .expect("configuration format invalid")
+    }
+
+    /// Reads the configuration again, without stopping when it's invalid
+    pub fn try_load(path: &Path) -> Result<Self, ::config::ConfigError> {
+        Self::source(path)?.try_deserialize()
+    }
+}
+
+/// Look at the configuration without starting the server
+#[derive(Args, Debug)]
+pub struct Command {
+    #[command(subcommand)]
+    inner: Action,
+}
+
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Validate the configuration file and print it with the default values filled in
+    Check,
+    /// Write an example configuration, with every option and its default value
+    Init {
+        /// Where to write the configuration, printed when not defined
+        path: Option<PathBuf>,
+        /// Replace the file if it already exists
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+impl Command {
+    pub fn run(self, path: &Path) {
+        match self.inner {
+            Action::Check => check(path),
+            Action::Init { path: None, .. } => print!("{EXAMPLE}"),
+            Action::Init {
+                path: Some(target),
+                force,
+            } => init(&target, force),
+        }
+    }
+}
+
+fn check(path: &Path) {
+    let config = match Config::try_load(path) {
+        Ok(found) => found,
+        Err(error) => {
+            tracing::error!("invalid configuration {}: {error}", path.display());
+            std::process::exit(1)
+        }
+    };
+    let effective = match toml::Value::try_from(&config) {
+        Ok(found) => found,
+        Err(error) => {
+            tracing::error!("unable to print the configuration: {error}");
+            std::process::exit(1)
+        }
+    };
+    print!("{effective}");
+    let mut problems = check::check(&config);
+    let raw = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| toml::from_str::<toml::Value>(&content).ok());
+    if let Some(raw) = raw {
+        problems.extend(
+            check::unknown_keys(&raw, &effective)
+                .into_iter()
+                .map(|key| format!("{key}: unknown key")),
+        );
+    }
+    for problem in problems.iter() {
+        tracing::error!("{problem}");
+    }
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    tracing::info!("configuration {} is valid", path.display());
+}
+
+fn init(path: &Path, force: bool) {
+    if path.exists() && !force {
+        tracing::error!(
+            "{} already exists, use --force to replace it",
+            path.display()
+        );
+        std::process::exit(1);
+    }
+    let result = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+    .and_then(|_| std::fs::write(path, EXAMPLE));
+    if let Err(error) = result {
+        tracing::error!("unable to write {}: {error}", path.display());
+        std::process::exit(1);
+    }
+    tracing::info!("configuration written to {}", path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, EXAMPLE};
+
+    fn keys(prefix: &str, value: &toml::Value, result: &mut Vec<String>) {
+        if let toml::Value::Table(table) = value {
+            for (key, value) in table.iter() {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                keys(&path, value, result);
+                result.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn should_document_every_option_in_example() {
+        let config: Config = ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                EXAMPLE,
+                ::config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let raw: toml::Value = toml::from_str(EXAMPLE).unwrap();
+        let effective = toml::Value::try_from(&config).unwrap();
+        assert_eq!(
+            super::check::unknown_keys(&raw, &effective),
+            Vec::<String>::new()
+        );
+
+        // options of the example, commented out or not, with the table they belong to
+        let mut table = String::new();
+        let mut documented = Vec::new();
+        for line in EXAMPLE.lines() {
+            let line = line.strip_prefix("# ").unwrap_or(line).trim();
+            if let Some(header) = line.strip_prefix('[') {
+                table = header.trim_matches(|c| c == '[' || c == ']').to_string();
+                documented.push(table.clone());
+            } else if let Some((key, _)) = line.split_once(" = ") {
+                documented.push(format!("{table}.{key}"));
+            }
+        }
+        let mut paths = Vec::new();
+        keys(
+            "",
+            &toml::Value::try_from(Config::default()).unwrap(),
+            &mut paths,
+        );
+        let missing = paths
+            .into_iter()
+            .filter(|path| {
+                !documented
+                    .iter()
+                    .any(|found| found.starts_with(path.as_str()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(missing, Vec::<String>::new());
+    }
+}
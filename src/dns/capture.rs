@@ -0,0 +1,309 @@
+//! Bounded on-disk ring buffer of the raw queries and responses,
+//! meant to be enabled while debugging a live issue.
+//!
+//! The file starts with a header (magic, capacity, next slot, number of used slots)
+//! followed by `capacity` slots of the same size, each containing a timestamp
+//! and the query and response prefixed by their length, the larger ones being cut.
+use std::io::{Read, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+const MAGIC: &[u8; 8] = b"DONOSRB2";
+const HEADER_SIZE: u64 = 8 + 4 + 4 + 4;
+/// Bytes kept of each packet, the usual EDNS buffer size
+const PACKET_SIZE: usize = 1232;
+const SLOT_SIZE: u64 = 8 + (2 + PACKET_SIZE as u64) * 2;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Capture the packets from startup, it can be toggled later by sending SIGUSR1
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "Config::default_path")]
+    pub path: PathBuf,
+    /// Maximum number of query and response pairs kept in the file
+    #[serde(default = "Config::default_capacity")]
+    pub capacity: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Self::default_path(),
+            capacity: Self::default_capacity(),
+        }
+    }
+}
+
+impl Config {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/var/lib/donos/capture.bin")
+    }
+
+    fn default_capacity() -> u32 {
+        10_000
+    }
+
+    pub async fn build(&self) -> std::io::Result<PacketCapture> {
+        PacketCapture::open(&self.path, self.capacity, self.enabled).await
+    }
+}
+
+struct RingFile {
+    file: tokio::fs::File,
+    capacity: u32,
+    next: u32,
+    count: u32,
+}
+
+/// Query and response exchanged with a client
+#[derive(Debug, PartialEq, Eq)]
+pub struct Exchange {
+    pub timestamp: SystemTime,
+    pub query: Vec<u8>,
+    /// Size of the query as received, larger than the bytes kept when it was cut
+    pub query_size: usize,
+    pub response: Vec<u8>,
+    pub response_size: usize,
+}
+
+pub struct PacketCapture {
+    enabled: AtomicBool,
+    path: PathBuf,
+    capacity: u32,
+    /// Opened once the capture is enabled, so that nothing is touched until then
+    ring: tokio::sync::Mutex<Option<RingFile>>,
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Writes the size of the packet, then its first bytes
+fn encode_packet(slot: &mut Vec<u8>, packet: &[u8]) {
+    let size = packet.len().min(u16::MAX as usize);
+    let packet = &packet[..size.min(PACKET_SIZE)];
+    slot.extend_from_slice(&(size as u16).to_be_bytes());
+    slot.extend_from_slice(packet);
+    slot.resize(slot.len() + PACKET_SIZE - packet.len(), 0);
+}
+
+/// Returns the bytes kept of the packet and its size
+fn decode_packet(slot: &[u8]) -> (Vec<u8>, usize) {
+    let size = u16::from_be_bytes([slot[0], slot[1]]) as usize;
+    (slot[2..2 + size.min(PACKET_SIZE)].to_vec(), size)
+}
+
+impl PacketCapture {
+    /// Prepares the capture, the ring buffer only being opened when it's enabled
+    pub async fn open(path: &Path, capacity: u32, enabled: bool) -> std::io::Result<Self> {
+        let ring = if enabled {
+            Some(RingFile::open(path, capacity).await?)
+        } else {
+            None
+        };
+        Ok(Self {
+            enabled: AtomicBool::new(enabled),
+            path: path.to_path_buf(),
+            capacity,
+            ring: tokio::sync::Mutex::new(ring),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Enables or disables the capture, returning the new state
+    pub fn toggle(&self) -> bool {
+        !self.enabled.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    /// Appends the exchange to the ring buffer, overwriting the oldest one when full
+    pub async fn record(&self, query: &[u8], response: &[u8]) -> std::io::Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut slot = Vec::with_capacity(SLOT_SIZE as usize);
+        slot.extend_from_slice(&timestamp.to_be_bytes());
+        encode_packet(&mut slot, query);
+        encode_packet(&mut slot, response);
+
+        let mut guard = self.ring.lock().await;
+        let ring = match guard.take() {
+            Some(found) => found,
+            None => RingFile::open(&self.path, self.capacity).await?,
+        };
+        let ring = guard.insert(ring);
+        let offset = HEADER_SIZE + SLOT_SIZE * ring.next as u64;
+        ring.file.seek(SeekFrom::Start(offset)).await?;
+        ring.file.write_all(&slot).await?;
+        ring.next = (ring.next + 1) % ring.capacity;
+        ring.count = ring.capacity.min(ring.count + 1);
+        ring.write_header().await
+    }
+}
+
+impl RingFile {
+    /// Opens the ring buffer, starting a new one when the capacity or the format changed
+    async fn open(path: &Path, capacity: u32) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        let length = file.metadata().await?.len();
+        let mut header = [0; HEADER_SIZE as usize];
+        let found = match file.read_exact(&mut header).await {
+            Ok(_) => read_header(&header, length),
+            Err(_) => None,
+        };
+        if let Some((_, next, count)) = found.filter(|(found, ..)| *found == capacity) {
+            return Ok(RingFile {
+                file,
+                capacity,
+                next,
+                count,
+            });
+        }
+        file.set_len(HEADER_SIZE + SLOT_SIZE * capacity as u64)
+            .await?;
+        let mut ring = RingFile {
+            file,
+            capacity,
+            next: 0,
+            count: 0,
+        };
+        ring.write_header().await?;
+        Ok(ring)
+    }
+
+    async fn write_header(&mut self) -> std::io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.capacity.to_be_bytes());
+        header.extend_from_slice(&self.next.to_be_bytes());
+        header.extend_from_slice(&self.count.to_be_bytes());
+        self.file.seek(SeekFrom::Start(0)).await?;
+        self.file.write_all(&header).await?;
+        self.file.flush().await
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Returns the capacity, next slot and count of the ring buffer, checked
+/// against the length of the whole file
+fn read_header(header: &[u8], length: u64) -> Option<(u32, u32, u32)> {
+    if header.len() < HEADER_SIZE as usize || &header[..8] != MAGIC {
+        return None;
+    }
+    let capacity = read_u32(&header[8..]);
+    let next = read_u32(&header[12..]);
+    let count = read_u32(&header[16..]);
+    let expected = HEADER_SIZE + SLOT_SIZE * capacity as u64;
+    (capacity > 0 && next < capacity && count <= capacity && length == expected)
+        .then_some((capacity, next, count))
+}
+
+/// Reads the exchanges kept in the ring buffer, from the oldest to the newest
+pub fn read(path: &Path) -> std::io::Result<Vec<Exchange>> {
+    let mut content = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut content)?;
+    let (capacity, next, count) = read_header(&content, content.len() as u64)
+        .ok_or_else(|| invalid_data("invalid capture file"))?;
+    let first = (next + capacity - count) % capacity;
+    Ok((0..count)
+        .map(|index| {
+            let slot = (first + index) % capacity;
+            let start = (HEADER_SIZE + SLOT_SIZE * slot as u64) as usize;
+            let slot = &content[start..start + SLOT_SIZE as usize];
+            let millis = u64::from_be_bytes(slot[..8].try_into().unwrap());
+            let (query, query_size) = decode_packet(&slot[8..]);
+            let (response, response_size) = decode_packet(&slot[8 + 2 + PACKET_SIZE..]);
+            Exchange {
+                timestamp: UNIX_EPOCH + Duration::from_millis(millis),
+                query,
+                query_size,
+                response,
+                response_size,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("donos-capture-{}-{name}.bin", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn should_only_keep_last_exchanges() {
+        let path = path("ring");
+        let capture = super::PacketCapture::open(&path, 3, true).await.unwrap();
+        for index in 0u8..5 {
+            capture.record(&[index], &[index, index]).await.unwrap();
+        }
+        let queries: Vec<Vec<u8>> = super::read(&path)
+            .unwrap()
+            .into_iter()
+            .map(|exchange| exchange.query)
+            .collect();
+        assert_eq!(queries, vec![vec![2], vec![3], vec![4]]);
+
+        // reopening keeps the content
+        let capture = super::PacketCapture::open(&path, 3, true).await.unwrap();
+        capture.record(&[5], &[]).await.unwrap();
+        let exchanges = super::read(&path).unwrap();
+        assert_eq!(exchanges.len(), 3);
+        assert_eq!(exchanges[2].query, vec![5]);
+        assert!(exchanges[2].response.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_keep_the_size_of_large_packets() {
+        let path = path("large");
+        let capture = super::PacketCapture::open(&path, 2, true).await.unwrap();
+        let response = vec![42; 4000];
+        capture.record(&[1; 1232], &response).await.unwrap();
+        let exchanges = super::read(&path).unwrap();
+        assert_eq!(exchanges[0].query, vec![1; 1232]);
+        assert_eq!(exchanges[0].query_size, 1232);
+        assert_eq!(exchanges[0].response, vec![42; super::PACKET_SIZE]);
+        assert_eq!(exchanges[0].response_size, 4000);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_not_record_when_disabled() {
+        let path = path("disabled");
+        let capture = super::PacketCapture::open(&path, 3, false).await.unwrap();
+        capture.record(&[1], &[1]).await.unwrap();
+        // the file isn't even created
+        assert!(!path.exists());
+
+        assert!(capture.toggle());
+        capture.record(&[1], &[1]).await.unwrap();
+        assert_eq!(super::read(&path).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
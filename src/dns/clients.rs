@@ -0,0 +1,108 @@
+//! Names of the clients, displayed in the logs and recorded with their queries
+//! so that the stats tell which device sent them instead of its address.
+use super::leases::Leases;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// Names known for the clients, from the most to the least explicit: the configuration,
+/// the `client` command, the reverse lookups answered locally and finally the DHCP leases.
+#[derive(Debug, Default)]
+pub(crate) struct ClientNames {
+    names: RwLock<Arc<HashMap<IpAddr, String>>>,
+    leases: Arc<Leases>,
+}
+
+impl ClientNames {
+    pub fn new(leases: Arc<Leases>) -> Self {
+        Self {
+            names: Default::default(),
+            leases,
+        }
+    }
+
+    /// Replaces the names given to the clients, like when the configuration is reloaded
+    pub fn update(
+        &self,
+        configured: &BTreeMap<IpAddr, String>,
+        database: HashMap<IpAddr, String>,
+        reverse: &HashMap<IpAddr, String>,
+    ) {
+        let mut names = reverse.clone();
+        names.extend(database);
+        names.extend(
+            configured
+                .iter()
+                .map(|(address, name)| (*address, name.clone())),
+        );
+        *self.names.write().unwrap() = Arc::new(names);
+    }
+
+    pub fn name(&self, address: &IpAddr) -> Option<String> {
+        let address = address.to_canonical();
+        if let Some(found) = self.names.read().unwrap().get(&address) {
+            return Some(found.clone());
+        }
+        self.leases.name(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientNames;
+    use crate::dns::leases::{Lease, Leases};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn should_prefer_explicit_names() {
+        let leases = Leases::default();
+        leases.replace(
+            &[
+                Lease {
+                    address: "192.168.1.10".parse().unwrap(),
+                    hostname: "android-1234".into(),
+                    expires: None,
+                },
+                Lease {
+                    address: "192.168.1.20".parse().unwrap(),
+                    hostname: "laptop".into(),
+                    expires: None,
+                },
+            ],
+            None,
+        );
+        let names = ClientNames::new(Arc::new(leases));
+        let address = |value: &str| value.parse().unwrap();
+        names.update(
+            &[(address("192.168.1.10"), "Kitchen iPad".to_string())]
+                .into_iter()
+                .collect(),
+            [
+                (address("192.168.1.10"), "iPad".to_string()),
+                (address("192.168.1.11"), "TV".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            &HashMap::from([
+                (address("192.168.1.11"), "tv.lan".to_string()),
+                (address("192.168.1.12"), "nas.lan".to_string()),
+            ]),
+        );
+
+        assert_eq!(
+            names.name(&address("192.168.1.10")).as_deref(),
+            Some("Kitchen iPad")
+        );
+        assert_eq!(names.name(&address("192.168.1.11")).as_deref(), Some("TV"));
+        assert_eq!(
+            names.name(&address("::ffff:192.168.1.12")).as_deref(),
+            Some("nas.lan")
+        );
+        assert_eq!(
+            names.name(&address("192.168.1.20")).as_deref(),
+            Some("laptop")
+        );
+        assert_eq!(names.name(&address("192.168.1.30")), None);
+    }
+}
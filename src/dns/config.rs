@@ -1,11 +1,88 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use super::pipeline::StageKind;
+use donos_parser::packet::record::Record;
+use donos_server::socket::BindOptions;
+use donos_server::workers::{Overflow, WorkerOptions};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     #[serde(default = "Config::default_host")]
     pub host: IpAddr,
     #[serde(default = "Config::default_port")]
     pub port: u16,
+    /// Addresses listened to instead of the host and port, like the ones of each interface
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    /// On an IPv6 host like `::`, only accept the IPv6 clients instead of both families
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// Also accept the queries over TCP, on the same address
+    #[serde(default = "Config::default_tcp")]
+    pub tcp: bool,
+    /// Number of TCP connections served at the same time, the other ones being closed
+    #[serde(default = "Config::default_max_tcp_connections")]
+    pub max_tcp_connections: usize,
+    /// Number of UDP sockets bound to each address with SO_REUSEPORT, each one with
+    /// its own receive loop, for the kernel to spread the queries between the cores
+    #[serde(default = "Config::default_sockets")]
+    pub sockets: usize,
+    /// How many UDP queries are handled at the same time, and how many can wait
+    #[serde(default)]
+    pub workers: WorkersConfig,
+    /// Port used when the configured one is already taken by another resolver,
+    /// like systemd-resolved. Nothing is tried when not defined.
+    #[serde(default)]
+    pub fallback_port: Option<u16>,
+    /// User the server switches to once listening, by name or id, staying the same when not defined
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Group the server switches to once listening, the one of the user when not defined
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Domain suffixes that are never forwarded to the upstream servers
+    /// and directly answered with NXDOMAIN.
+    #[serde(default = "Config::default_never_forward")]
+    pub never_forward: Vec<String>,
+    /// TTL of the records synthesized by donos
+    #[serde(default)]
+    pub ttl: TtlConfig,
+    /// Limits applied to the upstream responses
+    #[serde(default)]
+    pub limits: super::limits::Config,
+    /// Protection against public domains resolving to private addresses
+    #[serde(default)]
+    pub rebinding: super::rebinding::Config,
+    /// How the queries for blocked domains are answered
+    #[serde(default)]
+    pub blocking: BlockingConfig,
+    /// Behavior when the blocklist can't be checked
+    #[serde(default)]
+    pub on_blocklist_error: BlocklistFailure,
+    /// Ring buffer of the raw packets, for debugging
+    #[serde(default)]
+    pub capture: super::capture::Config,
+    /// Export of the queries and responses to a dnstap collector
+    #[serde(default)]
+    pub dnstap: super::dnstap::Config,
+    /// Filtering of the AAAA answers for some clients
+    #[serde(default)]
+    pub aaaa_filter: super::pipeline::aaaa::Config,
+    /// Reverse lookups answered locally
+    #[serde(default)]
+    pub reverse: super::pipeline::reverse::Config,
+    /// Names of the hosts of the local network, read from the DHCP leases
+    #[serde(default)]
+    pub leases: super::leases::Config,
+    /// Advertisement of donos on the local network
+    #[serde(default)]
+    pub mdns: super::mdns::Config,
+    /// Refresh of the blocklists while running
+    #[serde(default)]
+    pub refresh: super::refresh::Config,
+    /// Stages a query goes through, in order
+    #[serde(default = "Config::default_pipeline")]
+    pub pipeline: Vec<StageKind>,
 }
 
 impl Default for Config {
@@ -13,6 +90,29 @@ impl Default for Config {
         Self {
             host: Self::default_host(),
             port: Self::default_port(),
+            listen: Vec::new(),
+            ipv6_only: false,
+            tcp: Self::default_tcp(),
+            max_tcp_connections: Self::default_max_tcp_connections(),
+            sockets: Self::default_sockets(),
+            workers: Default::default(),
+            fallback_port: None,
+            user: None,
+            group: None,
+            aaaa_filter: Default::default(),
+            leases: Default::default(),
+            mdns: Default::default(),
+            reverse: Default::default(),
+            pipeline: Self::default_pipeline(),
+            never_forward: Self::default_never_forward(),
+            ttl: TtlConfig::default(),
+            limits: Default::default(),
+            rebinding: Default::default(),
+            blocking: Default::default(),
+            on_blocklist_error: Default::default(),
+            capture: Default::default(),
+            dnstap: Default::default(),
+            refresh: Default::default(),
         }
     }
 }
@@ -25,10 +125,307 @@ impl Config {
     fn default_port() -> u16 {
         53
     }
+
+    fn default_tcp() -> bool {
+        true
+    }
+
+    fn default_max_tcp_connections() -> usize {
+        donos_server::tcp::DEFAULT_MAX_CONNECTIONS
+    }
+
+    fn default_sockets() -> usize {
+        1
+    }
+
+    fn default_pipeline() -> Vec<StageKind> {
+        StageKind::DEFAULT.to_vec()
+    }
+
+    fn default_never_forward() -> Vec<String> {
+        [
+            "corp",
+            "home",
+            "internal",
+            "invalid",
+            "lan",
+            "local",
+            "localdomain",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
 }
 
 impl Config {
     pub fn address(&self) -> SocketAddr {
         SocketAddr::from((self.host, self.port))
     }
+
+    /// Addresses to listen to, the host and port when no list is defined
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            vec![self.address()]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    pub fn bind_options(&self) -> BindOptions {
+        BindOptions {
+            ipv6_only: self.ipv6_only,
+            reuse_port: self.sockets > 1,
+        }
+    }
+
+    pub fn worker_options(&self) -> WorkerOptions {
+        WorkerOptions {
+            concurrency: self.workers.concurrency,
+            timeout: (self.workers.timeout > 0)
+                .then(|| Duration::from_millis(self.workers.timeout)),
+            queue_size: self.workers.queue,
+            overflow: match self.workers.overflow {
+                QueueOverflow::DropNewest => Overflow::DropNewest,
+                QueueOverflow::DropOldest => Overflow::DropOldest,
+            },
+        }
+    }
+
+    /// Only used with the host and port, not with a list of addresses
+    pub fn fallback_address(&self) -> Option<SocketAddr> {
+        self.fallback_port
+            .filter(|_| self.listen.is_empty())
+            .map(|port| SocketAddr::from((self.host, port)))
+    }
+}
+
+/// Query dropped when too many are waiting to be handled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflow {
+    #[default]
+    DropNewest,
+    DropOldest,
+}
+
+/// Handling of the UDP queries, to trade throughput for memory on small devices
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct WorkersConfig {
+    /// Number of queries handled at the same time
+    #[serde(default = "WorkersConfig::default_concurrency")]
+    pub concurrency: usize,
+    /// Number of milliseconds after which a query gets no answer, 0 never giving up
+    #[serde(default = "WorkersConfig::default_timeout")]
+    pub timeout: u64,
+    /// Number of received queries waiting to be handled
+    #[serde(default = "WorkersConfig::default_queue")]
+    pub queue: usize,
+    #[serde(default)]
+    pub overflow: QueueOverflow,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: Self::default_concurrency(),
+            timeout: Self::default_timeout(),
+            queue: Self::default_queue(),
+            overflow: QueueOverflow::default(),
+        }
+    }
+}
+
+impl WorkersConfig {
+    fn default_concurrency() -> usize {
+        64
+    }
+
+    fn default_timeout() -> u64 {
+        10000
+    }
+
+    fn default_queue() -> usize {
+        1024
+    }
+}
+
+/// Answer given to the queries for blocked domains
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockMode {
+    /// Answer NXDOMAIN, like if the domain didn't exist
+    #[default]
+    Nxdomain,
+    /// Answer `0.0.0.0` to the A queries and `::` to the AAAA queries
+    ZeroIp,
+    /// Answer the configured sinkhole addresses, for example to host a block page
+    CustomIp,
+    /// Answer REFUSED
+    Refused,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct BlockingConfig {
+    #[serde(default)]
+    pub mode: BlockMode,
+    /// Sinkhole answered to the A queries in the `custom-ip` mode, `0.0.0.0` when not defined
+    #[serde(default)]
+    pub ipv4: Option<Ipv4Addr>,
+    /// Sinkhole answered to the AAAA queries in the `custom-ip` mode,
+    /// nothing is answered when not defined so that the clients use the IPv4 one
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl BlockingConfig {
+    /// Address answered to the A queries, if any
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        match self.mode {
+            BlockMode::ZeroIp => Some(Ipv4Addr::UNSPECIFIED),
+            BlockMode::CustomIp => Some(self.ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED)),
+            BlockMode::Nxdomain | BlockMode::Refused => None,
+        }
+    }
+
+    /// Address answered to the AAAA queries, if any
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        match self.mode {
+            BlockMode::ZeroIp => Some(Ipv6Addr::UNSPECIFIED),
+            BlockMode::CustomIp => self.ipv6,
+            BlockMode::Nxdomain | BlockMode::Refused => None,
+        }
+    }
+}
+
+/// What to do with a query when the blocklist backend fails
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlocklistFailure {
+    /// Resolve the domain as if it wasn't blocked
+    #[default]
+    Open,
+    /// Answer SERVFAIL
+    Closed,
+}
+
+/// TTL used in the responses built by donos (blocked domains, local records, negative answers)
+/// instead of coming from an upstream server, and bounds of the ones coming from upstream.
+///
+/// A low TTL makes clients query again constantly while a high TTL makes
+/// unblocking a domain slow to take effect, so each kind can be overridden.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct TtlConfig {
+    /// TTL used when no specific value is defined
+    #[serde(default = "TtlConfig::default_ttl")]
+    pub default: u32,
+    /// TTL of the responses for blocked domains
+    #[serde(default)]
+    pub blocked: Option<u32>,
+    /// TTL of the locally defined records
+    #[serde(default)]
+    pub local: Option<u32>,
+    /// TTL of the negative answers (NXDOMAIN, NODATA), also used
+    /// to keep empty upstream answers in cache
+    #[serde(default)]
+    pub negative: Option<u32>,
+    /// Lowest TTL of the upstream records, raising the very short ones to query them less often
+    #[serde(default)]
+    pub min: Option<u32>,
+    /// Highest TTL of the upstream records, for the changes of the blocklists to apply sooner
+    #[serde(default)]
+    pub max: Option<u32>,
+}
+
+impl Default for TtlConfig {
+    fn default() -> Self {
+        Self {
+            default: Self::default_ttl(),
+            blocked: None,
+            local: None,
+            negative: None,
+            min: None,
+            max: None,
+        }
+    }
+}
+
+impl TtlConfig {
+    fn default_ttl() -> u32 {
+        60
+    }
+
+    pub fn blocked(&self) -> u32 {
+        self.blocked.unwrap_or(self.default)
+    }
+
+    pub fn local(&self) -> u32 {
+        self.local.unwrap_or(self.default)
+    }
+
+    pub fn negative(&self) -> u32 {
+        self.negative.unwrap_or(self.default)
+    }
+
+    /// TTL of a negative upstream answer, following the SOA of the zone when
+    /// there is one (RFC 2308 section 5), unless overridden.
+    pub fn negative_with_soa(&self, soa: Option<&Record>) -> u32 {
+        match (self.negative, soa) {
+            (None, Some(Record::SOA { ttl, minimum, .. })) => self.clamp((*ttl).min(*minimum)),
+            _ => self.negative(),
+        }
+    }
+
+    /// TTL of an upstream record within the configured bounds, the maximum prevailing
+    pub fn clamp(&self, ttl: u32) -> u32 {
+        let ttl = self.min.map_or(ttl, |min| ttl.max(min));
+        self.max.map_or(ttl, |max| ttl.min(max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn ttl_should_fallback_on_default() {
+        let ttl = super::TtlConfig {
+            default: 30,
+            blocked: Some(10),
+            local: None,
+            negative: Some(3600),
+            min: None,
+            max: None,
+        };
+        assert_eq!(ttl.blocked(), 10);
+        assert_eq!(ttl.local(), 30);
+        assert_eq!(ttl.negative(), 3600);
+    }
+
+    #[test]
+    fn negative_ttl_should_follow_soa_unless_configured() {
+        let soa = donos_parser::packet::record::Record::SOA {
+            domain: "perdu.com".into(),
+            mname: "ns.perdu.com".into(),
+            rname: "admin.perdu.com".into(),
+            serial: 1,
+            refresh: 7200,
+            retry: 3600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 900,
+        };
+        let mut ttl = super::TtlConfig {
+            default: 30,
+            blocked: None,
+            local: None,
+            negative: None,
+            min: None,
+            max: None,
+        };
+        assert_eq!(ttl.negative_with_soa(None), 30);
+        assert_eq!(ttl.negative_with_soa(Some(&soa)), 300);
+        ttl.max = Some(120);
+        assert_eq!(ttl.negative_with_soa(Some(&soa)), 120);
+        ttl.negative = Some(60);
+        assert_eq!(ttl.negative_with_soa(Some(&soa)), 60);
+    }
 }
@@ -0,0 +1,401 @@
+//! Export of the queries and responses as dnstap messages, sent with the Frame Streams
+//! protocol to a collector listening on a unix socket, like `dnstap -u` or vector.
+//!
+//! The messages are encoded by hand, the few protobuf fields of dnstap don't need a code generator.
+use donos_server::prelude::Transport;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+
+/// Number of exchanges waiting to be sent before the next ones get dropped
+const QUEUE_SIZE: usize = 4096;
+/// Number of exchanges sent at once
+const BATCH_SIZE: usize = 64;
+/// Delay before connecting again to the collector
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Longest control frame accepted from the collector
+const MAX_CONTROL_SIZE: usize = 512;
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// `Message.Type` of dnstap, donos only reports what it exchanges with its clients
+const CLIENT_QUERY: u64 = 5;
+const CLIENT_RESPONSE: u64 = 6;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Unix socket of the collector
+    #[serde(default = "Config::default_path")]
+    pub path: PathBuf,
+    /// Name of this server in the messages, to tell several servers apart
+    #[serde(default)]
+    pub identity: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Self::default_path(),
+            identity: None,
+        }
+    }
+}
+
+impl Config {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/var/run/dnstap.sock")
+    }
+
+    /// Spawns the task sending the messages to the collector, returns `None` when disabled
+    pub fn build(&self) -> Option<DnstapLogger> {
+        if !self.enabled {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(self.path.clone(), self.identity.clone(), receiver));
+        Some(DnstapLogger { sender })
+    }
+}
+
+/// Query received from a client and the response it got, if any
+#[derive(Debug)]
+pub struct Exchange {
+    pub client: SocketAddr,
+    pub listener: SocketAddr,
+    pub transport: Transport,
+    pub query: Vec<u8>,
+    pub query_time: SystemTime,
+    pub response: Option<Vec<u8>>,
+    pub response_time: SystemTime,
+}
+
+/// Sends the exchanges to the collector, without waiting for it
+#[derive(Clone, Debug)]
+pub struct DnstapLogger {
+    sender: mpsc::Sender<Exchange>,
+}
+
+impl DnstapLogger {
+    pub fn log(&self, exchange: Exchange) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(exchange) {
+            tracing::debug!("dnstap queue is full, dropping message");
+        }
+    }
+}
+
+/// Protobuf fields, written in the order of their numbers
+#[derive(Default)]
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn fixed32(&mut self, field: u64, value: u32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+}
+
+fn address_bytes(address: &IpAddr) -> Vec<u8> {
+    match address {
+        IpAddr::V4(inner) => inner.octets().to_vec(),
+        IpAddr::V6(inner) => inner.octets().to_vec(),
+    }
+}
+
+fn time(protobuf: &mut Protobuf, seconds: u64, nanoseconds: u64, at: SystemTime) {
+    let elapsed = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    protobuf.uint(seconds, elapsed.as_secs());
+    protobuf.fixed32(nanoseconds, elapsed.subsec_nanos());
+}
+
+/// Encodes the query, or the response when there is one, as a `Dnstap` message
+fn encode(identity: Option<&str>, exchange: &Exchange, response: bool) -> Vec<u8> {
+    let mut message = Protobuf::default();
+    message.uint(
+        1,
+        if response {
+            CLIENT_RESPONSE
+        } else {
+            CLIENT_QUERY
+        },
+    );
+    message.uint(2, if exchange.client.is_ipv4() { 1 } else { 2 });
+    message.uint(
+        3,
+        match exchange.transport {
+            Transport::Udp => 1,
+            Transport::Tcp => 2,
+        },
+    );
+    message.bytes(4, &address_bytes(&exchange.client.ip()));
+    message.bytes(5, &address_bytes(&exchange.listener.ip()));
+    message.uint(6, exchange.client.port() as u64);
+    message.uint(7, exchange.listener.port() as u64);
+    time(&mut message, 8, 9, exchange.query_time);
+    match exchange.response.as_deref() {
+        Some(packet) if response => {
+            time(&mut message, 12, 13, exchange.response_time);
+            message.bytes(14, packet);
+        }
+        _ => message.bytes(10, &exchange.query),
+    }
+
+    let mut dnstap = Protobuf::default();
+    if let Some(identity) = identity {
+        dnstap.bytes(1, identity.as_bytes());
+    }
+    dnstap.bytes(2, concat!("donos ", env!("CARGO_PKG_VERSION")).as_bytes());
+    dnstap.bytes(14, &message.0);
+    // Dnstap.Type.MESSAGE
+    dnstap.uint(15, 1);
+    dnstap.0
+}
+
+fn data_frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Control frame, escaped by a zero length, with the content type when needed
+fn control_frame(kind: u32, content_type: bool) -> Vec<u8> {
+    let mut control = kind.to_be_bytes().to_vec();
+    if content_type {
+        control.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        control.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        control.extend_from_slice(CONTENT_TYPE);
+    }
+    let mut frame = vec![0; 4];
+    frame.extend_from_slice(&(control.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&control);
+    frame
+}
+
+async fn read_control(stream: &mut UnixStream) -> std::io::Result<u32> {
+    let escape = stream.read_u32().await?;
+    let size = stream.read_u32().await? as usize;
+    if escape != 0 || !(4..=MAX_CONTROL_SIZE).contains(&size) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid control frame",
+        ));
+    }
+    let mut control = vec![0; size];
+    stream.read_exact(&mut control).await?;
+    Ok(u32::from_be_bytes([
+        control[0], control[1], control[2], control[3],
+    ]))
+}
+
+/// Connects to the collector and negotiates the bidirectional stream
+async fn connect(path: &Path) -> std::io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(&control_frame(CONTROL_READY, true))
+        .await?;
+    let accepted = read_control(&mut stream).await?;
+    if accepted != CONTROL_ACCEPT {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("expected an accept control frame, got {accepted}"),
+        ));
+    }
+    stream
+        .write_all(&control_frame(CONTROL_START, true))
+        .await?;
+    Ok(stream)
+}
+
+/// Sends the exchanges to the collector by batches, connecting again when it goes away,
+/// until all the loggers are dropped.
+async fn run(path: PathBuf, identity: Option<String>, mut receiver: mpsc::Receiver<Exchange>) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut stream: Option<UnixStream> = None;
+    let mut failing = false;
+    loop {
+        if receiver.recv_many(&mut batch, BATCH_SIZE).await == 0 {
+            break;
+        }
+        if stream.is_none() {
+            match connect(&path).await {
+                Ok(found) => {
+                    tracing::info!("sending dnstap messages to {path:?}");
+                    stream = Some(found);
+                    failing = false;
+                }
+                Err(error) => {
+                    if !failing {
+                        tracing::warn!(
+                            "unable to connect to the dnstap collector {path:?}: {error}"
+                        );
+                        failing = true;
+                    }
+                    batch.clear();
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                    continue;
+                }
+            }
+        }
+        let mut frames = Vec::new();
+        for exchange in batch.drain(..) {
+            frames.extend(data_frame(&encode(identity.as_deref(), &exchange, false)));
+            if exchange.response.is_some() {
+                frames.extend(data_frame(&encode(identity.as_deref(), &exchange, true)));
+            }
+        }
+        if let Some(ref mut found) = stream {
+            if let Err(error) = found.write_all(&frames).await {
+                tracing::warn!("lost the connection to the dnstap collector: {error}");
+                stream = None;
+            }
+        }
+    }
+    if let Some(mut stream) = stream {
+        if stream
+            .write_all(&control_frame(CONTROL_STOP, false))
+            .await
+            .is_ok()
+        {
+            match read_control(&mut stream).await {
+                Ok(CONTROL_FINISH) | Err(_) => {}
+                Ok(kind) => tracing::debug!("expected a finish control frame, got {kind}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, Exchange, CONTENT_TYPE};
+    use donos_server::prelude::Transport;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    fn exchange() -> Exchange {
+        Exchange {
+            client: "192.168.1.10:5353".parse().unwrap(),
+            listener: "127.0.0.1:53".parse().unwrap(),
+            transport: Transport::Udp,
+            query: vec![0xab],
+            query_time: UNIX_EPOCH + Duration::new(1, 2),
+            response: Some(vec![0xcd]),
+            response_time: UNIX_EPOCH + Duration::new(1, 3),
+        }
+    }
+
+    /// Reads a data frame, or the content of a control frame
+    async fn read_frame(stream: &mut UnixStream) -> Vec<u8> {
+        let mut size = stream.read_u32().await.unwrap();
+        if size == 0 {
+            size = stream.read_u32().await.unwrap();
+        }
+        let mut frame = vec![0; size as usize];
+        stream.read_exact(&mut frame).await.unwrap();
+        frame
+    }
+
+    #[test]
+    fn should_encode_client_query() {
+        let version = concat!("donos ", env!("CARGO_PKG_VERSION"));
+        let mut expected = vec![0x12, version.len() as u8];
+        expected.extend_from_slice(version.as_bytes());
+        expected.extend_from_slice(&[
+            0x72, 0x21, // message
+            0x08, 0x05, // type: client query
+            0x10, 0x01, // family: inet
+            0x18, 0x01, // protocol: udp
+            0x22, 0x04, 192, 168, 1, 10, // query address
+            0x2a, 0x04, 127, 0, 0, 1, // response address
+            0x30, 0xe9, 0x29, // query port
+            0x38, 0x35, // response port
+            0x40, 0x01, // query time
+            0x4d, 0x02, 0x00, 0x00, 0x00, // query time nanoseconds
+            0x52, 0x01, 0xab, // query message
+            0x78, 0x01, // type: message
+        ]);
+        assert_eq!(encode(None, &exchange(), false), expected);
+
+        let response = encode(Some("home"), &exchange(), true);
+        assert!(response.starts_with(&[0x0a, 0x04, b'h', b'o', b'm', b'e']));
+        assert!(response.ends_with(&[0x72, 0x01, 0xcd, 0x78, 0x01]));
+    }
+
+    #[tokio::test]
+    async fn should_send_frames_to_collector() {
+        let path = std::env::temp_dir().join(format!("donos-dnstap-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let logger = super::Config {
+            enabled: true,
+            path: path.clone(),
+            identity: None,
+        }
+        .build()
+        .unwrap();
+        logger.log(exchange());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let ready = read_frame(&mut stream).await;
+        assert_eq!(&ready[..4], &super::CONTROL_READY.to_be_bytes());
+        assert!(ready.ends_with(CONTENT_TYPE));
+        stream
+            .write_all(&super::control_frame(super::CONTROL_ACCEPT, true))
+            .await
+            .unwrap();
+        let start = read_frame(&mut stream).await;
+        assert_eq!(&start[..4], &super::CONTROL_START.to_be_bytes());
+        assert_eq!(
+            read_frame(&mut stream).await,
+            encode(None, &exchange(), false)
+        );
+        assert_eq!(
+            read_frame(&mut stream).await,
+            encode(None, &exchange(), true)
+        );
+
+        drop(logger);
+        let stop = read_frame(&mut stream).await;
+        assert_eq!(stop, super::CONTROL_STOP.to_be_bytes());
+        stream
+            .write_all(&super::control_frame(super::CONTROL_FINISH, false))
+            .await
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,4 +1,5 @@
 // use crate::service::database::Error as DatabaseError;
+use super::limits::LimitError;
 use donos_parser::buffer::reader::ReaderError;
 use donos_parser::buffer::writer::WriterError;
 use std::fmt::Display;
@@ -7,17 +8,25 @@ use std::fmt::Display;
 pub enum HandleError {
     Blocklist(Box<dyn std::error::Error>),
     Cache(std::io::Error),
-    Lookup(std::io::Error),
     // Database(DatabaseError),
     Writer(WriterError),
     Reader(ReaderError),
     Io(std::io::Error),
+    Limit(LimitError),
     NoQuestion,
 }
 
 impl Display for HandleError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "handle error")
+        match self {
+            Self::Blocklist(inner) => write!(f, "blocklist error: {inner}"),
+            Self::Cache(inner) => write!(f, "cache error: {inner}"),
+            Self::Writer(inner) => write!(f, "writer error: {inner}"),
+            Self::Reader(inner) => write!(f, "reader error: {inner}"),
+            Self::Io(inner) => write!(f, "io error: {inner}"),
+            Self::Limit(inner) => write!(f, "limit error: {inner}"),
+            Self::NoQuestion => write!(f, "no question"),
+        }
     }
 }
 
@@ -1,19 +1,70 @@
+use super::capture::PacketCapture;
+use super::clients::ClientNames;
+use super::config::{BlockingConfig, BlocklistFailure, TtlConfig};
+use super::dnstap::{DnstapLogger, Exchange};
 use super::error::HandleError;
+use super::leases::Leases;
+use super::limits::Config as Limits;
+use super::metrics::{Metrics, Provenance};
+use super::pipeline::aaaa::{AaaaFilterStage, Config as AaaaFilterConfig};
+use super::pipeline::blocklist::{BlockingSwitch, BlocklistStage, CnameInspectionStage};
+use super::pipeline::cache::{CacheStage, PersistStage};
+use super::pipeline::leases::LeasesStage;
+use super::pipeline::local::{Config as LocalConfig, LocalStage};
+use super::pipeline::never_forward::NeverForwardStage;
+use super::pipeline::reverse::{Config as ReverseConfig, ReverseStage};
+use super::pipeline::safe_search::SafeSearchStage;
+use super::pipeline::upstream::UpstreamStage;
+use super::pipeline::{Pipeline, QueryContext, Stage, StageKind};
+use super::policy::Policy;
+use super::rebinding::Protection;
+use crate::common::domain::normalize;
+use crate::common::source::{InternalReason, QuerySource};
 use crate::repository::blocklist::BlocklistService;
 use crate::repository::cache::CacheService;
 use crate::repository::lookup::LookupService;
-use donos_parser::buffer::BytePacketBuffer;
-use donos_parser::packet::header::ResponseCode;
-use donos_parser::packet::DnsPacket;
-use donos_server::prelude::Message;
+use crate::repository::query::{LoggedQuery, QueryLogger};
+use donos_parser::buffer::{BytePacketBuffer, EDNS_PACKET_SIZE, UDP_PACKET_SIZE};
+use donos_parser::packet::header::{Header, ResponseCode};
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType, EDNS_DNSSEC_OK};
+use donos_server::prelude::{Message, Transport};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Instant, SystemTime};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::Instrument;
 
-#[allow(dead_code)]
+#[derive(Clone)]
 pub(crate) struct DnsHandler {
     blocklist: Arc<dyn BlocklistService + Send + Sync>,
     cache: Arc<dyn CacheService + Send + Sync>,
     lookup: Arc<dyn LookupService + Sync + Send>,
+    never_forward: Vec<String>,
+    /// Domains sent to dedicated servers, even when never forwarded
+    forwarded: Vec<String>,
+    ttl: TtlConfig,
+    metrics: Arc<Metrics>,
+    policy: Policy,
+    limits: Limits,
+    rebinding: Protection,
+    blocklist_failure: BlocklistFailure,
+    blocking: BlockingConfig,
+    blocking_switch: Arc<BlockingSwitch>,
+    capture: Option<Arc<PacketCapture>>,
+    dnstap: Option<DnstapLogger>,
+    clients: Arc<ClientNames>,
+    query_log: Option<QueryLogger>,
+    aaaa_filter: AaaaFilterConfig,
+    reverse: ReverseConfig,
+    local: LocalConfig,
+    /// Names of the DHCP leases, kept up to date by the watcher of the leases file
+    leases: Arc<Leases>,
+    stages: Vec<StageKind>,
+    /// Built on the first query, once the handler is configured, and shared
+    /// with the clones so that a rebuild applies to all the listeners
+    pipeline: Arc<RwLock<Option<Arc<Pipeline>>>>,
 }
 
 impl DnsHandler {
@@ -26,125 +77,578 @@ impl DnsHandler {
             blocklist,
             cache,
             lookup,
+            never_forward: Vec::new(),
+            forwarded: Vec::new(),
+            ttl: TtlConfig::default(),
+            metrics: Arc::default(),
+            policy: Policy::default(),
+            limits: Limits::default(),
+            rebinding: Protection::default(),
+            blocklist_failure: BlocklistFailure::default(),
+            blocking: BlockingConfig::default(),
+            blocking_switch: Arc::default(),
+            capture: None,
+            dnstap: None,
+            clients: Arc::default(),
+            query_log: None,
+            aaaa_filter: AaaaFilterConfig::default(),
+            reverse: ReverseConfig::default(),
+            local: LocalConfig::default(),
+            leases: Arc::default(),
+            stages: StageKind::DEFAULT.to_vec(),
+            pipeline: Arc::default(),
         }
     }
+
+    pub fn with_clients(mut self, clients: Arc<ClientNames>) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    pub fn with_query_log(mut self, query_log: QueryLogger) -> Self {
+        self.query_log = Some(query_log);
+        self
+    }
+
+    pub fn with_capture(mut self, capture: Arc<PacketCapture>) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn with_dnstap(mut self, dnstap: DnstapLogger) -> Self {
+        self.dnstap = Some(dnstap);
+        self
+    }
+
+    pub fn with_rebinding(mut self, rebinding: Protection) -> Self {
+        self.rebinding = rebinding;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_blocklist_failure(mut self, blocklist_failure: BlocklistFailure) -> Self {
+        self.blocklist_failure = blocklist_failure;
+        self
+    }
+
+    pub fn with_blocking(mut self, blocking: BlockingConfig) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    pub fn with_blocking_switch(mut self, switch: Arc<BlockingSwitch>) -> Self {
+        self.blocking_switch = switch;
+        self
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: TtlConfig) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn with_never_forward(mut self, suffixes: Vec<String>) -> Self {
+        self.never_forward = suffixes;
+        self
+    }
+
+    pub fn with_forwarded(mut self, domains: Vec<String>) -> Self {
+        self.forwarded = domains;
+        self
+    }
+
+    pub fn with_aaaa_filter(mut self, aaaa_filter: AaaaFilterConfig) -> Self {
+        self.aaaa_filter = aaaa_filter;
+        self
+    }
+
+    pub fn with_reverse(mut self, reverse: ReverseConfig) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    pub fn with_local_records(mut self, local: LocalConfig) -> Self {
+        self.local = local;
+        self
+    }
+
+    pub fn with_leases(mut self, leases: Arc<Leases>) -> Self {
+        self.leases = leases;
+        self
+    }
+
+    pub fn with_stages(mut self, stages: Vec<StageKind>) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    fn blocklist_stage(&self) -> BlocklistStage {
+        BlocklistStage::new(
+            self.blocklist.clone(),
+            self.policy.clone(),
+            self.blocklist_failure,
+        )
+        .with_blocking(self.blocking.clone(), self.ttl.blocked())
+        .with_switch(self.blocking_switch.clone())
+    }
+
+    fn stage(&self, kind: StageKind) -> Box<dyn Stage> {
+        match kind {
+            StageKind::Reverse => Box::new(ReverseStage::new(&self.reverse, self.ttl.local())),
+            StageKind::Local => Box::new(LocalStage::new(
+                &self.local,
+                self.lookup.clone(),
+                self.ttl.local(),
+            )),
+            StageKind::Leases => Box::new(LeasesStage::new(self.leases.clone(), self.ttl.local())),
+            StageKind::NeverForward => Box::new(NeverForwardStage::new(
+                &self.never_forward,
+                &self.forwarded,
+                self.ttl.negative(),
+            )),
+            StageKind::Blocklist => Box::new(self.blocklist_stage()),
+            StageKind::SafeSearch => Box::new(SafeSearchStage::new(
+                self.blocklist.clone(),
+                self.lookup.clone(),
+                self.ttl.local(),
+            )),
+            StageKind::Cache => Box::new(CacheStage::new(self.cache.clone())),
+            StageKind::Upstream => Box::new(
+                UpstreamStage::new(self.lookup.clone()).with_stale_cache(self.cache.clone()),
+            ),
+            StageKind::Limits => Box::new(self.limits.clone()),
+            StageKind::Rebinding => {
+                Box::new(self.rebinding.clone().with_forwarded(&self.forwarded))
+            }
+            StageKind::Ttl => Box::new(self.ttl.clone()),
+            StageKind::Persist => Box::new(PersistStage::new(self.cache.clone(), self.ttl.clone())),
+            StageKind::CnameInspection => {
+                Box::new(CnameInspectionStage::new(self.blocklist_stage()))
+            }
+            StageKind::AaaaFilter => Box::new(AaaaFilterStage::new(
+                &self.aaaa_filter,
+                self.metrics.clone(),
+            )),
+        }
+    }
+
+    fn build_pipeline(&self) -> Pipeline {
+        self.stages
+            .iter()
+            .fold(Pipeline::default(), |pipeline, kind| {
+                pipeline.with_stage(self.stage(*kind))
+            })
+    }
+
+    fn pipeline(&self) -> Arc<Pipeline> {
+        if let Some(found) = self.pipeline.read().unwrap().as_ref() {
+            return found.clone();
+        }
+        self.pipeline
+            .write()
+            .unwrap()
+            .get_or_insert_with(|| Arc::new(self.build_pipeline()))
+            .clone()
+    }
+
+    /// Builds the pipeline again from the configuration of this handler and uses it
+    /// for the next queries of all its clones, the ongoing ones finishing with the previous one
+    pub fn rebuild(&self) {
+        *self.pipeline.write().unwrap() = Some(Arc::new(self.build_pipeline()));
+    }
 }
 
 impl DnsHandler {
     async fn try_handle(
         &self,
-        origin: &SocketAddr,
+        source: QuerySource,
         packet: &DnsPacket,
-    ) -> Result<DnsPacket, HandleError> {
-        let question = match packet.questions.first() {
-            Some(found) => found,
-            None => return Err(HandleError::NoQuestion),
-        };
-        if self
-            .blocklist
-            .is_blocked(origin, question.name.as_str())
-            .await
-            .map_err(HandleError::Blocklist)?
-        {
-            let mut res = DnsPacket::response_from(packet);
-            res.header.response_code = ResponseCode::NameError;
-            return Ok(res);
+    ) -> Result<(DnsPacket, Provenance), HandleError> {
+        if packet.questions.len() <= 1 {
+            let ctx = QueryContext::new(source, packet)?;
+            return self.pipeline().run(ctx).await;
         }
-
-        if let Some(records) = self
-            .cache
-            .request(question.name.as_str(), question.qtype)
-            .await
-            .map_err(HandleError::Cache)?
-        {
-            return Ok(DnsPacket::response_from(packet).with_answers(records));
+        if let Err(error) = self.limits.check_questions(packet.questions.len()) {
+            tracing::debug!("refusing query: {error}");
+            return Ok((
+                DnsPacket::response_from(packet).with_response_code(ResponseCode::FormatError),
+                Provenance::Synthesized,
+            ));
         }
+        let pipeline = self.pipeline();
+        let mut responses = Vec::with_capacity(packet.questions.len());
+        for question in packet.questions.iter() {
+            let ctx = QueryContext::for_question(source, packet, question);
+            responses.push(pipeline.run(ctx).await?);
+        }
+        Ok(merge_responses(responses))
+    }
+}
 
-        let response = self
-            .lookup
-            .lookup(question.name.as_str(), question.qtype)
-            .await
-            .map_err(HandleError::Lookup)?;
+impl DnsHandler {
+    /// Answers the query of a client through the pipeline, without logging it,
+    /// to tell how a query would be answered
+    pub async fn resolve(
+        &self,
+        client: SocketAddr,
+        packet: &DnsPacket,
+    ) -> Result<(DnsPacket, Provenance), HandleError> {
+        self.try_handle(QuerySource::Client(client), packet).await
+    }
 
-        if let Err(error) = self
-            .cache
-            .persist(
-                question.name.as_str(),
-                question.qtype,
-                response.answers.clone(),
-            )
-            .await
-        {
-            tracing::error!("couldn't persist in cache: {error:?}");
+    /// Resolves a query on behalf of donos itself, bypassing the blocklists and the policy
+    pub async fn resolve_internal(
+        &self,
+        reason: InternalReason,
+        qname: &str,
+        qtype: QueryType,
+    ) -> Result<(DnsPacket, Provenance), HandleError> {
+        let packet = DnsPacket::new(Header::question(0))
+            .with_question(Question::new(qname.to_string(), qtype));
+        let source = QuerySource::Internal(reason);
+        let result = self
+            .try_handle(source, &packet)
+            .instrument(tracing::info_span!("internal", source = %source, qname, ?qtype))
+            .await;
+        self.metrics.record_internal();
+        result
+    }
+
+    /// Refreshes the cache entries of the queue through the pipeline, until the queue
+    /// is closed, for the prefetched answers to be checked like the ones of the clients
+    pub async fn prefetch(&self, mut queue: UnboundedReceiver<(String, QueryType)>) {
+        while let Some((qname, qtype)) = queue.recv().await {
+            tracing::debug!("prefetching {qname:?} {qtype:?}");
+            if let Err(error) = self
+                .resolve_internal(InternalReason::Prefetch, &qname, qtype)
+                .await
+            {
+                tracing::warn!("couldn't prefetch {qname:?}: {error}");
+            }
         }
+    }
+}
 
-        let res = DnsPacket::response_from(packet).with_answers(response.answers);
+/// Single response to a query with several questions, gathering the records found
+/// for each of them.
+///
+/// The first error code wins, and the query counts as blocked as soon as one question is.
+fn merge_responses(responses: Vec<(DnsPacket, Provenance)>) -> (DnsPacket, Provenance) {
+    let mut responses = responses.into_iter();
+    let Some((mut merged, mut provenance)) = responses.next() else {
+        return (DnsPacket::default(), Provenance::Synthesized);
+    };
+    for (packet, other) in responses {
+        if merged.header.response_code == ResponseCode::NoError {
+            merged.header.response_code = packet.header.response_code;
+        }
+        if other == Provenance::Blocked {
+            provenance = other;
+        }
+        for (records, found) in [
+            (&mut merged.answers, packet.answers),
+            (&mut merged.authorities, packet.authorities),
+            (&mut merged.resources, packet.resources),
+        ] {
+            for record in found {
+                if !records.contains(&record) {
+                    records.push(record);
+                }
+            }
+        }
+    }
+    (merged, provenance)
+}
 
-        Ok(res)
+/// Opcode of the standard queries, the only ones being answered
+const OPCODE_QUERY: u8 = 0;
+
+/// Code answered when a query couldn't be handled, for the client to fail fast
+fn error_code(error: &HandleError) -> ResponseCode {
+    match error {
+        HandleError::NoQuestion | HandleError::Reader(_) => ResponseCode::FormatError,
+        HandleError::Blocklist(_)
+        | HandleError::Cache(_)
+        | HandleError::Writer(_)
+        | HandleError::Io(_)
+        | HandleError::Limit(_) => ResponseCode::ServerFailure,
     }
 }
 
-#[async_trait::async_trait]
-impl donos_server::Handler for DnsHandler {
-    #[tracing::instrument(skip_all, fields(origin = ?message.address, id = tracing::field::Empty))]
-    async fn handle(&self, message: Message) -> Option<Message> {
-        let Message {
-            address,
-            buffer,
-            size: _,
-        } = message;
+/// FORMERR answered to a packet that couldn't be read, built from its first bytes
+/// as long as they are the ones of a query, the other packets being dropped
+fn malformed_response([high, low, flags]: [u8; 3]) -> Option<DnsPacket> {
+    // the QR bit is set on the responses
+    if flags & 0x80 != 0 {
+        return None;
+    }
+    let header = Header::response(u16::from_be_bytes([high, low]))
+        .with_recursion_desired(flags & 0x01 != 0)
+        .with_opcode((flags >> 3) & 0x0F)
+        .with_response_code(ResponseCode::FormatError);
+    Some(DnsPacket::new(header))
+}
+
+/// Response sent over UDP instead of one too large, so that the client retries over TCP
+fn truncated(packet: DnsPacket) -> DnsPacket {
+    DnsPacket {
+        header: packet.header.with_truncated_message(true),
+        questions: packet.questions,
+        ..Default::default()
+    }
+}
+
+/// Whether the record is only sent to the clients asking for the DNSSEC records
+/// with the DO bit, unless it's the type they queried (RFC 4035 section 3.2.1)
+fn is_dnssec_only(record: &Record, questions: &[Question]) -> bool {
+    let qtype = match record {
+        Record::RRSIG { .. } => QueryType::RRSIG,
+        Record::NSEC { .. } => QueryType::NSEC,
+        Record::NSEC3 { .. } => QueryType::NSEC3,
+        _ => return false,
+    };
+    !questions.iter().any(|question| question.qtype == qtype)
+}
+
+/// Response shaped for the EDNS capabilities of the client: the DNSSEC records
+/// are only kept when it set the DO bit, and an OPT record is only sent to the
+/// clients that sent one
+fn with_edns(request: &DnsPacket, mut response: DnsPacket) -> DnsPacket {
+    // the AD bit is only sent to the clients showing they understand it (RFC 6840 section 5.7)
+    response.header.authed_data &= request.dnssec_ok() || request.header.authed_data;
+    if !request.dnssec_ok() {
+        for records in [
+            &mut response.answers,
+            &mut response.authorities,
+            &mut response.resources,
+        ] {
+            records.retain(|record| !is_dnssec_only(record, &request.questions));
+        }
+    }
+    response
+        .resources
+        .retain(|record| !matches!(record, Record::OPT { .. }));
+    if request.edns().is_some() {
+        let flags = if request.dnssec_ok() {
+            EDNS_DNSSEC_OK
+        } else {
+            0
+        };
+        response.resources.push(Record::OPT {
+            payload_size: EDNS_PACKET_SIZE as u16,
+            flags,
+            data: Vec::new(),
+        });
+    }
+    response
+}
+
+/// Largest response that can be sent over UDP, the one advertised by the client
+/// with EDNS as long as it doesn't risk fragmentation
+fn udp_size(request: &DnsPacket) -> usize {
+    match request.edns() {
+        Some(Record::OPT { payload_size, .. }) => {
+            (*payload_size as usize).clamp(UDP_PACKET_SIZE, EDNS_PACKET_SIZE)
+        }
+        _ => UDP_PACKET_SIZE,
+    }
+}
 
+impl DnsHandler {
+    async fn handle_buffer(
+        &self,
+        address: &SocketAddr,
+        client: Option<&str>,
+        transport: Transport,
+        mut buffer: Vec<u8>,
+        size: usize,
+    ) -> Option<BytePacketBuffer> {
+        // the pooled buffers may hold the bytes of a previous, longer, message
+        buffer.truncate(size);
+        let head = buffer.first_chunk::<3>().copied();
         // With a socket ready, we can go ahead and read a packet. This will
         // block until one is received.
-        let buffer = BytePacketBuffer::new(buffer);
-        // Next, `DnsPacket::from_buffer` is used to parse the raw bytes into
-        // a `DnsPacket`.
-        let request = match DnsPacket::try_from(buffer) {
+        let mut buffer = BytePacketBuffer::new(buffer);
+        // Next, `DnsPacket::read` is used to parse the raw bytes into
+        // a `DnsPacket`, the buffer being kept to write the response in it.
+        let request = match DnsPacket::read(&mut buffer) {
             Ok(req) => req,
             Err(err) => {
                 tracing::debug!("unable to read packet: {err:?}");
-                return None;
+                return head.and_then(malformed_response).and_then(|packet| {
+                    packet.write_into(BytePacketBuffer::reuse(buffer.buf)).ok()
+                });
             }
         };
+        let buffer = BytePacketBuffer::reuse(buffer.buf);
 
         tracing::Span::current().record("id", request.header.id);
+        if request.header.response {
+            tracing::debug!("ignoring a response sent as a query");
+            return None;
+        }
+        if request.header.opcode != OPCODE_QUERY {
+            tracing::debug!("opcode {} not implemented", request.header.opcode);
+            return DnsPacket::response_from(&request)
+                .with_response_code(ResponseCode::NotImplemented)
+                .write_into(buffer)
+                .ok();
+        }
 
-        match self.try_handle(&address, &request).await {
-            Ok(packet) => {
+        match self
+            .try_handle(QuerySource::Client(*address), &request)
+            .await
+        {
+            Ok((packet, provenance)) => {
+                tracing::Span::current().record("provenance", provenance.as_str());
+                self.metrics.record(provenance);
+                if let Some(query_log) = self.query_log.as_ref() {
+                    for question in request.questions.iter() {
+                        query_log.log(
+                            LoggedQuery::new(
+                                address.ip(),
+                                normalize(&question.name).into_owned(),
+                                question.qtype,
+                                provenance.as_str(),
+                            )
+                            .with_client_name(client),
+                        );
+                    }
+                }
                 tracing::debug!("creating response");
-                let buffer = packet.create_buffer().unwrap();
-
-                Some(Message {
-                    address,
-                    buffer: buffer.buf,
-                    size: buffer.pos,
-                })
-            }
-            Err(HandleError::NoQuestion) => {
-                tracing::debug!("no question where specified");
-                None
+                let packet = with_edns(&request, packet);
+                let created = packet
+                    .write_into(buffer)
+                    .map_err(HandleError::from)
+                    .and_then(|buffer| {
+                        self.limits
+                            .check_response_size(buffer.pos)
+                            .map_err(HandleError::Limit)
+                            .map(|_| buffer)
+                    });
+                match created {
+                    Ok(buffer)
+                        if transport == Transport::Udp && buffer.pos > udp_size(&request) =>
+                    {
+                        tracing::debug!("response of {} bytes truncated", buffer.pos);
+                        with_edns(&request, truncated(packet))
+                            .write_into(BytePacketBuffer::reuse(buffer.buf))
+                            .ok()
+                    }
+                    Ok(buffer) => Some(buffer),
+                    Err(error) => {
+                        tracing::warn!("unable to create response: {error}");
+                        let response = DnsPacket::response_from(&request)
+                            .with_response_code(ResponseCode::ServerFailure);
+                        with_edns(&request, response).create_buffer().ok()
+                    }
+                }
             }
             Err(error) => {
-                tracing::warn!("unable to build response message: {error:?}");
+                let code = error_code(&error);
+                tracing::Span::current().record("provenance", Provenance::Synthesized.as_str());
+                self.metrics.record(Provenance::Synthesized);
+                if code == ResponseCode::ServerFailure {
+                    tracing::warn!("unable to build response message: {error}");
+                } else {
+                    tracing::debug!("unable to build response message: {error}");
+                }
+                let response = DnsPacket::response_from(&request).with_response_code(code);
+                with_edns(&request, response).write_into(buffer).ok()
+            }
+        }
+    }
+}
 
-                todo!()
+#[async_trait::async_trait]
+impl donos_server::Handler for DnsHandler {
+    #[tracing::instrument(skip_all, fields(origin = ?message.address, client = tracing::field::Empty, listener = %message.listener, transport = %message.transport, id = tracing::field::Empty, provenance = tracing::field::Empty))]
+    async fn handle(&self, message: Message) -> Option<Message> {
+        let started = Instant::now();
+        let Message {
+            address,
+            listener,
+            transport,
+            buffer,
+            size,
+        } = message;
+        let client = self.clients.name(&address.ip());
+        if let Some(name) = client.as_deref() {
+            tracing::Span::current().record("client", name);
+        }
+
+        // the query is only kept around when it has to be captured or exported
+        let query_time = SystemTime::now();
+        let capture = self.capture.as_ref().filter(|capture| capture.is_enabled());
+        let query = (capture.is_some() || self.dnstap.is_some())
+            .then(|| buffer[..size.min(buffer.len())].to_vec());
+        let response = self
+            .handle_buffer(&address, client.as_deref(), transport, buffer, size)
+            .await;
+        self.metrics
+            .record_query(listener, transport, started.elapsed());
+        if let (Some(capture), Some(query)) = (capture, query.as_ref()) {
+            let response = response
+                .as_ref()
+                .map(|buffer| &buffer.buf[..buffer.pos])
+                .unwrap_or_default();
+            if let Err(error) = capture.record(query, response).await {
+                tracing::warn!("unable to capture packets: {error}");
             }
         }
+        if let (Some(dnstap), Some(query)) = (self.dnstap.as_ref(), query) {
+            dnstap.log(Exchange {
+                client: address,
+                listener,
+                transport,
+                query,
+                query_time,
+                response: response
+                    .as_ref()
+                    .map(|buffer| buffer.buf[..buffer.pos].to_vec()),
+                response_time: SystemTime::now(),
+            });
+        }
+
+        response.map(|buffer| Message {
+            address,
+            listener,
+            transport,
+            buffer: buffer.buf,
+            size: buffer.pos,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::DnsHandler;
+    use crate::common::source::InternalReason;
+    use crate::dns::config::BlocklistFailure;
+    use crate::dns::metrics::{Metrics, Provenance};
     use crate::repository::blocklist::MemoryBlocklistService;
-    use crate::repository::cache::MockCacheService;
+    use crate::repository::cache::{CacheService, MemoryCacheService, MockCacheService};
     use crate::repository::lookup::MockLookupService;
     use donos_parser::buffer::BytePacketBuffer;
     use donos_parser::packet::header::{Header, ResponseCode};
     use donos_parser::packet::question::{DnsClass, Question};
     use donos_parser::packet::record::Record;
     use donos_parser::packet::{DnsPacket, QueryType};
-    use donos_server::{prelude::Message, Handler};
+    use donos_server::prelude::{Message, Transport};
+    use donos_server::Handler;
     use similar_asserts::assert_eq;
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
     use std::sync::Arc;
@@ -153,6 +657,10 @@ mod tests {
         SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 1, 0, 1), 42))
     }
 
+    fn listener_address() -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 53))
+    }
+
     #[tokio::test]
     async fn should_resolve_query() {
         crate::init_logs();
@@ -162,6 +670,8 @@ mod tests {
         let input_buffer = input_packet.create_buffer().unwrap();
         let input = Message {
             address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
             buffer: input_buffer.buf,
             size: input_buffer.pos,
         };
@@ -196,6 +706,135 @@ mod tests {
         assert_eq!(result.header.id, input_packet.header.id);
     }
 
+    #[tokio::test]
+    async fn should_only_read_the_received_bytes() {
+        crate::init_logs();
+
+        // the pooled buffer still holds a previous query, with one more question
+        let previous = DnsPacket::new(Header::question(1))
+            .with_question(Question::new("perdu.com".into(), QueryType::A))
+            .with_question(Question::new("secret.example.com".into(), QueryType::A))
+            .create_buffer()
+            .unwrap();
+        // the new one claims two questions but only has one
+        let mut query = DnsPacket::new(Header::question(2))
+            .with_question(Question::new("perdu.com".into(), QueryType::A))
+            .create_buffer()
+            .unwrap();
+        query.buf[5] = 2;
+        let mut buffer = previous.buf[..previous.pos].to_vec();
+        buffer[..query.pos].copy_from_slice(&query.buf[..query.pos]);
+        let input = Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            buffer,
+            size: query.pos,
+        };
+
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default()),
+            Arc::new(MockLookupService::default()),
+        );
+        let result = handler.handle(input).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.id, 2);
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
+        assert!(result.questions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_echo_the_case_of_the_question() {
+        crate::init_logs();
+
+        // the upstream servers get the normalized name, shared by both queries
+        let lookup = Arc::new(
+            MockLookupService::default().with_query(
+                "perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(10))
+                    .with_question(Question::new("perdu.com".into(), QueryType::A))
+                    .with_answer(Record::A {
+                        domain: "perdu.com".into(),
+                        addr: Ipv4Addr::new(99, 99, 99, 99),
+                        ttl: 100,
+                    }),
+            ),
+        );
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default()),
+            lookup,
+        );
+        for name in ["PeRdU.cOm", "perdu.com"] {
+            let input_buffer = DnsPacket::new(Header::question(1))
+                .with_question(Question::new(name.into(), QueryType::A))
+                .create_buffer()
+                .unwrap();
+            let input = Message {
+                address: socket_address(),
+                listener: listener_address(),
+                transport: Transport::Udp,
+                buffer: input_buffer.buf,
+                size: input_buffer.pos,
+            };
+            let result = handler.handle(input).await.unwrap();
+            let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+            assert_eq!(result.header.response_code, ResponseCode::NoError);
+            assert_eq!(result.questions[0].name, name);
+            assert_eq!(result.answers[0].domain(), name);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_truncate_large_udp_responses() {
+        crate::init_logs();
+
+        let input_packet = DnsPacket::new(Header::question(1))
+            .with_question(Question::new("perdu.com".into(), QueryType::A));
+        let input_buffer = input_packet.create_buffer().unwrap();
+
+        let answers = (0..40)
+            .map(|idx| Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(99, 99, 99, idx),
+                ttl: 100,
+            })
+            .collect();
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default()),
+            Arc::new(
+                MockLookupService::default().with_query(
+                    "perdu.com",
+                    QueryType::A,
+                    DnsPacket::new(Header::response(10))
+                        .with_question(Question::new("perdu.com".into(), QueryType::A))
+                        .with_answers(answers),
+                ),
+            ),
+        );
+
+        for (transport, truncated, count) in
+            [(Transport::Udp, true, 0), (Transport::Tcp, false, 40)]
+        {
+            let input = Message {
+                address: socket_address(),
+                listener: listener_address(),
+                transport,
+                buffer: input_buffer.buf.clone(),
+                size: input_buffer.pos,
+            };
+            let result = handler.handle(input).await.expect("should have a message");
+            assert_eq!(result.size > 512, !truncated);
+            let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+            assert_eq!(result.header.truncated_message, truncated);
+            assert_eq!(result.questions.len(), 1);
+            assert_eq!(result.answers.len(), count);
+        }
+    }
+
     #[tokio::test]
     async fn should_block_query() {
         crate::init_logs();
@@ -205,6 +844,8 @@ mod tests {
         let input_buffer = input_packet.create_buffer().unwrap();
         let input = Message {
             address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
             buffer: input_buffer.buf,
             size: input_buffer.pos,
         };
@@ -242,13 +883,15 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn should_not_answer_if_not_question() {
+    async fn should_answer_formerr_without_question() {
         crate::init_logs();
 
         let input_packet = DnsPacket::new(Header::question(1));
         let input_buffer = input_packet.create_buffer().unwrap();
         let input = Message {
             address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
             buffer: input_buffer.buf,
             size: input_buffer.pos,
         };
@@ -258,8 +901,11 @@ mod tests {
         let lookup = Arc::new(MockLookupService::default());
         let result = DnsHandler::new(blocklist, cache, lookup)
             .handle(input)
-            .await;
-        assert!(result.is_none());
+            .await
+            .expect("should have a message");
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.id, 1);
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
     }
 
     #[tokio::test]
@@ -271,6 +917,8 @@ mod tests {
         let input_buffer = input_packet.create_buffer().unwrap();
         let input = Message {
             address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
             buffer: input_buffer.buf,
             size: input_buffer.pos,
         };
@@ -286,7 +934,9 @@ mod tests {
             }],
         ));
         let lookup = Arc::new(MockLookupService::default());
+        let metrics = Arc::new(Metrics::default());
         let result = DnsHandler::new(blocklist, cache, lookup)
+            .with_metrics(metrics.clone())
             .handle(input)
             .await;
 
@@ -298,5 +948,585 @@ mod tests {
         assert!(result.header.response);
         assert_eq!(result.header.response_code, ResponseCode::NoError);
         assert_eq!(result.answers.len(), 1);
+        assert_eq!(metrics.responses(Provenance::Cache), 1);
+        assert_eq!(metrics.responses(Provenance::Upstream), 0);
+        let listeners = metrics.listeners();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].0, (listener_address(), Transport::Udp));
+        assert_eq!(listeners[0].1.queries, 1);
+    }
+
+    #[tokio::test]
+    async fn should_not_forward_junk_tld() {
+        crate::init_logs();
+
+        let input_packet = DnsPacket::new(Header::question(1))
+            .with_question(Question::new("printer.lan".into(), QueryType::A));
+        let input_buffer = input_packet.create_buffer().unwrap();
+        let input = Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            buffer: input_buffer.buf,
+            size: input_buffer.pos,
+        };
+
+        let blocklist = Arc::new(MemoryBlocklistService::default());
+        let cache = Arc::new(MockCacheService::default());
+        // the lookup would fail if called
+        let lookup = Arc::new(MockLookupService::default());
+        let result = DnsHandler::new(blocklist, cache, lookup)
+            .with_never_forward(vec!["lan".into()])
+            .handle(input)
+            .await;
+
+        let result = result.expect("should have a message");
+        let result = BytePacketBuffer::new(result.buffer);
+        let result = DnsPacket::try_from(result).unwrap();
+
+        assert_eq!(result.header.id, 1);
+        assert!(result.header.response);
+        assert_eq!(result.header.response_code, ResponseCode::NameError);
+    }
+
+    #[tokio::test]
+    async fn should_apply_rebuilt_pipeline_to_clones() {
+        crate::init_logs();
+
+        let serving = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default()),
+            // the lookup fails, so the query isn't answered with NXDOMAIN when forwarded
+            Arc::new(MockLookupService::default()),
+        );
+        let (packet, _) = serving
+            .resolve_internal(InternalReason::HealthCheck, "printer.lan", QueryType::A)
+            .await
+            .unwrap();
+        assert_eq!(packet.header.response_code, ResponseCode::ServerFailure);
+
+        serving
+            .clone()
+            .with_never_forward(vec!["lan".into()])
+            .rebuild();
+
+        let (packet, provenance) = serving
+            .resolve_internal(InternalReason::HealthCheck, "printer.lan", QueryType::A)
+            .await
+            .unwrap();
+        assert_eq!(packet.header.response_code, ResponseCode::NameError);
+        assert_eq!(provenance, Provenance::Synthesized);
+    }
+
+    #[tokio::test]
+    async fn should_only_resolve_allowed_domains_when_blocking_by_default() {
+        crate::init_logs();
+
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default().with_records(
+                "perdu.com",
+                QueryType::A,
+                vec![Record::A {
+                    domain: "perdu.com".into(),
+                    addr: Ipv4Addr::new(10, 0, 0, 1),
+                    ttl: 42,
+                }],
+            )),
+            Arc::new(MockLookupService::default()),
+        )
+        .with_policy(
+            crate::dns::policy::Config {
+                default_action: crate::dns::policy::Action::Block,
+                allow: vec!["perdu.com".into()],
+                templates: Vec::new(),
+            }
+            .build(),
+        );
+
+        for (domain, expected) in [
+            ("perdu.com", ResponseCode::NoError),
+            ("facebook.com", ResponseCode::NameError),
+        ] {
+            let input_packet = DnsPacket::new(Header::question(1))
+                .with_question(Question::new(domain.into(), QueryType::A));
+            let input_buffer = input_packet.create_buffer().unwrap();
+            let input = Message {
+                address: socket_address(),
+                listener: listener_address(),
+                transport: Transport::Udp,
+                buffer: input_buffer.buf,
+                size: input_buffer.pos,
+            };
+            let result = handler.handle(input).await.expect("should have a message");
+            let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+            assert_eq!(result.header.response_code, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn internal_queries_should_bypass_blocklist() {
+        crate::init_logs();
+
+        let blocklist = Arc::new(MemoryBlocklistService::default().with_domain("www.facebook.com"));
+        let cache = Arc::new(MockCacheService::default());
+        let lookup = Arc::new(MockLookupService::default().with_query(
+            "www.facebook.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(10)).with_answer(Record::A {
+                domain: "www.facebook.com".into(),
+                addr: Ipv4Addr::new(99, 99, 99, 99),
+                ttl: 100,
+            }),
+        ));
+        let metrics = Arc::new(Metrics::default());
+        let handler = DnsHandler::new(blocklist, cache, lookup).with_metrics(metrics.clone());
+
+        let (result, provenance) = handler
+            .resolve_internal(InternalReason::Prefetch, "www.facebook.com", QueryType::A)
+            .await
+            .unwrap();
+
+        assert_eq!(provenance, Provenance::Upstream);
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(metrics.internal(), 1);
+        assert_eq!(metrics.responses(Provenance::Upstream), 0);
+    }
+
+    #[tokio::test]
+    async fn prefetched_answers_should_be_checked() {
+        crate::init_logs();
+
+        let blocklist = Arc::new(MemoryBlocklistService::default());
+        let cache = Arc::new(MemoryCacheService::new(10));
+        cache
+            .persist(
+                "perdu.com",
+                QueryType::A,
+                vec![Record::A {
+                    domain: "perdu.com".into(),
+                    addr: Ipv4Addr::new(1, 2, 3, 4),
+                    ttl: 60,
+                }],
+            )
+            .await
+            .unwrap();
+        // the domain now resolves to an address of the local network
+        let lookup = Arc::new(MockLookupService::default().with_query(
+            "perdu.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(1)).with_answer(Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(192, 168, 1, 12),
+                ttl: 60,
+            }),
+        ));
+        let handler = DnsHandler::new(blocklist, cache.clone(), lookup);
+
+        let (sender, queue) = tokio::sync::mpsc::unbounded_channel();
+        sender
+            .send(("perdu.com".to_string(), QueryType::A))
+            .unwrap();
+        drop(sender);
+        handler.prefetch(queue).await;
+
+        // the entry was refreshed, without the private address
+        let found = cache.request("perdu.com", QueryType::A).await.unwrap();
+        assert_eq!(found.map(|found| found.records), Some(Vec::new()));
+    }
+
+    #[derive(Debug)]
+    struct FailingBlocklistService;
+
+    #[async_trait::async_trait]
+    impl crate::repository::blocklist::BlocklistService for FailingBlocklistService {
+        async fn is_blocked(
+            &self,
+            _origin: &SocketAddr,
+            _domain: &str,
+        ) -> Result<bool, Box<dyn std::error::Error>> {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "database unreachable",
+            )))
+        }
+
+        async fn import(&self) -> Result<(u64, u64), Box<dyn std::error::Error>> {
+            Ok((0, 0))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_follow_blocklist_failure_mode() {
+        crate::init_logs();
+
+        let input_packet = DnsPacket::new(Header::question(1))
+            .with_question(Question::new("perdu.com".into(), QueryType::A));
+        let input_buffer = input_packet.create_buffer().unwrap();
+        let input = Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            buffer: input_buffer.buf,
+            size: input_buffer.pos,
+        };
+        let lookup = Arc::new(MockLookupService::default().with_query(
+            "perdu.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(10)).with_answer(Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(99, 99, 99, 99),
+                ttl: 100,
+            }),
+        ));
+
+        for (mode, expected) in [
+            (BlocklistFailure::Open, ResponseCode::NoError),
+            (BlocklistFailure::Closed, ResponseCode::ServerFailure),
+        ] {
+            let result = DnsHandler::new(
+                Arc::new(FailingBlocklistService),
+                Arc::new(MockCacheService::default()),
+                lookup.clone(),
+            )
+            .with_blocklist_failure(mode)
+            .handle(input.clone())
+            .await
+            .expect("should have a message");
+            let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+            assert_eq!(result.header.response_code, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_forward_and_cache_reverse_lookups() {
+        crate::init_logs();
+
+        let qname = "99.99.99.99.in-addr.arpa";
+        let input_buffer = DnsPacket::new(Header::question(1))
+            .with_question(Question::new(qname.into(), QueryType::PTR))
+            .create_buffer()
+            .unwrap();
+        let input = Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            buffer: input_buffer.buf,
+            size: input_buffer.pos,
+        };
+        let lookup = Arc::new(MockLookupService::default().with_query(
+            qname,
+            QueryType::PTR,
+            DnsPacket::new(Header::response(10)).with_answer(Record::PTR {
+                domain: qname.into(),
+                host: "perdu.com".into(),
+                ttl: 100,
+            }),
+        ));
+        let metrics = Arc::new(Metrics::default());
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MemoryCacheService::new(10)),
+            lookup,
+        )
+        .with_metrics(metrics.clone());
+
+        for _ in 0..2 {
+            let result = handler
+                .handle(input.clone())
+                .await
+                .expect("should have a message");
+            let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+            assert!(matches!(
+                result.answers.as_slice(),
+                [Record::PTR { host, .. }] if host == "perdu.com"
+            ));
+        }
+        assert_eq!(metrics.responses(Provenance::Upstream), 1);
+        assert_eq!(metrics.responses(Provenance::Cache), 1);
+    }
+
+    #[tokio::test]
+    async fn should_keep_edns_when_the_response_is_too_large() {
+        use donos_parser::packet::generate::{self, Compliance};
+
+        crate::init_logs();
+
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default().with_records(
+                "perdu.com",
+                QueryType::A,
+                vec![Record::A {
+                    domain: "perdu.com".into(),
+                    addr: Ipv4Addr::new(99, 99, 99, 99),
+                    ttl: 42,
+                }],
+            )),
+            Arc::new(MockLookupService::default()),
+        )
+        .with_limits(super::Limits {
+            max_response_size: 40,
+            ..Default::default()
+        });
+        let buffer = generate::query(
+            1,
+            [Question::new("perdu.com".into(), QueryType::A)],
+            &Compliance::default().with_edns(1232),
+        )
+        .unwrap();
+        let input = Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            size: buffer.pos,
+            buffer: buffer.buf,
+        };
+        let result = handler.handle(input).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+        assert!(result.edns().is_some());
+    }
+
+    #[tokio::test]
+    async fn should_handle_unusual_but_legal_packets() {
+        use donos_parser::packet::generate::{self, Compliance};
+
+        crate::init_logs();
+
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(
+                MockCacheService::default()
+                    .with_records(
+                        "perdu.com",
+                        QueryType::A,
+                        vec![Record::A {
+                            domain: "perdu.com".into(),
+                            addr: Ipv4Addr::new(99, 99, 99, 99),
+                            ttl: 42,
+                        }],
+                    )
+                    .with_records(
+                        "perdu.com",
+                        QueryType::AAAA,
+                        vec![Record::AAAA {
+                            domain: "perdu.com".into(),
+                            addr: "::99".parse().unwrap(),
+                            ttl: 42,
+                        }],
+                    ),
+            ),
+            Arc::new(MockLookupService::default()),
+        );
+        let message = |buffer: BytePacketBuffer| Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            size: buffer.pos,
+            buffer: buffer.buf,
+        };
+
+        // each question is answered
+        let buffer = generate::query(
+            1,
+            [
+                Question::new("perdu.com".into(), QueryType::A),
+                Question::new("perdu.com".into(), QueryType::AAAA),
+            ],
+            &Compliance::default(),
+        )
+        .unwrap();
+        let result = handler.handle(message(buffer)).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.questions.len(), 2);
+        assert_eq!(result.header.response_code, ResponseCode::NoError);
+        assert_eq!(result.answers.len(), 2);
+
+        // but not too many of them
+        let buffer = generate::query(
+            1,
+            (0..5).map(|idx| Question::new(format!("{idx}.perdu.com"), QueryType::A)),
+            &Compliance::default(),
+        )
+        .unwrap();
+        let result = handler.handle(message(buffer)).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
+        assert!(result.answers.is_empty());
+
+        // the OPT record is answered with another one
+        let buffer = generate::query(
+            2,
+            [Question::new("perdu.com".into(), QueryType::A)],
+            &Compliance::default().with_edns(1232),
+        )
+        .unwrap();
+        let result = handler.handle(message(buffer)).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.answers.len(), 1);
+        assert_eq!(result.resources.len(), 1);
+        assert!(result.edns().is_some());
+
+        // the other opcodes aren't implemented
+        let result = handler
+            .handle(message(generate::empty_notify(3).unwrap()))
+            .await
+            .unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.id, 3);
+        assert_eq!(result.header.response_code, ResponseCode::NotImplemented);
+
+        // a query needs a question
+        let result = handler
+            .handle(message(generate::edns_only(4, 1232).unwrap()))
+            .await
+            .unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
+    }
+
+    #[tokio::test]
+    async fn should_only_send_signatures_to_dnssec_clients() {
+        use donos_parser::packet::generate::{self, Compliance};
+
+        crate::init_logs();
+
+        let signature = Record::RRSIG {
+            domain: "perdu.com".into(),
+            type_covered: 1,
+            algorithm: 13,
+            labels: 2,
+            original_ttl: 100,
+            expiration: 0x6789abcd,
+            inception: 0x67700000,
+            key_tag: 12345,
+            signer: "perdu.com".into(),
+            signature: vec![0xde; 64],
+            ttl: 100,
+        };
+        let lookup = MockLookupService::default().with_query(
+            "perdu.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(10))
+                .with_question(Question::new("perdu.com".into(), QueryType::A))
+                .with_answer(Record::A {
+                    domain: "perdu.com".into(),
+                    addr: Ipv4Addr::new(99, 99, 99, 99),
+                    ttl: 100,
+                })
+                .with_answer(signature.clone())
+                .with_resource(Record::OPT {
+                    payload_size: 1232,
+                    flags: 0x8000,
+                    data: Vec::new(),
+                }),
+        );
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MemoryCacheService::new(10)),
+            Arc::new(lookup),
+        );
+        let query = |compliance: Compliance| {
+            let buffer = generate::query(
+                1,
+                [Question::new("perdu.com".into(), QueryType::A)],
+                &compliance,
+            )
+            .unwrap();
+            let handler = handler.clone();
+            async move {
+                let result = handler
+                    .handle(Message {
+                        address: socket_address(),
+                        listener: listener_address(),
+                        transport: Transport::Udp,
+                        size: buffer.pos,
+                        buffer: buffer.buf,
+                    })
+                    .await
+                    .unwrap();
+                DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap()
+            }
+        };
+
+        // the signature is forwarded, then served from the cache
+        for _ in 0..2 {
+            let result = query(Compliance::default().with_edns(1232).with_dnssec_ok()).await;
+            assert_eq!(result.answers.len(), 2);
+            assert_eq!(
+                result.answers[1].delayed_ttl(100),
+                signature,
+                "the cached signature only has its ttl changed"
+            );
+            assert_eq!(
+                result.resources,
+                vec![Record::OPT {
+                    payload_size: 1232,
+                    flags: 0x8000,
+                    data: Vec::new(),
+                }]
+            );
+        }
+
+        let result = query(Compliance::default().with_edns(1232)).await;
+        assert_eq!(result.answers.len(), 1);
+        assert!(!result.dnssec_ok());
+
+        let result = query(Compliance::default()).await;
+        assert_eq!(result.answers.len(), 1);
+        assert!(result.resources.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_answer_formerr_to_malformed_queries() {
+        crate::init_logs();
+
+        let handler = DnsHandler::new(
+            Arc::new(MemoryBlocklistService::default()),
+            Arc::new(MockCacheService::default()),
+            Arc::new(MockLookupService::default()),
+        );
+        let message = |buffer: Vec<u8>| Message {
+            address: socket_address(),
+            listener: listener_address(),
+            transport: Transport::Udp,
+            size: buffer.len(),
+            buffer,
+        };
+
+        // a question cut in the middle of its name
+        let mut buffer = DnsPacket::new(Header::question(42))
+            .with_question(Question::new("perdu.com".into(), QueryType::A))
+            .create_buffer()
+            .unwrap();
+        buffer.buf.truncate(16);
+        let result = handler.handle(message(buffer.buf)).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.id, 42);
+        assert!(result.header.response);
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
+
+        // a name with a control character, that shouldn't reach the cache
+        let mut buffer = DnsPacket::new(Header::question(44))
+            .with_question(Question::new("perdu.com".into(), QueryType::A))
+            .create_buffer()
+            .unwrap();
+        buffer.buf[15] = b'\n';
+        buffer.buf.truncate(buffer.pos);
+        let result = handler.handle(message(buffer.buf)).await.unwrap();
+        let result = DnsPacket::try_from(BytePacketBuffer::new(result.buffer)).unwrap();
+        assert_eq!(result.header.id, 44);
+        assert_eq!(result.header.response_code, ResponseCode::FormatError);
+
+        // nothing to answer to responses nor to packets without an id
+        let mut buffer = DnsPacket::new(Header::response(43))
+            .create_buffer()
+            .unwrap();
+        buffer.buf.truncate(buffer.pos);
+        assert!(handler.handle(message(buffer.buf.clone())).await.is_none());
+        buffer.buf.truncate(8);
+        assert!(handler.handle(message(buffer.buf)).await.is_none());
+        assert!(handler.handle(message(vec![0])).await.is_none());
     }
 }
@@ -0,0 +1,521 @@
+//! Names of the hosts of the local network, read from the leases file of the DHCP server,
+//! so that donos answers for them like the DNS half of dnsmasq.
+//!
+//! The file is checked periodically and read again when it changes, or when one of its
+//! leases expires.
+use super::pipeline::reverse::reverse_name;
+use crate::common::domain::{matches_suffix, normalize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Format of the leases file, depending on the DHCP server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// `dnsmasq.leases`, one lease per line
+    #[default]
+    Dnsmasq,
+    /// CSV file of the memfile backend of Kea, for IPv4 or IPv6
+    Kea,
+    /// `dhcpd.leases` of the ISC DHCP server, only the IPv4 leases
+    Isc,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "Config::default_path")]
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: Format,
+    /// Domain appended to the hostnames, like `lan` to answer `nas.lan` as well as `nas`.
+    ///
+    /// The hostnames with several labels are only served under this domain.
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Number of seconds between two checks of the file
+    #[serde(default = "Config::default_interval")]
+    pub interval: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Self::default_path(),
+            format: Format::default(),
+            domain: None,
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        PathBuf::from("/var/lib/misc/dnsmasq.leases")
+    }
+
+    pub fn default_interval() -> u64 {
+        10
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Lease {
+    pub address: IpAddr,
+    pub hostname: String,
+    /// Unix timestamp of the end of the lease, none when it never ends
+    pub expires: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Checks that the hostname given by the client can be served, like `nas` or `nas.lan`
+fn valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && label
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        })
+}
+
+/// Leases still valid, the last one of an address replacing the previous ones
+fn active(leases: Vec<Lease>, now: u64) -> Vec<Lease> {
+    let mut found: HashMap<IpAddr, Lease> = HashMap::new();
+    for lease in leases {
+        if lease.hostname.is_empty() || lease.expires.is_some_and(|expires| expires <= now) {
+            found.remove(&lease.address);
+        } else {
+            found.insert(lease.address, lease);
+        }
+    }
+    let mut found: Vec<_> = found.into_values().collect();
+    found.sort_by_key(|lease| lease.address);
+    found
+}
+
+/// `<expiry> <mac or iaid> <address> <hostname or *> <client id or *>`, with
+/// an expiry of 0 for the leases that never end
+fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            // skips the line with the duid of the server, before the IPv6 leases
+            let [expires, _, address, hostname, ..] = fields.as_slice() else {
+                return None;
+            };
+            let expires: u64 = expires.parse().ok()?;
+            Some(Lease {
+                address: address.parse().ok()?,
+                hostname: if *hostname == "*" {
+                    String::new()
+                } else {
+                    hostname.to_string()
+                },
+                expires: (expires > 0).then_some(expires),
+            })
+        })
+        .collect()
+}
+
+/// Columns found by name in the header, so that both the IPv4 and IPv6 files
+/// are read. The leases are appended, the last line of an address being the current one.
+fn parse_kea(content: &str) -> Vec<Lease> {
+    let mut lines = content.lines();
+    let Some(header) = lines.next() else {
+        return Vec::new();
+    };
+    let column = |name: &str| header.split(',').position(|found| found.trim() == name);
+    let (Some(address), Some(hostname), Some(expire)) =
+        (column("address"), column("hostname"), column("expire"))
+    else {
+        tracing::warn!("missing columns in the header of the kea leases file");
+        return Vec::new();
+    };
+    let state = column("state");
+    lines
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split(',').collect();
+            let address = fields.get(address)?.parse().ok()?;
+            // the declined and reclaimed leases remove the previous ones
+            let assigned = state
+                .and_then(|state| fields.get(state))
+                .is_none_or(|state| *state == "0");
+            let hostname = match fields.get(hostname) {
+                Some(found) if assigned => found.to_string(),
+                _ => String::new(),
+            };
+            let expires = fields.get(expire)?.parse().ok()?;
+            Some(Lease {
+                address,
+                hostname,
+                expires: Some(expires),
+            })
+        })
+        .collect()
+}
+
+/// Days since the unix epoch of a date of the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Dates of `dhcpd.leases`, like `ends 4 2026/10/15 22:00:00` in UTC or `ends epoch 1760565600`
+fn parse_isc_date(fields: &[&str]) -> Option<Option<u64>> {
+    match fields {
+        ["never"] => Some(None),
+        ["epoch", timestamp, ..] => timestamp.parse().ok().map(Some),
+        [_, date, time, ..] => {
+            let date: Vec<i64> = date
+                .split('/')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?;
+            let time: Vec<i64> = time
+                .split(':')
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .ok()?;
+            let ([year, month, day], [hours, minutes, seconds]) =
+                (date.as_slice(), time.as_slice())
+            else {
+                return None;
+            };
+            let timestamp = days_from_civil(*year, *month, *day) * 86400
+                + hours * 3600
+                + minutes * 60
+                + seconds;
+            u64::try_from(timestamp).ok().map(Some)
+        }
+        _ => None,
+    }
+}
+
+/// `lease <address> { ... }` blocks, appended by the server like the kea lines
+fn parse_isc(content: &str) -> Vec<Lease> {
+    let mut leases = Vec::new();
+    let mut current: Option<(Lease, bool)> = None;
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+        if let Some(rest) = line.strip_prefix("lease ") {
+            current = rest
+                .trim_end_matches('{')
+                .trim()
+                .parse()
+                .ok()
+                .map(|address| {
+                    let lease = Lease {
+                        address,
+                        hostname: String::new(),
+                        expires: None,
+                    };
+                    (lease, true)
+                });
+            continue;
+        }
+        let Some((lease, active)) = current.as_mut() else {
+            continue;
+        };
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["}"] => {
+                if let Some((mut lease, active)) = current.take() {
+                    if !active {
+                        lease.hostname.clear();
+                    }
+                    leases.push(lease);
+                }
+            }
+            ["binding", "state", state] => *active = *state == "active",
+            ["ends", date @ ..] => {
+                if let Some(expires) = parse_isc_date(date) {
+                    lease.expires = expires;
+                }
+            }
+            ["client-hostname", hostname] => lease.hostname = hostname.trim_matches('"').into(),
+            _ => {}
+        }
+    }
+    leases
+}
+
+/// Reads the leases of the file, keeping the ones still valid at the given time
+pub(crate) fn parse(format: Format, content: &str, now: u64) -> Vec<Lease> {
+    let leases = match format {
+        Format::Dnsmasq => parse_dnsmasq(content),
+        Format::Kea => parse_kea(content),
+        Format::Isc => parse_isc(content),
+    };
+    active(leases, now)
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    /// Addresses of each name, with and without the domain
+    addresses: HashMap<String, Vec<IpAddr>>,
+    /// Name of each reverse name, with the domain
+    hosts: HashMap<String, String>,
+    /// Name of each address, with the domain
+    names: HashMap<IpAddr, String>,
+    /// When the first lease expires and the file should be read again
+    expires: Option<u64>,
+}
+
+impl Table {
+    fn new(leases: &[Lease], domain: Option<&str>) -> Self {
+        let domain = domain.map(normalize).filter(|domain| !domain.is_empty());
+        let mut table = Table::default();
+        for lease in leases {
+            let hostname = normalize(&lease.hostname);
+            if !valid_hostname(&hostname) {
+                tracing::debug!("ignoring the invalid hostname {:?}", lease.hostname);
+                continue;
+            }
+            let qualified = match domain.as_deref() {
+                Some(domain) if !hostname.contains('.') => {
+                    let qualified = format!("{hostname}.{domain}");
+                    table
+                        .addresses
+                        .entry(hostname.into_owned())
+                        .or_default()
+                        .push(lease.address);
+                    qualified
+                }
+                _ if !hostname.contains('.') => hostname.into_owned(),
+                // a client could otherwise take over any public domain
+                Some(domain) if matches_suffix(&hostname, domain) => hostname.into_owned(),
+                _ => {
+                    tracing::debug!("ignoring the hostname {hostname:?} outside of the domain");
+                    continue;
+                }
+            };
+            table
+                .addresses
+                .entry(qualified.clone())
+                .or_default()
+                .push(lease.address);
+            let address = lease.address.to_canonical();
+            table
+                .hosts
+                .insert(reverse_name(&address), qualified.clone());
+            table.names.insert(address, qualified);
+            table.expires = match (table.expires, lease.expires) {
+                (Some(current), Some(expires)) => Some(current.min(expires)),
+                (current, expires) => current.or(expires),
+            };
+        }
+        table
+    }
+}
+
+/// Names of the current leases, shared between the watcher and the handler
+#[derive(Debug, Default)]
+pub(crate) struct Leases {
+    table: RwLock<Arc<Table>>,
+}
+
+impl Leases {
+    /// Addresses leased to the host with this normalized name
+    pub fn addresses(&self, name: &str) -> Option<Vec<IpAddr>> {
+        self.table.read().unwrap().addresses.get(name).cloned()
+    }
+
+    /// Name of the host with this normalized reverse name, like `10.1.168.192.in-addr.arpa`
+    pub fn host(&self, reverse: &str) -> Option<String> {
+        self.table.read().unwrap().hosts.get(reverse).cloned()
+    }
+
+    /// Name of the host leasing this address
+    pub fn name(&self, address: &IpAddr) -> Option<String> {
+        self.table.read().unwrap().names.get(address).cloned()
+    }
+
+    pub fn replace(&self, leases: &[Lease], domain: Option<&str>) {
+        *self.table.write().unwrap() = Arc::new(Table::new(leases, domain));
+    }
+
+    fn expires(&self) -> Option<u64> {
+        self.table.read().unwrap().expires
+    }
+
+    async fn load(&self, config: &Config) -> std::io::Result<usize> {
+        let content = tokio::fs::read_to_string(&config.path).await?;
+        let leases = parse(config.format, &content, now());
+        self.replace(&leases, config.domain.as_deref());
+        Ok(leases.len())
+    }
+
+    /// Reads the leases file whenever it changes, keeping the previous leases when it can't be read
+    pub async fn watch(self: Arc<Self>, config: Config) {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+        let mut modified = None;
+        let mut failing = false;
+        loop {
+            interval.tick().await;
+            let current = match tokio::fs::metadata(&config.path).await {
+                Ok(found) => found.modified().ok(),
+                Err(error) => {
+                    if !failing {
+                        tracing::warn!("unable to read the leases file {:?}: {error}", config.path);
+                        failing = true;
+                    }
+                    continue;
+                }
+            };
+            let expired = self.expires().is_some_and(|expires| expires <= now());
+            if current.is_some() && current == modified && !expired {
+                continue;
+            }
+            match self.load(&config).await {
+                Ok(count) => {
+                    tracing::debug!("{count} dhcp leases loaded from {:?}", config.path);
+                    modified = current;
+                    failing = false;
+                }
+                Err(error) if !failing => {
+                    tracing::warn!("unable to read the leases file {:?}: {error}", config.path);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Format, Lease, Leases};
+
+    const NOW: u64 = 1_760_000_000;
+
+    fn lease(address: &str, hostname: &str, expires: Option<u64>) -> Lease {
+        Lease {
+            address: address.parse().unwrap(),
+            hostname: hostname.into(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn should_parse_dnsmasq_leases() {
+        let content = "1760003600 aa:bb:cc:dd:ee:01 192.168.1.10 nas 01:aa:bb:cc:dd:ee:01
+1759990000 aa:bb:cc:dd:ee:02 192.168.1.11 old *
+0 aa:bb:cc:dd:ee:03 192.168.1.12 * *
+0 aa:bb:cc:dd:ee:04 192.168.1.13 printer *
+duid 00:01:00:01:2a:2b:2c:2d:aa:bb:cc:dd:ee:ff
+1760003600 1234 fd00::10 nas 00:01:00:01:aa
+";
+        assert_eq!(
+            parse(Format::Dnsmasq, content, NOW),
+            vec![
+                lease("192.168.1.10", "nas", Some(1_760_003_600)),
+                lease("192.168.1.13", "printer", None),
+                lease("fd00::10", "nas", Some(1_760_003_600)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_kea_leases() {
+        let content = "address,hwaddr,client_id,valid_lifetime,expire,subnet_id,fqdn_fwd,fqdn_rev,hostname,state,user_context
+192.168.1.10,aa:bb:cc:dd:ee:01,,3600,1760003600,1,0,0,nas,0,
+192.168.1.11,aa:bb:cc:dd:ee:02,,3600,1760003600,1,0,0,tv,0,
+192.168.1.11,aa:bb:cc:dd:ee:02,,3600,1760003600,1,0,0,tv,2,
+192.168.1.12,aa:bb:cc:dd:ee:03,,3600,1759990000,1,0,0,old,0,
+192.168.1.10,aa:bb:cc:dd:ee:01,,3600,1760007200,1,0,0,nas.lan.,0,
+";
+        assert_eq!(
+            parse(Format::Kea, content, NOW),
+            vec![lease("192.168.1.10", "nas.lan.", Some(1_760_007_200))]
+        );
+    }
+
+    #[test]
+    fn should_parse_isc_leases() {
+        let content = r#"# The format of this file is documented in the dhcpd.leases(5) manual page.
+lease 192.168.1.10 {
+  starts 3 2025/10/08 09:00:00;
+  ends 4 2025/10/09 09:53:20;
+  binding state active;
+  next binding state free;
+  hardware ethernet aa:bb:cc:dd:ee:01;
+  client-hostname "nas";
+}
+lease 192.168.1.11 {
+  ends never;
+  binding state active;
+  client-hostname "printer";
+}
+lease 192.168.1.11 {
+  ends never;
+  binding state free;
+}
+lease 192.168.1.12 {
+  ends epoch 1760003600;
+  binding state active;
+  client-hostname "tv";
+}
+"#;
+        assert_eq!(
+            parse(Format::Isc, content, NOW),
+            vec![
+                lease("192.168.1.10", "nas", Some(1_760_003_600)),
+                lease("192.168.1.12", "tv", Some(1_760_003_600)),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_serve_names_with_the_domain() {
+        let leases = Leases::default();
+        leases.replace(
+            &[
+                lease("192.168.1.10", "NAS", None),
+                lease("fd00::10", "nas", None),
+                lease("192.168.1.20", "tv.home", None),
+                lease("192.168.1.30", "bad_name", None),
+                lease("192.168.1.40", "printer.lan", None),
+                lease("192.168.1.50", "www.google.com", None),
+            ],
+            Some("lan"),
+        );
+        let both = Some(vec![
+            "192.168.1.10".parse().unwrap(),
+            "fd00::10".parse().unwrap(),
+        ]);
+        assert_eq!(leases.addresses("nas"), both);
+        assert_eq!(leases.addresses("nas.lan"), both);
+        assert_eq!(
+            leases.addresses("printer.lan"),
+            Some(vec!["192.168.1.40".parse().unwrap()])
+        );
+        // the names outside of the domain are never served
+        assert_eq!(leases.addresses("tv.home"), None);
+        assert_eq!(leases.addresses("www.google.com"), None);
+        assert_eq!(leases.addresses("bad_name.lan"), None);
+        assert_eq!(
+            leases.host("10.1.168.192.in-addr.arpa").as_deref(),
+            Some("nas.lan")
+        );
+        assert_eq!(leases.host("20.1.168.192.in-addr.arpa"), None);
+    }
+}
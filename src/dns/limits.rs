@@ -0,0 +1,263 @@
+use super::error::HandleError;
+use super::metrics::Provenance;
+use super::pipeline::{Flow, QueryContext, Stage};
+use crate::common::domain::equals;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use std::fmt::Display;
+
+/// Limits applied to the answers coming from upstream servers,
+/// protecting the cache and the clients from absurd responses.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Maximum number of answers kept, the others are dropped
+    #[serde(default = "Config::default_max_answers")]
+    pub max_answers: usize,
+    /// Maximum number of CNAME records followed from the question name
+    #[serde(default = "Config::default_max_cname_chain")]
+    pub max_cname_chain: usize,
+    /// Maximum size of the response sent to the client, in bytes.
+    /// Over UDP, responses larger than 512 bytes are truncated anyway.
+    #[serde(default = "Config::default_max_response_size")]
+    pub max_response_size: usize,
+    /// Maximum number of questions resolved in a single query, FORMERR being
+    /// answered above, so that a query can't trigger an unbounded number of lookups
+    #[serde(default = "Config::default_max_questions")]
+    pub max_questions: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_answers: Self::default_max_answers(),
+            max_cname_chain: Self::default_max_cname_chain(),
+            max_response_size: Self::default_max_response_size(),
+            max_questions: Self::default_max_questions(),
+        }
+    }
+}
+
+impl Config {
+    fn default_max_answers() -> usize {
+        64
+    }
+
+    fn default_max_cname_chain() -> usize {
+        8
+    }
+
+    fn default_max_response_size() -> usize {
+        donos_parser::buffer::MAX_PACKET_SIZE
+    }
+
+    fn default_max_questions() -> usize {
+        4
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LimitError {
+    CnameChainTooLong(usize),
+    CnameLoop(String),
+    ResponseTooLarge(usize),
+    TooManyQuestions(usize),
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CnameChainTooLong(limit) => {
+                write!(f, "cname chain longer than {limit} records")
+            }
+            Self::CnameLoop(name) => write!(f, "cname loop detected on {name:?}"),
+            Self::ResponseTooLarge(size) => write!(f, "response of {size} bytes too large"),
+            Self::TooManyQuestions(count) => write!(f, "query with {count} questions"),
+        }
+    }
+}
+
+impl Config {
+    /// Drops the answers over the limit and checks the CNAME chain starting from the question name
+    pub fn check_answers(
+        &self,
+        qname: &str,
+        mut answers: Vec<Record>,
+    ) -> Result<Vec<Record>, LimitError> {
+        if answers.len() > self.max_answers {
+            tracing::warn!(
+                "upstream returned {} answers, keeping the first {}",
+                answers.len(),
+                self.max_answers
+            );
+            answers.truncate(self.max_answers);
+        }
+
+        let mut visited: Vec<&str> = vec![qname];
+        let mut current = qname;
+        while let Some(target) = answers.iter().find_map(|record| match record {
+            Record::CNAME { domain, host, .. } if equals(domain, current) => Some(host.as_str()),
+            _ => None,
+        }) {
+            if visited.iter().any(|name| equals(name, target)) {
+                return Err(LimitError::CnameLoop(target.to_string()));
+            }
+            if visited.len() > self.max_cname_chain {
+                return Err(LimitError::CnameChainTooLong(self.max_cname_chain));
+            }
+            visited.push(target);
+            current = target;
+        }
+
+        Ok(answers)
+    }
+
+    pub fn check_response_size(&self, size: usize) -> Result<(), LimitError> {
+        if size > self.max_response_size {
+            Err(LimitError::ResponseTooLarge(size))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn check_questions(&self, count: usize) -> Result<(), LimitError> {
+        if count > self.max_questions {
+            Err(LimitError::TooManyQuestions(count))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Drops the upstream answers over the limits, or answers SERVFAIL when they can't be fixed
+#[async_trait::async_trait]
+impl Stage for Config {
+    fn name(&self) -> &'static str {
+        "limits"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some((ref mut answers, Provenance::Upstream)) = ctx.answers else {
+            return Ok(Flow::Continue);
+        };
+        match self.check_answers(ctx.question.name.as_str(), std::mem::take(answers)) {
+            Ok(found) => {
+                *answers = found;
+                Ok(Flow::Continue)
+            }
+            Err(error) => {
+                tracing::warn!("invalid upstream response: {error}");
+                Ok(ctx.respond_with(ResponseCode::ServerFailure))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, LimitError};
+    use donos_parser::packet::record::Record;
+    use std::net::Ipv4Addr;
+
+    fn cname(domain: &str, host: &str) -> Record {
+        Record::CNAME {
+            domain: domain.into(),
+            host: host.into(),
+            ttl: 60,
+        }
+    }
+
+    fn a(domain: &str) -> Record {
+        Record::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 60,
+        }
+    }
+
+    #[test]
+    fn should_truncate_answers() {
+        let config = Config {
+            max_answers: 2,
+            ..Default::default()
+        };
+        let result = config
+            .check_answers(
+                "perdu.com",
+                vec![a("perdu.com"), a("perdu.com"), a("perdu.com")],
+            )
+            .unwrap();
+        assert_eq!(result.len(), 2);
+    }
+
+    #[test]
+    fn should_accept_short_cname_chain() {
+        let config = Config {
+            max_cname_chain: 2,
+            ..Default::default()
+        };
+        let answers = vec![cname("a.com", "b.com"), cname("b.com", "c.com"), a("c.com")];
+        assert_eq!(config.check_answers("a.com", answers).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn should_reject_long_cname_chain() {
+        let config = Config {
+            max_cname_chain: 2,
+            ..Default::default()
+        };
+        let answers = vec![
+            cname("a.com", "b.com"),
+            cname("b.com", "c.com"),
+            cname("c.com", "d.com"),
+            a("d.com"),
+        ];
+        assert_eq!(
+            config.check_answers("a.com", answers).unwrap_err(),
+            LimitError::CnameChainTooLong(2)
+        );
+    }
+
+    #[test]
+    fn should_reject_cname_loop() {
+        let answers = vec![cname("a.com", "b.com"), cname("b.com", "a.com")];
+        assert_eq!(
+            Config::default()
+                .check_answers("a.com", answers)
+                .unwrap_err(),
+            LimitError::CnameLoop("a.com".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn should_fail_server_on_invalid_upstream_answers() {
+        use crate::dns::metrics::Provenance;
+        use crate::dns::pipeline::tests::{client, request};
+        use crate::dns::pipeline::{Flow, QueryContext, Stage};
+        use donos_parser::packet::header::ResponseCode;
+        use donos_parser::packet::QueryType;
+
+        let packet = request("a.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((vec![a("a.com"), a("a.com")], Provenance::Upstream));
+        let config = Config {
+            max_answers: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.run(&mut ctx).await.unwrap(),
+            Flow::Continue
+        ));
+        assert_eq!(ctx.answers.as_ref().unwrap().0.len(), 1);
+
+        ctx.answers = Some((
+            vec![cname("a.com", "b.com"), cname("b.com", "a.com")],
+            Provenance::Upstream,
+        ));
+        match Config::default().run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => {
+                assert_eq!(res.header.response_code, ResponseCode::ServerFailure)
+            }
+            Flow::Continue => panic!("should respond"),
+        }
+    }
+}
@@ -0,0 +1,531 @@
+//! Advertisement of donos on the local network with DNS-SD over multicast DNS
+//! (RFC 6762 and 6763), so that other instances or companion apps can find it.
+//!
+//! Only the records of donos are answered, on IPv4. The advertisement starts once
+//! the listeners are ready and stops on handover, the new process announcing itself.
+use donos_parser::buffer::writer::WriterError;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::header::Header;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::time::Duration;
+
+const MDNS_ADDRESS: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE: &str = "_donos._tcp.local";
+const SERVICES_ENUMERATION: &str = "_services._dns-sd._udp.local";
+/// TTL of the records related to the host, as recommended by RFC 6762 section 10
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+/// Marks the unique records, replacing the ones cached by the other hosts
+const CACHE_FLUSH: u16 = 0x8000;
+/// Asks for a unicast response, on the question class
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Name of the instance, the hostname by default
+    #[serde(default)]
+    pub instance: Option<String>,
+    /// Address advertised, the dns listener address or the one of the default interface
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    /// Port advertised, the dns port by default
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+impl Config {
+    /// Builds the advertisement of the dns server listening on the given address.
+    pub fn build(&self, listener: SocketAddr) -> std::io::Result<Advertisement> {
+        let hostname = hostname()?;
+        let address = match (self.address, listener.ip()) {
+            (Some(found), _) => found,
+            (None, IpAddr::V4(found)) if !found.is_unspecified() => found,
+            _ => default_address()?,
+        };
+        let instance = self.instance.clone().unwrap_or_else(|| hostname.clone());
+        Ok(Advertisement {
+            // dots would be read as label separators
+            instance: format!("{}.{SERVICE}", instance.replace('.', "-")),
+            host: format!("{hostname}.local"),
+            address,
+            port: self.port.unwrap_or(listener.port()),
+            dns_port: listener.port(),
+        })
+    }
+}
+
+fn hostname() -> std::io::Result<String> {
+    let mut buffer = [0u8; 256];
+    // SAFETY: the buffer is large enough for any hostname and is null terminated below
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len() - 1) };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let size = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    let name = String::from_utf8_lossy(&buffer[..size]).to_lowercase();
+    // only the first label is kept, the host gets a name in the .local domain
+    Ok(name.split('.').next().unwrap_or_default().to_string())
+}
+
+/// Address of the interface used to reach the multicast group, nothing is sent
+fn default_address() -> std::io::Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_ADDRESS, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(found) => Ok(found),
+        IpAddr::V6(_) => Err(std::io::ErrorKind::AddrNotAvailable.into()),
+    }
+}
+
+/// Binds the mDNS port, shared with the other responders of the host like avahi
+fn bind_shared() -> std::io::Result<tokio::net::UdpSocket> {
+    fn check(result: libc::c_int) -> std::io::Result<libc::c_int> {
+        if result < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    // SAFETY: the file descriptor is owned by the socket right after being created
+    let socket = unsafe {
+        let fd = check(libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        UdpSocket::from_raw_fd(fd)
+    };
+    let fd = std::os::fd::AsRawFd::as_raw_fd(&socket);
+    let enabled: libc::c_int = 1;
+    for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+        // SAFETY: the option value is a valid c_int living during the call
+        check(unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                (&enabled as *const libc::c_int).cast(),
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        })?;
+    }
+    let address = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: MDNS_PORT.to_be(),
+        sin_addr: libc::in_addr { s_addr: 0 },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: the address is a valid sockaddr_in living during the call
+    check(unsafe {
+        libc::bind(
+            fd,
+            (&address as *const libc::sockaddr_in).cast(),
+            std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    })?;
+    socket.join_multicast_v4(&MDNS_ADDRESS, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::UdpSocket::from_std(socket)
+}
+
+struct MdnsQuestion {
+    name: String,
+    qtype: u16,
+    unicast: bool,
+}
+
+struct MdnsQuery {
+    id: u16,
+    questions: Vec<MdnsQuestion>,
+}
+
+/// Reads the questions of a query, the known answers being ignored.
+///
+/// The class is read by hand since the unicast response bit isn't a valid class.
+fn read_query(bytes: &[u8]) -> Option<MdnsQuery> {
+    let mut buffer = BytePacketBuffer::new(bytes);
+    let header = Header::read(&mut buffer).ok()?;
+    if header.response || header.opcode != 0 {
+        return None;
+    }
+    let count = buffer.read_u16().ok()?;
+    buffer.step(6).ok()?;
+    let questions = (0..count)
+        .map(|_| {
+            let name = buffer.read_qname().ok()?;
+            let qtype = buffer.read_u16().ok()?;
+            let qclass = buffer.read_u16().ok()?;
+            Some(MdnsQuestion {
+                name,
+                qtype,
+                unicast: qclass & UNICAST_RESPONSE != 0,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(MdnsQuery {
+        id: header.id,
+        questions,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Answer {
+    Enumeration,
+    Pointer,
+    Service,
+    Text,
+    Address,
+}
+
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    /// Full name of the instance, like `myhost._donos._tcp.local`
+    instance: String,
+    host: String,
+    address: Ipv4Addr,
+    port: u16,
+    dns_port: u16,
+}
+
+impl Advertisement {
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    fn answers_to(&self, name: &str, qtype: u16) -> Vec<Answer> {
+        let any = qtype == TYPE_ANY;
+        let mut result = Vec::new();
+        if name == SERVICES_ENUMERATION && (any || qtype == TYPE_PTR) {
+            result.push(Answer::Enumeration);
+        }
+        if name == SERVICE && (any || qtype == TYPE_PTR) {
+            result.push(Answer::Pointer);
+        }
+        if name == self.instance {
+            if any || qtype == TYPE_SRV {
+                result.push(Answer::Service);
+            }
+            if any || qtype == TYPE_TXT {
+                result.push(Answer::Text);
+            }
+        }
+        if name == self.host && (any || qtype == TYPE_A) {
+            result.push(Answer::Address);
+        }
+        result
+    }
+
+    fn write_header(
+        buffer: &mut BytePacketBuffer,
+        name: &str,
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+    ) -> Result<usize, WriterError> {
+        buffer.write_qname(name)?;
+        buffer.write_u16(rtype)?;
+        buffer.write_u16(class)?;
+        buffer.write_u32(ttl)?;
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+        Ok(pos)
+    }
+
+    fn write_answer(
+        &self,
+        buffer: &mut BytePacketBuffer,
+        answer: Answer,
+    ) -> Result<(), WriterError> {
+        let pos = match answer {
+            Answer::Enumeration => {
+                let pos = Self::write_header(buffer, SERVICES_ENUMERATION, TYPE_PTR, 1, OTHER_TTL)?;
+                buffer.write_qname(SERVICE)?;
+                pos
+            }
+            Answer::Pointer => {
+                let pos = Self::write_header(buffer, SERVICE, TYPE_PTR, 1, OTHER_TTL)?;
+                buffer.write_qname(&self.instance)?;
+                pos
+            }
+            Answer::Service => {
+                let pos = Self::write_header(
+                    buffer,
+                    &self.instance,
+                    TYPE_SRV,
+                    CACHE_FLUSH | 1,
+                    HOST_TTL,
+                )?;
+                buffer.write_u16(0)?; // priority
+                buffer.write_u16(0)?; // weight
+                buffer.write_u16(self.port)?;
+                buffer.write_qname(&self.host)?;
+                pos
+            }
+            Answer::Text => {
+                let pos = Self::write_header(
+                    buffer,
+                    &self.instance,
+                    TYPE_TXT,
+                    CACHE_FLUSH | 1,
+                    OTHER_TTL,
+                )?;
+                for item in [
+                    format!("version={}", env!("CARGO_PKG_VERSION")),
+                    format!("dns={}", self.dns_port),
+                ] {
+                    buffer.write_u8(item.len() as u8)?;
+                    for b in item.as_bytes() {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+                pos
+            }
+            Answer::Address => {
+                let pos =
+                    Self::write_header(buffer, &self.host, TYPE_A, CACHE_FLUSH | 1, HOST_TTL)?;
+                for octet in self.address.octets() {
+                    buffer.write_u8(octet)?;
+                }
+                pos
+            }
+        };
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)
+    }
+
+    /// Writes a response with the given answers, the questions being repeated
+    /// for legacy unicast queries only.
+    fn write_response(
+        &self,
+        id: u16,
+        questions: &[&MdnsQuestion],
+        answers: &[Answer],
+    ) -> Result<BytePacketBuffer, WriterError> {
+        let mut buffer = BytePacketBuffer::default();
+        Header::response(id)
+            .with_authoritative_answer(true)
+            .write(&mut buffer)?;
+        buffer.write_u16(questions.len() as u16)?;
+        buffer.write_u16(answers.len() as u16)?;
+        buffer.write_u16(0)?;
+        buffer.write_u16(0)?;
+        for question in questions {
+            buffer.write_qname(&question.name)?;
+            buffer.write_u16(question.qtype)?;
+            buffer.write_u16(1)?;
+        }
+        for answer in answers {
+            self.write_answer(&mut buffer, *answer)?;
+        }
+        Ok(buffer)
+    }
+
+    /// Unsolicited response sent when starting
+    fn announcement(&self) -> Result<BytePacketBuffer, WriterError> {
+        self.write_response(
+            0,
+            &[],
+            &[
+                Answer::Pointer,
+                Answer::Service,
+                Answer::Text,
+                Answer::Address,
+            ],
+        )
+    }
+
+    /// Builds the response to a query and where to send it, if any question concerns donos
+    fn respond(&self, bytes: &[u8], origin: SocketAddr) -> Option<(BytePacketBuffer, SocketAddr)> {
+        let query = read_query(bytes)?;
+        let mut answers: Vec<Answer> = Vec::new();
+        let mut unicast = false;
+        for question in query.questions.iter() {
+            let found = self.answers_to(&question.name, question.qtype);
+            if !found.is_empty() {
+                unicast |= question.unicast;
+            }
+            for answer in found {
+                if !answers.contains(&answer) {
+                    answers.push(answer);
+                }
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        // queries not coming from the mdns port are legacy unicast ones (RFC 6762 section 6.7)
+        if origin.port() != MDNS_PORT {
+            let questions: Vec<_> = query.questions.iter().collect();
+            let buffer = self.write_response(query.id, &questions, &answers).ok()?;
+            return Some((buffer, origin));
+        }
+        let buffer = self.write_response(0, &[], &answers).ok()?;
+        let target = if unicast {
+            origin
+        } else {
+            SocketAddr::V4(SocketAddrV4::new(MDNS_ADDRESS, MDNS_PORT))
+        };
+        Some((buffer, target))
+    }
+
+    /// Announces donos and answers the queries until the shutdown future completes
+    pub async fn run_until<F: Future<Output = ()>>(&self, shutdown: F) -> std::io::Result<()> {
+        let socket = bind_shared()?;
+        let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDRESS, MDNS_PORT));
+        let announcement = self.announcement()?;
+        // announced twice, one second apart (RFC 6762 section 8.3)
+        for idx in 0..2 {
+            if idx > 0 {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            socket
+                .send_to(&announcement.buf[..announcement.pos], group)
+                .await?;
+        }
+        tracing::info!("advertising {} with mdns", self.instance);
+
+        tokio::pin!(shutdown);
+        let mut buffer = vec![0u8; 9000];
+        loop {
+            let (size, origin) = tokio::select! {
+                biased;
+                _ = &mut shutdown => break,
+                received = socket.recv_from(&mut buffer) => received?,
+            };
+            if let Some((response, target)) = self.respond(&buffer[..size], origin) {
+                if let Err(error) = socket.send_to(&response.buf[..response.pos], target).await {
+                    tracing::debug!("unable to send mdns response to {target}: {error}");
+                }
+            }
+        }
+        tracing::info!("stopped advertising {} with mdns", self.instance);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Advertisement, Answer, TYPE_A, TYPE_ANY, TYPE_PTR, TYPE_SRV};
+    use donos_parser::buffer::BytePacketBuffer;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::DnsPacket;
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn advertisement() -> Advertisement {
+        Advertisement {
+            instance: "box._donos._tcp.local".into(),
+            host: "box.local".into(),
+            address: Ipv4Addr::new(192, 168, 1, 2),
+            port: 53,
+            dns_port: 53,
+        }
+    }
+
+    fn query(name: &str, qtype: u16, qclass: u16) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::default();
+        buffer.write_u16(42).unwrap();
+        buffer.write_u16(0).unwrap();
+        buffer.write_u16(1).unwrap();
+        buffer.write_u16(0).unwrap();
+        buffer.write_u16(0).unwrap();
+        buffer.write_u16(0).unwrap();
+        buffer.write_qname(name).unwrap();
+        buffer.write_u16(qtype).unwrap();
+        buffer.write_u16(qclass).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    #[test]
+    fn should_only_answer_own_names() {
+        let ad = advertisement();
+        assert_eq!(
+            ad.answers_to("_donos._tcp.local", TYPE_PTR),
+            vec![Answer::Pointer]
+        );
+        assert_eq!(
+            ad.answers_to("box._donos._tcp.local", TYPE_ANY),
+            vec![Answer::Service, Answer::Text]
+        );
+        assert_eq!(ad.answers_to("box.local", TYPE_A), vec![Answer::Address]);
+        assert!(ad.answers_to("box.local", TYPE_SRV).is_empty());
+        assert!(ad.answers_to("_http._tcp.local", TYPE_PTR).is_empty());
+    }
+
+    #[test]
+    fn should_multicast_response_to_browsing() {
+        let origin: SocketAddr = "192.168.1.3:5353".parse().unwrap();
+        let (response, target) = advertisement()
+            .respond(&query("_donos._tcp.local", TYPE_PTR, 1), origin)
+            .unwrap();
+        assert_eq!(target, "224.0.0.251:5353".parse().unwrap());
+
+        let packet = DnsPacket::try_from(BytePacketBuffer::new(response.buf)).unwrap();
+        assert!(packet.header.response);
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.id, 0);
+        assert!(packet.questions.is_empty());
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(packet.answers[0].domain(), "_donos._tcp.local");
+    }
+
+    #[test]
+    fn should_answer_unicast_when_asked() {
+        let origin: SocketAddr = "192.168.1.3:5353".parse().unwrap();
+        let (_, target) = advertisement()
+            .respond(&query("box.local", TYPE_A, 0x8001), origin)
+            .unwrap();
+        assert_eq!(target, origin);
+    }
+
+    #[test]
+    fn should_answer_legacy_unicast_with_question() {
+        let origin: SocketAddr = "192.168.1.3:41000".parse().unwrap();
+        let (response, target) = advertisement()
+            .respond(&query("box.local", TYPE_A, 1), origin)
+            .unwrap();
+        assert_eq!(target, origin);
+
+        let packet = DnsPacket::try_from(BytePacketBuffer::new(response.buf)).unwrap();
+        assert_eq!(packet.header.id, 42);
+        assert_eq!(packet.questions.len(), 1);
+        assert_eq!(
+            packet.answers,
+            vec![Record::A {
+                domain: "box.local".into(),
+                addr: Ipv4Addr::new(192, 168, 1, 2),
+                ttl: 120,
+            }]
+        );
+    }
+
+    #[test]
+    fn should_ignore_other_queries() {
+        let origin: SocketAddr = "192.168.1.3:5353".parse().unwrap();
+        assert!(advertisement()
+            .respond(&query("printer.local", TYPE_A, 1), origin)
+            .is_none());
+    }
+
+    #[test]
+    fn should_announce_all_records() {
+        let announcement = advertisement().announcement().unwrap();
+        let packet = DnsPacket::try_from(BytePacketBuffer::new(announcement.buf)).unwrap();
+        assert_eq!(packet.answers.len(), 4);
+        assert!(packet.answers.iter().any(|record| matches!(
+            record,
+            Record::TXT { data, .. } if data.contains(&b"dns=53".to_vec())
+        )));
+    }
+}
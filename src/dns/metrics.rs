@@ -0,0 +1,196 @@
+use donos_server::prelude::Transport;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Where the answer of a response comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provenance {
+    /// Forwarded to an upstream server
+    Upstream,
+    /// Found in the cache
+    Cache,
+    /// Built by donos (never forwarded domains, safe search, errors)
+    Synthesized,
+    /// Built by donos for a domain blocked by the policy or the blocklists
+    Blocked,
+    /// Answered from the local records, the reverse names or the dhcp leases
+    Local,
+    /// Expired records of the cache, served when the upstream servers fail
+    Stale,
+}
+
+impl Provenance {
+    const COUNT: usize = 6;
+
+    pub const ALL: [Provenance; Self::COUNT] = [
+        Self::Upstream,
+        Self::Cache,
+        Self::Synthesized,
+        Self::Blocked,
+        Self::Local,
+        Self::Stale,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Upstream => "upstream",
+            Self::Cache => "cache",
+            Self::Synthesized => "synthesized",
+            Self::Blocked => "blocked",
+            Self::Local => "local",
+            Self::Stale => "stale",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Queries handled by a listener for a given transport
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerStats {
+    pub queries: u64,
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl ListenerStats {
+    pub fn average_latency(&self) -> Duration {
+        if self.queries == 0 {
+            Duration::ZERO
+        } else {
+            self.total_latency / self.queries as u32
+        }
+    }
+}
+
+impl Display for ListenerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "queries={}, average_latency={:?}, max_latency={:?}",
+            self.queries,
+            self.average_latency(),
+            self.max_latency
+        )
+    }
+}
+
+/// Counters of the responses sent by the server
+#[derive(Debug, Default)]
+pub struct Metrics {
+    responses: [AtomicU64; Provenance::COUNT],
+    internal: AtomicU64,
+    aaaa_filtered: AtomicU64,
+    listeners: Mutex<BTreeMap<(SocketAddr, Transport), ListenerStats>>,
+}
+
+impl Metrics {
+    pub fn record_query(&self, listener: SocketAddr, transport: Transport, latency: Duration) {
+        let mut listeners = self.listeners.lock().unwrap();
+        let stats = listeners.entry((listener, transport)).or_default();
+        stats.queries += 1;
+        stats.total_latency += latency;
+        stats.max_latency = stats.max_latency.max(latency);
+    }
+
+    pub fn listeners(&self) -> Vec<((SocketAddr, Transport), ListenerStats)> {
+        self.listeners
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, stats)| (*key, *stats))
+            .collect()
+    }
+
+    pub fn record(&self, provenance: Provenance) {
+        self.responses[provenance.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn responses(&self, provenance: Provenance) -> u64 {
+        self.responses[provenance.index()].load(Ordering::Relaxed)
+    }
+
+    /// Counts a query sent by donos itself, kept apart from the client responses
+    pub fn record_internal(&self) {
+        self.internal.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn internal(&self) -> u64 {
+        self.internal.load(Ordering::Relaxed)
+    }
+
+    /// Counts a response where the AAAA answers have been filtered
+    pub fn record_aaaa_filtered(&self) {
+        self.aaaa_filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn aaaa_filtered(&self) -> u64 {
+        self.aaaa_filtered.load(Ordering::Relaxed)
+    }
+}
+
+impl Display for Metrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, provenance) in Provenance::ALL.iter().enumerate() {
+            if idx > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{provenance}={}", self.responses(*provenance))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, Provenance};
+    use donos_server::prelude::Transport;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn should_aggregate_by_listener() {
+        let first: SocketAddr = "127.0.0.1:53".parse().unwrap();
+        let second: SocketAddr = "192.168.1.1:53".parse().unwrap();
+        let metrics = Metrics::default();
+        metrics.record_query(first, Transport::Udp, Duration::from_millis(10));
+        metrics.record_query(first, Transport::Udp, Duration::from_millis(30));
+        metrics.record_query(second, Transport::Udp, Duration::from_millis(5));
+        let listeners = metrics.listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].0, (first, Transport::Udp));
+        assert_eq!(listeners[0].1.queries, 2);
+        assert_eq!(listeners[0].1.average_latency(), Duration::from_millis(20));
+        assert_eq!(listeners[0].1.max_latency, Duration::from_millis(30));
+        assert_eq!(listeners[1].1.queries, 1);
+    }
+
+    #[test]
+    fn should_count_by_provenance() {
+        let metrics = Metrics::default();
+        metrics.record(Provenance::Cache);
+        metrics.record(Provenance::Cache);
+        metrics.record(Provenance::Upstream);
+        metrics.record(Provenance::Local);
+        metrics.record(Provenance::Stale);
+        assert_eq!(metrics.responses(Provenance::Cache), 2);
+        assert_eq!(metrics.responses(Provenance::Upstream), 1);
+        assert_eq!(metrics.responses(Provenance::Synthesized), 0);
+        assert_eq!(
+            metrics.to_string(),
+            "upstream=1, cache=2, synthesized=0, blocked=0, local=1, stale=1"
+        );
+    }
+}
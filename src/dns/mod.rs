@@ -1,49 +1,597 @@
+use crate::repository::cache::{CacheService, MemoryCacheService};
+use crate::repository::lookup::{LookupService, Mode};
+use crate::repository::recursive::RecursiveLookupService;
+use crate::repository::routing::RoutingLookupService;
 use clap::Args;
-use donos_server::UdpServer;
+use donos_server::socket::BindOptions;
+use donos_server::{TcpServer, UdpServer};
+use futures::FutureExt;
+use std::fmt::Display;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) mod capture;
+pub(crate) mod clients;
 pub(crate) mod config;
+pub(crate) mod dnstap;
 pub(crate) mod error;
 pub(crate) mod handler;
+pub(crate) mod leases;
+pub(crate) mod limits;
+pub(crate) mod mdns;
+pub(crate) mod metrics;
+pub(crate) mod pipeline;
+pub(crate) mod policy;
+pub(crate) mod privileges;
+pub(crate) mod rebinding;
+pub(crate) mod refresh;
+pub(crate) mod reload;
+pub(crate) mod resolved;
+pub(crate) mod upgrade;
+
+fn join<T: Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Logs the error with a hint on how to solve it and stops the process.
+fn exit_with<E: Display>(message: &str, error: E) -> ! {
+    tracing::error!("{message}: {error}");
+    std::process::exit(1)
+}
+
+fn bind_hint(error: &std::io::Error, address: &SocketAddr) -> String {
+    match error.kind() {
+        ErrorKind::AddrInUse if resolved::conflicts(address) && resolved::detect() => {
+            resolved::conflict_hint(address)
+        }
+        ErrorKind::AddrInUse => format!(
+            "address {address} is already in use, another DNS server (systemd-resolved, dnsmasq...) might be running, stop it or change the port in the configuration"
+        ),
+        ErrorKind::PermissionDenied => format!(
+            "permission denied when binding {address}, ports below 1024 require root or the CAP_NET_BIND_SERVICE capability"
+        ),
+        ErrorKind::AddrNotAvailable => format!(
+            "address {address} is not available on this machine, check the configured host"
+        ),
+        _ => format!("unable to bind {address}"),
+    }
+}
+
+async fn save_snapshot(cache: &MemoryCacheService, path: &std::path::Path) {
+    match cache.save_snapshot(path).await {
+        Ok(count) => tracing::debug!("saved {count} cache entries to the snapshot"),
+        Err(error) => tracing::warn!(
+            "unable to save the cache snapshot {}: {error}",
+            path.display()
+        ),
+    }
+}
+
+/// Completes on SIGTERM, sent by systemd or docker to stop the server, or on ctrl-c
+async fn wait_for_termination() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut signals) => {
+                signals.recv().await;
+            }
+            Err(error) => {
+                tracing::warn!("unable to listen to SIGTERM: {error}");
+                futures::future::pending::<()>().await;
+            }
+        }
+    };
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            tracing::warn!("unable to listen to ctrl-c: {error}");
+            futures::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate => tracing::info!("received SIGTERM, stopping"),
+        _ = interrupt => tracing::info!("interrupted, stopping"),
+    }
+}
+
+/// Binds the udp socket of an address, or the fallback one when the address is already taken
+fn bind_udp(
+    address: SocketAddr,
+    fallback: Option<SocketAddr>,
+    options: BindOptions,
+    handler: &handler::DnsHandler,
+) -> UdpServer<handler::DnsHandler> {
+    match (
+        UdpServer::bind_with(address, options, handler.clone()),
+        fallback,
+    ) {
+        (Ok(found), _) => found,
+        (Err(error), Some(fallback)) if error.kind() == ErrorKind::AddrInUse => {
+            tracing::warn!(
+                "{}, falling back on {fallback}",
+                bind_hint(&error, &address)
+            );
+            match UdpServer::bind_with(fallback, options, handler.clone()) {
+                Ok(found) => {
+                    if resolved::detect() {
+                        tracing::info!("{}", resolved::forward_hint(&fallback));
+                    }
+                    found
+                }
+                Err(error) => exit_with(&bind_hint(&error, &fallback), error),
+            }
+        }
+        (Err(error), _) => exit_with(&bind_hint(&error, &address), error),
+    }
+}
+
+/// Applies to the handler the parts of the configuration that can change while running
+pub(crate) fn configure(
+    handler: handler::DnsHandler,
+    dns: config::Config,
+    records: pipeline::local::Config,
+    policy: policy::Config,
+    lookup: &crate::repository::lookup::Config,
+) -> handler::DnsHandler {
+    handler
+        .with_never_forward(dns.never_forward)
+        .with_forwarded(
+            lookup
+                .forward
+                .iter()
+                .map(|zone| zone.domain.clone())
+                .collect(),
+        )
+        .with_aaaa_filter(dns.aaaa_filter)
+        .with_reverse(dns.reverse)
+        .with_local_records(records)
+        .with_stages(dns.pipeline)
+        .with_ttl(dns.ttl)
+        .with_limits(dns.limits)
+        .with_rebinding(dns.rebinding.build())
+        .with_blocklist_failure(dns.on_blocklist_error)
+        .with_blocking(dns.blocking)
+        .with_policy(policy.build())
+}
 
 /// Starts the DNS server, the core of the machine
 #[derive(Args, Debug)]
 pub struct Command;
 
 impl Command {
-    pub async fn run(&self, config: crate::config::Config) {
+    pub async fn run(&self, mut config: crate::config::Config, config_path: PathBuf) {
         tracing::info!("preparing dns server");
-        let database = config
-            .database
-            .build()
-            .await
-            .expect("unable to connect database");
-        crate::service::database::migrate(&database)
-            .await
-            .expect("unable to run database migration");
-
-        let cache_service = config
-            .cache
-            .build()
-            .await
-            .expect("unable to build cache service");
-        let lookup_service = config
-            .lookup
-            .build()
-            .await
-            .expect("unable to build lookup service");
-        let blocklist_service = config.blocklists.build(database);
-
-        let handler = handler::DnsHandler::new(
-            Arc::new(blocklist_service),
-            Arc::new(cache_service),
-            Arc::new(lookup_service),
+        let database_url = config.database.url.clone();
+        let database = match config.database.build().await {
+            Ok(found) => found,
+            Err(error) => exit_with(&format!("unable to open database {database_url:?}"), error),
+        };
+        if let Err(error) = crate::service::database::migrate(&database).await {
+            exit_with("unable to run database migrations", error);
+        }
+
+        let cache_size = config.cache.size;
+        let snapshot = config.cache.snapshot.clone();
+        let cache_service = match config.cache.build().await {
+            Ok(found) => Arc::new(found),
+            Err(error) => exit_with("unable to build cache service", error),
+        };
+        if snapshot.enabled {
+            match cache_service.load_snapshot(&snapshot.path).await {
+                Ok(count) => tracing::info!("loaded {count} cache entries from the snapshot"),
+                Err(error) => tracing::warn!(
+                    "unable to load the cache snapshot {}: {error}",
+                    snapshot.path.display()
+                ),
+            }
+            let cache = cache_service.clone();
+            let snapshot = snapshot.clone();
+            tokio::spawn(async move {
+                let mut interval =
+                    tokio::time::interval(Duration::from_secs(snapshot.interval.max(1)));
+                // the first tick completes right away and the snapshot has just been loaded
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    save_snapshot(&cache, &snapshot.path).await;
+                }
+            });
+        }
+        if upgrade::is_successor() && config.lookup.address.port() != 0 {
+            // the previous process still holds the lookup socket while draining
+            tracing::info!("taking over from a previous process, using a random lookup port");
+            config.lookup.address.set_port(0);
+        }
+        let lookup_address = config.lookup.address;
+        let validation = std::mem::take(&mut config.lookup.validation);
+        if validation.enabled && !config.lookup.dnssec {
+            tracing::warn!("dnssec validation enabled without requesting the signatures upstream");
+        }
+        let recursive = config.lookup.mode == Mode::Recursive;
+        let base: Option<Arc<dyn LookupService + Send + Sync>> = recursive.then(|| {
+            Arc::new(RecursiveLookupService::new(&config.lookup))
+                as Arc<dyn LookupService + Send + Sync>
+        });
+        let lookup_service = match config.lookup.build().await {
+            Ok(found) => Arc::new(found),
+            Err(error) => exit_with(&bind_hint(&error, &lookup_address), error),
+        };
+        let router = Arc::new(RoutingLookupService::new(
+            base.unwrap_or_else(|| lookup_service.clone()),
+        ));
+        router.set_zones(&config.lookup).await;
+        let resolver: Arc<dyn LookupService + Send + Sync> = if validation.enabled {
+            match validation.build(router.clone()) {
+                Ok(found) => {
+                    tracing::info!("validating the upstream answers with dnssec");
+                    Arc::new(found)
+                }
+                Err(error) => exit_with("unable to build the dnssec validation", error),
+            }
+        } else {
+            router.clone()
+        };
+        if recursive {
+            tracing::info!("resolving the queries from the root servers");
+        } else if lookup_service.probe_config().enabled {
+            let ranking = lookup_service.probe().await;
+            tracing::info!("upstream ranking: {}", join(&ranking));
+            let prober = lookup_service.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(prober.probe_config().interval());
+                // the first tick completes right away and the probe has just been done
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    let ranking = prober.probe().await;
+                    tracing::debug!("upstream ranking: {}", join(&ranking));
+                }
+            });
+        }
+        let (upstreams, upstream_protocol) = if recursive {
+            ("root servers".to_string(), "udp".to_string())
+        } else {
+            (
+                lookup_service.upstreams().join(", "),
+                lookup_service.protocols().join("+"),
+            )
+        };
+        let client_service =
+            crate::repository::client::DatabaseClientService::new(database.clone());
+        let client_names = match client_service.names().await {
+            Ok(found) => found,
+            Err(error) => exit_with("unable to load client names", error),
+        };
+        let leases = Arc::new(leases::Leases::default());
+        let clients = Arc::new(clients::ClientNames::new(leases.clone()));
+        clients.update(&config.clients, client_names, &config.dns.reverse.hosts);
+        let query_log = config.query_log.build(database.clone());
+        let query_log_service =
+            crate::repository::query::DatabaseQueryLogService::new(database.clone());
+        let blocklist_service =
+            Arc::new(config.blocklists.build(database).with_groups(config.groups));
+        let blocked_domains = match blocklist_service.load().await {
+            Ok(found) => found,
+            Err(error) => exit_with("unable to load blocked domains", error),
+        };
+        tokio::spawn(blocklist_service.clone().watch_changes());
+        if config.dns.refresh.enabled {
+            tokio::spawn(refresh::run(
+                config.dns.refresh.clone(),
+                blocklist_service.clone(),
+                blocked_domains == 0,
+            ));
+        }
+
+        let capture = match config.dns.capture.build().await {
+            Ok(found) => Some(Arc::new(found)),
+            Err(error) => {
+                tracing::warn!(
+                    "unable to open capture file {:?}, packets won't be captured: {error}",
+                    config.dns.capture.path
+                );
+                None
+            }
+        };
+        if let Some(toggled) = capture.clone() {
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut signals = match signal(SignalKind::user_defined1()) {
+                    Ok(found) => found,
+                    Err(error) => {
+                        tracing::warn!(
+                            "unable to listen to SIGUSR1, capture can't be toggled: {error}"
+                        );
+                        return;
+                    }
+                };
+                while signals.recv().await.is_some() {
+                    if toggled.toggle() {
+                        tracing::info!("packet capture enabled");
+                    } else {
+                        tracing::info!("packet capture disabled");
+                    }
+                }
+            });
+        }
+
+        let metrics = Arc::new(metrics::Metrics::default());
+        let addresses = config.dns.addresses();
+        let fallback_address = config.dns.fallback_address();
+        let bind_options = config.dns.bind_options();
+        let worker_options = config.dns.worker_options();
+        let sockets = config.dns.sockets.max(1);
+        if sockets > 1 && !cfg!(target_os = "linux") {
+            tracing::warn!("the queries are only spread between the sockets on linux");
+        }
+        let blocking_switch = Arc::new(pipeline::blocklist::BlockingSwitch::default());
+        let tcp = config.dns.tcp;
+        let max_tcp_connections = config.dns.max_tcp_connections;
+        let user = config.dns.user.take();
+        let group = config.dns.group.take();
+        let dnstap = config.dns.dnstap.build();
+        let mdns = std::mem::take(&mut config.dns.mdns);
+        let leases_config = std::mem::take(&mut config.dns.leases);
+        if leases_config.enabled {
+            tracing::info!("serving the dhcp leases of {:?}", leases_config.path);
+            tokio::spawn(leases.clone().watch(leases_config));
+        }
+        let handler =
+            handler::DnsHandler::new(blocklist_service.clone(), cache_service.clone(), resolver)
+                .with_blocking_switch(blocking_switch.clone())
+                .with_leases(leases)
+                .with_clients(clients.clone())
+                .with_metrics(metrics.clone());
+        let handler = configure(
+            handler,
+            config.dns,
+            config.records,
+            config.policy,
+            &config.lookup,
+        );
+        let handler = match capture {
+            Some(capture) => handler.with_capture(capture),
+            None => handler,
+        };
+        let handler = match dnstap {
+            Some(dnstap) => handler.with_dnstap(dnstap),
+            None => handler,
+        };
+        let handler = match query_log {
+            Some(query_log) => handler.with_query_log(query_log),
+            None => handler,
+        };
+        if let Some(queue) = cache_service.prefetch_queue() {
+            let prefetcher = handler.clone();
+            tokio::spawn(async move { prefetcher.prefetch(queue).await });
+        }
+        let mut api_listener = None;
+        if config.api.enabled {
+            let state = crate::api::ApiState {
+                blocklist: blocklist_service.clone(),
+                blocking: blocking_switch.clone(),
+                cache: cache_service.clone(),
+                queries: query_log_service,
+                metrics: metrics.clone(),
+                health: Arc::new(crate::healthcheck::Probe::new(
+                    handler.clone(),
+                    &config.healthcheck,
+                )),
+            };
+            let address = config.api.address;
+            // the previous process keeps its listener while draining, it's handed over
+            let listener = match upgrade::inherited_api_listener() {
+                Some(found) => Ok(found),
+                None => std::net::TcpListener::bind(address),
+            };
+            match listener {
+                Ok(listener) => {
+                    api_listener = Some(listener.as_raw_fd());
+                    tokio::spawn(async move {
+                        if let Err(error) = crate::api::serve(listener, state).await {
+                            tracing::error!("unable to serve the api on {address}: {error}");
+                        }
+                    });
+                }
+                Err(error) => tracing::error!("unable to serve the api on {address}: {error}"),
+            }
+        }
+        tokio::spawn(
+            reload::Reloader {
+                path: config_path,
+                handler: handler.clone(),
+                blocklist: blocklist_service,
+                lookup: lookup_service.clone(),
+                router,
+                clients,
+                client_service,
+            }
+            .run(),
+        );
+
+        let inherited = upgrade::inherited_sockets();
+        let servers = if inherited.is_empty() {
+            addresses
+                .iter()
+                .flat_map(|address| {
+                    let first = bind_udp(*address, fallback_address, bind_options, &handler);
+                    // the other sockets share the address the first one ended up with
+                    let local = first.local_addr().unwrap_or(*address);
+                    let others = (1..sockets).map(|_| {
+                        match UdpServer::bind_with(local, bind_options, handler.clone()) {
+                            Ok(found) => found,
+                            Err(error) => exit_with(&bind_hint(&error, &local), error),
+                        }
+                    });
+                    std::iter::once(first).chain(others).collect::<Vec<_>>()
+                })
+                .map(|server| server.with_workers(worker_options))
+                .collect::<Vec<_>>()
+        } else {
+            inherited
+                .into_iter()
+                .map(
+                    |socket| match UdpServer::from_std(socket, handler.clone()) {
+                        Ok(found) => found.with_workers(worker_options),
+                        Err(error) => exit_with("unable to use the inherited socket", error),
+                    },
+                )
+                .collect()
+        };
+        let mut listeners = servers
+            .iter()
+            .filter_map(|server| server.local_addr().ok())
+            .collect::<Vec<_>>();
+        listeners.dedup();
+        let servers = servers.into_iter().map(Arc::new).collect::<Vec<_>>();
+        let inherited = upgrade::inherited_tcp_listeners();
+        let tcp_servers = if !inherited.is_empty() {
+            inherited
+                .into_iter()
+                .map(|found| match TcpServer::from_std(found, handler.clone()) {
+                    Ok(found) => found.with_max_connections(max_tcp_connections),
+                    Err(error) => exit_with("unable to use the inherited tcp listener", error),
+                })
+                .collect()
+        } else if tcp {
+            listeners
+                .iter()
+                .map(|listener| {
+                    match TcpServer::bind_with(*listener, bind_options, handler.clone()) {
+                        Ok(found) => found.with_max_connections(max_tcp_connections),
+                        Err(error) => exit_with(&bind_hint(&error, listener), error),
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let advertisement = match listeners.first() {
+            // advertising the first address is enough for the clients to find the server
+            Some(listener) if mdns.enabled => match mdns.build(*listener) {
+                Ok(found) => Some(found),
+                Err(error) => {
+                    tracing::warn!("unable to prepare the mdns advertisement: {error}");
+                    None
+                }
+            },
+            _ => None,
+        };
+        // everything needing root is done: the sockets are bound and the files opened
+        if let Err(error) = privileges::drop_privileges(user.as_deref(), group.as_deref()) {
+            exit_with("unable to drop the privileges", error);
+        }
+        if user.is_some() || group.is_some() {
+            tracing::info!(
+                "running as user {} and group {}",
+                user.as_deref().unwrap_or("unchanged"),
+                group.as_deref().unwrap_or("of the user")
+            );
+        }
+        let protocol = if !tcp_servers.is_empty() {
+            "udp+tcp"
+        } else {
+            "udp"
+        };
+
+        tracing::info!(
+            listener = %join(&listeners),
+            protocol,
+            upstreams = %upstreams,
+            upstream_protocol = %upstream_protocol,
+            blocked_domains,
+            cache_backend = "memory",
+            cache_size,
+            database = %database_url,
+            "dns server ready"
         );
 
-        let address = config.dns.address();
-        UdpServer::new(address, handler)
-            .run()
-            .await
-            .expect("unable to run udp server")
+        let cache = cache_service.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(METRICS_INTERVAL);
+            loop {
+                interval.tick().await;
+                tracing::info!(
+                    "responses by provenance: {metrics}, internal queries: {}, aaaa filtered: {}",
+                    metrics.internal(),
+                    metrics.aaaa_filtered()
+                );
+                if let Some(stats) = cache.stats() {
+                    tracing::info!("cache: {stats}");
+                }
+                let ranking = lookup_service.ranking();
+                if !ranking.is_empty() {
+                    tracing::info!("upstream ranking: {}", join(&ranking));
+                }
+                let mut health: Vec<_> = lookup_service.health().into_iter().collect();
+                health.sort_by(|(left, _), (right, _)| left.cmp(right));
+                for (server, health) in health {
+                    tracing::info!("upstream {server}: {health}");
+                }
+                for ((listener, transport), stats) in metrics.listeners() {
+                    tracing::info!("queries on {transport}://{listener}: {stats}");
+                }
+            }
+        });
+
+        upgrade::notify_ready();
+
+        let handover = upgrade::wait_for_handover(
+            servers.iter().map(|server| server.as_raw_fd()).collect(),
+            tcp_servers
+                .iter()
+                .map(|server| server.as_raw_fd())
+                .collect(),
+            api_listener,
+        );
+        // the servers stop the same way when handing the sockets over or when terminated,
+        // finishing the queries being handled
+        let handover = async {
+            tokio::select! {
+                _ = handover => {}
+                _ = wait_for_termination() => {}
+            }
+        }
+        .shared();
+        // each socket gets its own task, for the receive loops to run on different cores
+        let udp = futures::future::try_join_all(servers.iter().map(|server| {
+            let server = server.clone();
+            let handover = handover.clone();
+            tokio::spawn(async move { server.run_until(handover).await })
+                .map(|joined| joined.unwrap_or_else(|error| Err(std::io::Error::other(error))))
+        }));
+        let tcp = futures::future::try_join_all(
+            tcp_servers
+                .iter()
+                .map(|server| server.run_until(handover.clone())),
+        );
+        let mdns = async {
+            if let Some(ref advertisement) = advertisement {
+                // donos keeps answering the queries without the advertisement
+                if let Err(error) = advertisement.run_until(handover.clone()).await {
+                    tracing::warn!("unable to advertise {}: {error}", advertisement.instance());
+                }
+            }
+            Ok(())
+        };
+        let stopped = tokio::try_join!(udp, tcp, mdns);
+        if snapshot.enabled {
+            save_snapshot(&cache_service, &snapshot.path).await;
+        }
+        if let Err(error) = stopped {
+            exit_with("dns server stopped", error);
+        }
+        tracing::info!("dns server stopped");
     }
 }
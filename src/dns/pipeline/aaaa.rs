@@ -0,0 +1,230 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::domain::{matches_suffix, normalize};
+use crate::common::source::QuerySource;
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Metrics;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::QueryType;
+use ipnet::IpNet;
+use std::sync::Arc;
+
+/// How the AAAA queries of the filtered clients are answered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Remove the AAAA records from the answers, keeping the rest like the CNAME records
+    #[default]
+    Strip,
+    /// Answer an empty NOERROR to the AAAA queries
+    Empty,
+}
+
+/// Filtering of the IPv6 addresses for the clients on a network with a broken IPv6,
+/// or for the domains unreachable over IPv6, so that they fall back to IPv4.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Networks of the filtered clients, like `192.168.20.0/24`
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+    /// Filtered domains, with their subdomains, whatever the client
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Filters all the queries, when IPv6 is broken for the whole network
+    #[serde(default)]
+    pub all: bool,
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+pub(crate) struct AaaaFilterStage {
+    clients: Vec<IpNet>,
+    domains: Vec<String>,
+    all: bool,
+    mode: Mode,
+    metrics: Arc<Metrics>,
+}
+
+impl AaaaFilterStage {
+    pub fn new(config: &Config, metrics: Arc<Metrics>) -> Self {
+        Self {
+            clients: config.clients.clone(),
+            domains: config
+                .domains
+                .iter()
+                .map(|domain| normalize(domain).into_owned())
+                .collect(),
+            all: config.all,
+            mode: config.mode,
+            metrics,
+        }
+    }
+
+    fn is_filtered(&self, source: &QuerySource, domain: &str) -> bool {
+        match source {
+            QuerySource::Client(origin) => {
+                let ip = origin.ip().to_canonical();
+                self.all
+                    || self.clients.iter().any(|net| net.contains(&ip))
+                    || self
+                        .domains
+                        .iter()
+                        .any(|suffix| matches_suffix(domain, suffix))
+            }
+            QuerySource::Internal(_) => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for AaaaFilterStage {
+    fn name(&self) -> &'static str {
+        "aaaa-filter"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        if !self.is_filtered(&ctx.source, &ctx.domain) {
+            return Ok(Flow::Continue);
+        }
+        if self.mode == Mode::Empty && ctx.question.qtype == QueryType::AAAA {
+            self.metrics.record_aaaa_filtered();
+            return Ok(ctx.respond_with(ResponseCode::NoError));
+        }
+        if let Some((ref mut answers, _)) = ctx.answers {
+            let count = answers.len();
+            answers.retain(|record| !matches!(record, Record::AAAA { .. }));
+            if answers.len() < count {
+                self.metrics.record_aaaa_filtered();
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AaaaFilterStage, Config, Mode};
+    use crate::common::source::QuerySource;
+    use crate::dns::metrics::{Metrics, Provenance};
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+
+    fn answers() -> Vec<Record> {
+        vec![
+            Record::CNAME {
+                domain: "perdu.com".into(),
+                host: "www.perdu.com".into(),
+                ttl: 100,
+            },
+            Record::AAAA {
+                domain: "www.perdu.com".into(),
+                addr: Ipv6Addr::LOCALHOST,
+                ttl: 100,
+            },
+        ]
+    }
+
+    fn stage(mode: Mode, metrics: Arc<Metrics>) -> AaaaFilterStage {
+        let config = Config {
+            clients: vec!["127.1.0.0/16".parse().unwrap()],
+            mode,
+            ..Default::default()
+        };
+        AaaaFilterStage::new(&config, metrics)
+    }
+
+    #[tokio::test]
+    async fn should_strip_aaaa_answers_of_filtered_clients() {
+        let metrics = Arc::new(Metrics::default());
+        let stage = stage(Mode::Strip, metrics.clone());
+
+        let packet = request("perdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((answers(), Provenance::Upstream));
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        assert_eq!(ctx.answers.unwrap().0.len(), 1);
+        assert_eq!(metrics.aaaa_filtered(), 1);
+
+        let other = QuerySource::Client("192.168.1.2:42".parse().unwrap());
+        let mut ctx = QueryContext::new(other, &packet).unwrap();
+        ctx.answers = Some((answers(), Provenance::Upstream));
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(ctx.answers.unwrap().0.len(), 2);
+
+        // nothing to strip
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((
+            vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))],
+            Provenance::Upstream,
+        ));
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(metrics.aaaa_filtered(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_answer_empty_aaaa_queries() {
+        let metrics = Arc::new(Metrics::default());
+        let stage = stage(Mode::Empty, metrics.clone());
+
+        let packet = request("perdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, provenance) => {
+                assert_eq!(res.header.response_code, ResponseCode::NoError);
+                assert!(res.answers.is_empty());
+                assert_eq!(provenance, Provenance::Synthesized);
+            }
+            Flow::Continue => panic!("should respond"),
+        }
+        assert_eq!(metrics.aaaa_filtered(), 1);
+
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+    }
+
+    #[tokio::test]
+    async fn should_filter_domains_for_all_clients() {
+        let metrics = Arc::new(Metrics::default());
+        let config = Config {
+            domains: vec!["Perdu.com".into()],
+            mode: Mode::Empty,
+            ..Default::default()
+        };
+        let stage = AaaaFilterStage::new(&config, metrics.clone());
+        let other = QuerySource::Client("192.168.1.2:42".parse().unwrap());
+
+        for domain in ["perdu.com", "www.PERDU.com"] {
+            let packet = request(domain, QueryType::AAAA);
+            let mut ctx = QueryContext::new(other, &packet).unwrap();
+            assert!(matches!(
+                stage.run(&mut ctx).await.unwrap(),
+                Flow::Respond(..)
+            ));
+        }
+        assert_eq!(metrics.aaaa_filtered(), 2);
+
+        let packet = request("notperdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(other, &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+
+        // or all of them
+        let stage = AaaaFilterStage::new(
+            &Config {
+                all: true,
+                ..Default::default()
+            },
+            metrics.clone(),
+        );
+        let mut ctx = QueryContext::new(other, &packet).unwrap();
+        ctx.answers = Some((answers(), Provenance::Upstream));
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(ctx.answers.unwrap().0.len(), 1);
+    }
+}
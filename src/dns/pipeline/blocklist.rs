@@ -0,0 +1,522 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::domain::normalize;
+use crate::common::source::QuerySource;
+use crate::dns::config::{BlockMode, BlockingConfig, BlocklistFailure};
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use crate::dns::policy::{Action, Policy};
+use crate::repository::blocklist::BlocklistService;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Runtime switch pausing the blocking for a while, blocking again once the delay is over
+#[derive(Debug, Default)]
+pub(crate) struct BlockingSwitch {
+    disabled_until: Mutex<Option<Instant>>,
+}
+
+impl BlockingSwitch {
+    pub fn disable(&self, duration: Duration) {
+        tracing::info!("blocking disabled for {} seconds", duration.as_secs());
+        *self.disabled_until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    pub fn enable(&self) {
+        if self.disabled_until.lock().unwrap().take().is_some() {
+            tracing::info!("blocking enabled");
+        }
+    }
+
+    /// Time left before the blocking applies again, `None` when it's applied
+    pub fn remaining(&self) -> Option<Duration> {
+        let mut disabled_until = self.disabled_until.lock().unwrap();
+        let remaining = (*disabled_until)?.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            tracing::info!("blocking enabled again");
+            *disabled_until = None;
+        }
+        remaining
+    }
+}
+
+/// SOA sent with the negative answers for a blocked domain, as if it was its own zone,
+/// telling the clients to cache the absence of answer for the given TTL (RFC 2308)
+fn blocked_soa(domain: String, ttl: u32) -> Record {
+    Record::SOA {
+        domain,
+        mname: "blocked.donos".into(),
+        rname: "hostmaster.blocked.donos".into(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: ttl,
+        ttl,
+    }
+}
+
+/// Answers the queries for the domains blocked by the policy or the blocklists,
+/// following the blocking mode.
+///
+/// The queries made by donos itself are never blocked.
+pub(crate) struct BlocklistStage {
+    blocklist: Arc<dyn BlocklistService + Send + Sync>,
+    policy: Policy,
+    failure: BlocklistFailure,
+    blocking: BlockingConfig,
+    /// TTL of the sinkhole addresses
+    ttl: u32,
+    switch: Arc<BlockingSwitch>,
+}
+
+impl BlocklistStage {
+    pub fn new(
+        blocklist: Arc<dyn BlocklistService + Send + Sync>,
+        policy: Policy,
+        failure: BlocklistFailure,
+    ) -> Self {
+        Self {
+            blocklist,
+            policy,
+            failure,
+            blocking: BlockingConfig::default(),
+            ttl: 0,
+            switch: Arc::default(),
+        }
+    }
+
+    pub fn with_switch(mut self, switch: Arc<BlockingSwitch>) -> Self {
+        self.switch = switch;
+        self
+    }
+
+    pub fn with_blocking(mut self, blocking: BlockingConfig, ttl: u32) -> Self {
+        self.blocking = blocking;
+        self.ttl = ttl;
+        self
+    }
+
+    /// Response for a blocked domain, consistent whatever the type of the query.
+    ///
+    /// Only the A and AAAA queries get the sinkhole addresses, when the mode has some, the
+    /// other types (HTTPS, SVCB, TXT...) getting an empty answer. Except for the refused ones,
+    /// the responses come with a SOA so that the clients cache them for the blocked TTL.
+    fn blocked_response(&self, ctx: &QueryContext<'_>) -> Flow {
+        let domain = ctx.question.name.clone();
+        let (code, answer) = match (self.blocking.mode, ctx.question.qtype) {
+            (BlockMode::Refused, _) => return self.blocked_with(ctx, ResponseCode::Refused),
+            (BlockMode::Nxdomain, _) => (ResponseCode::NameError, None),
+            (_, QueryType::A) => (
+                ResponseCode::NoError,
+                self.blocking.ipv4().map(|addr| Record::A {
+                    domain: domain.clone(),
+                    addr,
+                    ttl: self.ttl,
+                }),
+            ),
+            (_, QueryType::AAAA) => (
+                ResponseCode::NoError,
+                self.blocking.ipv6().map(|addr| Record::AAAA {
+                    domain: domain.clone(),
+                    addr,
+                    ttl: self.ttl,
+                }),
+            ),
+            _ => (ResponseCode::NoError, None),
+        };
+        let mut packet = DnsPacket::response_from(ctx.request).with_response_code(code);
+        match answer {
+            Some(record) => packet.answers.push(record),
+            None => packet.authorities.push(blocked_soa(domain, self.ttl)),
+        }
+        Flow::Respond(packet, Provenance::Blocked)
+    }
+
+    fn blocked_with(&self, ctx: &QueryContext<'_>, code: ResponseCode) -> Flow {
+        Flow::Respond(
+            DnsPacket::response_from(ctx.request).with_response_code(code),
+            Provenance::Blocked,
+        )
+    }
+
+    async fn is_blocked(&self, origin: &SocketAddr, domain: &str) -> Result<bool, HandleError> {
+        if self.policy.is_allowed(domain) {
+            tracing::debug!("domain allowed by policy");
+            return Ok(false);
+        }
+        if self.policy.default_action() == Action::Block {
+            tracing::debug!("domain blocked by default policy");
+            return Ok(true);
+        }
+        self.blocklist
+            .is_blocked(origin, domain)
+            .await
+            .map_err(HandleError::Blocklist)
+    }
+
+    fn failed(&self, ctx: &QueryContext<'_>, error: HandleError) -> Flow {
+        if self.failure == BlocklistFailure::Open {
+            tracing::warn!("unable to check the blocklist, resolving anyway: {error}");
+            Flow::Continue
+        } else {
+            tracing::warn!("unable to check the blocklist, failing: {error}");
+            ctx.respond_with(ResponseCode::ServerFailure)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for BlocklistStage {
+    fn name(&self) -> &'static str {
+        "blocklist"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        // donos shouldn't be prevented from resolving what it needs
+        let QuerySource::Client(ref origin) = ctx.source else {
+            return Ok(Flow::Continue);
+        };
+        if self.switch.remaining().is_some() {
+            tracing::debug!("blocking disabled for now");
+            return Ok(Flow::Continue);
+        }
+        match self.is_blocked(origin, &ctx.domain).await {
+            Ok(true) => Ok(self.blocked_response(ctx)),
+            Ok(false) => Ok(Flow::Continue),
+            Err(error) => Ok(self.failed(ctx, error)),
+        }
+    }
+}
+
+/// Blocks the answers going through a blocked domain, like the trackers hidden
+/// behind a CNAME of a first party subdomain.
+///
+/// The domain asked by the client has already been checked by the blocklist stage,
+/// so only the targets of the CNAME records are, unless the policy allows the domain.
+pub(crate) struct CnameInspectionStage {
+    inner: BlocklistStage,
+}
+
+impl CnameInspectionStage {
+    pub fn new(inner: BlocklistStage) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for CnameInspectionStage {
+    fn name(&self) -> &'static str {
+        "cname-inspection"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let QuerySource::Client(ref origin) = ctx.source else {
+            return Ok(Flow::Continue);
+        };
+        let Some((ref answers, Provenance::Upstream | Provenance::Cache | Provenance::Stale)) =
+            ctx.answers
+        else {
+            return Ok(Flow::Continue);
+        };
+        if self.inner.switch.remaining().is_some() || self.inner.policy.is_allowed(&ctx.domain) {
+            return Ok(Flow::Continue);
+        }
+        let targets = answers.iter().filter_map(|record| match record {
+            Record::CNAME { host, .. } => Some(normalize(host)),
+            _ => None,
+        });
+        for target in targets {
+            if self.inner.policy.is_allowed(&target) {
+                continue;
+            }
+            match self.inner.blocklist.is_blocked(origin, &target).await {
+                Ok(true) => {
+                    tracing::debug!("cname target {target} is blocked");
+                    return Ok(self.inner.blocked_response(ctx));
+                }
+                Ok(false) => {}
+                Err(error) => return Ok(self.inner.failed(ctx, HandleError::Blocklist(error))),
+            }
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockingSwitch, BlocklistStage, CnameInspectionStage};
+    use crate::common::source::{InternalReason, QuerySource};
+    use crate::dns::config::{BlockMode, BlockingConfig, BlocklistFailure};
+    use crate::dns::metrics::Provenance;
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use crate::dns::policy::{Action, Config as PolicyConfig, Policy};
+    use crate::repository::blocklist::MemoryBlocklistService;
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn response_code(
+        stage: &BlocklistStage,
+        source: QuerySource,
+        qname: &str,
+    ) -> Option<ResponseCode> {
+        let packet = request(qname, QueryType::A);
+        let mut ctx = QueryContext::new(source, &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => Some(res.header.response_code),
+            Flow::Continue => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_block_listed_domains_for_clients() {
+        let stage = BlocklistStage::new(
+            Arc::new(MemoryBlocklistService::default().with_domain("facebook.com")),
+            Policy::default(),
+            BlocklistFailure::Open,
+        );
+        assert_eq!(
+            response_code(&stage, client(), "Facebook.com").await,
+            Some(ResponseCode::NameError)
+        );
+        assert_eq!(response_code(&stage, client(), "perdu.com").await, None);
+        let internal = QuerySource::Internal(InternalReason::HealthCheck);
+        assert_eq!(response_code(&stage, internal, "facebook.com").await, None);
+    }
+
+    #[tokio::test]
+    async fn should_follow_the_policy() {
+        let policy = PolicyConfig {
+            default_action: Action::Block,
+            allow: vec!["perdu.com".into()],
+            templates: Vec::new(),
+        }
+        .build();
+        let stage = BlocklistStage::new(
+            Arc::new(MemoryBlocklistService::default()),
+            policy,
+            BlocklistFailure::Open,
+        );
+        assert_eq!(response_code(&stage, client(), "www.perdu.com").await, None);
+        assert_eq!(
+            response_code(&stage, client(), "example.com").await,
+            Some(ResponseCode::NameError)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_answer_following_block_mode() {
+        let stage = |mode| {
+            BlocklistStage::new(
+                Arc::new(MemoryBlocklistService::default().with_domain("facebook.com")),
+                Policy::default(),
+                BlocklistFailure::Open,
+            )
+            .with_blocking(
+                BlockingConfig {
+                    mode,
+                    ipv4: Some(Ipv4Addr::new(192, 168, 1, 2)),
+                    ipv6: None,
+                },
+                10,
+            )
+        };
+        let answers = |stage: BlocklistStage, qtype| async move {
+            let packet = request("facebook.com", qtype);
+            let mut ctx = QueryContext::new(client(), &packet).unwrap();
+            match stage.run(&mut ctx).await.unwrap() {
+                Flow::Respond(res, _) => (res.header.response_code, res.answers),
+                Flow::Continue => panic!("should respond"),
+            }
+        };
+
+        let (code, _) = answers(stage(BlockMode::Refused), QueryType::A).await;
+        assert_eq!(code, ResponseCode::Refused);
+
+        let (code, records) = answers(stage(BlockMode::ZeroIp), QueryType::AAAA).await;
+        assert_eq!(code, ResponseCode::NoError);
+        assert_eq!(
+            records,
+            vec![Record::AAAA {
+                domain: "facebook.com".into(),
+                addr: Ipv6Addr::UNSPECIFIED,
+                ttl: 10,
+            }]
+        );
+
+        let (code, records) = answers(stage(BlockMode::CustomIp), QueryType::A).await;
+        assert_eq!(code, ResponseCode::NoError);
+        assert_eq!(
+            records,
+            vec![Record::A {
+                domain: "facebook.com".into(),
+                addr: Ipv4Addr::new(192, 168, 1, 2),
+                ttl: 10,
+            }]
+        );
+
+        // no ipv6 sinkhole, nor anything to answer for the other types
+        for qtype in [QueryType::AAAA, QueryType::MX] {
+            let (code, records) = answers(stage(BlockMode::CustomIp), qtype).await;
+            assert_eq!(code, ResponseCode::NoError);
+            assert!(records.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn should_send_soa_with_negative_blocked_answers() {
+        let stage = |mode| {
+            BlocklistStage::new(
+                Arc::new(MemoryBlocklistService::default().with_domain("facebook.com")),
+                Policy::default(),
+                BlocklistFailure::Open,
+            )
+            .with_blocking(
+                BlockingConfig {
+                    mode,
+                    ipv4: None,
+                    ipv6: None,
+                },
+                42,
+            )
+        };
+        let respond = |stage: BlocklistStage, qtype| async move {
+            let packet = request("facebook.com", qtype);
+            let mut ctx = QueryContext::new(client(), &packet).unwrap();
+            match stage.run(&mut ctx).await.unwrap() {
+                Flow::Respond(res, _) => res,
+                Flow::Continue => panic!("should respond"),
+            }
+        };
+        let negative_ttl = |records: &[Record]| match records {
+            [Record::SOA {
+                domain,
+                minimum,
+                ttl,
+                ..
+            }] if domain == "facebook.com" => Some((*minimum, *ttl)),
+            _ => None,
+        };
+
+        for qtype in [QueryType::A, QueryType::HTTPS, QueryType::TXT] {
+            let res = respond(stage(BlockMode::Nxdomain), qtype).await;
+            assert_eq!(res.header.response_code, ResponseCode::NameError);
+            assert_eq!(negative_ttl(&res.authorities), Some((42, 42)));
+        }
+
+        // the https and svcb queries get no answer, for the clients to use the sinkhole
+        for qtype in [QueryType::SVCB, QueryType::HTTPS, QueryType::TXT] {
+            let res = respond(stage(BlockMode::ZeroIp), qtype).await;
+            assert_eq!(res.header.response_code, ResponseCode::NoError);
+            assert!(res.answers.is_empty());
+            assert_eq!(negative_ttl(&res.authorities), Some((42, 42)));
+        }
+        let res = respond(stage(BlockMode::ZeroIp), QueryType::A).await;
+        assert_eq!(res.answers.len(), 1);
+        assert!(res.authorities.is_empty());
+
+        let res = respond(stage(BlockMode::Refused), QueryType::A).await;
+        assert_eq!(res.header.response_code, ResponseCode::Refused);
+        assert!(res.authorities.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_not_block_while_disabled() {
+        let switch = Arc::new(BlockingSwitch::default());
+        let stage = BlocklistStage::new(
+            Arc::new(MemoryBlocklistService::default().with_domain("facebook.com")),
+            Policy::default(),
+            BlocklistFailure::Open,
+        )
+        .with_switch(switch.clone());
+
+        switch.disable(Duration::from_secs(60));
+        assert!(switch.remaining().is_some());
+        assert_eq!(response_code(&stage, client(), "facebook.com").await, None);
+
+        switch.enable();
+        assert_eq!(
+            response_code(&stage, client(), "facebook.com").await,
+            Some(ResponseCode::NameError)
+        );
+
+        // enabled again once the delay is over
+        switch.disable(Duration::ZERO);
+        assert!(switch.remaining().is_none());
+        assert_eq!(
+            response_code(&stage, client(), "facebook.com").await,
+            Some(ResponseCode::NameError)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_block_cloaked_cnames() {
+        let policy = PolicyConfig {
+            default_action: Action::Allow,
+            allow: vec!["allowed.perdu.com".into()],
+            templates: Vec::new(),
+        }
+        .build();
+        let stage = CnameInspectionStage::new(BlocklistStage::new(
+            Arc::new(MemoryBlocklistService::default().with_domain("tracker.com")),
+            policy,
+            BlocklistFailure::Open,
+        ));
+        let inspect = |qname: &'static str, target: &'static str, provenance| {
+            let stage = &stage;
+            async move {
+                let packet = request(qname, QueryType::A);
+                let mut ctx = QueryContext::new(client(), &packet).unwrap();
+                ctx.answers = Some((
+                    vec![
+                        Record::CNAME {
+                            domain: qname.into(),
+                            host: target.into(),
+                            ttl: 10,
+                        },
+                        record(target, Ipv4Addr::new(1, 2, 3, 4)),
+                    ],
+                    provenance,
+                ));
+                match stage.run(&mut ctx).await.unwrap() {
+                    Flow::Respond(res, provenance) => {
+                        assert!(res.answers.is_empty());
+                        Some((res.header.response_code, provenance))
+                    }
+                    Flow::Continue => None,
+                }
+            }
+        };
+
+        let blocked = Some((ResponseCode::NameError, Provenance::Blocked));
+        assert_eq!(
+            inspect("metrics.perdu.com", "Tracker.com", Provenance::Upstream).await,
+            blocked
+        );
+        assert_eq!(
+            inspect("metrics.perdu.com", "tracker.com", Provenance::Cache).await,
+            blocked
+        );
+        assert_eq!(
+            inspect("www.perdu.com", "cdn.perdu.com", Provenance::Upstream).await,
+            None
+        );
+        // the local records and the domains allowed by the policy are trusted
+        assert_eq!(
+            inspect("metrics.perdu.com", "tracker.com", Provenance::Synthesized).await,
+            None
+        );
+        assert_eq!(
+            inspect("allowed.perdu.com", "tracker.com", Provenance::Upstream).await,
+            None
+        );
+    }
+}
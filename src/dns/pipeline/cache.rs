@@ -0,0 +1,229 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::source::{InternalReason, QuerySource};
+use crate::dns::config::TtlConfig;
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use crate::repository::cache::CacheService;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use std::sync::Arc;
+
+/// Looks for the answers in the cache, the next stages being able to filter them
+///
+/// The prefetch queries skip it, the entry they refresh being still in cache.
+pub(crate) struct CacheStage {
+    cache: Arc<dyn CacheService + Send + Sync>,
+}
+
+impl CacheStage {
+    pub fn new(cache: Arc<dyn CacheService + Send + Sync>) -> Self {
+        Self { cache }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for CacheStage {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        if ctx.answers.is_some() || ctx.source == QuerySource::Internal(InternalReason::Prefetch) {
+            return Ok(Flow::Continue);
+        }
+        if let Some(found) = self
+            .cache
+            .request(&ctx.domain, ctx.question.qtype)
+            .await
+            .map_err(HandleError::Cache)?
+        {
+            ctx.response_code = found.code;
+            ctx.authentic = found.authentic;
+            ctx.authorities.extend(found.soa);
+            ctx.answers = Some((found.records, Provenance::Cache));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// Keeps the answers of the upstream servers in the cache, an empty answer
+/// being kept as a negative one with its response code and its SOA.
+///
+/// The refused queries and the NXDOMAIN coming with records aren't kept.
+pub(crate) struct PersistStage {
+    cache: Arc<dyn CacheService + Send + Sync>,
+    ttl: TtlConfig,
+}
+
+impl PersistStage {
+    pub fn new(cache: Arc<dyn CacheService + Send + Sync>, ttl: TtlConfig) -> Self {
+        Self { cache, ttl }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for PersistStage {
+    fn name(&self) -> &'static str {
+        "persist"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some((ref answers, Provenance::Upstream)) = ctx.answers else {
+            return Ok(Flow::Continue);
+        };
+        let persisted = match ctx.response_code {
+            ResponseCode::NoError | ResponseCode::NameError if answers.is_empty() => {
+                let soa = ctx
+                    .authorities
+                    .iter()
+                    .find(|record| matches!(record, Record::SOA { .. }));
+                let ttl = self.ttl.negative_with_soa(soa);
+                self.cache
+                    .persist_negative(
+                        &ctx.domain,
+                        ctx.question.qtype,
+                        ctx.response_code,
+                        soa.cloned(),
+                        ttl,
+                    )
+                    .await
+            }
+            ResponseCode::NoError if ctx.authentic => {
+                self.cache
+                    .persist_authentic(&ctx.domain, ctx.question.qtype, answers.clone())
+                    .await
+            }
+            ResponseCode::NoError => {
+                self.cache
+                    .persist(&ctx.domain, ctx.question.qtype, answers.clone())
+                    .await
+            }
+            _ => return Ok(Flow::Continue),
+        };
+        if let Err(error) = persisted {
+            tracing::error!("couldn't persist in cache: {error:?}");
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStage, PersistStage};
+    use crate::dns::config::TtlConfig;
+    use crate::dns::metrics::Provenance;
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use crate::repository::cache::{CacheService, MemoryCacheService, MockCacheService};
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn should_answer_from_cache() {
+        let stage = CacheStage::new(Arc::new(MockCacheService::default().with_records(
+            "perdu.com",
+            QueryType::A,
+            vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))],
+        )));
+
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        let (answers, provenance) = ctx.answers.unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(provenance, Provenance::Cache);
+
+        let packet = request("perdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        assert!(ctx.answers.is_none());
+    }
+
+    #[tokio::test]
+    async fn should_only_persist_upstream_answers() {
+        let cache = Arc::new(MemoryCacheService::new(10));
+        let stage = PersistStage::new(cache.clone(), TtlConfig::default());
+
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((
+            vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))],
+            Provenance::Synthesized,
+        ));
+        stage.run(&mut ctx).await.unwrap();
+        assert!(cache
+            .request("perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .is_none());
+
+        ctx.answers = Some((
+            vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))],
+            Provenance::Upstream,
+        ));
+        stage.run(&mut ctx).await.unwrap();
+        let found = cache.request("perdu.com", QueryType::A).await.unwrap();
+        assert_eq!(found.map(|found| found.records.len()), Some(1));
+    }
+
+    #[tokio::test]
+    async fn should_keep_answers_authentic() {
+        let cache = Arc::new(MemoryCacheService::new(10));
+        let persist = PersistStage::new(cache.clone(), TtlConfig::default());
+
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.authentic = true;
+        ctx.answers = Some((
+            vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))],
+            Provenance::Upstream,
+        ));
+        persist.run(&mut ctx).await.unwrap();
+
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        CacheStage::new(cache).run(&mut ctx).await.unwrap();
+        assert!(ctx.authentic);
+        assert_eq!(
+            ctx.answers.map(|(_, provenance)| provenance),
+            Some(Provenance::Cache)
+        );
+    }
+
+    #[tokio::test]
+    async fn should_answer_cached_nxdomain() {
+        let cache = Arc::new(MemoryCacheService::new(10));
+        let packet = request("nope.perdu.com", QueryType::A);
+
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.response_code = ResponseCode::NameError;
+        ctx.authorities.push(Record::SOA {
+            domain: "perdu.com".into(),
+            mname: "ns.perdu.com".into(),
+            rname: "admin.perdu.com".into(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        });
+        ctx.answers = Some((Vec::new(), Provenance::Upstream));
+        PersistStage::new(cache.clone(), TtlConfig::default())
+            .run(&mut ctx)
+            .await
+            .unwrap();
+
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        CacheStage::new(cache).run(&mut ctx).await.unwrap();
+        assert_eq!(ctx.response_code, ResponseCode::NameError);
+        assert_eq!(ctx.answers, Some((Vec::new(), Provenance::Cache)));
+        // the SOA comes along, with the ttl left in cache
+        assert!(matches!(
+            ctx.authorities.as_slice(),
+            [Record::SOA { domain, ttl, .. }] if domain == "perdu.com" && *ttl <= 300
+        ));
+    }
+}
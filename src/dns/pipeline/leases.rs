@@ -0,0 +1,72 @@
+use super::{Flow, QueryContext, Stage};
+use crate::dns::error::HandleError;
+use crate::dns::leases::Leases;
+use crate::dns::metrics::Provenance;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Answers the names of the DHCP leases and their reverse lookups
+pub(crate) struct LeasesStage {
+    leases: Arc<Leases>,
+    ttl: u32,
+}
+
+impl LeasesStage {
+    pub fn new(leases: Arc<Leases>, ttl: u32) -> Self {
+        Self { leases, ttl }
+    }
+
+    fn respond(&self, ctx: &QueryContext<'_>, answers: Vec<Record>) -> Flow {
+        Flow::Respond(
+            DnsPacket::response_from(ctx.request).with_answers(answers),
+            Provenance::Local,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for LeasesStage {
+    fn name(&self) -> &'static str {
+        "leases"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let qtype = ctx.question.qtype;
+        if let Some(addresses) = self.leases.addresses(ctx.domain.as_ref()) {
+            tracing::debug!("answering with the dhcp leases");
+            // a leased name without address of the type still exists, the answer stays empty
+            let answers = addresses
+                .into_iter()
+                .filter_map(|address| match (address, qtype) {
+                    (IpAddr::V4(addr), QueryType::A) => Some(Record::A {
+                        domain: ctx.question.name.clone(),
+                        addr,
+                        ttl: self.ttl,
+                    }),
+                    (IpAddr::V6(addr), QueryType::AAAA) => Some(Record::AAAA {
+                        domain: ctx.question.name.clone(),
+                        addr,
+                        ttl: self.ttl,
+                    }),
+                    _ => None,
+                })
+                .collect();
+            return Ok(self.respond(ctx, answers));
+        }
+        if qtype != QueryType::PTR {
+            return Ok(Flow::Continue);
+        }
+        let Some(host) = self.leases.host(ctx.domain.as_ref()) else {
+            return Ok(Flow::Continue);
+        };
+        tracing::debug!("answering reverse lookup with the dhcp leases");
+        let record = Record::PTR {
+            domain: ctx.question.name.clone(),
+            host,
+            ttl: self.ttl,
+        };
+        Ok(self.respond(ctx, vec![record]))
+    }
+}
@@ -0,0 +1,275 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::domain::normalize;
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use crate::repository::lookup::LookupService;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Number of local aliases followed before giving up, in case of a loop
+const MAX_ALIASES: usize = 8;
+
+/// What a local name resolves to
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum LocalRecord {
+    /// A or AAAA record, like `"nas.home" = "192.168.1.10"`
+    Address(IpAddr),
+    /// Several A and AAAA records, like `"nas.home" = ["192.168.1.10", "fd00::10"]`
+    Addresses(Vec<IpAddr>),
+    /// CNAME record, like `"media.home" = "nas.home"`
+    Alias(String),
+}
+
+/// Records defined by the operator, answered before the cache and the upstream servers
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(flatten)]
+    pub inner: BTreeMap<String, LocalRecord>,
+}
+
+pub(crate) struct LocalStage {
+    records: HashMap<String, LocalRecord>,
+    lookup: Arc<dyn LookupService + Sync + Send>,
+    ttl: u32,
+}
+
+impl LocalStage {
+    pub fn new(config: &Config, lookup: Arc<dyn LookupService + Sync + Send>, ttl: u32) -> Self {
+        let records = config
+            .inner
+            .iter()
+            .map(|(name, record)| {
+                let record = match record {
+                    LocalRecord::Alias(target) => {
+                        LocalRecord::Alias(normalize(target).into_owned())
+                    }
+                    other => other.clone(),
+                };
+                (normalize(name).into_owned(), record)
+            })
+            .collect();
+        Self {
+            records,
+            lookup,
+            ttl,
+        }
+    }
+
+    fn addresses(&self, domain: &str, addresses: &[IpAddr], qtype: QueryType) -> Vec<Record> {
+        addresses
+            .iter()
+            .filter_map(|address| match (address, qtype) {
+                (IpAddr::V4(addr), QueryType::A) => Some(Record::A {
+                    domain: domain.to_string(),
+                    addr: *addr,
+                    ttl: self.ttl,
+                }),
+                (IpAddr::V6(addr), QueryType::AAAA) => Some(Record::AAAA {
+                    domain: domain.to_string(),
+                    addr: *addr,
+                    ttl: self.ttl,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Follows the local aliases, then asks the upstream servers when the last one
+    /// points outside of the local records.
+    async fn resolve(&self, ctx: &QueryContext<'_>, found: &LocalRecord) -> Vec<Record> {
+        let qtype = ctx.question.qtype;
+        let mut answers = Vec::new();
+        let mut domain = ctx.question.name.clone();
+        let mut current = found;
+        for _ in 0..MAX_ALIASES {
+            let target = match current {
+                LocalRecord::Address(address) => {
+                    answers.extend(self.addresses(&domain, &[*address], qtype));
+                    return answers;
+                }
+                LocalRecord::Addresses(addresses) => {
+                    answers.extend(self.addresses(&domain, addresses, qtype));
+                    return answers;
+                }
+                LocalRecord::Alias(target) => target,
+            };
+            answers.push(Record::CNAME {
+                domain,
+                host: target.clone(),
+                ttl: self.ttl,
+            });
+            if qtype == QueryType::CNAME {
+                return answers;
+            }
+            match self.records.get(target) {
+                Some(next) => {
+                    domain = target.clone();
+                    current = next;
+                }
+                None => {
+                    match self.lookup.lookup(target, qtype, ctx.source).await {
+                        Ok(response) => answers.extend(response.answers),
+                        Err(error) => {
+                            tracing::warn!("unable to resolve the alias {target:?}: {error}")
+                        }
+                    }
+                    return answers;
+                }
+            }
+        }
+        tracing::warn!("too many local aliases, there might be a loop");
+        answers
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for LocalStage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some(found) = self.records.get(ctx.domain.as_ref()) else {
+            return Ok(Flow::Continue);
+        };
+        tracing::debug!("answering with local records");
+        // a name without record for the type still exists, the answer stays empty
+        let answers = self.resolve(ctx, found).await;
+        Ok(Flow::Respond(
+            DnsPacket::response_from(ctx.request).with_answers(answers),
+            Provenance::Local,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, LocalRecord, LocalStage};
+    use crate::dns::metrics::Provenance;
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use crate::repository::lookup::MockLookupService;
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    fn stage() -> LocalStage {
+        let config = Config {
+            inner: [
+                (
+                    "NAS.home",
+                    LocalRecord::Addresses(vec![
+                        "192.168.1.10".parse().unwrap(),
+                        "fd00::10".parse().unwrap(),
+                    ]),
+                ),
+                ("media.home", LocalRecord::Alias("nas.home".into())),
+                ("tv.home", LocalRecord::Alias("tv.vendor.com".into())),
+            ]
+            .into_iter()
+            .map(|(name, record)| (name.to_string(), record))
+            .collect(),
+        };
+        let lookup = MockLookupService::default().with_query(
+            "tv.vendor.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(1))
+                .with_answer(record("tv.vendor.com", Ipv4Addr::new(1, 2, 3, 4))),
+        );
+        LocalStage::new(&config, Arc::new(lookup), 30)
+    }
+
+    async fn answers(stage: &LocalStage, qname: &str, qtype: QueryType) -> Option<Vec<Record>> {
+        let packet = request(qname, qtype);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, provenance) => {
+                assert_eq!(provenance, Provenance::Local);
+                Some(res.answers)
+            }
+            Flow::Continue => None,
+        }
+    }
+
+    #[test]
+    fn should_parse_config() {
+        // the names are quoted keys of a table, not dotted paths
+        let config = parse(
+            r#"
+[records]
+"nas.home" = "192.168.1.10"
+"media.home" = "nas.home"
+"dual.home" = ["192.168.1.11", "fd00::11"]
+"#,
+        );
+        assert_eq!(
+            config.inner["nas.home"],
+            LocalRecord::Address("192.168.1.10".parse().unwrap())
+        );
+        assert_eq!(
+            config.inner["media.home"],
+            LocalRecord::Alias("nas.home".into())
+        );
+        assert!(matches!(
+            config.inner["dual.home"],
+            LocalRecord::Addresses(ref list) if list.len() == 2
+        ));
+    }
+
+    fn parse(input: &str) -> Config {
+        #[derive(serde::Deserialize)]
+        struct Root {
+            records: Config,
+        }
+
+        ::config::Config::builder()
+            .add_source(::config::File::from_str(input, ::config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize::<Root>()
+            .unwrap()
+            .records
+    }
+
+    #[tokio::test]
+    async fn should_answer_addresses_by_type() {
+        let stage = stage();
+        let found = answers(&stage, "nas.home", QueryType::A).await.unwrap();
+        assert_eq!(
+            found,
+            vec![Record::A {
+                domain: "nas.home".into(),
+                addr: Ipv4Addr::new(192, 168, 1, 10),
+                ttl: 30,
+            }]
+        );
+        let found = answers(&stage, "nas.home", QueryType::AAAA).await.unwrap();
+        assert!(matches!(found.as_slice(), [Record::AAAA { .. }]));
+        let found = answers(&stage, "nas.home", QueryType::MX).await.unwrap();
+        assert!(found.is_empty());
+        assert!(answers(&stage, "www.nas.home", QueryType::A)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn should_follow_aliases() {
+        let stage = stage();
+        let found = answers(&stage, "media.home", QueryType::A).await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Record::CNAME { host, .. }, Record::A { domain, .. }] if host == "nas.home" && domain == "nas.home"
+        ));
+        let found = answers(&stage, "tv.home", QueryType::A).await.unwrap();
+        assert!(matches!(
+            found.as_slice(),
+            [Record::CNAME { .. }, Record::A { addr, .. }] if *addr == Ipv4Addr::new(1, 2, 3, 4)
+        ));
+    }
+}
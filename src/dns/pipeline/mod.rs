@@ -0,0 +1,299 @@
+//! Steps a query goes through before being answered.
+//!
+//! Each stage can either let the query go to the next one, possibly after changing
+//! the answers found so far, or stop the pipeline with a response.
+use super::error::HandleError;
+use super::metrics::Provenance;
+use crate::common::domain::{equals, normalize};
+use crate::common::source::QuerySource;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::DnsPacket;
+use std::borrow::Cow;
+
+pub(crate) mod aaaa;
+pub(crate) mod blocklist;
+pub(crate) mod cache;
+pub(crate) mod leases;
+pub(crate) mod local;
+pub(crate) mod never_forward;
+pub(crate) mod reverse;
+pub(crate) mod safe_search;
+pub(crate) mod ttl;
+pub(crate) mod upstream;
+
+/// State of a query going through the pipeline
+pub(crate) struct QueryContext<'a> {
+    pub source: QuerySource,
+    pub request: &'a DnsPacket,
+    pub question: &'a Question,
+    /// Normalized name of the question, borrowed as long as the client sent a lowercase name
+    pub domain: Cow<'a, str>,
+    /// Answers found so far, with where they come from
+    pub answers: Option<(Vec<Record>, Provenance)>,
+    /// Code of the response, like the NXDOMAIN of an upstream server
+    pub response_code: ResponseCode,
+    /// Records of the authority section, like the SOA of a negative upstream answer
+    pub authorities: Vec<Record>,
+    /// Records of the additional section
+    pub resources: Vec<Record>,
+    /// Whether the answers were validated with DNSSEC, setting the AD bit
+    pub authentic: bool,
+}
+
+impl<'a> QueryContext<'a> {
+    pub fn new(source: QuerySource, request: &'a DnsPacket) -> Result<Self, HandleError> {
+        let question = request.questions.first().ok_or(HandleError::NoQuestion)?;
+        Ok(Self::for_question(source, request, question))
+    }
+
+    /// Context of one of the questions of the request
+    pub fn for_question(
+        source: QuerySource,
+        request: &'a DnsPacket,
+        question: &'a Question,
+    ) -> Self {
+        Self {
+            source,
+            request,
+            question,
+            domain: normalize(question.name.as_str()),
+            answers: None,
+            response_code: ResponseCode::NoError,
+            authorities: Vec::new(),
+            resources: Vec::new(),
+            authentic: false,
+        }
+    }
+
+    /// Stops the pipeline with an empty response and the given code
+    pub fn respond_with(&self, code: ResponseCode) -> Flow {
+        Flow::Respond(
+            DnsPacket::response_from(self.request).with_response_code(code),
+            Provenance::Synthesized,
+        )
+    }
+
+    fn into_response(self) -> (DnsPacket, Provenance) {
+        let (mut answers, provenance) = self
+            .answers
+            .unwrap_or((Vec::new(), Provenance::Synthesized));
+        // the answers from the cache or the upstream servers are for the normalized name,
+        // the client gets them with the case of its question (RFC 4343 section 4.1)
+        for answer in answers.iter_mut() {
+            if answer.domain() != self.question.name && equals(answer.domain(), &self.question.name)
+            {
+                answer.set_domain(&self.question.name);
+            }
+        }
+        let mut packet = DnsPacket::response_from(self.request)
+            .with_response_code(self.response_code)
+            .with_answers(answers);
+        packet.header.authed_data = self.authentic;
+        packet.authorities = self.authorities;
+        packet.resources = self.resources;
+        (packet, provenance)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Flow {
+    Continue,
+    Respond(DnsPacket, Provenance),
+}
+
+#[async_trait::async_trait]
+pub(crate) trait Stage: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError>;
+}
+
+/// Stages that can be listed in the configuration
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StageKind {
+    /// Answers the reverse lookups of the configured hosts
+    Reverse,
+    /// Answers with the records defined in the configuration
+    Local,
+    /// Answers the names and the reverse lookups of the DHCP leases
+    Leases,
+    /// Answers NXDOMAIN for the domains that should never leave the network
+    NeverForward,
+    /// Answers the queries for the blocked domains, following the policy
+    Blocklist,
+    /// Answers the search engines with their safe search, for the groups enforcing it
+    SafeSearch,
+    /// Looks for the answers in the cache
+    Cache,
+    /// Queries the upstream servers when nothing was found in the cache
+    Upstream,
+    /// Checks the limits of the upstream answers
+    Limits,
+    /// Protects against DNS rebinding in the upstream answers
+    Rebinding,
+    /// Brings the TTL of the upstream answers within the configured bounds
+    Ttl,
+    /// Keeps the upstream answers in the cache
+    Persist,
+    /// Blocks the answers with a CNAME pointing to a blocked domain
+    CnameInspection,
+    /// Removes the AAAA answers for the clients with a broken IPv6 network
+    AaaaFilter,
+}
+
+impl StageKind {
+    pub const DEFAULT: [StageKind; 14] = [
+        Self::Reverse,
+        Self::Local,
+        Self::Leases,
+        Self::NeverForward,
+        Self::Blocklist,
+        Self::SafeSearch,
+        Self::Cache,
+        Self::Upstream,
+        Self::Limits,
+        Self::Rebinding,
+        Self::Ttl,
+        Self::Persist,
+        Self::CnameInspection,
+        Self::AaaaFilter,
+    ];
+}
+
+#[derive(Default)]
+pub(crate) struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn with_stage(mut self, stage: Box<dyn Stage>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Runs the stages in order until one of them responds, or builds the response
+    /// from the answers found once they all ran.
+    pub async fn run(
+        &self,
+        mut ctx: QueryContext<'_>,
+    ) -> Result<(DnsPacket, Provenance), HandleError> {
+        for stage in self.stages.iter() {
+            if let Flow::Respond(packet, provenance) = stage.run(&mut ctx).await? {
+                tracing::debug!("answered by the {} stage", stage.name());
+                return Ok((packet, provenance));
+            }
+        }
+        Ok(ctx.into_response())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Flow, Pipeline, QueryContext, Stage};
+    use crate::common::source::QuerySource;
+    use crate::dns::error::HandleError;
+    use crate::dns::metrics::Provenance;
+    use donos_parser::packet::header::{Header, ResponseCode};
+    use donos_parser::packet::question::Question;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::net::Ipv4Addr;
+
+    pub(crate) fn client() -> QuerySource {
+        QuerySource::Client("127.1.0.1:42".parse().unwrap())
+    }
+
+    pub(crate) fn request(qname: &str, qtype: QueryType) -> DnsPacket {
+        DnsPacket::new(Header::question(1)).with_question(Question::new(qname.into(), qtype))
+    }
+
+    pub(crate) fn record(domain: &str, addr: Ipv4Addr) -> Record {
+        Record::A {
+            domain: domain.into(),
+            addr,
+            ttl: 100,
+        }
+    }
+
+    struct Answer;
+
+    #[async_trait::async_trait]
+    impl Stage for Answer {
+        fn name(&self) -> &'static str {
+            "answer"
+        }
+
+        async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+            ctx.answers = Some((
+                vec![record(&ctx.domain, Ipv4Addr::new(1, 2, 3, 4))],
+                Provenance::Upstream,
+            ));
+            Ok(Flow::Continue)
+        }
+    }
+
+    struct Refuse;
+
+    #[async_trait::async_trait]
+    impl Stage for Refuse {
+        fn name(&self) -> &'static str {
+            "refuse"
+        }
+
+        async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+            Ok(ctx.respond_with(ResponseCode::Refused))
+        }
+    }
+
+    #[test]
+    fn should_require_a_question() {
+        let request = DnsPacket::new(Header::question(1));
+        assert!(matches!(
+            QueryContext::new(client(), &request),
+            Err(HandleError::NoQuestion)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_build_response_from_answers() {
+        let request = request("Perdu.com", QueryType::A);
+        let ctx = QueryContext::new(client(), &request).unwrap();
+        assert_eq!(ctx.domain, "perdu.com");
+
+        let (packet, provenance) = Pipeline::default()
+            .with_stage(Box::new(Answer))
+            .run(ctx)
+            .await
+            .unwrap();
+        assert_eq!(provenance, Provenance::Upstream);
+        assert_eq!(packet.header.id, 1);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_stop_at_first_response() {
+        let request = request("perdu.com", QueryType::A);
+        let ctx = QueryContext::new(client(), &request).unwrap();
+        let (packet, provenance) = Pipeline::default()
+            .with_stage(Box::new(Refuse))
+            .with_stage(Box::new(Answer))
+            .run(ctx)
+            .await
+            .unwrap();
+        assert_eq!(provenance, Provenance::Synthesized);
+        assert_eq!(packet.header.response_code, ResponseCode::Refused);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_answer_empty_without_stages() {
+        let request = request("perdu.com", QueryType::A);
+        let ctx = QueryContext::new(client(), &request).unwrap();
+        let (packet, provenance) = Pipeline::default().run(ctx).await.unwrap();
+        assert_eq!(provenance, Provenance::Synthesized);
+        assert_eq!(packet.header.response_code, ResponseCode::NoError);
+        assert!(packet.answers.is_empty());
+    }
+}
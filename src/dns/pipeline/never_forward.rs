@@ -0,0 +1,131 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::domain::{matches_suffix, normalize};
+use crate::dns::error::HandleError;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+
+/// SOA of the suffix sent with the NXDOMAIN, like for the zones served locally
+/// (RFC 6303 section 3), for the clients to cache it for the given TTL (RFC 2308)
+fn local_soa(zone: &str, ttl: u32) -> Record {
+    Record::SOA {
+        domain: zone.to_string(),
+        mname: "localhost".into(),
+        rname: "nobody.invalid".into(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: ttl,
+        ttl,
+    }
+}
+
+/// Answers NXDOMAIN for the domain suffixes that should never reach the upstream servers
+///
+/// The domains sent to dedicated servers are still resolved, like `home.lan` by the router.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NeverForwardStage {
+    suffixes: Vec<String>,
+    forwarded: Vec<String>,
+    /// TTL of the negative answers
+    ttl: u32,
+}
+
+impl NeverForwardStage {
+    pub fn new(suffixes: &[String], forwarded: &[String], ttl: u32) -> Self {
+        let normalized = |items: &[String]| {
+            items
+                .iter()
+                .map(|item| normalize(item).into_owned())
+                .collect()
+        };
+        Self {
+            suffixes: normalized(suffixes),
+            forwarded: normalized(forwarded),
+            ttl,
+        }
+    }
+
+    /// Suffix of the domain that is never forwarded, if any
+    fn never_forwarded_suffix(&self, domain: &str) -> Option<&str> {
+        if self
+            .forwarded
+            .iter()
+            .any(|forwarded| matches_suffix(domain, forwarded))
+        {
+            return None;
+        }
+        self.suffixes
+            .iter()
+            .find(|suffix| matches_suffix(domain, suffix))
+            .map(String::as_str)
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for NeverForwardStage {
+    fn name(&self) -> &'static str {
+        "never-forward"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some(suffix) = self.never_forwarded_suffix(&ctx.domain) else {
+            return Ok(Flow::Continue);
+        };
+        tracing::debug!("domain not forwarded to upstream");
+        let mut flow = ctx.respond_with(ResponseCode::NameError);
+        if let Flow::Respond(ref mut packet, _) = flow {
+            packet.authorities.push(local_soa(suffix, self.ttl));
+        }
+        Ok(flow)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NeverForwardStage;
+    use crate::dns::pipeline::tests::{client, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+
+    #[tokio::test]
+    async fn should_answer_nxdomain_for_suffixes() {
+        let stage = NeverForwardStage::new(&["Lan.".to_string()], &[], 3600);
+
+        let packet = request("printer.lan", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => {
+                assert_eq!(res.header.response_code, ResponseCode::NameError);
+                // the clients can cache the answer for the negative ttl
+                assert!(matches!(
+                    res.authorities.as_slice(),
+                    [Record::SOA { domain, ttl: 3600, minimum: 3600, .. }] if domain == "lan"
+                ));
+            }
+            Flow::Continue => panic!("should respond"),
+        }
+
+        let packet = request("plan.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+    }
+
+    #[tokio::test]
+    async fn should_let_forwarded_domains_through() {
+        let stage = NeverForwardStage::new(&["lan".to_string()], &["Home.Lan".to_string()], 3600);
+
+        let packet = request("router.home.lan", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+
+        let packet = request("printer.lan", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(
+            stage.run(&mut ctx).await.unwrap(),
+            Flow::Respond(..)
+        ));
+    }
+}
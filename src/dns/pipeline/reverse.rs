@@ -0,0 +1,140 @@
+use super::{Flow, QueryContext, Stage};
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Reverse lookups answered by donos, for the hosts of the local network
+/// that the upstream servers don't know about.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Name of each host, like `"192.168.1.10" = "nas.lan"`
+    #[serde(default)]
+    pub hosts: HashMap<IpAddr, String>,
+}
+
+/// Name queried for the reverse lookup of an address, like `4.3.2.1.in-addr.arpa`
+/// or the nibbles of an IPv6 address under `ip6.arpa`.
+pub(crate) fn reverse_name(address: &IpAddr) -> String {
+    match address {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::with_capacity(72);
+            for octet in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0F, octet >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Answers the PTR queries of the configured hosts, the other ones go to the next stages.
+pub(crate) struct ReverseStage {
+    /// Host names indexed by reverse name
+    names: HashMap<String, String>,
+    ttl: u32,
+}
+
+impl ReverseStage {
+    pub fn new(config: &Config, ttl: u32) -> Self {
+        Self {
+            names: config
+                .hosts
+                .iter()
+                .map(|(address, host)| {
+                    (
+                        reverse_name(&address.to_canonical()),
+                        host.trim_end_matches('.').to_string(),
+                    )
+                })
+                .collect(),
+            ttl,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for ReverseStage {
+    fn name(&self) -> &'static str {
+        "reverse"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        if ctx.question.qtype != QueryType::PTR {
+            return Ok(Flow::Continue);
+        }
+        let Some(host) = self.names.get(ctx.domain.as_ref()) else {
+            return Ok(Flow::Continue);
+        };
+        tracing::debug!("answering reverse lookup locally");
+        let record = Record::PTR {
+            domain: ctx.question.name.clone(),
+            host: host.clone(),
+            ttl: self.ttl,
+        };
+        Ok(Flow::Respond(
+            DnsPacket::response_from(ctx.request).with_answer(record),
+            Provenance::Local,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_name, Config, ReverseStage};
+    use crate::dns::pipeline::tests::{client, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+
+    #[test]
+    fn should_build_reverse_names() {
+        assert_eq!(
+            reverse_name(&"192.168.1.10".parse().unwrap()),
+            "10.1.168.192.in-addr.arpa"
+        );
+        assert_eq!(
+            reverse_name(&"2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_answer_configured_hosts() {
+        let config = Config {
+            hosts: [("192.168.1.10".parse().unwrap(), "nas.lan.".to_string())]
+                .into_iter()
+                .collect(),
+        };
+        let stage = ReverseStage::new(&config, 30);
+
+        let packet = request("10.1.168.192.IN-ADDR.ARPA", QueryType::PTR);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => assert_eq!(
+                res.answers,
+                vec![Record::PTR {
+                    domain: "10.1.168.192.IN-ADDR.ARPA".into(),
+                    host: "nas.lan".into(),
+                    ttl: 30,
+                }]
+            ),
+            Flow::Continue => panic!("should respond"),
+        }
+
+        for (qname, qtype) in [
+            ("11.1.168.192.in-addr.arpa", QueryType::PTR),
+            ("10.1.168.192.in-addr.arpa", QueryType::A),
+        ] {
+            let packet = request(qname, qtype);
+            let mut ctx = QueryContext::new(client(), &packet).unwrap();
+            assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use super::{Flow, QueryContext, Stage};
+use crate::common::source::QuerySource;
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use crate::repository::blocklist::BlocklistService;
+use crate::repository::lookup::LookupService;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::sync::Arc;
+
+/// Domains of the search engines, with the host serving their safe search.
+///
+/// Only the names themselves are rewritten, the other services of the same
+/// domains, like `mail.google.com`, keep working. Google is matched on all its
+/// country domains instead, see `is_google_search`.
+const SAFE_SEARCH_HOSTS: &[(&str, &str)] = &[
+    ("bing.com", "strict.bing.com"),
+    ("www.bing.com", "strict.bing.com"),
+    ("duckduckgo.com", "safe.duckduckgo.com"),
+    ("www.duckduckgo.com", "safe.duckduckgo.com"),
+    ("youtube.com", "restrict.youtube.com"),
+    ("www.youtube.com", "restrict.youtube.com"),
+    ("m.youtube.com", "restrict.youtube.com"),
+    ("youtubei.googleapis.com", "restrict.youtube.com"),
+    ("youtube.googleapis.com", "restrict.youtube.com"),
+    ("www.youtube-nocookie.com", "restrict.youtube.com"),
+];
+
+const GOOGLE_SAFE_SEARCH_HOST: &str = "forcesafesearch.google.com";
+
+/// Whether the domain is `google.<tld>` or `www.google.<tld>`, the country domains
+/// being like `google.fr`, `google.co.uk` or `google.com.br`
+fn is_google_search(domain: &str) -> bool {
+    let domain = domain.strip_prefix("www.").unwrap_or(domain);
+    let Some(tld) = domain.strip_prefix("google.") else {
+        return false;
+    };
+    match tld.split_once('.') {
+        None => !tld.is_empty(),
+        Some((second, country)) => {
+            matches!(second, "co" | "com") && !country.is_empty() && !country.contains('.')
+        }
+    }
+}
+
+fn safe_search_host(domain: &str) -> Option<&'static str> {
+    if is_google_search(domain) {
+        return Some(GOOGLE_SAFE_SEARCH_HOST);
+    }
+    SAFE_SEARCH_HOSTS
+        .iter()
+        .find(|(name, _)| *name == domain)
+        .map(|(_, host)| *host)
+}
+
+/// Answers the search engines with a CNAME to their safe search, for the clients
+/// of the groups enforcing it.
+///
+/// Runs before the cache, which is shared by all the clients.
+pub(crate) struct SafeSearchStage {
+    blocklist: Arc<dyn BlocklistService + Send + Sync>,
+    lookup: Arc<dyn LookupService + Sync + Send>,
+    ttl: u32,
+}
+
+impl SafeSearchStage {
+    pub fn new(
+        blocklist: Arc<dyn BlocklistService + Send + Sync>,
+        lookup: Arc<dyn LookupService + Sync + Send>,
+        ttl: u32,
+    ) -> Self {
+        Self {
+            blocklist,
+            lookup,
+            ttl,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for SafeSearchStage {
+    fn name(&self) -> &'static str {
+        "safe-search"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some(host) = safe_search_host(&ctx.domain) else {
+            return Ok(Flow::Continue);
+        };
+        let QuerySource::Client(origin) = ctx.source else {
+            return Ok(Flow::Continue);
+        };
+        if !self.blocklist.is_safe_search(&origin) {
+            return Ok(Flow::Continue);
+        }
+        tracing::debug!("answering with the safe search host {host:?}");
+        let qtype = ctx.question.qtype;
+        let mut answers = vec![Record::CNAME {
+            domain: ctx.question.name.clone(),
+            host: host.to_string(),
+            ttl: self.ttl,
+        }];
+        if qtype != QueryType::CNAME {
+            match self.lookup.lookup(host, qtype, ctx.source).await {
+                Ok(response) => answers.extend(response.answers),
+                Err(error) => tracing::warn!("unable to resolve {host:?}: {error}"),
+            }
+        }
+        Ok(Flow::Respond(
+            DnsPacket::response_from(ctx.request).with_answers(answers),
+            Provenance::Synthesized,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{safe_search_host, SafeSearchStage};
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use crate::repository::blocklist::MemoryBlocklistService;
+    use crate::repository::lookup::MockLookupService;
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    async fn answers(stage: &SafeSearchStage, qname: &str) -> Option<Vec<Record>> {
+        let packet = request(qname, QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => Some(res.answers),
+            Flow::Continue => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_answer_safe_search_hosts() {
+        let lookup = Arc::new(MockLookupService::default().with_query(
+            "forcesafesearch.google.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(1)).with_answer(record(
+                "forcesafesearch.google.com",
+                Ipv4Addr::new(216, 239, 38, 120),
+            )),
+        ));
+        let stage = SafeSearchStage::new(
+            Arc::new(MemoryBlocklistService::default().with_safe_search()),
+            lookup.clone(),
+            30,
+        );
+        let found = answers(&stage, "www.Google.com").await.unwrap();
+        assert_eq!(
+            found,
+            vec![
+                Record::CNAME {
+                    domain: "www.Google.com".into(),
+                    host: "forcesafesearch.google.com".into(),
+                    ttl: 30,
+                },
+                record(
+                    "forcesafesearch.google.com",
+                    Ipv4Addr::new(216, 239, 38, 120)
+                ),
+            ]
+        );
+        assert!(answers(&stage, "mail.google.com").await.is_none());
+
+        // not enforced for the group of the client
+        let stage = SafeSearchStage::new(Arc::new(MemoryBlocklistService::default()), lookup, 30);
+        assert!(answers(&stage, "www.google.com").await.is_none());
+    }
+
+    #[test]
+    fn should_match_the_country_domains_of_google() {
+        for domain in [
+            "google.com",
+            "www.google.com",
+            "google.fr",
+            "www.google.co.uk",
+            "google.com.br",
+        ] {
+            assert_eq!(
+                safe_search_host(domain),
+                Some("forcesafesearch.google.com"),
+                "{domain}"
+            );
+        }
+        for domain in [
+            "mail.google.com",
+            "google",
+            "www.google.",
+            "google.example.com",
+            "notgoogle.fr",
+        ] {
+            assert_eq!(safe_search_host(domain), None, "{domain}");
+        }
+        assert_eq!(safe_search_host("www.bing.com"), Some("strict.bing.com"));
+    }
+}
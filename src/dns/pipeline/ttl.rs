@@ -0,0 +1,73 @@
+use super::{Flow, QueryContext, Stage};
+use crate::dns::config::TtlConfig;
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+
+/// Brings the TTL of the upstream records within the configured bounds, before they're
+/// kept in the cache so that they expire from it at the same time as from the clients
+#[async_trait::async_trait]
+impl Stage for TtlConfig {
+    fn name(&self) -> &'static str {
+        "ttl"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        if self.min.is_none() && self.max.is_none() {
+            return Ok(Flow::Continue);
+        }
+        let Some((ref mut answers, Provenance::Upstream)) = ctx.answers else {
+            return Ok(Flow::Continue);
+        };
+        for record in answers.iter_mut().chain(ctx.authorities.iter_mut()) {
+            record.set_ttl(self.clamp(record.ttl()));
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::config::TtlConfig;
+    use crate::dns::metrics::Provenance;
+    use crate::dns::pipeline::tests::{client, request};
+    use crate::dns::pipeline::{QueryContext, Stage};
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::QueryType;
+    use std::net::Ipv4Addr;
+
+    fn answers() -> Vec<Record> {
+        [5, 600, 86400]
+            .into_iter()
+            .map(|ttl| Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl,
+            })
+            .collect()
+    }
+
+    fn ttls(records: &[Record]) -> Vec<u32> {
+        records.iter().map(Record::ttl).collect()
+    }
+
+    #[tokio::test]
+    async fn should_clamp_upstream_ttls() {
+        let stage = TtlConfig {
+            min: Some(60),
+            max: Some(3600),
+            ..Default::default()
+        };
+        let packet = request("perdu.com", QueryType::A);
+
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((answers(), Provenance::Upstream));
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(ttls(&ctx.answers.unwrap().0), vec![60, 600, 3600]);
+
+        // the cache already holds clamped records, counting down from there
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        ctx.answers = Some((answers(), Provenance::Cache));
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(ttls(&ctx.answers.unwrap().0), vec![5, 600, 86400]);
+    }
+}
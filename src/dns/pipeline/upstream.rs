@@ -0,0 +1,345 @@
+use super::{Flow, QueryContext, Stage};
+use crate::dns::error::HandleError;
+use crate::dns::metrics::Provenance;
+use crate::repository::cache::CacheService;
+use crate::repository::lookup::LookupService;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+type InflightKey = (String, QueryType);
+
+/// Upstream query shared by all the identical queries received while it's running
+type Inflight = Shared<BoxFuture<'static, Result<DnsPacket, Arc<std::io::Error>>>>;
+
+/// Forgets the upstream query once the query that started it is done with it
+struct InflightGuard<'a> {
+    inflight: &'a Mutex<HashMap<InflightKey, Inflight>>,
+    key: InflightKey,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.inflight.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Whether the code of an upstream response can be sent to the client, the other ones
+/// meaning the upstream server couldn't understand or answer the query
+fn is_forwarded(code: ResponseCode) -> bool {
+    matches!(
+        code,
+        ResponseCode::NoError | ResponseCode::NameError | ResponseCode::Refused
+    )
+}
+
+/// Records of an upstream response that can be sent to the client, leaving out
+/// the OPT pseudo record that only applies between donos and the upstream server
+fn forwarded_records(records: Vec<Record>) -> Vec<Record> {
+    records
+        .into_iter()
+        .filter(|record| !matches!(record, Record::OPT { .. }))
+        .collect()
+}
+
+/// Forwards the query to the upstream servers when no answer has been found yet,
+/// their answers being checked by the next stages
+pub(crate) struct UpstreamStage {
+    lookup: Arc<dyn LookupService + Sync + Send>,
+    inflight: Mutex<HashMap<InflightKey, Inflight>>,
+    /// Cache looked up for expired answers when the upstream servers can't answer
+    stale: Option<Arc<dyn CacheService + Send + Sync>>,
+}
+
+impl UpstreamStage {
+    pub fn new(lookup: Arc<dyn LookupService + Sync + Send>) -> Self {
+        Self {
+            lookup,
+            inflight: Mutex::default(),
+            stale: None,
+        }
+    }
+
+    pub fn with_stale_cache(mut self, cache: Arc<dyn CacheService + Send + Sync>) -> Self {
+        self.stale = Some(cache);
+        self
+    }
+
+    /// Answers with the expired records of the cache when there are some, SERVFAIL otherwise
+    async fn fallback(&self, ctx: &mut QueryContext<'_>) -> Flow {
+        let Some(ref cache) = self.stale else {
+            return ctx.respond_with(ResponseCode::ServerFailure);
+        };
+        match cache.request_stale(&ctx.domain, ctx.question.qtype).await {
+            Ok(Some(found)) => {
+                ctx.response_code = found.code;
+                ctx.authentic = found.authentic;
+                ctx.authorities.extend(found.soa);
+                ctx.answers = Some((found.records, Provenance::Stale));
+                Flow::Continue
+            }
+            Ok(None) => ctx.respond_with(ResponseCode::ServerFailure),
+            Err(error) => {
+                tracing::error!("couldn't look for stale answers in cache: {error:?}");
+                ctx.respond_with(ResponseCode::ServerFailure)
+            }
+        }
+    }
+
+    /// Queries the upstream servers, unless the same query is already running,
+    /// in which case its result is awaited instead.
+    async fn lookup(&self, ctx: &QueryContext<'_>) -> Result<DnsPacket, Arc<std::io::Error>> {
+        let key = (ctx.domain.to_string(), ctx.question.qtype);
+        let (future, _guard) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(found) => {
+                    tracing::debug!("waiting for the same query already sent upstream");
+                    (found.clone(), None)
+                }
+                None => {
+                    let lookup = self.lookup.clone();
+                    // the queries sharing the key can differ by their case, the answers
+                    // get the case of each question once the pipeline is done
+                    let (qname, qtype, source) =
+                        (ctx.domain.to_string(), ctx.question.qtype, ctx.source);
+                    let future = async move {
+                        lookup
+                            .lookup(qname.as_str(), qtype, source)
+                            .await
+                            .map_err(Arc::new)
+                    }
+                    .boxed()
+                    .shared();
+                    inflight.insert(key.clone(), future.clone());
+                    let guard = InflightGuard {
+                        inflight: &self.inflight,
+                        key,
+                    };
+                    (future, Some(guard))
+                }
+            }
+        };
+        future.await
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for UpstreamStage {
+    fn name(&self) -> &'static str {
+        "upstream"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        if ctx.answers.is_some() {
+            return Ok(Flow::Continue);
+        }
+        let response = match self.lookup(ctx).await {
+            Ok(found) if is_forwarded(found.header.response_code) => found,
+            Ok(found) => {
+                tracing::warn!(
+                    "no upstream server could answer, got {:?}",
+                    found.header.response_code
+                );
+                return Ok(self.fallback(ctx).await);
+            }
+            Err(error) => {
+                tracing::warn!("unable to reach the upstream servers: {error}");
+                return Ok(self.fallback(ctx).await);
+            }
+        };
+        ctx.response_code = response.header.response_code;
+        ctx.authentic = response.header.authed_data;
+        ctx.authorities = forwarded_records(response.authorities);
+        ctx.resources = forwarded_records(response.resources);
+        ctx.answers = Some((response.answers, Provenance::Upstream));
+        Ok(Flow::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UpstreamStage;
+    use crate::common::source::QuerySource;
+    use crate::dns::metrics::Provenance;
+    use crate::dns::pipeline::tests::{client, record, request};
+    use crate::dns::pipeline::{Flow, QueryContext, Stage};
+    use crate::repository::cache::{CacheService, MemoryCacheService};
+    use crate::repository::lookup::{LookupService, MockLookupService};
+    use donos_parser::packet::header::{Header, ResponseCode};
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn should_keep_upstream_answers() {
+        let stage = UpstreamStage::new(Arc::new(
+            MockLookupService::default().with_query(
+                "perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(10))
+                    .with_answer(record("perdu.com", Ipv4Addr::new(1, 2, 3, 4))),
+            ),
+        ));
+
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        let (answers, provenance) = ctx.answers.unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(provenance, Provenance::Upstream);
+
+        // the upstream servers can't be reached
+        let packet = request("perdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, provenance) => {
+                assert_eq!(res.header.response_code, ResponseCode::ServerFailure);
+                assert_eq!(provenance, Provenance::Synthesized);
+            }
+            Flow::Continue => panic!("should respond"),
+        }
+
+        // already answered by the cache
+        ctx.answers = Some((Vec::new(), Provenance::Cache));
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+    }
+
+    #[tokio::test]
+    async fn should_forward_upstream_response_codes() {
+        let soa = Record::SOA {
+            domain: "perdu.com".into(),
+            mname: "ns.perdu.com".into(),
+            rname: "admin.perdu.com".into(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        };
+        let mut nxdomain =
+            DnsPacket::new(Header::response(10)).with_response_code(ResponseCode::NameError);
+        nxdomain.authorities.push(soa.clone());
+        let stage = UpstreamStage::new(Arc::new(
+            MockLookupService::default()
+                .with_query("nope.perdu.com", QueryType::A, nxdomain)
+                .with_query(
+                    "perdu.com",
+                    QueryType::A,
+                    DnsPacket::new(Header::response(11))
+                        .with_response_code(ResponseCode::FormatError),
+                ),
+        ));
+
+        let packet = request("nope.perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        assert_eq!(ctx.response_code, ResponseCode::NameError);
+        assert_eq!(ctx.authorities, vec![soa]);
+
+        // the client isn't blamed for what the upstream server couldn't understand
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        match stage.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => {
+                assert_eq!(res.header.response_code, ResponseCode::ServerFailure)
+            }
+            Flow::Continue => panic!("should respond"),
+        }
+    }
+
+    /// Takes a while to answer, counting the queries it receives
+    #[derive(Default)]
+    struct SlowLookupService {
+        count: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl LookupService for SlowLookupService {
+        async fn lookup(
+            &self,
+            qname: &str,
+            _qtype: QueryType,
+            _source: QuerySource,
+        ) -> std::io::Result<DnsPacket> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(DnsPacket::new(Header::response(10))
+                .with_answer(record(qname, Ipv4Addr::new(1, 2, 3, 4))))
+        }
+    }
+
+    #[tokio::test]
+    async fn should_share_identical_inflight_lookups() {
+        let lookup = Arc::new(SlowLookupService::default());
+        let stage = UpstreamStage::new(lookup.clone());
+
+        let packets: Vec<_> = (0..10)
+            .map(|index| {
+                let qname = if index % 2 == 0 {
+                    "perdu.com"
+                } else {
+                    "Perdu.com"
+                };
+                request(qname, QueryType::A)
+            })
+            .collect();
+        let results = futures::future::join_all(packets.iter().map(|packet| async {
+            let mut ctx = QueryContext::new(client(), packet).unwrap();
+            stage.run(&mut ctx).await.unwrap();
+            ctx.answers.unwrap().0
+        }))
+        .await;
+        assert!(results.iter().all(|answers| answers.len() == 1));
+        assert_eq!(lookup.count.load(Ordering::SeqCst), 1);
+
+        // once done, the next query goes upstream again
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        stage.run(&mut ctx).await.unwrap();
+        assert_eq!(lookup.count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn should_serve_stale_answers_when_upstream_fails() {
+        let cache =
+            Arc::new(MemoryCacheService::new(10).with_serve_stale(Duration::from_secs(60), 30));
+        cache
+            .persist(
+                "perdu.com",
+                QueryType::A,
+                vec![record("perdu.com", Ipv4Addr::new(1, 2, 3, 4)).delayed_ttl(0)],
+            )
+            .await
+            .unwrap();
+        assert!(cache
+            .request("perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .is_none());
+
+        let stage =
+            UpstreamStage::new(Arc::new(MockLookupService::default())).with_stale_cache(cache);
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(stage.run(&mut ctx).await.unwrap(), Flow::Continue));
+        let (answers, provenance) = ctx.answers.unwrap();
+        assert_eq!(provenance, Provenance::Stale);
+        assert!(matches!(answers.as_slice(), [record] if record.ttl() == 30));
+
+        // nothing stale to serve
+        let packet = request("perdu.com", QueryType::AAAA);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        assert!(matches!(
+            stage.run(&mut ctx).await.unwrap(),
+            Flow::Respond(..)
+        ));
+    }
+}
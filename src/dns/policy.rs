@@ -0,0 +1,144 @@
+use crate::common::domain::{matches_suffix, normalize};
+
+/// What to do with a domain that is not explicitly allowed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Resolve the domain unless it's in a blocklist
+    #[default]
+    Allow,
+    /// Block the domain, only allowed domains are resolved
+    Block,
+}
+
+/// Sets of domains required by common infrastructure,
+/// to allow them easily when blocking everything by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Template {
+    /// Time synchronization servers
+    Ntp,
+    /// Apple software updates
+    AppleUpdates,
+    /// Microsoft Windows updates
+    WindowsUpdates,
+    /// Debian and Ubuntu package repositories
+    LinuxUpdates,
+    /// Connectivity checks used to detect captive portals
+    ConnectivityCheck,
+}
+
+impl Template {
+    fn domains(&self) -> &'static [&'static str] {
+        match self {
+            Self::Ntp => &[
+                "pool.ntp.org",
+                "time.apple.com",
+                "time.windows.com",
+                "time.google.com",
+                "time.cloudflare.com",
+                "ntp.ubuntu.com",
+            ],
+            Self::AppleUpdates => &[
+                "swscan.apple.com",
+                "swcdn.apple.com",
+                "swdist.apple.com",
+                "updates.cdn-apple.com",
+                "mesu.apple.com",
+                "gdmf.apple.com",
+            ],
+            Self::WindowsUpdates => &[
+                "windowsupdate.com",
+                "update.microsoft.com",
+                "delivery.mp.microsoft.com",
+                "download.microsoft.com",
+            ],
+            Self::LinuxUpdates => &[
+                "deb.debian.org",
+                "security.debian.org",
+                "archive.ubuntu.com",
+                "security.ubuntu.com",
+            ],
+            Self::ConnectivityCheck => &[
+                "captive.apple.com",
+                "connectivitycheck.gstatic.com",
+                "www.msftconnecttest.com",
+                "detectportal.firefox.com",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Action applied to the domains that are not allowed explicitly
+    #[serde(default)]
+    pub default_action: Action,
+    /// Domains, and their subdomains, that are never blocked
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Predefined sets of domains that are never blocked
+    #[serde(default)]
+    pub templates: Vec<Template>,
+}
+
+impl Config {
+    pub fn build(self) -> Policy {
+        let mut allowed: Vec<String> = self
+            .templates
+            .iter()
+            .flat_map(|template| template.domains().iter())
+            .map(|domain| domain.to_string())
+            .chain(
+                self.allow
+                    .iter()
+                    .map(|domain| normalize(domain).into_owned()),
+            )
+            .collect();
+        allowed.sort();
+        allowed.dedup();
+        Policy {
+            default_action: self.default_action,
+            allowed,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    default_action: Action,
+    allowed: Vec<String>,
+}
+
+impl Policy {
+    pub fn default_action(&self) -> Action {
+        self.default_action
+    }
+
+    /// Checks if the domain, or one of its parents, is explicitly allowed
+    pub fn is_allowed(&self, domain: &str) -> bool {
+        self.allowed
+            .iter()
+            .any(|suffix| matches_suffix(domain, suffix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Config, Template};
+
+    #[test]
+    fn should_allow_templates_and_domains() {
+        let policy = Config {
+            default_action: Action::Block,
+            allow: vec!["Perdu.com.".into()],
+            templates: vec![Template::Ntp],
+        }
+        .build();
+        assert_eq!(policy.default_action(), Action::Block);
+        assert!(policy.is_allowed("perdu.com"));
+        assert!(policy.is_allowed("www.perdu.com"));
+        assert!(policy.is_allowed("0.pool.ntp.org"));
+        assert!(!policy.is_allowed("facebook.com"));
+    }
+}
@@ -0,0 +1,133 @@
+//! Switch to an unprivileged user once the port 53 is bound, so that donos
+//! doesn't keep running as root.
+use std::ffi::CString;
+use std::io::{Error, ErrorKind, Result};
+
+fn check(result: libc::c_int) -> Result<()> {
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn not_found(kind: &str, name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("unknown {kind} {name:?}"))
+}
+
+/// Buffer size for the `getpwnam_r` and `getgrnam_r` calls, when the system doesn't tell
+const BUFFER_SIZE: usize = 16384;
+
+/// Ids of a user, from its name or its numeric id, with its primary group
+fn resolve_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name).map_err(|_| not_found("user", name))?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    // SAFETY: an all zero passwd is valid, it's only read after being filled
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found: *mut libc::passwd = std::ptr::null_mut();
+    // SAFETY: the pointers are valid during the call and the buffer size is the real one
+    let result = unsafe {
+        libc::getpwnam_r(
+            cname.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if result != 0 {
+        return Err(Error::from_raw_os_error(result));
+    }
+    if !found.is_null() {
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+    match name.parse::<libc::uid_t>() {
+        // a numeric user without entry keeps the group of the same id
+        Ok(uid) => Ok((uid, uid)),
+        Err(_) => Err(not_found("user", name)),
+    }
+}
+
+/// Id of a group, from its name or its numeric id
+fn resolve_group(name: &str) -> Result<libc::gid_t> {
+    let cname = CString::new(name).map_err(|_| not_found("group", name))?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    // SAFETY: an all zero group is valid, it's only read after being filled
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut found: *mut libc::group = std::ptr::null_mut();
+    // SAFETY: the pointers are valid during the call and the buffer size is the real one
+    let result = unsafe {
+        libc::getgrnam_r(
+            cname.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    if result != 0 {
+        return Err(Error::from_raw_os_error(result));
+    }
+    if !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+    name.parse().map_err(|_| not_found("group", name))
+}
+
+/// Switches the process to the user and group, the group defaulting to the one of the user.
+///
+/// The supplementary groups are dropped, the group being changed before the user since
+/// an unprivileged user can't change it anymore.
+pub(crate) fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    // SAFETY: these calls can't fail
+    let (current_uid, current_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let (uid, user_gid) = match user {
+        Some(name) => resolve_user(name)?,
+        None => (current_uid, current_gid),
+    };
+    let gid = match group {
+        Some(name) => resolve_group(name)?,
+        None => user_gid,
+    };
+    if current_uid != 0 {
+        if uid == current_uid && gid == current_gid {
+            return Ok(());
+        }
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "only root can switch to another user or group",
+        ));
+    }
+    // SAFETY: the group list is valid during the call
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    // SAFETY: plain system calls on integers
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+    // being able to become root again would mean the switch didn't happen
+    // SAFETY: plain system call on an integer
+    if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "root privileges are still available",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_group, resolve_user};
+
+    #[test]
+    fn should_resolve_names_and_ids() {
+        assert_eq!(resolve_user("root").unwrap(), (0, 0));
+        assert_eq!(resolve_user("4242").unwrap(), (4242, 4242));
+        assert!(resolve_user("not-a-donos-user").is_err());
+        assert_eq!(resolve_group("root").unwrap(), 0);
+        assert_eq!(resolve_group("4242").unwrap(), 4242);
+        assert!(resolve_group("not-a-donos-group").is_err());
+    }
+}
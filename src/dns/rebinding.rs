@@ -0,0 +1,317 @@
+use super::error::HandleError;
+use super::metrics::Provenance;
+use super::pipeline::{Flow, QueryContext, Stage};
+use crate::common::domain::{matches_suffix, normalize};
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::record::Record;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// What to do with a public domain resolving to a private address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Remove the private addresses from the answers
+    #[default]
+    Strip,
+    /// Answer NXDOMAIN for the whole query
+    Block,
+}
+
+/// Protection against DNS rebinding, where a public domain resolves to
+/// an address of the local network to reach it from a browser.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub action: Action,
+    /// Domains, and their subdomains, allowed to resolve to private addresses
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            action: Action::default(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn build(self) -> Protection {
+        Protection {
+            enabled: self.enabled,
+            action: self.action,
+            allowed: self
+                .allow
+                .iter()
+                .map(|domain| normalize(domain).into_owned())
+                .collect(),
+            forwarded: Vec::new(),
+        }
+    }
+}
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // shared address space (RFC 6598)
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_private_v6(ip: &Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // unique local (fc00::/7) and link local (fe80::/10)
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || ip.to_ipv4_mapped().is_some_and(|ip| is_private_v4(&ip))
+}
+
+/// Checks if the address belongs to a private, loopback or link local range
+pub fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => is_private_v6(ip),
+    }
+}
+
+fn private_address(record: &Record) -> Option<IpAddr> {
+    let ip = match record {
+        Record::A { addr, .. } => IpAddr::V4(*addr),
+        Record::AAAA { addr, .. } => IpAddr::V6(*addr),
+        _ => return None,
+    };
+    is_private(&ip).then_some(ip)
+}
+
+#[derive(Clone, Debug)]
+pub struct Protection {
+    enabled: bool,
+    action: Action,
+    allowed: Vec<String>,
+    /// Domains sent to dedicated servers, like the ones of a corporate network,
+    /// whose private addresses are expected
+    forwarded: Vec<String>,
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Config::default().build()
+    }
+}
+
+impl Protection {
+    pub fn with_forwarded(mut self, domains: &[String]) -> Self {
+        self.forwarded = domains
+            .iter()
+            .map(|domain| normalize(domain).into_owned())
+            .collect();
+        self
+    }
+
+    /// Filters the answers of an upstream server for the given domain.
+    ///
+    /// Returns the private address found as an error when the query should be blocked.
+    pub fn check(&self, domain: &str, answers: Vec<Record>) -> Result<Vec<Record>, IpAddr> {
+        if !self.enabled
+            || self
+                .allowed
+                .iter()
+                .chain(self.forwarded.iter())
+                .any(|suffix| matches_suffix(domain, suffix))
+        {
+            return Ok(answers);
+        }
+        match self.action {
+            Action::Block => match answers.iter().find_map(private_address) {
+                Some(ip) => Err(ip),
+                None => Ok(answers),
+            },
+            Action::Strip => Ok(answers
+                .into_iter()
+                .filter(|record| match private_address(record) {
+                    Some(ip) => {
+                        tracing::warn!("removed private address {ip} for {domain:?}");
+                        false
+                    }
+                    None => true,
+                })
+                .collect()),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Stage for Protection {
+    fn name(&self) -> &'static str {
+        "rebinding"
+    }
+
+    async fn run(&self, ctx: &mut QueryContext<'_>) -> Result<Flow, HandleError> {
+        let Some((ref mut answers, Provenance::Upstream)) = ctx.answers else {
+            return Ok(Flow::Continue);
+        };
+        match self.check(&ctx.domain, std::mem::take(answers)) {
+            Ok(found) => {
+                *answers = found;
+                Ok(Flow::Continue)
+            }
+            Err(address) => {
+                tracing::warn!("blocked rebinding attempt to {address}");
+                Ok(ctx.respond_with(ResponseCode::NameError))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Config};
+    use donos_parser::packet::record::Record;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    fn a(addr: Ipv4Addr) -> Record {
+        Record::A {
+            domain: "perdu.com".into(),
+            addr,
+            ttl: 60,
+        }
+    }
+
+    #[test]
+    fn should_detect_private_addresses() {
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(super::is_private(&ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["1.1.1.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!super::is_private(&ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn should_strip_private_addresses() {
+        let protection = Config::default().build();
+        let answers = protection
+            .check(
+                "perdu.com",
+                vec![
+                    a(Ipv4Addr::new(1, 2, 3, 4)),
+                    a(Ipv4Addr::new(192, 168, 1, 1)),
+                ],
+            )
+            .unwrap();
+        assert_eq!(answers, vec![a(Ipv4Addr::new(1, 2, 3, 4))]);
+    }
+
+    #[test]
+    fn should_block_private_addresses() {
+        let protection = Config {
+            action: Action::Block,
+            ..Default::default()
+        }
+        .build();
+        let error = protection
+            .check(
+                "perdu.com",
+                vec![Record::AAAA {
+                    domain: "perdu.com".into(),
+                    addr: Ipv6Addr::LOCALHOST,
+                    ttl: 60,
+                }],
+            )
+            .unwrap_err();
+        assert_eq!(error, IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn should_keep_allowed_domains() {
+        let protection = Config {
+            allow: vec!["Perdu.com".into()],
+            ..Default::default()
+        }
+        .build();
+        let answers = vec![a(Ipv4Addr::new(10, 0, 0, 1))];
+        assert_eq!(
+            protection.check("www.perdu.com", answers.clone()).unwrap(),
+            answers
+        );
+    }
+
+    #[test]
+    fn should_keep_forwarded_domains() {
+        let protection = Config {
+            action: Action::Block,
+            ..Default::default()
+        }
+        .build()
+        .with_forwarded(&["Corp.Example".into()]);
+        let answers = vec![Record::A {
+            domain: "wiki.corp.example".into(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: 60,
+        }];
+        assert_eq!(
+            protection
+                .check("wiki.corp.example", answers.clone())
+                .unwrap(),
+            answers
+        );
+        assert!(protection
+            .check("perdu.com", vec![a(Ipv4Addr::new(10, 0, 0, 1))])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_block_upstream_answers() {
+        use crate::dns::metrics::Provenance;
+        use crate::dns::pipeline::tests::{client, request};
+        use crate::dns::pipeline::{Flow, QueryContext, Stage};
+        use donos_parser::packet::header::ResponseCode;
+        use donos_parser::packet::QueryType;
+
+        let protection = Config {
+            action: Action::Block,
+            ..Default::default()
+        }
+        .build();
+        let packet = request("perdu.com", QueryType::A);
+        let mut ctx = QueryContext::new(client(), &packet).unwrap();
+        // the cache answers have already been checked
+        ctx.answers = Some((vec![a(Ipv4Addr::new(10, 0, 0, 1))], Provenance::Cache));
+        assert!(matches!(
+            protection.run(&mut ctx).await.unwrap(),
+            Flow::Continue
+        ));
+
+        ctx.answers = Some((vec![a(Ipv4Addr::new(10, 0, 0, 1))], Provenance::Upstream));
+        match protection.run(&mut ctx).await.unwrap() {
+            Flow::Respond(res, _) => assert_eq!(res.header.response_code, ResponseCode::NameError),
+            Flow::Continue => panic!("should respond"),
+        }
+    }
+}
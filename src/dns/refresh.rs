@@ -0,0 +1,125 @@
+//! Refresh of the configured blocklists while the server is running, so that
+//! they stay up to date without running the import command.
+use crate::repository::blocklist::BlocklistService;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+    /// Number of seconds between two refreshes
+    #[serde(default = "Config::default_interval")]
+    pub interval: u64,
+    /// Maximum number of seconds randomly added to the interval, so that the
+    /// blocklist servers don't get all the instances at the same time
+    #[serde(default = "Config::default_jitter")]
+    pub jitter: u64,
+    /// Number of seconds before retrying a failed refresh, doubled after each failure
+    #[serde(default = "Config::default_retry")]
+    pub retry: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            interval: Self::default_interval(),
+            jitter: Self::default_jitter(),
+            retry: Self::default_retry(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn default_interval() -> u64 {
+        86400
+    }
+
+    pub fn default_jitter() -> u64 {
+        3600
+    }
+
+    pub fn default_retry() -> u64 {
+        300
+    }
+
+    /// Delay before the next refresh, given the number of refreshes that failed in a row.
+    ///
+    /// The retries never wait longer than the regular interval.
+    fn next_delay(&self, failures: u32, random: u64) -> Duration {
+        let seconds = if failures == 0 {
+            self.interval + random % (self.jitter + 1)
+        } else {
+            self.retry
+                .saturating_mul(1 << failures.min(16).saturating_sub(1))
+                .min(self.interval)
+        };
+        Duration::from_secs(seconds)
+    }
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Imports the blocklists again and again, right away when none has been imported yet.
+pub(crate) async fn run(
+    config: Config,
+    blocklist: Arc<dyn BlocklistService + Send + Sync>,
+    immediately: bool,
+) {
+    let mut failures = 0;
+    if !immediately {
+        tokio::time::sleep(config.next_delay(failures, random())).await;
+    }
+    loop {
+        tracing::info!("refreshing blocklists");
+        match blocklist.import().await {
+            Ok((inserted, deleted)) => {
+                tracing::info!(
+                    "blocklists refreshed, inserted {inserted} new domains and deleted {deleted} existing domains"
+                );
+                failures = 0;
+            }
+            Err(error) => {
+                failures += 1;
+                tracing::warn!(
+                    "couldn't refresh blocklists ({failures} failures in a row): {error}"
+                );
+            }
+        }
+        let delay = config.next_delay(failures, random());
+        tracing::debug!("next blocklists refresh in {} seconds", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use std::time::Duration;
+
+    #[test]
+    fn should_back_off_on_failures() {
+        let config = Config {
+            enabled: true,
+            interval: 3600,
+            jitter: 60,
+            retry: 300,
+        };
+        assert_eq!(config.next_delay(0, 0), Duration::from_secs(3600));
+        assert_eq!(config.next_delay(0, 61 + 30), Duration::from_secs(3630));
+        assert_eq!(config.next_delay(1, 42), Duration::from_secs(300));
+        assert_eq!(config.next_delay(2, 42), Duration::from_secs(600));
+        assert_eq!(config.next_delay(3, 42), Duration::from_secs(1200));
+        assert_eq!(config.next_delay(5, 42), Duration::from_secs(3600));
+        assert_eq!(config.next_delay(100, 42), Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,104 @@
+//! Reload of the configuration on SIGHUP, applying the changes that don't
+//! require closing the sockets, so that the network keeps resolving names.
+use super::clients::ClientNames;
+use super::handler::DnsHandler;
+use crate::repository::blocklist::{BlocklistService, DatabaseBlocklistService};
+use crate::repository::client::DatabaseClientService;
+use crate::repository::lookup::RemoteLookupService;
+use crate::repository::routing::RoutingLookupService;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) struct Reloader {
+    pub path: PathBuf,
+    pub handler: DnsHandler,
+    pub blocklist: Arc<DatabaseBlocklistService>,
+    pub lookup: Arc<RemoteLookupService>,
+    pub router: Arc<RoutingLookupService>,
+    pub clients: Arc<ClientNames>,
+    pub client_service: DatabaseClientService,
+}
+
+impl Reloader {
+    fn reload(&self) -> Result<(), ::config::ConfigError> {
+        let config = crate::config::Config::try_load(&self.path)?;
+
+        if self
+            .blocklist
+            .update(config.blocklists.inner, config.groups)
+        {
+            let blocklist = self.blocklist.clone();
+            tokio::spawn(async move {
+                match blocklist.import().await {
+                    Ok((inserted, deleted)) => tracing::info!(
+                        "new blocklists imported, inserted {inserted} new domains and deleted {deleted} existing domains"
+                    ),
+                    Err(error) => tracing::warn!("couldn't import the new blocklists: {error}"),
+                }
+            });
+        } else {
+            // applies the changes made by another process, like the allow command
+            let blocklist = self.blocklist.clone();
+            tokio::spawn(async move {
+                if let Err(error) = blocklist.reload().await {
+                    tracing::warn!("couldn't reload the blocklists: {error:?}");
+                }
+            });
+        }
+        // the names given with the client command are read again as well
+        let clients = self.clients.clone();
+        let client_service = self.client_service.clone();
+        let configured = config.clients;
+        let reverse = config.dns.reverse.hosts.clone();
+        tokio::spawn(async move {
+            match client_service.names().await {
+                Ok(found) => clients.update(&configured, found, &reverse),
+                Err(error) => tracing::warn!("couldn't reload the client names: {error:?}"),
+            }
+        });
+        super::configure(
+            self.handler.clone(),
+            config.dns,
+            config.records,
+            config.policy,
+            &config.lookup,
+        )
+        .rebuild();
+        // the hostnames of the servers are resolved again in the background
+        let lookup = self.lookup.clone();
+        let router = self.router.clone();
+        let lookup_config = config.lookup;
+        tokio::spawn(async move {
+            lookup.set_servers(lookup_config.servers.clone()).await;
+            tracing::info!("upstream servers: {}", lookup.upstreams().join(", "));
+            router.set_zones(&lookup_config).await;
+        });
+        Ok(())
+    }
+
+    /// Reloads the configuration each time the process receives SIGHUP
+    pub(crate) async fn run(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = match signal(SignalKind::hangup()) {
+            Ok(found) => found,
+            Err(error) => {
+                tracing::warn!(
+                    "unable to listen to SIGHUP, configuration can't be reloaded: {error}"
+                );
+                return;
+            }
+        };
+        while signals.recv().await.is_some() {
+            tracing::info!("reloading configuration from {:?}", self.path);
+            match self.reload() {
+                Ok(_) => tracing::info!(
+                    "configuration reloaded, changes to the listeners, database, cache, query log and api apply on restart"
+                ),
+                Err(error) => {
+                    tracing::warn!("unable to reload configuration, keeping the current one: {error}")
+                }
+            }
+        }
+    }
+}
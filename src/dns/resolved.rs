@@ -0,0 +1,84 @@
+//! Detection of systemd-resolved, which listens on `127.0.0.53:53` on most
+//! linux distributions and prevents donos from binding the DNS port.
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const RESOLVED_CONF: &str = "/etc/systemd/resolved.conf";
+const STUB_ADDRESSES: [Ipv4Addr; 2] = [Ipv4Addr::new(127, 0, 0, 53), Ipv4Addr::new(127, 0, 0, 54)];
+
+/// Checks if the content of a `resolv.conf` points to the systemd-resolved stub
+fn is_stub_resolver(content: &str) -> bool {
+    content
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|value| value.trim().parse::<Ipv4Addr>().ok())
+        .any(|address| STUB_ADDRESSES.contains(&address))
+}
+
+/// Checks if systemd-resolved is the resolver of the machine
+pub fn detect() -> bool {
+    std::fs::read_to_string(RESOLV_CONF)
+        .map(|content| is_stub_resolver(&content))
+        .unwrap_or(false)
+}
+
+/// Checks if binding the address would collide with the systemd-resolved stub listener
+pub fn conflicts(address: &SocketAddr) -> bool {
+    address.port() == 53
+        && match address.ip() {
+            IpAddr::V4(ip) => ip.is_unspecified() || STUB_ADDRESSES.contains(&ip),
+            IpAddr::V6(ip) => ip.is_unspecified(),
+        }
+}
+
+/// Explains how to get rid of the collision with systemd-resolved
+pub fn conflict_hint(address: &SocketAddr) -> String {
+    format!(
+        "address {address} is already used by systemd-resolved, either set `DNSStubListener=no` in {RESOLVED_CONF}, bind donos on a dedicated address (like 127.0.0.2 or the LAN address) with `dns.host` or set `dns.fallback_port`"
+    )
+}
+
+/// Explains how to make systemd-resolved forward its queries to donos
+/// when donos is running on an alternate port.
+pub fn forward_hint(address: &SocketAddr) -> String {
+    let target = if address.ip().is_unspecified() {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, address.port()))
+    } else {
+        *address
+    };
+    format!(
+        "to use donos through systemd-resolved, set `DNS={target}` and `Domains=~.` in the [Resolve] section of {RESOLVED_CONF} and restart it"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    #[test]
+    fn should_detect_stub_resolver() {
+        let content = r#"# This is /run/systemd/resolve/stub-resolv.conf managed by man:systemd-resolved(8).
+nameserver 127.0.0.53
+options edns0 trust-ad
+search .
+"#;
+        assert!(super::is_stub_resolver(content));
+        assert!(!super::is_stub_resolver("nameserver 1.1.1.1\n"));
+        assert!(!super::is_stub_resolver("# nameserver 127.0.0.53\n"));
+    }
+
+    #[test]
+    fn should_detect_conflicting_addresses() {
+        assert!(super::conflicts(&SocketAddr::from(([0, 0, 0, 0], 53))));
+        assert!(super::conflicts(&SocketAddr::from(([127, 0, 0, 53], 53))));
+        assert!(!super::conflicts(&SocketAddr::from(([127, 0, 0, 2], 53))));
+        assert!(!super::conflicts(&SocketAddr::from(([0, 0, 0, 0], 5353))));
+    }
+
+    #[test]
+    fn should_target_localhost_when_unspecified() {
+        let hint = super::forward_hint(&SocketAddr::from(([0, 0, 0, 0], 5353)));
+        assert!(hint.contains("DNS=127.0.0.1:5353"));
+    }
+}
@@ -0,0 +1,249 @@
+//! Zero downtime upgrades, by handing the bound socket over to a new process.
+//!
+//! On SIGUSR2, the running process executes its own binary again with the socket
+//! and tcp listener file descriptors, one of each by listened address, the listener
+//! of the api when enabled, and one end of a unix socket pair. The new process uses the inherited sockets instead of
+//! binding them and notifies the old one when it's ready to handle queries. The old
+//! process then finishes the query it's handling and exits. Open tcp connections are
+//! not drained.
+//!
+//! The sockets passed by systemd with socket activation (`LISTEN_FDS`) are used the same way.
+use std::io::Write;
+use std::net::{TcpListener, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+
+const LISTEN_FD_ENV: &str = "DONOS_LISTEN_FD";
+const LISTEN_TCP_FD_ENV: &str = "DONOS_LISTEN_TCP_FD";
+const LISTEN_API_FD_ENV: &str = "DONOS_LISTEN_API_FD";
+const READY_FD_ENV: &str = "DONOS_READY_FD";
+/// First file descriptor passed by systemd, the udp sockets and tcp listeners following it
+const SYSTEMD_LISTEN_FD: RawFd = 3;
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn take_env_fd(name: &str) -> Option<RawFd> {
+    let value = std::env::var(name).ok()?;
+    std::env::remove_var(name);
+    value.parse().ok()
+}
+
+/// File descriptors of a variable holding a comma separated list
+fn take_env_fds(name: &str) -> Vec<RawFd> {
+    let Ok(value) = std::env::var(name) else {
+        return Vec::new();
+    };
+    std::env::remove_var(name);
+    value
+        .split(',')
+        .filter_map(|item| item.trim().parse().ok())
+        .collect()
+}
+
+fn join_fds(fds: &[RawFd]) -> String {
+    fds.iter()
+        .map(|fd| fd.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Sockets passed by systemd of the given type, like `SOCK_DGRAM`
+fn systemd_listen_fds(kind: libc::c_int) -> Vec<RawFd> {
+    let count = (|| {
+        let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+        let count: RawFd = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+        (pid == std::process::id()).then_some(count)
+    })()
+    .unwrap_or(0);
+    (SYSTEMD_LISTEN_FD..SYSTEMD_LISTEN_FD + count)
+        .filter(|fd| socket_type(*fd) == Some(kind))
+        .collect()
+}
+
+fn socket_type(fd: RawFd) -> Option<libc::c_int> {
+    let mut kind: libc::c_int = 0;
+    let mut size = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: the value and its size are valid during the call
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut kind as *mut libc::c_int).cast(),
+            &mut size,
+        )
+    };
+    (result == 0).then_some(kind)
+}
+
+/// Checks if the process has been started by a previous one to take over its socket
+pub fn is_successor() -> bool {
+    std::env::var_os(LISTEN_FD_ENV).is_some()
+}
+
+/// Sockets handed over by the previous process or by systemd
+pub fn inherited_sockets() -> Vec<UdpSocket> {
+    let mut fds = take_env_fds(LISTEN_FD_ENV);
+    if fds.is_empty() {
+        fds = systemd_listen_fds(libc::SOCK_DGRAM);
+    }
+    fds.into_iter()
+        // SAFETY: the file descriptors have been opened for us by the parent process
+        // and nothing else in this process uses them.
+        .map(|fd| unsafe { UdpSocket::from_raw_fd(fd) })
+        .collect()
+}
+
+/// TCP listeners handed over by the previous process or by systemd
+pub fn inherited_tcp_listeners() -> Vec<TcpListener> {
+    let mut fds = take_env_fds(LISTEN_TCP_FD_ENV);
+    if fds.is_empty() {
+        fds = systemd_listen_fds(libc::SOCK_STREAM);
+    }
+    fds.into_iter()
+        // SAFETY: same as above
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Listener of the api handed over by the previous process
+pub fn inherited_api_listener() -> Option<TcpListener> {
+    let fd = take_env_fd(LISTEN_API_FD_ENV)?;
+    // SAFETY: same as above
+    Some(unsafe { TcpListener::from_raw_fd(fd) })
+}
+
+/// Tells the previous process, if any, that it can stop handling queries
+pub fn notify_ready() {
+    let Some(fd) = take_env_fd(READY_FD_ENV) else {
+        return;
+    };
+    // SAFETY: same as above, the file descriptor is closed when dropped
+    let mut stream = unsafe { std::os::unix::net::UnixStream::from_raw_fd(fd) };
+    if let Err(error) = stream.write_all(b"ready") {
+        tracing::warn!("unable to notify the previous process: {error}");
+    }
+}
+
+fn inheritable(fd: RawFd) -> std::io::Result<()> {
+    // SAFETY: only changes the flags of a file descriptor we own
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Starts a new instance of donos with the sockets and waits for it to be ready.
+///
+/// When it fails, the current process should keep handling the queries.
+pub async fn spawn_successor(
+    sockets: &[RawFd],
+    tcp: &[RawFd],
+    api: Option<RawFd>,
+) -> std::io::Result<()> {
+    let (parent, child) = std::os::unix::net::UnixStream::pair()?;
+    let child_fd = child.as_raw_fd();
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, join_fds(sockets))
+        .env(READY_FD_ENV, child_fd.to_string());
+    if !tcp.is_empty() {
+        command.env(LISTEN_TCP_FD_ENV, join_fds(tcp));
+    }
+    if let Some(fd) = api {
+        command.env(LISTEN_API_FD_ENV, fd.to_string());
+    }
+    let inherited: Vec<RawFd> = sockets
+        .iter()
+        .chain(tcp.iter())
+        .copied()
+        .chain(api)
+        .chain(std::iter::once(child_fd))
+        .collect();
+    // SAFETY: fcntl is async-signal-safe
+    unsafe {
+        command.pre_exec(move || inherited.iter().try_for_each(|fd| inheritable(*fd)));
+    }
+    let mut process = command.spawn()?;
+    drop(child);
+
+    parent.set_nonblocking(true)?;
+    let mut parent = tokio::net::UnixStream::from_std(parent)?;
+    let mut buffer = [0u8; 5];
+    match tokio::time::timeout(READY_TIMEOUT, parent.read_exact(&mut buffer)).await {
+        Ok(Ok(_)) if &buffer == b"ready" => Ok(()),
+        result => {
+            let _ = process.kill();
+            let _ = process.wait();
+            Err(match result {
+                Ok(Err(error)) => error,
+                _ => std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "new process didn't get ready",
+                ),
+            })
+        }
+    }
+}
+
+/// Completes once a successor took over the sockets, after a SIGUSR2
+pub async fn wait_for_handover(sockets: Vec<RawFd>, tcp: Vec<RawFd>, api: Option<RawFd>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined2()) {
+        Ok(found) => found,
+        Err(error) => {
+            tracing::warn!("unable to listen to SIGUSR2, upgrades are disabled: {error}");
+            return futures::future::pending().await;
+        }
+    };
+    while signals.recv().await.is_some() {
+        tracing::info!("starting a new process to hand the sockets over");
+        match spawn_successor(&sockets, &tcp, api).await {
+            Ok(_) => {
+                tracing::info!("new process ready, draining");
+                return;
+            }
+            Err(error) => tracing::error!("unable to upgrade, still running: {error}"),
+        }
+    }
+    futures::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::IntoRawFd;
+
+    #[test]
+    fn should_inherit_sockets_from_env() {
+        let first = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addresses = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        std::env::set_var(
+            super::LISTEN_FD_ENV,
+            super::join_fds(&[first.into_raw_fd(), second.into_raw_fd()]),
+        );
+
+        let inherited = super::inherited_sockets()
+            .into_iter()
+            .map(|socket| socket.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(inherited, addresses);
+        assert!(std::env::var(super::LISTEN_FD_ENV).is_err());
+    }
+
+    #[test]
+    fn should_inherit_the_api_listener_from_env() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::env::set_var(super::LISTEN_API_FD_ENV, listener.into_raw_fd().to_string());
+
+        let inherited = super::inherited_api_listener().unwrap();
+        assert_eq!(inherited.local_addr().unwrap(), address);
+        assert!(super::inherited_api_listener().is_none());
+    }
+}
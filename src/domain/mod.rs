@@ -0,0 +1,81 @@
+use clap::{Args, Subcommand};
+
+use crate::common::domain::normalize;
+use crate::repository::blocklist::{DatabaseBlocklistService, DomainRule};
+
+/// Block or allow domains by hand, whatever the blocklists and the allowlist say
+#[derive(Args, Debug)]
+pub struct Command {
+    #[command(subcommand)]
+    inner: Action,
+}
+
+#[derive(Debug, Subcommand)]
+enum Action {
+    /// Block a domain and its subdomains, even when allowed
+    Block { domain: String },
+    /// Allow a domain and its subdomains, even when in a blocklist
+    Allow { domain: String },
+    /// Remove the rule of a domain, the blocklists applying again
+    Remove { domain: String },
+    /// List the domains blocked or allowed by hand
+    List,
+}
+
+/// Normalized domain, `None` when it can't be a domain name
+fn domain_name(value: &str) -> Option<String> {
+    let normalized = normalize(value);
+    (!normalized.is_empty() && !normalized.contains('*') && !normalized.contains(".."))
+        .then(|| normalized.into_owned())
+}
+
+async fn set_rule(blocklist: &DatabaseBlocklistService, domain: &str, rule: DomainRule) {
+    let Some(domain) = domain_name(domain) else {
+        tracing::error!("{domain:?} is not a domain name");
+        return;
+    };
+    let state = match rule {
+        DomainRule::Block => "blocked",
+        DomainRule::Allow => "allowed",
+    };
+    match blocklist.set_rule(&domain, rule).await {
+        Ok(true) => tracing::info!("{domain} {state}"),
+        Ok(false) => tracing::warn!("{domain} was already {state}"),
+        Err(err) => tracing::error!("couldn't set the rule of {domain}: {err:?}"),
+    }
+}
+
+impl Command {
+    pub async fn run(self, config: crate::config::Config) {
+        let database = config
+            .database
+            .build()
+            .await
+            .expect("unable to connect to database");
+        crate::service::database::migrate(&database)
+            .await
+            .expect("unable to migrate the database");
+
+        let blocklist = config.blocklists.build(database);
+        match self.inner {
+            Action::Block { domain } => set_rule(&blocklist, &domain, DomainRule::Block).await,
+            Action::Allow { domain } => set_rule(&blocklist, &domain, DomainRule::Allow).await,
+            Action::Remove { domain } => {
+                let domain = normalize(&domain);
+                match blocklist.remove_rule(&domain).await {
+                    Ok(true) => tracing::info!("rule of {domain} removed"),
+                    Ok(false) => tracing::warn!("{domain} has no rule"),
+                    Err(err) => tracing::error!("couldn't remove the rule of {domain}: {err:?}"),
+                }
+            }
+            Action::List => match blocklist.rules().await {
+                Ok(list) => {
+                    for (domain, rule) in list {
+                        println!("{rule}\t{domain}");
+                    }
+                }
+                Err(err) => tracing::error!("couldn't list the rules: {err:?}"),
+            },
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Checks that the server answers, for the supervisors like systemd, Docker or Kubernetes.
+use crate::common::source::InternalReason;
+use crate::dns::handler::DnsHandler;
+use clap::Args;
+use donos_parser::buffer::BytePacketBuffer;
+use donos_parser::packet::header::ResponseCode;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// Domain queried to check that the server answers
+    #[serde(default = "Config::default_domain")]
+    pub domain: String,
+    /// Number of milliseconds to wait for the answer
+    #[serde(default = "Config::default_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            domain: Self::default_domain(),
+            timeout: Self::default_timeout(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_domain() -> String {
+        String::from("example.com")
+    }
+
+    pub fn default_timeout() -> u64 {
+        2000
+    }
+}
+
+/// The server is healthy when it resolves the domain, whether it exists or not
+fn is_healthy(code: ResponseCode) -> bool {
+    matches!(code, ResponseCode::NoError | ResponseCode::NameError)
+}
+
+/// Result of a successful check
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Status {
+    pub domain: String,
+    pub code: String,
+    /// Number of milliseconds to get the answer
+    pub duration: u64,
+}
+
+/// Resolves the domain through the pipeline of the running server, for the `/health` endpoint
+pub(crate) struct Probe {
+    handler: DnsHandler,
+    domain: String,
+    timeout: Duration,
+}
+
+impl Probe {
+    pub fn new(handler: DnsHandler, config: &Config) -> Self {
+        Self {
+            handler,
+            domain: config.domain.clone(),
+            timeout: Duration::from_millis(config.timeout),
+        }
+    }
+
+    pub async fn check(&self) -> Result<Status, String> {
+        let started = Instant::now();
+        let resolved =
+            self.handler
+                .resolve_internal(InternalReason::HealthCheck, &self.domain, QueryType::A);
+        let (packet, _) = match tokio::time::timeout(self.timeout, resolved).await {
+            Ok(Ok(found)) => found,
+            Ok(Err(error)) => return Err(format!("unable to resolve {}: {error:?}", self.domain)),
+            Err(_) => return Err(format!("no answer for {} in time", self.domain)),
+        };
+        let code = packet.header.response_code;
+        if !is_healthy(code) {
+            return Err(format!("{} answered with {code:?}", self.domain));
+        }
+        Ok(Status {
+            domain: self.domain.clone(),
+            code: format!("{code:?}"),
+            duration: started.elapsed().as_millis() as u64,
+        })
+    }
+}
+
+/// Send a query to the running server and exit with an error when it doesn't answer,
+/// or answers SERVFAIL
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Address of the server, defaults to the dns listener of the configuration
+    #[arg(long)]
+    address: Option<SocketAddr>,
+    /// Domain to query, defaults to the one of the configuration
+    #[arg(long)]
+    domain: Option<String>,
+}
+
+/// Local address to reach a listener, when it listens on all the interfaces
+fn local(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    address
+}
+
+async fn query(address: SocketAddr, domain: &str, timeout: Duration) -> Result<DnsPacket, String> {
+    let local = match address {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local)
+        .await
+        .map_err(|error| format!("unable to open a socket: {error}"))?;
+    let id = std::process::id() as u16;
+    let request = DnsPacket::query(id, Question::new(domain.to_string(), QueryType::A));
+    let buffer = request
+        .create_buffer()
+        .map_err(|error| format!("unable to write the query: {error:?}"))?;
+    socket
+        .send_to(&buffer.buf[..buffer.pos], address)
+        .await
+        .map_err(|error| format!("unable to send the query to {address}: {error}"))?;
+    let receive = async {
+        loop {
+            let mut response = BytePacketBuffer::default();
+            let (_, from) = socket
+                .recv_from(&mut response.buf)
+                .await
+                .map_err(|error| format!("unable to read the answer of {address}: {error}"))?;
+            if from != address {
+                continue;
+            }
+            match DnsPacket::try_from(response) {
+                Ok(packet) if packet.header.id == id => return Ok(packet),
+                Ok(_) => continue,
+                Err(error) => return Err(format!("invalid answer from {address}: {error:?}")),
+            }
+        }
+    };
+    tokio::time::timeout(timeout, receive)
+        .await
+        .unwrap_or_else(|_| Err(format!("no answer from {address} in time")))
+}
+
+impl Command {
+    pub async fn run(self, config: crate::config::Config) {
+        let domain = self
+            .domain
+            .unwrap_or_else(|| config.healthcheck.domain.clone());
+        let timeout = Duration::from_millis(config.healthcheck.timeout);
+        // the server listens on the fallback port when the main one is taken
+        let addresses = match self.address {
+            Some(address) => vec![address],
+            None => config
+                .dns
+                .addresses()
+                .into_iter()
+                .chain(config.dns.fallback_address())
+                .map(local)
+                .collect(),
+        };
+        let mut failure = String::new();
+        for address in addresses {
+            let started = Instant::now();
+            match query(address, &domain, timeout).await {
+                Ok(packet) if is_healthy(packet.header.response_code) => {
+                    tracing::info!(
+                        "{address} answered {domain} with {:?} in {} ms",
+                        packet.header.response_code,
+                        started.elapsed().as_millis()
+                    );
+                    return;
+                }
+                Ok(packet) => {
+                    failure = format!(
+                        "{address} answered {domain} with {:?}",
+                        packet.header.response_code
+                    );
+                }
+                Err(error) => failure = error,
+            }
+        }
+        tracing::error!("{failure}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Probe};
+    use crate::dns::handler::DnsHandler;
+    use crate::repository::blocklist::MemoryBlocklistService;
+    use crate::repository::cache::MockCacheService;
+    use crate::repository::lookup::MockLookupService;
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::question::Question;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn should_fail_when_upstream_fails() {
+        let config = Config::default();
+        let answer = DnsPacket::new(Header::response(0))
+            .with_question(Question::new("example.com".into(), QueryType::A));
+        let healthy = Probe::new(
+            DnsHandler::new(
+                Arc::new(MemoryBlocklistService::default()),
+                Arc::new(MockCacheService::default()),
+                Arc::new(MockLookupService::default().with_query(
+                    "example.com",
+                    QueryType::A,
+                    answer,
+                )),
+            ),
+            &config,
+        );
+        let status = healthy.check().await.unwrap();
+        assert_eq!(status.domain, "example.com");
+        assert_eq!(status.code, "NoError");
+
+        let failing = Probe::new(
+            DnsHandler::new(
+                Arc::new(MemoryBlocklistService::default()),
+                Arc::new(MockCacheService::default()),
+                Arc::new(MockLookupService::default()),
+            ),
+            &config,
+        );
+        assert_eq!(
+            failing.check().await.unwrap_err(),
+            "example.com answered with ServerFailure"
+        );
+    }
+}
@@ -1,6 +1,14 @@
+mod api;
 mod blocklist;
+mod cache;
+mod capture;
+mod client;
 mod common;
 mod dns;
+mod domain;
+mod healthcheck;
+mod query;
+mod stats;
 
 mod config;
 mod repository;
@@ -41,10 +49,23 @@ pub struct Args {
 
 impl Args {
     pub async fn run(self) {
+        let inner = match self.inner {
+            // the configuration can be invalid, it's loaded by the command itself
+            Commands::Config(inner) => return inner.run(&self.config_path),
+            other => other,
+        };
         let config = crate::config::Config::load(&self.config_path);
-        match self.inner {
+        match inner {
             Commands::Blocklist(inner) => inner.run(config).await,
-            Commands::Dns(inner) => inner.run(config).await,
+            Commands::Cache(inner) => inner.run(config).await,
+            Commands::Capture(inner) => inner.run(config).await,
+            Commands::Client(inner) => inner.run(config).await,
+            Commands::Config(_) => unreachable!(),
+            Commands::Dns(inner) => inner.run(config, self.config_path).await,
+            Commands::Domain(inner) => inner.run(config).await,
+            Commands::Healthcheck(inner) => inner.run(config).await,
+            Commands::Query(inner) => inner.run(config).await,
+            Commands::Stats(inner) => inner.run(config).await,
         }
     }
 }
@@ -52,7 +73,15 @@ impl Args {
 #[derive(Debug, Subcommand)]
 enum Commands {
     Blocklist(crate::blocklist::Command),
+    Cache(crate::cache::Command),
+    Capture(crate::capture::Command),
+    Client(crate::client::Command),
+    Config(crate::config::Command),
     Dns(crate::dns::Command),
+    Domain(crate::domain::Command),
+    Healthcheck(crate::healthcheck::Command),
+    Query(crate::query::Command),
+    Stats(crate::stats::Command),
 }
 
 #[tokio::main]
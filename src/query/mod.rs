@@ -0,0 +1,128 @@
+use crate::dns::handler::DnsHandler;
+use crate::repository::lookup::{LookupService, Mode, ServerConfig};
+use crate::repository::recursive::RecursiveLookupService;
+use crate::repository::routing::RoutingLookupService;
+use clap::Args;
+use donos_parser::packet::question::Question;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Resolve a name like a client of donos would, going through the records, the blocklists
+/// and the upstream servers, then print the response and how it was answered.
+///
+/// The cache starts empty, the answers of a running server might come from its cache instead.
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Name to resolve
+    name: String,
+    /// Type of the query, like A, AAAA, MX or TYPE65
+    #[arg(default_value = "A")]
+    qtype: QueryType,
+    /// Upstream server used instead of the configured ones, like 1.1.1.1 or [2606:4700::1111]:53
+    #[arg(long)]
+    server: Option<String>,
+    /// Resolve the name from the root servers instead of forwarding it
+    #[arg(long)]
+    recursive: bool,
+    /// Address of the client sending the query, deciding the blocklists of its group
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    client: IpAddr,
+}
+
+fn exit_with<E: std::fmt::Display>(message: &str, error: E) -> ! {
+    tracing::error!("{message}: {error}");
+    std::process::exit(1)
+}
+
+impl Command {
+    pub async fn run(self, mut config: crate::config::Config) {
+        let database = match config.database.build().await {
+            Ok(found) => found,
+            Err(error) => exit_with("unable to open database", error),
+        };
+        if let Err(error) = crate::service::database::migrate(&database).await {
+            exit_with("unable to run database migrations", error);
+        }
+        let blocklist = Arc::new(config.blocklists.build(database).with_groups(config.groups));
+        if let Err(error) = blocklist.load().await {
+            exit_with("unable to load blocked domains", error);
+        }
+        let cache = match config.cache.build().await {
+            Ok(found) => Arc::new(found),
+            Err(error) => exit_with("unable to build cache service", error),
+        };
+
+        // the lookup socket of a running server must not be taken
+        let lookup = &mut config.lookup;
+        lookup.address.set_port(0);
+        lookup.probe.enabled = false;
+        if let Some(server) = self.server.as_deref() {
+            lookup.servers = vec![ServerConfig::from(server)];
+            lookup.forward.clear();
+        }
+        let recursive = self.recursive || lookup.mode == Mode::Recursive;
+        let base: Arc<dyn LookupService + Send + Sync> = if recursive {
+            Arc::new(RecursiveLookupService::new(lookup))
+        } else {
+            match lookup.build().await {
+                Ok(found) => Arc::new(found),
+                Err(error) => exit_with("unable to reach the upstream servers", error),
+            }
+        };
+        let router = Arc::new(RoutingLookupService::new(base));
+        router.set_zones(lookup).await;
+        let validation = std::mem::take(&mut lookup.validation);
+        let resolver: Arc<dyn LookupService + Send + Sync> = if validation.enabled {
+            match validation.build(router) {
+                Ok(found) => Arc::new(found),
+                Err(error) => exit_with("unable to build the dnssec validation", error),
+            }
+        } else {
+            router
+        };
+
+        let handler = crate::dns::configure(
+            DnsHandler::new(blocklist, cache, resolver),
+            config.dns,
+            config.records,
+            config.policy,
+            &config.lookup,
+        );
+        let request = DnsPacket::query(rand_id(), Question::new(self.name.clone(), self.qtype));
+        let started = Instant::now();
+        let (response, provenance) = match handler
+            .resolve(SocketAddr::new(self.client, 0), &request)
+            .await
+        {
+            Ok(found) => found,
+            Err(error) => exit_with("unable to resolve the query", error),
+        };
+        let elapsed = started.elapsed();
+
+        print!("{response}");
+        println!();
+        println!(";; answered from: {provenance}");
+        println!(";; query time: {} ms", elapsed.as_millis());
+        if recursive {
+            println!(";; servers: root servers");
+        } else {
+            let servers = config
+                .lookup
+                .servers
+                .iter()
+                .map(|server| server.address().to_string())
+                .collect::<Vec<_>>();
+            println!(";; servers: {}", servers.join(", "));
+        }
+    }
+}
+
+fn rand_id() -> u16 {
+    use std::hash::{BuildHasher, Hasher};
+
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u16
+}
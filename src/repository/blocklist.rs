@@ -1,20 +1,136 @@
-use donos_blocklist_loader::BlocklistKind;
-use sqlx::{Pool, Sqlite};
+use donos_blocklist_loader::{BlocklistKind, BlocklistStream};
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use sqlx::{Connection, Pool, QueryBuilder, Sqlite};
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     error::Error,
-    net::SocketAddr,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
 };
 
+use crate::common::schedule::{is_active, Clock, LocalTime, SystemClock, Window};
 use crate::service::database::Transaction;
 
-#[derive(Debug, Clone, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct BlocklistItem {
     pub url: String,
     pub kind: BlocklistKind,
+    /// Whether the domains of the blocklist are blocked, it is imported anyway
+    #[serde(default = "BlocklistItem::default_enabled")]
+    pub enabled: bool,
+    /// Time windows during which the blocklist applies, like `mon-fri 09:00-17:00`,
+    /// all the time without any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<Window>,
 }
 
-#[derive(Debug, Default, serde::Deserialize)]
+impl BlocklistItem {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    /// Whether the blocklist is enabled and scheduled at the given time
+    fn applies_at(&self, time: LocalTime) -> bool {
+        self.enabled && is_active(&self.schedule, time)
+    }
+}
+
+/// Blocklist imported in database, with the number of domains it blocks
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ImportedBlocklist {
+    pub url: String,
+    pub description: String,
+    pub last_refresh_at: i64,
+    pub domains: i64,
+    pub enabled: bool,
+}
+
+/// Domains an import would add to and remove from a blocklist
+#[derive(Debug, Default)]
+pub struct BlocklistDiff {
+    pub added: u64,
+    pub removed: u64,
+    /// First added domains, in alphabetical order
+    pub added_samples: Vec<String>,
+    /// First removed domains, in alphabetical order
+    pub removed_samples: Vec<String>,
+}
+
+/// Blocklist containing a checked domain
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlocklistMatch {
+    /// `None` for the domains imported without blocklist
+    pub url: Option<String>,
+    /// Name of the blocklist in the configuration
+    pub name: Option<String>,
+    /// Enabled in database and in the configuration, and within its schedule
+    pub enabled: bool,
+    /// Applied to the client, given its group
+    pub applied: bool,
+}
+
+/// Domain blocked or allowed by hand, with its subdomains, whatever the blocklists
+/// and the allowlist say
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DomainRule {
+    Block,
+    Allow,
+}
+
+impl DomainRule {
+    fn from_blocked(blocked: bool) -> Self {
+        if blocked {
+            Self::Block
+        } else {
+            Self::Allow
+        }
+    }
+}
+
+impl Display for DomainRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Block => "block",
+            Self::Allow => "allow",
+        })
+    }
+}
+
+/// Domain itself and its parents, from the closest one
+fn parents(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |current| {
+        current.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+/// Why a domain is blocked, or not
+#[derive(Debug)]
+pub struct DomainCheck {
+    pub blocklists: Vec<BlocklistMatch>,
+    /// Patterns of the allowlist matching the domain
+    pub allowed_by: Vec<String>,
+    /// Closest rule set by hand for the domain or one of its parents
+    pub rule: Option<(String, DomainRule)>,
+}
+
+impl DomainCheck {
+    pub fn is_blocked(&self) -> bool {
+        if let Some((_, rule)) = self.rule {
+            return rule == DomainRule::Block;
+        }
+        self.allowed_by.is_empty()
+            && self
+                .blocklists
+                .iter()
+                .any(|found| found.enabled && found.applied)
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     #[serde(flatten)]
     pub inner: BTreeMap<String, BlocklistItem>,
@@ -26,32 +142,861 @@ impl Config {
     }
 }
 
+/// Name of the group used for the clients that don't belong to any other group
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Maximum number of blocklists told apart in memory, one bit each
+const MAX_BLOCKLISTS: usize = u64::BITS as usize;
+/// Number of domains inserted by statement when importing a blocklist
+const INSERT_BATCH_SIZE: usize = 500;
+/// Delay between two checks for the changes made by another process, like the import command
+const CHANGES_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Clients sharing the same blocklists, like the devices of the kids
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ClientGroup {
+    /// Networks of the clients, like `192.168.1.0/28` or `192.168.1.12/32`
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+    /// Names of the blocklists applied to the clients of the group
+    #[serde(default)]
+    pub blocklists: Vec<String>,
+    /// Sends the clients of the group to the safe search of the search engines
+    #[serde(default)]
+    pub safe_search: bool,
+}
+
+/// Domain that is never blocked, `*.example.com` allowing only the subdomains of `example.com`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowPattern(String);
+
+impl AllowPattern {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Patterns that would allow the domain: the domain itself and the wildcards of its parents
+    fn candidates(domain: &str) -> Vec<String> {
+        let mut result = vec![domain.to_string()];
+        let mut rest = domain;
+        while let Some((_, parent)) = rest.split_once('.') {
+            result.push(format!("*.{parent}"));
+            rest = parent;
+        }
+        result
+    }
+}
+
+impl Display for AllowPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidAllowPattern(String);
+
+impl Display for InvalidAllowPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?} is neither a domain nor a wildcard like *.example.com",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidAllowPattern {}
+
+impl FromStr for AllowPattern {
+    type Err = InvalidAllowPattern;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let normalized = crate::common::domain::normalize(value);
+        let domain = normalized.strip_prefix("*.").unwrap_or(&normalized);
+        if domain.is_empty() || domain.contains('*') || domain.split('.').any(str::is_empty) {
+            return Err(InvalidAllowPattern(value.to_string()));
+        }
+        Ok(Self(normalized.into_owned()))
+    }
+}
+
+/// Group with the urls of its blocklists, as they are stored in the database
+#[derive(Debug, Clone)]
+struct ResolvedGroup {
+    name: String,
+    clients: Vec<IpNet>,
+    urls: Vec<String>,
+    safe_search: bool,
+}
+
+/// Blocked domains and allowlist loaded from the database, so that the queries
+/// are answered without touching it
+#[derive(Debug, Default)]
+struct Snapshot {
+    /// Blocklists blocking each domain, as a mask of their indexes in `urls`
+    domains: HashMap<Box<str>, u64>,
+    /// Blocklists blocking the subdomains of each domain, imported as `*.example.com`
+    suffixes: HashMap<Box<str>, u64>,
+    urls: Vec<String>,
+    /// Blocklists disabled in database
+    disabled: u64,
+    allowed: HashSet<String>,
+    /// Domains blocked, or allowed, by hand
+    rules: HashMap<String, bool>,
+    /// Fingerprint of the database when loaded, to detect the changes
+    version: String,
+}
+
+impl Snapshot {
+    fn mask(&self, mut selected: impl FnMut(&str) -> bool) -> u64 {
+        self.urls
+            .iter()
+            .enumerate()
+            .filter(|(_, url)| selected(url))
+            .fold(0, |mask, (index, _)| mask | (1 << index))
+    }
+
+    /// Whether the domain is blocked by the given blocklists, `None` meaning all of them,
+    /// unless they're disabled in database or in the configuration
+    fn is_blocked(&self, applied: Option<u64>, disabled: u64, domain: &str) -> bool {
+        if let Some(blocked) = parents(domain).find_map(|parent| self.rules.get(parent)) {
+            tracing::debug!("domain with a rule");
+            return *blocked;
+        }
+        if AllowPattern::candidates(domain)
+            .iter()
+            .any(|candidate| self.allowed.contains(candidate))
+        {
+            tracing::debug!("domain in the allowlist");
+            return false;
+        }
+        let enabled = !(self.disabled | disabled);
+        let suffixes = parents(domain)
+            .skip(1)
+            .filter_map(|parent| self.suffixes.get(parent));
+        self.domains
+            .get(domain)
+            .into_iter()
+            .chain(suffixes)
+            .any(|mask| match applied {
+                // the domains without a known blocklist can't be disabled
+                None => *mask == 0 || mask & enabled != 0,
+                Some(applied) => mask & applied & enabled != 0,
+            })
+    }
+
+    /// Number of distinct entries, the domains and the wildcards of their subdomains
+    fn len(&self) -> usize {
+        self.domains.len() + self.suffixes.len()
+    }
+}
+
 #[async_trait::async_trait]
 pub trait BlocklistService {
     async fn is_blocked(&self, origin: &SocketAddr, domain: &str) -> Result<bool, Box<dyn Error>>;
     async fn import(&self) -> Result<(u64, u64), Box<dyn Error>>;
+    /// Whether the client belongs to a group with the safe search enforced
+    fn is_safe_search(&self, _origin: &SocketAddr) -> bool {
+        false
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Blocklists and groups of the configuration, replaced when it's reloaded
+#[derive(Debug, Default)]
+struct Lists {
+    items: BTreeMap<String, BlocklistItem>,
+    groups: Vec<ResolvedGroup>,
+}
+
+/// Blocklists of the groups and the ones disabled by the configuration, as masks of
+/// a snapshot, for the queries not to go through the urls and the schedules
+#[derive(Debug)]
+struct Masks {
+    /// Snapshot giving the indexes of the blocklists
+    snapshot: Weak<Snapshot>,
+    /// Value of `Clock::minute` when computed
+    minute: u64,
+    /// Disabled in the configuration or out of their schedule
+    disabled: u64,
+    /// Blocklists of each group, in the same order as `Lists::groups`
+    groups: Vec<u64>,
+    default: Option<u64>,
+}
+
+impl Masks {
+    fn new(snapshot: &Arc<Snapshot>, lists: &Lists, now: LocalTime, minute: u64) -> Self {
+        let disabled = snapshot.mask(|url| {
+            lists
+                .items
+                .values()
+                .any(|item| item.url == url && !item.applies_at(now))
+        });
+        let groups: Vec<u64> = lists
+            .groups
+            .iter()
+            .map(|group| snapshot.mask(|url| group.urls.iter().any(|item| item == url)))
+            .collect();
+        let default = lists
+            .groups
+            .iter()
+            .zip(groups.iter())
+            .find(|(group, _)| group.name == DEFAULT_GROUP)
+            .map(|(_, mask)| *mask);
+        Self {
+            snapshot: Arc::downgrade(snapshot),
+            minute,
+            disabled,
+            groups,
+            default,
+        }
+    }
+
+    fn is_valid(&self, snapshot: &Arc<Snapshot>, minute: u64) -> bool {
+        self.minute == minute && std::ptr::eq(self.snapshot.as_ptr(), Arc::as_ptr(snapshot))
+    }
+
+    /// Blocklists applied to the client, the same way as `client_groups`
+    fn applied(&self, lists: &Lists, client: IpAddr) -> Option<u64> {
+        let client = client.to_canonical();
+        lists
+            .groups
+            .iter()
+            .zip(self.groups.iter())
+            .filter(|(group, _)| group.clients.iter().any(|net| net.contains(&client)))
+            .map(|(_, mask)| *mask)
+            .reduce(|left, right| left | right)
+            .or(self.default)
+    }
+}
+
+/// Resolves the blocklists of the groups to their urls
+fn resolve_groups(
+    items: &BTreeMap<String, BlocklistItem>,
+    groups: BTreeMap<String, ClientGroup>,
+) -> Vec<ResolvedGroup> {
+    groups
+        .into_iter()
+        .map(|(name, group)| {
+            let urls = group
+                .blocklists
+                .iter()
+                .filter_map(|list| match items.get(list) {
+                    Some(item) => Some(item.url.clone()),
+                    None => {
+                        tracing::warn!("unknown blocklist {list:?} in group {name:?}");
+                        None
+                    }
+                })
+                .collect();
+            ResolvedGroup {
+                name,
+                clients: group.clients,
+                urls,
+                safe_search: group.safe_search,
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone)]
 pub struct DatabaseBlocklistService {
-    #[allow(dead_code)]
     database: Pool<Sqlite>,
-    items: BTreeMap<String, BlocklistItem>,
+    lists: Arc<RwLock<Lists>>,
+    /// Loaded on the first query, or when the server starts
+    snapshot: Arc<RwLock<Option<Arc<Snapshot>>>>,
+    /// Held while loading, so that an older snapshot never replaces a newer one
+    loading: Arc<tokio::sync::Mutex<()>>,
+    /// Time checked against the schedules of the blocklists
+    clock: Arc<dyn Clock + Send + Sync>,
+    /// Computed again when the snapshot, the configuration or the minute change,
+    /// locked after `lists`
+    masks: Arc<RwLock<Option<Masks>>>,
+}
+
+impl std::fmt::Debug for DatabaseBlocklistService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatabaseBlocklistService")
+            .field("lists", &self.lists)
+            .finish_non_exhaustive()
+    }
 }
 
 impl DatabaseBlocklistService {
     pub fn new(items: BTreeMap<String, BlocklistItem>, database: Pool<Sqlite>) -> Self {
-        Self { items, database }
+        Self {
+            database,
+            lists: Arc::new(RwLock::new(Lists {
+                items,
+                groups: Vec::new(),
+            })),
+            snapshot: Arc::default(),
+            loading: Arc::default(),
+            clock: Arc::new(SystemClock),
+            masks: Arc::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock + Send + Sync>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn current(&self) -> Option<Arc<Snapshot>> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Fingerprint of the blocklists, the allowlist and the rules, changing when one of them changes
+    async fn version(&self) -> Result<String, sqlx::Error> {
+        sqlx::query_scalar(
+            r#"SELECT (SELECT count(id) || ':' || coalesce(max(id), 0) FROM allowed_domains)
+    || '/' || (SELECT count(id) || ':' || coalesce(sum(last_refresh_at), 0) || ':' || coalesce(sum(enabled * id), 0) FROM blocklists)
+    || '/' || (SELECT count(id) || ':' || coalesce(max(id), 0) || ':' || coalesce(sum(blocked * id), 0) FROM domain_rules)"#,
+        )
+        .fetch_one(&self.database)
+        .await
+    }
+
+    /// Reads the blocked domains, the allowlist and the rules, to be called with the loading lock
+    async fn load_snapshot(&self) -> Result<Arc<Snapshot>, sqlx::Error> {
+        let started = std::time::Instant::now();
+        let version = self.version().await?;
+        let blocklists: Vec<(i64, String, bool)> =
+            sqlx::query_as("SELECT id, url, enabled FROM blocklists ORDER BY id")
+                .fetch_all(&self.database)
+                .await?;
+        if blocklists.len() > MAX_BLOCKLISTS {
+            tracing::warn!(
+                "only the first {MAX_BLOCKLISTS} of the {} blocklists are applied to the groups",
+                blocklists.len()
+            );
+        }
+        let indexes: HashMap<i64, usize> = blocklists
+            .iter()
+            .take(MAX_BLOCKLISTS)
+            .enumerate()
+            .map(|(index, (id, _, _))| (*id, index))
+            .collect();
+        let disabled = blocklists
+            .iter()
+            .take(MAX_BLOCKLISTS)
+            .enumerate()
+            .filter(|(_, (_, _, enabled))| !enabled)
+            .fold(0, |mask, (index, _)| mask | (1 << index));
+
+        let mut domains: HashMap<Box<str>, u64> = HashMap::new();
+        let mut suffixes: HashMap<Box<str>, u64> = HashMap::new();
+        let mut rows = sqlx::query_as::<_, (String, Option<i64>)>(
+            "SELECT domain, blocklist_id FROM blocked_domains",
+        )
+        .fetch(&self.database);
+        while let Some((domain, blocklist_id)) = rows.try_next().await? {
+            let bit = blocklist_id
+                .and_then(|id| indexes.get(&id))
+                .map_or(0, |index| 1 << index);
+            let entry = match domain.strip_prefix("*.") {
+                Some(parent) => suffixes.entry(parent.into()),
+                None => domains.entry(domain.into_boxed_str()),
+            };
+            *entry.or_default() |= bit;
+        }
+        drop(rows);
+        domains.shrink_to_fit();
+        suffixes.shrink_to_fit();
+
+        let allowed = self.allowed().await?.into_iter().collect();
+        let rules = self
+            .rules()
+            .await?
+            .into_iter()
+            .map(|(domain, rule)| (domain, rule == DomainRule::Block))
+            .collect();
+        let snapshot = Arc::new(Snapshot {
+            domains,
+            suffixes,
+            urls: blocklists
+                .into_iter()
+                .take(MAX_BLOCKLISTS)
+                .map(|(_, url, _)| url)
+                .collect(),
+            disabled,
+            allowed,
+            rules,
+            version,
+        });
+        tracing::debug!(
+            "loaded {} blocked domains in {:?}",
+            snapshot.len(),
+            started.elapsed()
+        );
+        *self.snapshot.write().unwrap() = Some(snapshot.clone());
+        Ok(snapshot)
+    }
+
+    async fn snapshot(&self) -> Result<Arc<Snapshot>, sqlx::Error> {
+        if let Some(found) = self.current() {
+            return Ok(found);
+        }
+        let _loading = self.loading.lock().await;
+        match self.current() {
+            Some(found) => Ok(found),
+            None => self.load_snapshot().await,
+        }
+    }
+
+    /// Loads the blocked domains in memory, returns the number of distinct domains
+    pub async fn load(&self) -> Result<u64, sqlx::Error> {
+        let _loading = self.loading.lock().await;
+        let snapshot = self.load_snapshot().await?;
+        Ok(snapshot.len() as u64)
+    }
+
+    /// Loads again the blocked domains and the allowlist, so that their changes apply
+    /// to the next queries. Nothing is done when they were never loaded, like in the commands.
+    pub async fn reload(&self) -> Result<(), sqlx::Error> {
+        let _loading = self.loading.lock().await;
+        if self.current().is_some() {
+            tracing::debug!("reloading blocklist state");
+            self.load_snapshot().await?;
+        }
+        Ok(())
+    }
+
+    /// Reloads the blocked domains when another process changes them, until the service is dropped
+    pub async fn watch_changes(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CHANGES_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(current) = self.current() else {
+                continue;
+            };
+            match self.version().await {
+                Ok(version) if version == current.version => {}
+                Ok(_) => {
+                    tracing::info!("blocklists changed, reloading them");
+                    if let Err(error) = self.reload().await {
+                        tracing::warn!("unable to reload blocklists: {error:?}");
+                    }
+                }
+                Err(error) => tracing::warn!("unable to check the blocklists changes: {error:?}"),
+            }
+        }
+    }
+
+    /// Applies only the blocklists of their groups to the clients. Without any group,
+    /// or when a client belongs to none of them and there is no default group,
+    /// all the blocklists apply.
+    pub fn with_groups(self, groups: BTreeMap<String, ClientGroup>) -> Self {
+        {
+            let mut lists = self.lists.write().unwrap();
+            lists.groups = resolve_groups(&lists.items, groups);
+            *self.masks.write().unwrap() = None;
+        }
+        self
+    }
+
+    /// Replaces the blocklists and the groups with the ones of a reloaded configuration,
+    /// returns true when some blocklists were added or changed and need to be imported
+    pub fn update(
+        &self,
+        items: BTreeMap<String, BlocklistItem>,
+        groups: BTreeMap<String, ClientGroup>,
+    ) -> bool {
+        let groups = resolve_groups(&items, groups);
+        let changed = {
+            let mut lists = self.lists.write().unwrap();
+            let changed = items.iter().any(|(name, item)| {
+                lists
+                    .items
+                    .get(name)
+                    .is_none_or(|previous| previous.url != item.url || previous.kind != item.kind)
+            });
+            *lists = Lists { items, groups };
+            *self.masks.write().unwrap() = None;
+            changed
+        };
+        changed
+    }
+
+    /// Url of a blocklist of the configuration
+    pub fn url(&self, name: &str) -> Option<String> {
+        let lists = self.lists.read().unwrap();
+        lists.items.get(name).map(|item| item.url.clone())
+    }
+
+    /// Blocklists of the snapshot applied to the client, `None` meaning all of them,
+    /// and the ones disabled in the configuration or out of their schedule
+    fn masks(&self, snapshot: &Arc<Snapshot>, client: IpAddr) -> (Option<u64>, u64) {
+        let minute = self.clock.minute();
+        let lists = self.lists.read().unwrap();
+        if let Some(masks) = self
+            .masks
+            .read()
+            .unwrap()
+            .as_ref()
+            .filter(|masks| masks.is_valid(snapshot, minute))
+        {
+            return (masks.applied(&lists, client), masks.disabled);
+        }
+        let masks = Masks::new(snapshot, &lists, self.clock.now(), minute);
+        let result = (masks.applied(&lists, client), masks.disabled);
+        *self.masks.write().unwrap() = Some(masks);
+        result
+    }
+
+    /// Enables or disables an imported blocklist, returns false if it wasn't imported
+    pub async fn set_enabled(&self, url: &str, enabled: bool) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE blocklists SET enabled = $2 WHERE url = $1")
+            .bind(url)
+            .bind(enabled)
+            .execute(&self.database)
+            .await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Groups of the client, the default one when it belongs to none of them,
+    /// `None` when there is no default group either
+    fn client_groups(lists: &Lists, client: IpAddr) -> Option<Vec<&ResolvedGroup>> {
+        let client = client.to_canonical();
+        let groups: Vec<_> = lists
+            .groups
+            .iter()
+            .filter(|group| group.clients.iter().any(|net| net.contains(&client)))
+            .collect();
+        if !groups.is_empty() {
+            return Some(groups);
+        }
+        lists
+            .groups
+            .iter()
+            .find(|group| group.name == DEFAULT_GROUP)
+            .map(|group| vec![group])
+    }
+
+    /// Urls of the blocklists applied to the client, `None` meaning all of them
+    fn blocklist_urls(&self, client: IpAddr) -> Option<BTreeSet<String>> {
+        let lists = self.lists.read().unwrap();
+        Self::client_groups(&lists, client).map(|groups| {
+            groups
+                .into_iter()
+                .flat_map(|group| group.urls.iter().cloned())
+                .collect()
+        })
+    }
+
+    /// Allows the domains matching the pattern, returns false if it was already allowed
+    pub async fn allow(&self, pattern: &AllowPattern) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"INSERT INTO allowed_domains (pattern, created_at)
+VALUES ($1, UNIXEPOCH())
+ON CONFLICT (pattern) DO NOTHING"#,
+        )
+        .bind(pattern.as_str())
+        .execute(&self.database)
+        .await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes the pattern from the allowlist, returns false if it wasn't there
+    pub async fn disallow(&self, pattern: &AllowPattern) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM allowed_domains WHERE pattern = $1")
+            .bind(pattern.as_str())
+            .execute(&self.database)
+            .await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn allowed(&self) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT pattern FROM allowed_domains ORDER BY pattern")
+            .fetch_all(&self.database)
+            .await
+    }
+
+    /// Blocks or allows the normalized domain by hand, returns false if it already had this rule
+    pub async fn set_rule(&self, domain: &str, rule: DomainRule) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"INSERT INTO domain_rules (domain, blocked, created_at)
+VALUES ($1, $2, UNIXEPOCH())
+ON CONFLICT (domain) DO UPDATE SET blocked = excluded.blocked, created_at = excluded.created_at
+WHERE blocked != excluded.blocked"#,
+        )
+        .bind(domain)
+        .bind(rule == DomainRule::Block)
+        .execute(&self.database)
+        .await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Removes the rule of the domain, returns false if it had none
+    pub async fn remove_rule(&self, domain: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM domain_rules WHERE domain = $1")
+            .bind(domain)
+            .execute(&self.database)
+            .await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Domains blocked or allowed by hand
+    pub async fn rules(&self) -> Result<Vec<(String, DomainRule)>, sqlx::Error> {
+        let rows: Vec<(String, bool)> =
+            sqlx::query_as("SELECT domain, blocked FROM domain_rules ORDER BY domain")
+                .fetch_all(&self.database)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(domain, blocked)| (domain, DomainRule::from_blocked(blocked)))
+            .collect())
+    }
+
+    /// Blocklists imported in database, including the ones not in the configuration anymore
+    pub async fn list(&self) -> Result<Vec<ImportedBlocklist>, sqlx::Error> {
+        let rows: Vec<(String, String, i64, i64, bool)> = sqlx::query_as(
+            r#"SELECT blocklists.url, blocklists.description, blocklists.last_refresh_at, count(blocked_domains.id), blocklists.enabled
+FROM blocklists
+LEFT JOIN blocked_domains ON blocked_domains.blocklist_id = blocklists.id
+GROUP BY blocklists.id
+ORDER BY blocklists.url"#,
+        )
+        .fetch_all(&self.database)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(url, description, last_refresh_at, domains, enabled)| ImportedBlocklist {
+                    url,
+                    description,
+                    last_refresh_at,
+                    domains,
+                    enabled,
+                },
+            )
+            .collect())
+    }
+
+    /// Loads a single blocklist and imports its domains, returns how many were inserted and deleted
+    pub async fn add(
+        &self,
+        name: &str,
+        item: &BlocklistItem,
+    ) -> Result<(u64, u64), Box<dyn Error>> {
+        let stream = donos_blocklist_loader::BlocklistLoader
+            .stream(&item.url, item.kind)
+            .await?;
+        let mut tx = self.database.begin().await?;
+        let description = format!("{name} blocklist of {:?} kind", item.kind);
+        let counts = import_list(&mut tx, &item.url, &description, stream)
+            .await
+            .map_err(|error| error as Box<dyn Error>)?;
+        tx.commit().await?;
+        self.reload().await?;
+        Ok(counts)
+    }
+
+    /// Loads the configured blocklists and compares them with the database, without
+    /// changing it, keeping up to `samples` domains of each change
+    pub async fn diff(
+        &self,
+        samples: u32,
+    ) -> Result<Vec<(String, Result<BlocklistDiff, Box<dyn Error + Send + Sync>>)>, sqlx::Error>
+    {
+        let loader = donos_blocklist_loader::BlocklistLoader;
+        let items = self.lists.read().unwrap().items.clone();
+        let mut result = Vec::with_capacity(items.len());
+        for (name, item) in items {
+            let mut tx = self.database.begin().await?;
+            let diff = match loader.stream(&item.url, item.kind).await {
+                Ok(stream) => diff_list(&mut tx, &item.url, stream, samples).await,
+                Err(error) => Err(error.into()),
+            };
+            tx.rollback().await?;
+            result.push((name, diff));
+        }
+        Ok(result)
+    }
+
+    /// Looks for the domain in the database, with the blocklists containing it, or the
+    /// wildcard of one of its parents, and the allowlist patterns matching it. Without
+    /// client, all the blocklists apply.
+    pub async fn check(
+        &self,
+        domain: &str,
+        client: Option<IpAddr>,
+    ) -> Result<DomainCheck, sqlx::Error> {
+        // the domain and the wildcards of its parents, like the allowlist patterns
+        let candidates = AllowPattern::candidates(domain);
+        let placeholders = vec!["?"; candidates.len()].join(", ");
+        let query = format!(
+            r#"SELECT DISTINCT blocklists.url, blocklists.enabled
+FROM blocked_domains
+LEFT JOIN blocklists ON blocklists.id = blocked_domains.blocklist_id
+WHERE blocked_domains.domain IN ({placeholders})
+ORDER BY blocklists.url"#
+        );
+        let rows: Vec<(Option<String>, Option<bool>)> = candidates
+            .iter()
+            .fold(sqlx::query_as(&query), |query, item| query.bind(item))
+            .fetch_all(&self.database)
+            .await?;
+        let applied = client.and_then(|client| self.blocklist_urls(client));
+        let now = self.clock.now();
+        let blocklists = {
+            let lists = self.lists.read().unwrap();
+            rows.into_iter()
+                .map(|(url, enabled)| {
+                    let configured = url
+                        .as_ref()
+                        .and_then(|url| lists.items.iter().find(|(_, item)| &item.url == url));
+                    BlocklistMatch {
+                        name: configured.map(|(name, _)| name.clone()),
+                        enabled: enabled.unwrap_or(true)
+                            && configured.is_none_or(|(_, item)| item.applies_at(now)),
+                        applied: match (&applied, &url) {
+                            (Some(applied), Some(url)) => applied.contains(url),
+                            _ => true,
+                        },
+                        url,
+                    }
+                })
+                .collect()
+        };
+        let query =
+            format!("SELECT pattern FROM allowed_domains WHERE pattern IN ({placeholders}) ORDER BY pattern");
+        let allowed_by = candidates
+            .iter()
+            .fold(sqlx::query_scalar(&query), |query, item| query.bind(item))
+            .fetch_all(&self.database)
+            .await?;
+        let rules = self.rules().await?;
+        let rule = parents(domain)
+            .find_map(|parent| rules.iter().find(|(domain, _)| domain == parent).cloned());
+        Ok(DomainCheck {
+            blocklists,
+            allowed_by,
+            rule,
+        })
+    }
+
+    /// Removes a blocklist with its domains, returns false if it wasn't imported
+    pub async fn remove(&self, url: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.database.begin().await?;
+        sqlx::query(
+            "DELETE FROM blocked_domains WHERE blocklist_id IN (SELECT id FROM blocklists WHERE url = $1)",
+        )
+        .bind(url)
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query("DELETE FROM blocklists WHERE url = $1")
+            .bind(url)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        self.reload().await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Reads the blocklist in a temporary table by batches, without the domains it excepts,
+/// returns the hash of the blocklist
+async fn stage_list<'t>(
+    tx: &mut Transaction<'t>,
+    url: &str,
+    mut stream: BlocklistStream,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    // create a temporary table
+    sqlx::query("CREATE TEMPORARY TABLE import_blocked_domains (domain TEXT UNIQUE NOT NULL)")
+        .execute(&mut *tx)
+        .await?;
+
+    // insert domains in temporary table
+    let mut read = 0;
+    while let Some(domains) = stream.next_batch().await? {
+        read += domains.len();
+        for chunk in domains.chunks(INSERT_BATCH_SIZE) {
+            let mut builder = QueryBuilder::<Sqlite>::new(
+                "INSERT OR IGNORE INTO import_blocked_domains (domain) ",
+            );
+            builder.push_values(chunk, |mut row, domain| {
+                row.push_bind(domain.as_str());
+            });
+            builder.build().execute(&mut *tx).await?;
+        }
     }
+    let (hash, exceptions) = stream.finish();
+    tracing::debug!("read {read} domains from {url:?} with hash {hash}");
+    for domain in exceptions {
+        sqlx::query(
+            "DELETE FROM import_blocked_domains WHERE domain = $1 OR substr(domain, -length($1) - 1) = '.' || $1",
+        )
+        .bind(domain)
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(hash)
+}
+
+/// Compares the staged domains with the ones imported from the blocklist before
+async fn diff_list<'t>(
+    tx: &mut Transaction<'t>,
+    url: &str,
+    stream: BlocklistStream,
+    samples: u32,
+) -> Result<BlocklistDiff, Box<dyn Error + Send + Sync>> {
+    stage_list(tx, url, stream).await?;
+    const ADDED: &str = r#"FROM import_blocked_domains
+WHERE domain NOT IN (
+    SELECT blocked_domains.domain FROM blocked_domains
+    JOIN blocklists ON blocklists.id = blocked_domains.blocklist_id
+    WHERE blocklists.url = $1
+)"#;
+    const REMOVED: &str = r#"FROM blocked_domains
+JOIN blocklists ON blocklists.id = blocked_domains.blocklist_id
+WHERE blocklists.url = $1 AND blocked_domains.domain NOT IN (SELECT domain FROM import_blocked_domains)"#;
+    let added: i64 = sqlx::query_scalar(&format!("SELECT count(*) {ADDED}"))
+        .bind(url)
+        .fetch_one(&mut *tx)
+        .await?;
+    let added_samples =
+        sqlx::query_scalar(&format!("SELECT domain {ADDED} ORDER BY domain LIMIT $2"))
+            .bind(url)
+            .bind(samples)
+            .fetch_all(&mut *tx)
+            .await?;
+    let removed: i64 = sqlx::query_scalar(&format!("SELECT count(*) {REMOVED}"))
+        .bind(url)
+        .fetch_one(&mut *tx)
+        .await?;
+    let removed_samples = sqlx::query_scalar(&format!(
+        "SELECT blocked_domains.domain {REMOVED} ORDER BY blocked_domains.domain LIMIT $2"
+    ))
+    .bind(url)
+    .bind(samples)
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(BlocklistDiff {
+        added: added as u64,
+        removed: removed as u64,
+        added_samples,
+        removed_samples,
+    })
 }
 
+/// Replaces the domains of the previous import of the blocklist with the ones
+/// it contains now, unless it didn't change.
 async fn import_list<'t>(
     tx: &mut Transaction<'t>,
     url: &str,
     description: &str,
-    hash: &str,
-    domains: HashSet<String>,
-) -> Result<(u64, u64), sqlx::Error> {
+    stream: BlocklistStream,
+) -> Result<(u64, u64), Box<dyn Error + Send + Sync>> {
+    let hash = stage_list(tx, url, stream).await?;
+
     // check if exists with same hash
     let exists: bool = sqlx::query_scalar(
         r#"SELECT count(id) > 0
@@ -59,11 +1004,14 @@ FROM blocklists
 WHERE url = $1 AND last_refresh_hash = $2"#,
     )
     .bind(url)
-    .bind(hash)
+    .bind(&hash)
     .fetch_one(&mut *tx)
     .await?;
     // The same hash as already been imported, we can pass
     if exists {
+        sqlx::query("DROP TABLE import_blocked_domains")
+            .execute(&mut *tx)
+            .await?;
         return Ok((0, 0));
     }
     // upsert the blocklist
@@ -75,23 +1023,10 @@ RETURNING id"#,
     )
     .bind(url)
     .bind(description)
-    .bind(hash)
+    .bind(&hash)
     .fetch_one(&mut *tx)
     .await?;
 
-    // create a temporary table
-    sqlx::query("CREATE TEMPORARY TABLE import_blocked_domains (domain TEXT UNIQUE NOT NULL)")
-        .execute(&mut *tx)
-        .await?;
-
-    // insert domains in temporary table
-    for item in domains {
-        sqlx::query("INSERT INTO import_blocked_domains (domain) VALUES ($1)")
-            .bind(&item)
-            .execute(&mut *tx)
-            .await?;
-    }
-
     // removing entries that are not there anymore
     let deleted = sqlx::query("DELETE FROM blocked_domains WHERE domain NOT IN (SELECT domain FROM import_blocked_domains) AND blocklist_id = $1")
         .bind(blocklist_id)
@@ -119,60 +1054,68 @@ ON CONFLICT (blocklist_id, domain) DO NOTHING"#,
 
 #[async_trait::async_trait]
 impl BlocklistService for DatabaseBlocklistService {
-    #[tracing::instrument(skip(self, _origin))]
-    async fn is_blocked(&self, _origin: &SocketAddr, domain: &str) -> Result<bool, Box<dyn Error>> {
+    #[tracing::instrument(skip(self, origin))]
+    async fn is_blocked(&self, origin: &SocketAddr, domain: &str) -> Result<bool, Box<dyn Error>> {
         tracing::debug!("checking in the blocklist");
-        let exists: bool =
-            sqlx::query_scalar("SELECT count(id) > 0 FROM blocked_domains WHERE domain = ?")
-                .bind(domain)
-                .fetch_one(&self.database)
-                .await?;
-        Ok(exists)
+        let snapshot = self.snapshot().await?;
+        let (applied, disabled) = self.masks(&snapshot, origin.ip());
+        Ok(snapshot.is_blocked(applied, disabled, domain))
     }
 
+    fn is_safe_search(&self, origin: &SocketAddr) -> bool {
+        let lists = self.lists.read().unwrap();
+        Self::client_groups(&lists, origin.ip())
+            .is_some_and(|groups| groups.iter().any(|group| group.safe_search))
+    }
     #[tracing::instrument(skip(self))]
     async fn import(&self) -> Result<(u64, u64), Box<dyn Error>> {
         let mut tx = self.database.begin().await?;
 
         let mut total_inserted = 0;
         let mut total_deleted = 0;
+        let mut failed = Vec::new();
 
-        let loader = donos_blocklist_loader::BlocklistLoader::default();
-        for (name, item) in self.items.iter() {
+        let loader = donos_blocklist_loader::BlocklistLoader;
+        let items = self.lists.read().unwrap().items.clone();
+        for (name, item) in items.iter() {
             tracing::debug!("start loading {name:?}");
-            match loader.load(&item.url, item.kind).await {
-                Ok(result) => {
-                    tracing::debug!(
-                        "loaded blocklist {name:?} with {} domains and hash {}",
-                        result.entries.len(),
-                        result.hash
-                    );
-                    let description = format!("{name} blocklist of {:?} kind", item.kind);
-                    let (inserted, deleted) = import_list(
-                        &mut tx,
-                        &item.url,
-                        &description,
-                        &result.hash,
-                        result.entries,
-                    )
-                    .await
-                    .expect("couldn't import blocklist");
+            let description = format!("{name} blocklist of {:?} kind", item.kind);
+            // a blocklist failing half way is rolled back without the others
+            let mut savepoint = tx.begin().await?;
+            let result = match loader.stream(&item.url, item.kind).await {
+                Ok(stream) => import_list(&mut savepoint, &item.url, &description, stream).await,
+                Err(error) => Err(error.into()),
+            };
+            match result {
+                Ok((inserted, deleted)) => {
+                    savepoint.commit().await?;
                     tracing::debug!("blocklist {name:?} inserted {inserted} new domains and deleted {deleted} existing domains");
                     total_inserted += inserted;
                     total_deleted += deleted;
                 }
-                Err(error) => tracing::warn!("unable to load blocklist {name:?}: {error:?}"),
+                Err(error) => {
+                    savepoint.rollback().await?;
+                    tracing::warn!("unable to load blocklist {name:?}: {error:?}");
+                    failed.push(name.as_str());
+                }
             };
         }
 
-        tx.commit().await.expect("couldn't commit changes");
+        tx.commit().await?;
+        self.reload().await?;
+        if !failed.is_empty() {
+            // the other blocklists are imported anyway
+            return Err(format!("unable to load blocklists {}", failed.join(", ")).into());
+        }
         Ok((total_inserted, total_deleted))
     }
 }
 
+#[cfg(test)]
 #[derive(Debug, Default)]
 pub struct MemoryBlocklistService {
     inner: std::collections::HashSet<String>,
+    safe_search: bool,
 }
 
 #[cfg(test)]
@@ -181,8 +1124,14 @@ impl MemoryBlocklistService {
         self.inner.insert(domain.into());
         self
     }
+
+    pub fn with_safe_search(mut self) -> Self {
+        self.safe_search = true;
+        self
+    }
 }
 
+#[cfg(test)]
 #[async_trait::async_trait]
 impl BlocklistService for MemoryBlocklistService {
     #[tracing::instrument(skip(self, _origin))]
@@ -195,11 +1144,16 @@ impl BlocklistService for MemoryBlocklistService {
     async fn import(&self) -> Result<(u64, u64), Box<dyn Error>> {
         Ok((0, 0))
     }
+
+    fn is_safe_search(&self, _origin: &SocketAddr) -> bool {
+        self.safe_search
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::repository::blocklist::BlocklistService;
+    use crate::common::schedule::{Clock, LocalTime};
+    use crate::repository::blocklist::{BlocklistService, DomainRule};
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
     fn address() -> SocketAddr {
@@ -233,4 +1187,463 @@ mod tests {
         let is_blocked = service.is_blocked(&addr, "perdu.com").await.unwrap();
         assert!(!is_blocked);
     }
+
+    /// Clock set by the tests, counting how many times the local time is read
+    struct FixedClock(std::sync::Mutex<LocalTime>, std::sync::atomic::AtomicUsize);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> LocalTime {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            *self.0.lock().unwrap()
+        }
+
+        fn minute(&self) -> u64 {
+            let now = *self.0.lock().unwrap();
+            u64::from(now.weekday) * 24 * 60 + u64::from(now.minute)
+        }
+    }
+
+    #[tokio::test]
+    async fn database_service_should_follow_schedules() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+        let blocklist_id: u32 = sqlx::query_scalar(
+            "insert into blocklists (url, created_at, last_refresh_at, last_refresh_hash) values ('http://social', UNIXEPOCH(), UNIXEPOCH(), '') returning id",
+        )
+        .fetch_one(&database)
+        .await
+        .unwrap();
+        sqlx::query("insert into blocked_domains (blocklist_id, domain, created_at) values (?, 'facebook.com', UNIXEPOCH())")
+            .bind(blocklist_id)
+            .execute(&database)
+            .await
+            .unwrap();
+
+        let items = [(
+            "social".to_string(),
+            super::BlocklistItem {
+                url: "http://social".into(),
+                kind: donos_blocklist_loader::BlocklistKind::EtcHosts,
+                enabled: true,
+                schedule: vec!["mon-fri 09:00-17:00".parse().unwrap()],
+            },
+        )]
+        .into_iter()
+        .collect();
+        // on a monday morning
+        let clock = std::sync::Arc::new(FixedClock(
+            std::sync::Mutex::new(LocalTime::new(1, 10, 0)),
+            Default::default(),
+        ));
+        let service =
+            super::DatabaseBlocklistService::new(items, database).with_clock(clock.clone());
+
+        let addr = address();
+        assert!(service.is_blocked(&addr, "facebook.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "facebook.com").await.unwrap());
+        // the schedules are only checked again the next minute
+        assert_eq!(clock.1.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert!(service
+            .check("facebook.com", None)
+            .await
+            .unwrap()
+            .is_blocked());
+
+        // then on saturday
+        *clock.0.lock().unwrap() = LocalTime::new(6, 10, 0);
+        assert!(!service.is_blocked(&addr, "facebook.com").await.unwrap());
+        let check = service.check("facebook.com", None).await.unwrap();
+        assert!(!check.is_blocked());
+        assert!(!check.blocklists[0].enabled);
+    }
+
+    #[tokio::test]
+    async fn database_service_should_block_per_group() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        for (url, domain) in [("http://ads", "ads.com"), ("http://adult", "adult.com")] {
+            let blocklist_id: u32 = sqlx::query_scalar(
+                "insert into blocklists (url, created_at, last_refresh_at, last_refresh_hash) values (?, UNIXEPOCH(), UNIXEPOCH(), '') returning id",
+            )
+            .bind(url)
+            .fetch_one(&database)
+            .await
+            .unwrap();
+            sqlx::query("insert into blocked_domains (blocklist_id, domain, created_at) values (?, ?, UNIXEPOCH())")
+                .bind(blocklist_id)
+                .bind(domain)
+                .execute(&database)
+                .await
+                .unwrap();
+        }
+
+        let items: std::collections::BTreeMap<_, _> =
+            [("ads", "http://ads"), ("adult", "http://adult")]
+                .into_iter()
+                .map(|(name, url)| {
+                    (
+                        name.to_string(),
+                        super::BlocklistItem {
+                            url: url.into(),
+                            kind: donos_blocklist_loader::BlocklistKind::EtcHosts,
+                            enabled: true,
+                            schedule: Vec::new(),
+                        },
+                    )
+                })
+                .collect();
+        let groups = [
+            (
+                "default",
+                super::ClientGroup {
+                    clients: Vec::new(),
+                    blocklists: vec!["ads".into()],
+                    safe_search: false,
+                },
+            ),
+            (
+                "kids",
+                super::ClientGroup {
+                    clients: vec!["10.0.0.0/24".parse().unwrap()],
+                    blocklists: vec!["ads".into(), "adult".into()],
+                    safe_search: true,
+                },
+            ),
+        ]
+        .into_iter()
+        .map(|(name, group)| (name.to_string(), group))
+        .collect();
+        let service =
+            super::DatabaseBlocklistService::new(items.clone(), database).with_groups(groups);
+
+        let kid: SocketAddr = "10.0.0.12:42".parse().unwrap();
+        assert!(service.is_blocked(&kid, "adult.com").await.unwrap());
+        assert!(service.is_blocked(&kid, "ads.com").await.unwrap());
+        let adult = address();
+        assert!(!service.is_blocked(&adult, "adult.com").await.unwrap());
+        assert!(service.is_blocked(&adult, "ads.com").await.unwrap());
+        assert!(service.is_safe_search(&kid));
+        assert!(!service.is_safe_search(&adult));
+
+        // the kids group is removed from the configuration, nothing to import
+        let groups = [(
+            "default".to_string(),
+            super::ClientGroup {
+                clients: Vec::new(),
+                blocklists: vec!["ads".into()],
+                safe_search: false,
+            },
+        )]
+        .into_iter()
+        .collect();
+        assert!(!service.update(items.clone(), groups));
+        assert!(!service.is_blocked(&kid, "adult.com").await.unwrap());
+        assert!(!service.is_safe_search(&kid));
+        assert!(service.is_blocked(&kid, "ads.com").await.unwrap());
+
+        // disabled in database, then in the configuration
+        assert!(service.set_enabled("http://ads", false).await.unwrap());
+        assert!(!service.is_blocked(&kid, "ads.com").await.unwrap());
+        assert!(!service.is_blocked(&adult, "ads.com").await.unwrap());
+        assert!(service.set_enabled("http://ads", true).await.unwrap());
+        assert!(service.is_blocked(&kid, "ads.com").await.unwrap());
+        let mut items = items;
+        items.get_mut("ads").unwrap().enabled = false;
+        service.update(items, Default::default());
+        assert!(!service.is_blocked(&adult, "ads.com").await.unwrap());
+        assert!(service.is_blocked(&adult, "adult.com").await.unwrap());
+        assert!(!service.set_enabled("http://unknown", false).await.unwrap());
+
+        let check = service
+            .check("ads.com", Some("10.0.0.12".parse().unwrap()))
+            .await
+            .unwrap();
+        assert!(!check.is_blocked());
+        assert_eq!(
+            check.blocklists,
+            vec![super::BlocklistMatch {
+                url: Some("http://ads".into()),
+                name: Some("ads".into()),
+                enabled: false,
+                applied: true,
+            }]
+        );
+        assert!(check.allowed_by.is_empty());
+        let check = service.check("adult.com", None).await.unwrap();
+        assert!(check.is_blocked());
+        service.allow(&"*.com".parse().unwrap()).await.unwrap();
+        let check = service.check("adult.com", None).await.unwrap();
+        assert!(!check.is_blocked());
+        assert_eq!(check.allowed_by, vec!["*.com".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn database_service_should_import_by_batches() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        let service = super::DatabaseBlocklistService::new(Default::default(), database);
+        let item = super::BlocklistItem {
+            url: concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/donos-blocklist-loader/data/ads.txt"
+            )
+            .into(),
+            kind: donos_blocklist_loader::BlocklistKind::EtcHosts,
+            enabled: true,
+            schedule: Vec::new(),
+        };
+        let (inserted, deleted) = service.add("ads", &item).await.unwrap();
+        assert!(inserted > super::INSERT_BATCH_SIZE as u64);
+        assert_eq!(deleted, 0);
+        let addr = address();
+        assert!(service.is_blocked(&addr, "0.r.msn.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "perdu.com").await.unwrap());
+
+        // nothing changed since the last import
+        assert_eq!(service.add("ads", &item).await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn database_service_should_diff_without_importing() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        let items = [(
+            "basic".to_string(),
+            super::BlocklistItem {
+                url: concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/donos-blocklist-loader/data/basic.txt"
+                )
+                .into(),
+                kind: donos_blocklist_loader::BlocklistKind::EtcHosts,
+                enabled: true,
+                schedule: Vec::new(),
+            },
+        )]
+        .into_iter()
+        .collect();
+        let service = super::DatabaseBlocklistService::new(items, database);
+
+        let result = service.diff(2).await.unwrap();
+        assert_eq!(result.len(), 1);
+        let (name, diff) = &result[0];
+        assert_eq!(name, "basic");
+        let diff = diff.as_ref().unwrap();
+        assert!(diff.added > 2);
+        assert_eq!(diff.removed, 0);
+        assert_eq!(diff.added_samples.len(), 2);
+        let addr = address();
+        assert!(!service
+            .is_blocked(&addr, &diff.added_samples[0])
+            .await
+            .unwrap());
+
+        let (inserted, _) = service.import().await.unwrap();
+        assert_eq!(inserted, diff.added);
+        let (_, diff) = service.diff(2).await.unwrap().remove(0);
+        let diff = diff.unwrap();
+        assert_eq!((diff.added, diff.removed), (0, 0));
+        assert!(diff.added_samples.is_empty());
+    }
+
+    #[test]
+    fn should_parse_allow_patterns() {
+        use super::AllowPattern;
+
+        let pattern: AllowPattern = "*.Example.com.".parse().unwrap();
+        assert_eq!(pattern.as_str(), "*.example.com");
+        assert!("*".parse::<AllowPattern>().is_err());
+        assert!("www.*.com".parse::<AllowPattern>().is_err());
+        assert!("a..com".parse::<AllowPattern>().is_err());
+        assert_eq!(
+            AllowPattern::candidates("a.b.com"),
+            vec!["a.b.com", "*.b.com", "*.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn database_service_should_not_block_allowed_domains() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        for domain in ["ads.com", "www.ads.com", "tracker.com"] {
+            sqlx::query("insert into blocked_domains (domain, created_at) values (?, UNIXEPOCH())")
+                .bind(domain)
+                .execute(&database)
+                .await
+                .unwrap();
+        }
+
+        let service = super::DatabaseBlocklistService::new(Default::default(), database);
+        for pattern in ["*.ads.com", "tracker.com"] {
+            assert!(service.allow(&pattern.parse().unwrap()).await.unwrap());
+        }
+        assert!(!service
+            .allow(&"tracker.com".parse().unwrap())
+            .await
+            .unwrap());
+        assert_eq!(
+            service.allowed().await.unwrap(),
+            vec!["*.ads.com", "tracker.com"]
+        );
+
+        let addr = address();
+        assert!(service.is_blocked(&addr, "ads.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "www.ads.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "tracker.com").await.unwrap());
+
+        assert!(service
+            .disallow(&"tracker.com".parse().unwrap())
+            .await
+            .unwrap());
+        assert!(service.is_blocked(&addr, "tracker.com").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn database_service_should_apply_domain_rules() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        for domain in ["ads.com", "cdn.ads.com"] {
+            sqlx::query("insert into blocked_domains (domain, created_at) values (?, UNIXEPOCH())")
+                .bind(domain)
+                .execute(&database)
+                .await
+                .unwrap();
+        }
+
+        let service = super::DatabaseBlocklistService::new(Default::default(), database);
+        service.allow(&"perdu.com".parse().unwrap()).await.unwrap();
+        assert!(service
+            .set_rule("perdu.com", DomainRule::Block)
+            .await
+            .unwrap());
+        assert!(service
+            .set_rule("cdn.ads.com", DomainRule::Allow)
+            .await
+            .unwrap());
+        assert!(!service
+            .set_rule("cdn.ads.com", DomainRule::Allow)
+            .await
+            .unwrap());
+        assert_eq!(
+            service.rules().await.unwrap(),
+            vec![
+                ("cdn.ads.com".to_string(), DomainRule::Allow),
+                ("perdu.com".to_string(), DomainRule::Block),
+            ]
+        );
+
+        // the rules go before the allowlist and the blocklists
+        let addr = address();
+        assert!(service.is_blocked(&addr, "www.perdu.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "ads.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "cdn.ads.com").await.unwrap());
+        let check = service.check("www.perdu.com", None).await.unwrap();
+        assert!(check.is_blocked());
+        assert_eq!(check.rule, Some(("perdu.com".into(), DomainRule::Block)));
+
+        assert!(service
+            .set_rule("perdu.com", DomainRule::Allow)
+            .await
+            .unwrap());
+        assert!(!service.is_blocked(&addr, "perdu.com").await.unwrap());
+        assert!(service.remove_rule("cdn.ads.com").await.unwrap());
+        assert!(!service.remove_rule("cdn.ads.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "cdn.ads.com").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn database_service_should_block_subdomains() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        // like the ||ads.com^ rules of the adblock filter lists
+        for domain in ["ads.com", "*.ads.com"] {
+            sqlx::query("insert into blocked_domains (domain, created_at) values (?, UNIXEPOCH())")
+                .bind(domain)
+                .execute(&database)
+                .await
+                .unwrap();
+        }
+
+        let service = super::DatabaseBlocklistService::new(Default::default(), database);
+        let addr = address();
+        assert!(service.is_blocked(&addr, "ads.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "cdn.ads.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "eu.cdn.ads.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "bads.com").await.unwrap());
+        assert!(!service.is_blocked(&addr, "com").await.unwrap());
+        let check = service.check("cdn.ads.com", None).await.unwrap();
+        assert!(check.is_blocked());
+        assert_eq!(check.blocklists.len(), 1);
+
+        service.allow(&"*.ads.com".parse().unwrap()).await.unwrap();
+        assert!(!service.is_blocked(&addr, "cdn.ads.com").await.unwrap());
+        assert!(service.is_blocked(&addr, "ads.com").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn database_service_should_reload_domains() {
+        crate::init_logs();
+
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        let service = super::DatabaseBlocklistService::new(Default::default(), database.clone());
+        let addr = address();
+        assert!(!service.is_blocked(&addr, "facebook.com").await.unwrap());
+
+        // imported by another process
+        sqlx::query("insert into blocked_domains (domain, created_at) values (?, UNIXEPOCH())")
+            .bind("facebook.com")
+            .execute(&database)
+            .await
+            .unwrap();
+        assert!(!service.is_blocked(&addr, "facebook.com").await.unwrap());
+
+        service.reload().await.unwrap();
+        assert!(service.is_blocked(&addr, "facebook.com").await.unwrap());
+    }
 }
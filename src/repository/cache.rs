@@ -1,19 +1,109 @@
+use donos_parser::packet::header::ResponseCode;
 use donos_parser::packet::record::Record;
 use donos_parser::packet::QueryType;
-use moka::future::Cache;
+use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
+use std::borrow::Borrow;
+use std::hash::{Hash, Hasher};
 use std::io::Result;
 use std::ops::Add;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
 
-#[derive(Debug, serde::Deserialize)]
+type CacheKey = (String, QueryType);
+
+/// View over a cache key, so that the cache can be requested with a `(&str, QueryType)`
+/// without allocating a `String` for each query.
+trait CacheKeyView: Sync {
+    fn qname(&self) -> &str;
+    fn qtype(&self) -> QueryType;
+}
+
+impl CacheKeyView for CacheKey {
+    fn qname(&self) -> &str {
+        self.0.as_str()
+    }
+
+    fn qtype(&self) -> QueryType {
+        self.1
+    }
+}
+
+impl CacheKeyView for (&str, QueryType) {
+    fn qname(&self) -> &str {
+        self.0
+    }
+
+    fn qtype(&self) -> QueryType {
+        self.1
+    }
+}
+
+impl<'a> Borrow<dyn CacheKeyView + 'a> for CacheKey {
+    fn borrow(&self) -> &(dyn CacheKeyView + 'a) {
+        self
+    }
+}
+
+// Must be consistent with the derived implementation of the tuple
+impl Hash for dyn CacheKeyView + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.qname().hash(state);
+        self.qtype().hash(state);
+    }
+}
+
+impl PartialEq for dyn CacheKeyView + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.qname() == other.qname() && self.qtype() == other.qtype()
+    }
+}
+
+impl Eq for dyn CacheKeyView + '_ {}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     #[serde(default = "Config::default_size")]
-    size: u64,
+    pub size: u64,
+    /// Number of bytes the entries can take in memory, estimated from the size
+    /// of their records, replacing the number of entries when set
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    /// Number of seconds an entry is kept at most, whatever the TTL of its records
+    #[serde(default)]
+    pub max_ttl: Option<u32>,
+    /// Number of seconds the expired entries are kept, to be served when
+    /// the upstream servers can't be reached (RFC 8767), disabled with 0
+    #[serde(default)]
+    pub serve_stale: u64,
+    /// TTL of the records served once expired
+    #[serde(default = "Config::default_stale_ttl")]
+    pub stale_ttl: u32,
+    #[serde(default)]
+    pub prefetch: PrefetchConfig,
+    #[serde(default)]
+    pub snapshot: SnapshotConfig,
+    /// Rotates the addresses of the answers served from cache, so that the clients
+    /// using the first one spread over all of them
+    #[serde(default)]
+    pub rotate: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self { size: 1000 }
+        Self {
+            size: Self::default_size(),
+            max_memory: None,
+            max_ttl: None,
+            serve_stale: 0,
+            stale_ttl: Self::default_stale_ttl(),
+            prefetch: PrefetchConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            rotate: false,
+        }
     }
 }
 
@@ -21,29 +111,473 @@ impl Config {
     pub fn default_size() -> u64 {
         1000
     }
+
+    pub fn default_stale_ttl() -> u32 {
+        30
+    }
 }
 
 impl Config {
     pub async fn build(self) -> Result<MemoryCacheService> {
-        Ok(MemoryCacheService::new(self.size))
+        let builder = match self.max_memory {
+            Some(bytes) => Cache::builder().weigher(weigh).max_capacity(bytes),
+            None => Cache::builder().max_capacity(self.size),
+        };
+        // the expired entries are removed even when not requested anymore
+        let builder = match self.max_ttl {
+            Some(max_ttl) => {
+                builder.time_to_live(Duration::from_secs(max_ttl as u64 + self.serve_stale))
+            }
+            None => builder,
+        };
+        Ok(MemoryCacheService::build(builder)
+            .with_max_ttl(self.max_ttl)
+            .with_serve_stale(Duration::from_secs(self.serve_stale), self.stale_ttl)
+            .with_prefetch(&self.prefetch)
+            .with_rotation(self.rotate))
+    }
+}
+
+/// Refreshes the popular entries shortly before they expire, so that they stay in cache
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PrefetchConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Number of times an entry must be requested before being refreshed
+    #[serde(default = "PrefetchConfig::default_min_hits")]
+    pub min_hits: u32,
+    /// Percentage of the TTL left under which a requested entry is refreshed
+    #[serde(default = "PrefetchConfig::default_threshold")]
+    pub threshold: u8,
+}
+
+impl Default for PrefetchConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_hits: Self::default_min_hits(),
+            threshold: Self::default_threshold(),
+        }
     }
 }
 
+impl PrefetchConfig {
+    pub fn default_min_hits() -> u32 {
+        3
+    }
+
+    pub fn default_threshold() -> u8 {
+        10
+    }
+}
+
+/// Copy of the cache written to a file, and loaded on startup so that a restart
+/// doesn't send all the queries of the network upstream at once
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "SnapshotConfig::default_path")]
+    pub path: PathBuf,
+    /// Number of seconds between two snapshots, one being also written when stopping
+    #[serde(default = "SnapshotConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: Self::default_path(),
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+impl SnapshotConfig {
+    pub fn default_path() -> PathBuf {
+        PathBuf::from("/var/lib/donos/cache.json")
+    }
+
+    pub fn default_interval() -> u64 {
+        300
+    }
+}
+
+/// Answer found in the cache
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedAnswer {
+    /// Code of the response, `NameError` when the domain doesn't exist
+    pub code: ResponseCode,
+    pub records: Vec<Record>,
+    /// SOA of the zone sent along with a negative answer, for the clients to cache it too
+    pub soa: Option<Record>,
+    /// Whether the records were validated with DNSSEC
+    pub authentic: bool,
+}
+
 #[async_trait::async_trait]
 pub trait CacheService {
     async fn persist(&self, qname: &str, qtype: QueryType, records: Vec<Record>) -> Result<()>;
-    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<Vec<Record>>>;
+    /// Same as `persist` for the records validated with DNSSEC, so that they are
+    /// answered as authentic
+    async fn persist_authentic(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        records: Vec<Record>,
+    ) -> Result<()> {
+        self.persist(qname, qtype, records).await
+    }
+    /// Keeps track of a query that has no answer for the given duration,
+    /// with the code of its response and the SOA of the zone
+    async fn persist_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        code: ResponseCode,
+        soa: Option<Record>,
+        ttl: u32,
+    ) -> Result<()>;
+    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<CachedAnswer>>;
+    /// Removes all the entries
+    async fn flush(&self) -> Result<()>;
+    /// Looks for the records, even expired, when no fresh answer can be found
+    async fn request_stale(&self, _qname: &str, _qtype: QueryType) -> Result<Option<CachedAnswer>> {
+        Ok(None)
+    }
+    /// Lists the entries, the expired ones included until they are removed
+    async fn entries(&self) -> Result<Vec<CacheEntry>> {
+        Ok(Vec::new())
+    }
+    /// Removes the entries of the normalized name, whatever their type, returns how many were removed
+    async fn evict(&self, _qname: &str) -> Result<usize> {
+        Ok(0)
+    }
+    /// Counters of the cache, `None` when the backend doesn't keep track of them
+    fn stats(&self) -> Option<CacheStats> {
+        None
+    }
+}
+
+/// State of the cache since the server started
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+pub struct CacheStats {
+    pub entries: u64,
+    /// Estimated number of bytes taken by the entries, or their number without `max_memory`
+    pub weight: u64,
+    /// Entries removed to make room for new ones
+    pub evictions: u64,
+    /// Entries removed once expired
+    pub expirations: u64,
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "entries={}, weight={}, evictions={}, expirations={}",
+            self.entries, self.weight, self.evictions, self.expirations
+        )
+    }
+}
+
+/// Entry of the cache, as listed by the `cache` command
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CacheEntry {
+    pub domain: String,
+    pub qtype: String,
+    /// Code of the negative answers, like `NameError`
+    pub code: String,
+    pub records: usize,
+    /// Number of seconds before the entry expires, negative once expired
+    pub ttl: i64,
+}
+
+/// Records kept in cache, with their popularity
+#[derive(Clone, Debug)]
+struct Entry {
+    until: SystemTime,
+    code: ResponseCode,
+    records: Vec<Record>,
+    soa: Option<Record>,
+    authentic: bool,
+    /// TTL of the records when they were persisted
+    ttl: u32,
+    hits: Arc<AtomicU32>,
+    /// Set once the entry has been sent to be refreshed
+    refreshing: Arc<AtomicBool>,
+}
+
+impl Entry {
+    fn new(ttl: u32, records: Vec<Record>) -> Self {
+        Self::with_code(ttl, ResponseCode::NoError, records)
+    }
+
+    fn with_code(ttl: u32, code: ResponseCode, records: Vec<Record>) -> Self {
+        Self {
+            until: SystemTime::now().add(Duration::new(ttl as u64, 0)),
+            code,
+            records,
+            soa: None,
+            authentic: false,
+            ttl,
+            hits: Default::default(),
+            refreshing: Default::default(),
+        }
+    }
+
+    /// Answer with the TTL of the records, and of the SOA, set to the given one
+    fn answer(&self, ttl: u32) -> CachedAnswer {
+        CachedAnswer {
+            code: self.code,
+            records: self
+                .records
+                .iter()
+                .map(|record| record.delayed_ttl(ttl))
+                .collect(),
+            soa: self.soa.as_ref().map(|soa| soa.delayed_ttl(ttl)),
+            authentic: self.authentic,
+        }
+    }
+
+    /// Whether the entry is popular and close enough to its expiration to be refreshed
+    fn should_prefetch(&self, prefetcher: &Prefetcher, hits: u32, remaining: Duration) -> bool {
+        hits >= prefetcher.min_hits
+            && remaining.as_secs() * 100 <= self.ttl as u64 * prefetcher.threshold as u64
+            && !self.refreshing.swap(true, Ordering::Relaxed)
+    }
+}
+
+/// Estimated number of bytes taken in memory by an entry
+fn weigh(key: &CacheKey, entry: &Entry) -> u32 {
+    let records: usize = entry
+        .records
+        .iter()
+        .chain(entry.soa.iter())
+        .map(|record| {
+            std::mem::size_of::<Record>()
+                + record.domain().len()
+                + record.canonical_data().map_or(0, |data| data.len())
+        })
+        .sum();
+    let size = std::mem::size_of::<(CacheKey, Entry)>() + key.0.len() + records;
+    u32::try_from(size).unwrap_or(u32::MAX)
+}
+
+/// Entries removed by the cache itself, the explicit removals being left aside
+#[derive(Debug, Default)]
+struct Counters {
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, cause: RemovalCause) {
+        match cause {
+            RemovalCause::Size => self.evictions.fetch_add(1, Ordering::Relaxed),
+            RemovalCause::Expired => self.expirations.fetch_add(1, Ordering::Relaxed),
+            RemovalCause::Explicit | RemovalCause::Replaced => return,
+        };
+    }
+}
+
+/// Entry as written in the snapshot file
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotEntry {
+    domain: String,
+    qtype: QueryType,
+    code: ResponseCode,
+    records: Vec<Record>,
+    #[serde(default)]
+    soa: Option<Record>,
+    authentic: bool,
+    ttl: u32,
+    /// Expiration of the entry, in seconds since the epoch
+    until: u64,
+}
+
+/// Moves the addresses of the answer by the given number of positions,
+/// the other records like the CNAME leading to them keeping their place
+fn rotate_addresses(records: &mut [Record], count: u32) {
+    let positions: Vec<usize> = records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| matches!(record, Record::A { .. } | Record::AAAA { .. }))
+        .map(|(index, _)| index)
+        .collect();
+    if positions.len() < 2 {
+        return;
+    }
+    let mut addresses: Vec<Record> = positions
+        .iter()
+        .map(|index| records[*index].clone())
+        .collect();
+    addresses.rotate_left(count as usize % positions.len());
+    for (index, record) in positions.into_iter().zip(addresses) {
+        records[index] = record;
+    }
+}
+
+/// Queue of the entries to refresh, taken with [`MemoryCacheService::prefetch_queue`]
+struct Prefetcher {
+    min_hits: u32,
+    threshold: u8,
+    sender: mpsc::UnboundedSender<CacheKey>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<CacheKey>>>,
 }
 
 pub struct MemoryCacheService {
-    inner: Cache<(String, QueryType), (SystemTime, Vec<Record>)>,
+    inner: Cache<CacheKey, Entry>,
+    /// How long the entries are kept once expired
+    serve_stale: Duration,
+    stale_ttl: u32,
+    prefetcher: Option<Prefetcher>,
+    rotate: bool,
+    max_ttl: Option<u32>,
+    counters: Arc<Counters>,
 }
 
 impl MemoryCacheService {
-    #[inline]
-    fn new(size: u64) -> Self {
+    #[cfg(test)]
+    pub(crate) fn new(size: u64) -> Self {
+        Self::build(Cache::builder().max_capacity(size))
+    }
+
+    fn build(builder: CacheBuilder<CacheKey, Entry, Cache<CacheKey, Entry>>) -> Self {
+        let counters = Arc::new(Counters::default());
+        let listener = counters.clone();
         Self {
-            inner: Cache::new(size),
+            inner: builder
+                .eviction_listener_with_queued_delivery_mode(move |_, _, cause| {
+                    listener.record(cause)
+                })
+                .build(),
+            serve_stale: Duration::ZERO,
+            stale_ttl: Config::default_stale_ttl(),
+            prefetcher: None,
+            rotate: false,
+            max_ttl: None,
+            counters,
+        }
+    }
+
+    pub fn with_max_ttl(mut self, max_ttl: Option<u32>) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    fn capped_ttl(&self, ttl: u32) -> u32 {
+        self.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl))
+    }
+
+    pub fn with_rotation(mut self, rotate: bool) -> Self {
+        self.rotate = rotate;
+        self
+    }
+
+    pub fn with_prefetch(mut self, config: &PrefetchConfig) -> Self {
+        self.prefetcher = config.enabled.then(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            Prefetcher {
+                min_hits: config.min_hits,
+                threshold: config.threshold,
+                sender,
+                receiver: Mutex::new(Some(receiver)),
+            }
+        });
+        self
+    }
+
+    /// Takes the queue of the entries to refresh, filled by the requests until the cache is dropped.
+    ///
+    /// The refresh is left to the handler, for the answers to go through the same checks
+    /// as the ones of the clients. Returns `None` when the prefetch is disabled or the queue
+    /// already taken.
+    pub fn prefetch_queue(&self) -> Option<mpsc::UnboundedReceiver<CacheKey>> {
+        self.prefetcher
+            .as_ref()
+            .and_then(|prefetcher| prefetcher.receiver.lock().unwrap().take())
+    }
+
+    pub fn with_serve_stale(mut self, serve_stale: Duration, stale_ttl: u32) -> Self {
+        self.serve_stale = serve_stale;
+        self.stale_ttl = stale_ttl;
+        self
+    }
+
+    fn is_stale_expired(&self, until: SystemTime, now: SystemTime) -> bool {
+        until.add(self.serve_stale) <= now
+    }
+
+    /// Writes the entries that can still be served to the file, replacing it at once
+    /// so that a crash while writing keeps the previous snapshot
+    pub async fn save_snapshot(&self, path: &Path) -> Result<usize> {
+        let now = SystemTime::now();
+        let entries: Vec<_> = self
+            .inner
+            .iter()
+            .filter(|(_, entry)| !self.is_stale_expired(entry.until, now))
+            .map(|(key, entry)| SnapshotEntry {
+                domain: key.0.clone(),
+                qtype: key.1,
+                code: entry.code,
+                records: entry.records.clone(),
+                soa: entry.soa.clone(),
+                authentic: entry.authentic,
+                ttl: entry.ttl,
+                until: entry
+                    .until
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs()),
+            })
+            .collect();
+        let content = serde_json::to_vec(&entries)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let temporary = path.with_extension("tmp");
+        tokio::fs::write(&temporary, content).await?;
+        tokio::fs::rename(&temporary, path).await?;
+        Ok(entries.len())
+    }
+
+    /// Fills the cache with the entries of the snapshot that didn't expire since it was
+    /// written, their records being served with the TTL they have left
+    pub async fn load_snapshot(&self, path: &Path) -> Result<usize> {
+        let content = match tokio::fs::read(path).await {
+            Ok(found) => found,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(error) => return Err(error),
+        };
+        let entries: Vec<SnapshotEntry> = serde_json::from_slice(&content)?;
+        let now = SystemTime::now();
+        let mut count = 0;
+        for item in entries {
+            let until = UNIX_EPOCH.add(Duration::from_secs(item.until));
+            if self.is_stale_expired(until, now) {
+                continue;
+            }
+            let entry = Entry {
+                until,
+                soa: item.soa,
+                authentic: item.authentic,
+                ..Entry::with_code(item.ttl, item.code, item.records)
+            };
+            self.inner.insert((item.domain, item.qtype), entry).await;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    async fn insert(&self, qname: &str, qtype: QueryType, records: Vec<Record>, authentic: bool) {
+        if let Some(min_ttl) = records.iter().map(|item| item.ttl()).min() {
+            tracing::debug!("persisting with a ttl of {min_ttl} seconds");
+            let entry = Entry {
+                authentic,
+                ..Entry::new(self.capped_ttl(min_ttl), records)
+            };
+            self.inner.insert((qname.to_string(), qtype), entry).await;
         }
     }
 }
@@ -52,32 +586,64 @@ impl MemoryCacheService {
 impl CacheService for MemoryCacheService {
     #[tracing::instrument(skip(self, records))]
     async fn persist(&self, qname: &str, qtype: QueryType, records: Vec<Record>) -> Result<()> {
-        if let Some(min_ttl) = records.iter().map(|item| item.ttl()).min() {
-            tracing::debug!("persisting with a ttl of {min_ttl} seconds");
-            let deadline = SystemTime::now().add(Duration::new(min_ttl as u64, 0));
-            self.inner
-                .insert((qname.to_string(), qtype), (deadline, records))
-                .await;
-        }
+        self.insert(qname, qtype, records, false).await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self, records))]
+    async fn persist_authentic(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        records: Vec<Record>,
+    ) -> Result<()> {
+        self.insert(qname, qtype, records, true).await;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn persist_negative(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        code: ResponseCode,
+        soa: Option<Record>,
+        ttl: u32,
+    ) -> Result<()> {
+        tracing::debug!("persisting negative answer with a ttl of {ttl} seconds");
+        let entry = Entry {
+            soa,
+            ..Entry::with_code(self.capped_ttl(ttl), code, Vec::new())
+        };
+        self.inner.insert((qname.to_string(), qtype), entry).await;
         Ok(())
     }
 
     #[tracing::instrument(skip(self))]
-    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<Vec<Record>>> {
-        let key = (qname.to_string(), qtype);
-        if let Some((until, records)) = self.inner.get(&key) {
+    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<CachedAnswer>> {
+        let key = (qname, qtype);
+        let key = &key as &dyn CacheKeyView;
+        if let Some(entry) = self.inner.get(key) {
             let now = SystemTime::now();
-            if let Ok(diff) = until.duration_since(now) {
+            if let Ok(diff) = entry.until.duration_since(now) {
                 tracing::debug!("found in cache with a ttl of {} seconds", diff.as_secs());
-                Ok(Some(
-                    records
-                        .iter()
-                        .map(|record| record.delayed_ttl(diff.as_secs() as u32))
-                        .collect(),
-                ))
+                let hits = entry.hits.fetch_add(1, Ordering::Relaxed);
+                if let Some(ref prefetcher) = self.prefetcher {
+                    if entry.should_prefetch(prefetcher, hits + 1, diff) {
+                        let _ = prefetcher.sender.send((qname.to_string(), qtype));
+                    }
+                }
+                let mut answer = entry.answer(diff.as_secs() as u32);
+                if self.rotate {
+                    rotate_addresses(&mut answer.records, hits);
+                }
+                Ok(Some(answer))
             } else {
                 tracing::debug!("found in cache but expired");
-                self.inner.invalidate(&key).await;
+                if self.is_stale_expired(entry.until, now) {
+                    self.inner.invalidate(key).await;
+                    self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+                }
                 Ok(None)
             }
         } else {
@@ -85,6 +651,69 @@ impl CacheService for MemoryCacheService {
             Ok(None)
         }
     }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.invalidate_all();
+        Ok(())
+    }
+
+    async fn entries(&self) -> Result<Vec<CacheEntry>> {
+        let now = SystemTime::now();
+        let mut entries: Vec<_> = self
+            .inner
+            .iter()
+            .map(|(key, entry)| CacheEntry {
+                domain: key.0.clone(),
+                qtype: format!("{:?}", key.1),
+                code: format!("{:?}", entry.code),
+                records: entry.records.len(),
+                ttl: match entry.until.duration_since(now) {
+                    Ok(remaining) => remaining.as_secs() as i64,
+                    Err(expired) => -(expired.duration().as_secs() as i64),
+                },
+            })
+            .collect();
+        entries.sort_by(|left, right| {
+            (left.domain.as_str(), left.qtype.as_str())
+                .cmp(&(right.domain.as_str(), right.qtype.as_str()))
+        });
+        Ok(entries)
+    }
+
+    async fn evict(&self, qname: &str) -> Result<usize> {
+        let keys: Vec<_> = self
+            .inner
+            .iter()
+            .filter(|(key, _)| key.0 == qname)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys.iter() {
+            self.inner.invalidate(key.as_ref()).await;
+        }
+        Ok(keys.len())
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn request_stale(&self, qname: &str, qtype: QueryType) -> Result<Option<CachedAnswer>> {
+        let key = (qname, qtype);
+        let key = &key as &dyn CacheKeyView;
+        match self.inner.get(key) {
+            Some(entry) if !self.is_stale_expired(entry.until, SystemTime::now()) => {
+                tracing::debug!("serving stale answer from cache");
+                Ok(Some(entry.answer(self.stale_ttl)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn stats(&self) -> Option<CacheStats> {
+        Some(CacheStats {
+            entries: self.inner.entry_count(),
+            weight: self.inner.weighted_size(),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expirations: self.counters.expirations.load(Ordering::Relaxed),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -113,12 +742,28 @@ impl CacheService for MockCacheService {
         Ok(())
     }
 
-    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<Vec<Record>>> {
-        if let Some(found) = self.inner.get(&(qname, qtype)) {
-            Ok(Some(found.clone()))
-        } else {
-            Ok(None)
-        }
+    async fn persist_negative(
+        &self,
+        _qname: &str,
+        _qtype: QueryType,
+        _code: ResponseCode,
+        _soa: Option<Record>,
+        _ttl: u32,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn request(&self, qname: &str, qtype: QueryType) -> Result<Option<CachedAnswer>> {
+        Ok(self.inner.get(&(qname, qtype)).map(|records| CachedAnswer {
+            code: ResponseCode::NoError,
+            records: records.clone(),
+            soa: None,
+            authentic: false,
+        }))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        Ok(())
     }
 }
 
@@ -130,9 +775,68 @@ mod tests {
         time::{Duration, SystemTime},
     };
 
-    use super::{CacheService, MemoryCacheService};
+    use super::{
+        weigh, CacheEntry, CacheKeyView, CacheService, Config, Entry, MemoryCacheService,
+        PrefetchConfig,
+    };
+    use donos_parser::packet::header::ResponseCode;
     use donos_parser::packet::{record::Record, QueryType};
 
+    #[tokio::test]
+    async fn should_list_and_evict_entries() {
+        let srv = MemoryCacheService::new(10);
+        let record = |domain: &str| Record::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 60,
+        };
+        srv.persist("perdu.com", QueryType::A, vec![record("perdu.com")])
+            .await
+            .unwrap();
+        srv.persist_negative(
+            "perdu.com",
+            QueryType::AAAA,
+            ResponseCode::NoError,
+            None,
+            30,
+        )
+        .await
+        .unwrap();
+        srv.persist_negative(
+            "nowhere.com",
+            QueryType::A,
+            ResponseCode::NameError,
+            None,
+            30,
+        )
+        .await
+        .unwrap();
+
+        let entries = srv.entries().await.unwrap();
+        assert_eq!(
+            entries[0],
+            CacheEntry {
+                domain: "nowhere.com".into(),
+                qtype: "A".into(),
+                code: "NameError".into(),
+                records: 0,
+                ttl: entries[0].ttl,
+            }
+        );
+        assert!(entries[0].ttl > 25 && entries[0].ttl <= 30);
+        assert_eq!(entries[1].records, 1);
+        assert_eq!(entries.len(), 3);
+
+        assert_eq!(srv.evict("perdu.com").await.unwrap(), 2);
+        assert_eq!(srv.evict("perdu.com").await.unwrap(), 0);
+        assert!(srv
+            .request("perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(srv.entries().await.unwrap().len(), 1);
+    }
+
     #[tokio::test]
     async fn should_persist_in_cache() {
         let srv = MemoryCacheService::new(10);
@@ -151,21 +855,74 @@ mod tests {
         assert!(found.is_some());
     }
 
+    #[test]
+    fn borrowed_key_should_hash_like_owned_key() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let owned = ("perdu.com".to_string(), QueryType::A);
+        let mut hasher = DefaultHasher::new();
+        owned.hash(&mut hasher);
+        let owned_hash = hasher.finish();
+
+        let borrowed = ("perdu.com", QueryType::A);
+        let borrowed = &borrowed as &dyn CacheKeyView;
+        let mut hasher = DefaultHasher::new();
+        borrowed.hash(&mut hasher);
+        assert_eq!(owned_hash, hasher.finish());
+    }
+
+    #[tokio::test]
+    async fn should_persist_negative_in_cache() {
+        let srv = MemoryCacheService::new(10);
+        srv.persist_negative(
+            "perdu.com",
+            QueryType::AAAA,
+            ResponseCode::NoError,
+            None,
+            60,
+        )
+        .await
+        .unwrap();
+        srv.persist_negative(
+            "nope.perdu.com",
+            QueryType::A,
+            ResponseCode::NameError,
+            None,
+            60,
+        )
+        .await
+        .unwrap();
+        let found = srv
+            .request("perdu.com", QueryType::AAAA)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.code, ResponseCode::NoError);
+        assert!(found.records.is_empty());
+        let found = srv
+            .request("nope.perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.code, ResponseCode::NameError);
+        assert!(found.records.is_empty());
+    }
+
     #[tokio::test]
     async fn should_not_return_if_outdated() {
         let srv = MemoryCacheService::new(10);
+        let mut entry = Entry::new(
+            5,
+            vec![Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 5,
+            }],
+        );
+        entry.until = SystemTime::now().sub(Duration::new(10, 0));
         srv.inner
-            .insert(
-                ("perdu.com".to_string(), QueryType::A),
-                (
-                    SystemTime::now().sub(Duration::new(10, 0)),
-                    vec![Record::A {
-                        domain: "perdu.com".into(),
-                        addr: Ipv4Addr::new(1, 2, 3, 4),
-                        ttl: 5,
-                    }],
-                ),
-            )
+            .insert(("perdu.com".to_string(), QueryType::A), entry)
             .await;
         let found = srv.request("perdu.com", QueryType::A).await.unwrap();
         assert!(found.is_none());
@@ -179,26 +936,200 @@ mod tests {
     #[tokio::test]
     async fn should_return() {
         let srv = MemoryCacheService::new(10);
+        let mut entry = Entry::new(
+            180,
+            vec![Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 180,
+            }],
+        );
+        entry.until = SystemTime::now().add(Duration::new(60, 0));
         srv.inner
-            .insert(
-                ("perdu.com".to_string(), QueryType::A),
-                (
-                    SystemTime::now().add(Duration::new(60, 0)),
-                    vec![Record::A {
-                        domain: "perdu.com".into(),
-                        addr: Ipv4Addr::new(1, 2, 3, 4),
-                        ttl: 180,
-                    }],
-                ),
-            )
+            .insert(("perdu.com".to_string(), QueryType::A), entry)
             .await;
         let found = srv
             .request("perdu.com", QueryType::A)
             .await
             .unwrap()
             .unwrap();
-        for item in found {
+        for item in found.records {
             assert_eq!(item.ttl(), 59);
         }
     }
+
+    #[tokio::test]
+    async fn should_restore_snapshot() {
+        let path = std::env::temp_dir()
+            .join("donos-cache-snapshot")
+            .join("cache.json");
+        let srv = MemoryCacheService::new(10);
+        srv.persist_authentic(
+            "perdu.com",
+            QueryType::A,
+            vec![Record::A {
+                domain: "perdu.com".into(),
+                addr: Ipv4Addr::new(1, 2, 3, 4),
+                ttl: 180,
+            }],
+        )
+        .await
+        .unwrap();
+        srv.persist_negative(
+            "nope.perdu.com",
+            QueryType::A,
+            ResponseCode::NameError,
+            None,
+            60,
+        )
+        .await
+        .unwrap();
+        let mut expired = Entry::new(5, Vec::new());
+        expired.until = SystemTime::now().sub(Duration::new(10, 0));
+        srv.inner
+            .insert(("old.perdu.com".to_string(), QueryType::A), expired)
+            .await;
+        assert_eq!(srv.save_snapshot(&path).await.unwrap(), 2);
+
+        let restored = MemoryCacheService::new(10);
+        assert_eq!(restored.load_snapshot(&path).await.unwrap(), 2);
+        let found = restored
+            .request("perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(found.authentic);
+        assert!((178..=180).contains(&found.records[0].ttl()));
+        let found = restored
+            .request("nope.perdu.com", QueryType::A)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.code, ResponseCode::NameError);
+
+        // starting empty without snapshot
+        let missing = path.with_file_name("missing.json");
+        assert_eq!(restored.load_snapshot(&missing).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn should_rotate_addresses() {
+        let address = |last: u8| Record::A {
+            domain: "www.perdu.com".into(),
+            addr: Ipv4Addr::new(1, 2, 3, last),
+            ttl: 60,
+        };
+        let records = vec![
+            Record::CNAME {
+                domain: "perdu.com".into(),
+                host: "www.perdu.com".into(),
+                ttl: 60,
+            },
+            address(1),
+            address(2),
+            address(3),
+        ];
+        let first_served = |records: &[Record]| match records {
+            [Record::CNAME { .. }, Record::A { addr, .. }, ..] => addr.octets()[3],
+            other => panic!("unexpected answer {other:?}"),
+        };
+        let srv = MemoryCacheService::new(10).with_rotation(true);
+        srv.persist("perdu.com", QueryType::A, records.clone())
+            .await
+            .unwrap();
+        let mut served = Vec::new();
+        for _ in 0..4 {
+            let found = srv.request("perdu.com", QueryType::A).await.unwrap();
+            served.push(first_served(&found.unwrap().records));
+        }
+        assert_eq!(served, vec![1, 2, 3, 1]);
+
+        // kept in order otherwise
+        let srv = MemoryCacheService::new(10);
+        srv.persist("perdu.com", QueryType::A, records)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let found = srv.request("perdu.com", QueryType::A).await.unwrap();
+            assert_eq!(first_served(&found.unwrap().records), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_bound_entries_and_count_evictions() {
+        use moka::future::ConcurrentCacheExt;
+
+        let record = |domain: &str| Record::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 3600,
+        };
+        let max_memory = 3 * weigh(&("0.perdu.com".into(), QueryType::A), &{
+            Entry::new(3600, vec![record("0.perdu.com")])
+        }) as u64;
+        let srv = Config {
+            max_memory: Some(max_memory),
+            max_ttl: Some(60),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        for idx in 0..10 {
+            let domain = format!("{idx}.perdu.com");
+            srv.persist(&domain, QueryType::A, vec![record(&domain)])
+                .await
+                .unwrap();
+        }
+        srv.inner.sync();
+        // the removals are notified by another thread
+        let mut stats = srv.stats().unwrap();
+        for _ in 0..100 {
+            if stats.evictions >= 7 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            stats = srv.stats().unwrap();
+        }
+        assert!(stats.weight <= max_memory);
+        assert!(stats.entries <= 3);
+        assert_eq!(stats.evictions, 7);
+        assert_eq!(stats.expirations, 0);
+
+        // the ttl of the records is capped
+        let found = srv.entries().await.unwrap();
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|entry| entry.ttl <= 60));
+    }
+
+    #[tokio::test]
+    async fn should_prefetch_popular_entries() {
+        let srv = MemoryCacheService::new(10).with_prefetch(&PrefetchConfig {
+            enabled: true,
+            min_hits: 2,
+            threshold: 100,
+        });
+        let record = Record::A {
+            domain: "perdu.com".into(),
+            addr: Ipv4Addr::new(1, 2, 3, 4),
+            ttl: 60,
+        };
+        srv.persist("perdu.com", QueryType::A, vec![record.clone()])
+            .await
+            .unwrap();
+        let mut receiver = srv.prefetch_queue().unwrap();
+        // the queue is only taken once
+        assert!(srv.prefetch_queue().is_none());
+
+        srv.request("perdu.com", QueryType::A).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+        srv.request("perdu.com", QueryType::A).await.unwrap();
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            ("perdu.com".to_string(), QueryType::A)
+        );
+        // only queued once
+        srv.request("perdu.com", QueryType::A).await.unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
 }
@@ -0,0 +1,175 @@
+use sqlx::{Pool, Sqlite};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Address identifying a client on the network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAddress {
+    Ip(IpAddr),
+    Mac([u8; 6]),
+}
+
+impl Display for ClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(inner) => inner.fmt(f),
+            Self::Mac([a, b, c, d, e, g]) => {
+                write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidClientAddress(String);
+
+impl Display for InvalidClientAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} is neither an ip nor a mac address", self.0)
+    }
+}
+
+impl std::error::Error for InvalidClientAddress {}
+
+impl FromStr for ClientAddress {
+    type Err = InvalidClientAddress;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return Ok(Self::Ip(ip));
+        }
+        let mut mac = [0u8; 6];
+        let mut parts = value.split([':', '-']);
+        for byte in mac.iter_mut() {
+            *byte = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .and_then(|part| u8::from_str_radix(part, 16).ok())
+                .ok_or_else(|| InvalidClientAddress(value.to_string()))?;
+        }
+        if parts.next().is_some() {
+            return Err(InvalidClientAddress(value.to_string()));
+        }
+        Ok(Self::Mac(mac))
+    }
+}
+
+#[derive(Debug)]
+pub struct Client {
+    pub address: String,
+    pub name: String,
+}
+
+/// Human names given to the clients, to be displayed instead of their addresses
+#[derive(Debug, Clone)]
+pub struct DatabaseClientService {
+    database: Pool<Sqlite>,
+}
+
+impl DatabaseClientService {
+    pub fn new(database: Pool<Sqlite>) -> Self {
+        Self { database }
+    }
+
+    /// Gives a name to the client, replacing the previous one
+    pub async fn set(&self, address: ClientAddress, name: &str) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"INSERT INTO clients (address, name, created_at)
+VALUES ($1, $2, UNIXEPOCH())
+ON CONFLICT (address) DO UPDATE SET name = $2"#,
+        )
+        .bind(address.to_string())
+        .bind(name)
+        .execute(&self.database)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes the name of the client, returns false if it had none
+    pub async fn remove(&self, address: ClientAddress) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM clients WHERE address = $1")
+            .bind(address.to_string())
+            .execute(&self.database)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn list(&self) -> Result<Vec<Client>, sqlx::Error> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT address, name FROM clients ORDER BY name, address")
+                .fetch_all(&self.database)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(address, name)| Client { address, name })
+            .collect())
+    }
+
+    /// Names of the clients identified by their ip address
+    pub async fn names(&self) -> Result<HashMap<IpAddr, String>, sqlx::Error> {
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .filter_map(|client| {
+                client
+                    .address
+                    .parse::<IpAddr>()
+                    .ok()
+                    .map(|ip| (ip, client.name))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientAddress;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn should_parse_addresses() {
+        assert_eq!(
+            "192.168.1.12".parse::<ClientAddress>().unwrap(),
+            ClientAddress::Ip(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 12)))
+        );
+        let mac = "AA-bb-cc-01-02-03".parse::<ClientAddress>().unwrap();
+        assert_eq!(mac.to_string(), "aa:bb:cc:01:02:03");
+        assert!("aa:bb:cc:01:02".parse::<ClientAddress>().is_err());
+        assert!("aa:bb:cc:01:02:03:04".parse::<ClientAddress>().is_err());
+        assert!("kitchen".parse::<ClientAddress>().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_name_clients() {
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+
+        let service = super::DatabaseClientService::new(database);
+        let ipad: ClientAddress = "192.168.1.12".parse().unwrap();
+        service.set(ipad, "Kitchen iPad").await.unwrap();
+        service.set(ipad, "Living room iPad").await.unwrap();
+        service
+            .set("aa:bb:cc:01:02:03".parse().unwrap(), "Laptop")
+            .await
+            .unwrap();
+
+        assert_eq!(service.list().await.unwrap().len(), 2);
+        let names = service.names().await.unwrap();
+        assert_eq!(names.len(), 1);
+        assert_eq!(
+            names
+                .get(&IpAddr::V4(Ipv4Addr::new(192, 168, 1, 12)))
+                .unwrap(),
+            "Living room iPad"
+        );
+
+        assert!(service.remove(ipad).await.unwrap());
+        assert!(!service.remove(ipad).await.unwrap());
+    }
+}
@@ -1,90 +1,985 @@
-use donos_parser::buffer::BytePacketBuffer;
+use crate::common::source::QuerySource;
+use donos_parser::buffer::{BytePacketBuffer, EDNS_PACKET_SIZE};
+use donos_parser::packet::header::ResponseCode;
 use donos_parser::packet::question::Question;
-use donos_parser::packet::{DnsPacket, QueryType};
-use std::io::Result;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::atomic::{AtomicU16, Ordering};
-use tokio::net::UdpSocket;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType, EDNS_DNSSEC_OK};
+use donos_server::socket::{self, bind_udp, BindOptions};
+use ring::rand::SystemRandom;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{oneshot, watch};
+use tokio_rustls::rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::TlsConnector;
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
+    /// Whether the queries are forwarded to the servers or resolved from the root servers
+    #[serde(default)]
+    pub mode: Mode,
     #[serde(default = "Config::default_address")]
     pub address: SocketAddr,
     #[serde(default = "Config::default_servers")]
-    pub servers: Vec<String>,
+    pub servers: Vec<ServerConfig>,
+    /// How the server of a query is picked, the others being tried when it fails
+    #[serde(default)]
+    pub strategy: Strategy,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub probe: ProbeConfig,
+    /// Requests the DNSSEC records along with the answers, for the clients setting the DO bit
+    #[serde(default = "Config::default_dnssec")]
+    pub dnssec: bool,
+    #[serde(default)]
+    pub validation: super::validator::Config,
+    /// Domains whose queries are sent to dedicated servers instead
+    #[serde(default)]
+    pub forward: Vec<super::routing::ForwardConfig>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            mode: Mode::default(),
             address: Self::default_address(),
             servers: Self::default_servers(),
+            strategy: Strategy::default(),
+            retry: RetryConfig::default(),
+            probe: ProbeConfig::default(),
+            dnssec: Self::default_dnssec(),
+            validation: Default::default(),
+            forward: Vec::new(),
+        }
+    }
+}
+
+/// How the queries that are not in cache are resolved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Forward the queries to the upstream servers
+    #[default]
+    Forward,
+    /// Iterate from the root servers, following the referrals, without any upstream server
+    Recursive,
+}
+
+/// Order in which the upstream servers are tried
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Always start with the first server, following the ranking of the probes
+    #[default]
+    Ordered,
+    /// Start with the next server for each query, to spread the load
+    RoundRobin,
+    /// Start with the server that answered the fastest recently
+    Fastest,
+}
+
+/// Timeout of the queries and retries when all the servers failed
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RetryConfig {
+    /// Delay after which a server is considered as not answering and the next one is tried,
+    /// in milliseconds
+    #[serde(default = "RetryConfig::default_timeout")]
+    pub timeout: u64,
+    /// Number of times all the servers are tried again when none of them answered
+    #[serde(default = "RetryConfig::default_retries")]
+    pub retries: u32,
+    /// Delay before the first retry, in milliseconds, doubled for each of the next ones
+    #[serde(default = "RetryConfig::default_backoff")]
+    pub backoff: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Self::default_timeout(),
+            retries: Self::default_retries(),
+            backoff: Self::default_backoff(),
+        }
+    }
+}
+
+impl RetryConfig {
+    fn default_timeout() -> u64 {
+        2000
+    }
+
+    fn default_retries() -> u32 {
+        1
+    }
+
+    fn default_backoff() -> u64 {
+        100
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout)
+    }
+
+    pub fn backoff(&self) -> Duration {
+        Duration::from_millis(self.backoff)
+    }
+}
+
+/// Consecutive failures after which a server is only tried when the others failed too
+const MAX_FAILURES: u32 = 3;
+
+/// Health of an upstream server, based on the queries it answered
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ServerHealth {
+    pub consecutive_failures: u32,
+    /// Moving average of the latency of the successful queries
+    pub latency: Option<Duration>,
+}
+
+impl ServerHealth {
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures < MAX_FAILURES
+    }
+
+    fn record_success(&mut self, elapsed: Duration) {
+        self.consecutive_failures = 0;
+        self.latency = Some(match self.latency {
+            Some(previous) => (previous * 3 + elapsed) / 4,
+            None => elapsed,
+        });
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+    }
+}
+
+impl Display for ServerHealth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} consecutive failures", self.consecutive_failures)?;
+        if let Some(latency) = self.latency {
+            write!(f, ", {latency:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Servers in the order they should be tried for a query, the unhealthy ones last
+fn attempt_order<'a>(
+    servers: &'a [Upstream],
+    health: &HashMap<String, ServerHealth>,
+    strategy: Strategy,
+    turn: usize,
+) -> Vec<&'a Upstream> {
+    let health_of = |server: &Upstream| health.get(&server.name).copied().unwrap_or_default();
+    let mut result: Vec<_> = servers.iter().collect();
+    match strategy {
+        Strategy::Ordered => {}
+        Strategy::RoundRobin if !result.is_empty() => {
+            let len = result.len();
+            result.rotate_left(turn % len)
+        }
+        Strategy::RoundRobin => {}
+        Strategy::Fastest => {
+            result.sort_by_key(|server| health_of(server).latency.unwrap_or(Duration::MAX))
+        }
+    }
+    // stable, so the order of the strategy is kept among the healthy servers
+    result.sort_by_key(|server| !health_of(server).is_healthy());
+    result
+}
+
+/// Probing of the upstream servers, to use the fastest and most reliable one first
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProbeConfig {
+    #[serde(default = "ProbeConfig::default_enabled")]
+    pub enabled: bool,
+    /// Domain queried to measure the latency
+    #[serde(default = "ProbeConfig::default_domain")]
+    pub domain: String,
+    /// Number of queries sent to each server
+    #[serde(default = "ProbeConfig::default_queries")]
+    pub queries: u32,
+    /// Delay between two probes, in seconds
+    #[serde(default = "ProbeConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            domain: Self::default_domain(),
+            queries: Self::default_queries(),
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+impl ProbeConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_domain() -> String {
+        "example.com".to_string()
+    }
+
+    fn default_queries() -> u32 {
+        3
+    }
+
+    fn default_interval() -> u64 {
+        600
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+}
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of sockets sending the udp queries, one of them being picked at random
+/// for each query so that its source port can't be guessed
+const UDP_SOCKETS: usize = 16;
+
+/// Longest pause of a dispatcher between two failed receptions
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Result of the probe of an upstream server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    pub server: String,
+    pub queries: u32,
+    pub answered: u32,
+    pub average_latency: Option<Duration>,
+}
+
+impl Display for UpstreamStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}/{} answered",
+            self.server, self.answered, self.queries
+        )?;
+        if let Some(latency) = self.average_latency {
+            write!(f, ", {latency:?}")?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// Orders the servers, the most reliable first then the fastest.
+///
+/// The sort is stable so the configuration order is kept between equivalent servers.
+fn rank(statuses: &mut [UpstreamStatus]) {
+    statuses.sort_by(|left, right| {
+        right.answered.cmp(&left.answered).then_with(|| {
+            left.average_latency
+                .unwrap_or(Duration::MAX)
+                .cmp(&right.average_latency.unwrap_or(Duration::MAX))
+        })
+    });
+}
+
+/// Protocol used to reach an upstream server
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamTransport {
+    #[default]
+    Udp,
+    Tcp,
+    /// DNS over TLS (RFC 7858)
+    Tls,
+}
+
+impl UpstreamTransport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
+        }
+    }
+
+    fn default_port(&self) -> u16 {
+        match self {
+            Self::Tls => 853,
+            _ => 53,
+        }
+    }
+}
+
+/// Upstream server, as written in the configuration
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ServerConfig {
+    /// Address of a server queried over UDP
+    Address(String),
+    /// Address of a server with the way to reach it
+    Detailed {
+        address: String,
+        #[serde(default)]
+        transport: UpstreamTransport,
+        /// Name expected in the certificate of the server with the tls transport,
+        /// the host of the address when not defined
+        #[serde(default)]
+        tls_name: Option<String>,
+    },
+}
+
+impl From<&str> for ServerConfig {
+    fn from(value: &str) -> Self {
+        Self::Address(value.to_string())
+    }
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> &str {
+        match self {
+            Self::Address(address) | Self::Detailed { address, .. } => address,
+        }
+    }
+
+    pub fn transport(&self) -> UpstreamTransport {
+        match self {
+            Self::Address(_) => UpstreamTransport::Udp,
+            Self::Detailed { transport, .. } => *transport,
+        }
+    }
+
+    /// Resolves the address of the server, with the system resolver for the hostnames
+    pub async fn resolve(&self) -> Result<Upstream> {
+        let transport = self.transport();
+        let (host, port) = split_address(self.address(), transport.default_port());
+        let address = match host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            Err(_) => tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .next()
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("no address found for {host}"))
+                })?,
+        };
+        let tls_name = match self {
+            Self::Detailed {
+                tls_name: Some(name),
+                ..
+            } => name.clone(),
+            _ => host,
+        };
+        if transport == UpstreamTransport::Tls && ServerName::try_from(tls_name.as_str()).is_err() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid tls name {tls_name:?}"),
+            ));
+        }
+        Ok(Upstream {
+            name: self.address().to_string(),
+            address,
+            transport,
+            tls_name,
+        })
+    }
+}
+
+/// Host and port of an address written like `1.1.1.1`, `1.1.1.1:5353`, `2606:4700::1111`,
+/// `[2606:4700::1111]:5353`, `dns.example.com` or `dns.example.com:5353`
+pub(crate) fn split_address(value: &str, default_port: u16) -> (String, u16) {
+    if let Ok(address) = value.parse::<SocketAddr>() {
+        return (address.ip().to_string(), address.port());
+    }
+    let host = value.trim_start_matches('[').trim_end_matches(']');
+    if host.parse::<IpAddr>().is_ok() {
+        return (host.to_string(), default_port);
+    }
+    match value.rsplit_once(':') {
+        Some((host, port)) => match port.parse() {
+            Ok(port) => (host.to_string(), port),
+            Err(_) => (value.to_string(), default_port),
+        },
+        None => (value.to_string(), default_port),
+    }
+}
+
+/// Upstream server, with its address resolved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    /// Address as written in the configuration, identifying the server in the logs
+    pub name: String,
+    pub address: SocketAddr,
+    pub transport: UpstreamTransport,
+    /// Name checked against the certificate of the server, with the tls transport
+    pub tls_name: String,
+}
+
+/// Resolves the servers, the ones that can't be resolved being left aside
+async fn resolve_servers(servers: &[ServerConfig]) -> Vec<Upstream> {
+    let mut result = Vec::with_capacity(servers.len());
+    for server in servers {
+        match server.resolve().await {
+            Ok(found) => result.push(found),
+            Err(error) => tracing::warn!(
+                "unable to resolve upstream server {}, it won't be used: {error}",
+                server.address()
+            ),
+        }
+    }
+    result
+}
+
+/// Binds the lookup socket, on both address families when it's not bound to a specific
+/// address and some upstream servers are only reachable over IPv6
+fn bind(address: SocketAddr, servers: &[Upstream]) -> Result<std::net::UdpSocket> {
+    let ipv6 = servers
+        .iter()
+        .any(|server| server.transport == UpstreamTransport::Udp && server.address.is_ipv6());
+    if ipv6 && address.ip() == IpAddr::V4(Ipv4Addr::UNSPECIFIED) {
+        let dual_stack = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), address.port());
+        match bind_udp(dual_stack, BindOptions::default()) {
+            Ok(found) => return Ok(found),
+            Err(error) => tracing::warn!(
+                "unable to bind {dual_stack}, the ipv6 upstream servers won't be reachable: {error}"
+            ),
+        }
+    }
+    bind_udp(address, BindOptions::default())
+}
+
+/// Verifies the certificates of the DNS over TLS servers with the Mozilla root certificates
+fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Sends a query to the server on a dedicated socket and waits for its response
+async fn exchange(socket: &UdpSocket, server: &Upstream, packet: &DnsPacket) -> Result<DnsPacket> {
+    let target = socket::destination(server.address, socket.local_addr()?);
+    let req_buffer = packet.create_buffer()?;
+    socket
+        .send_to(&req_buffer.buf[0..req_buffer.pos], target)
+        .await?;
+
+    let mut res_buffer = BytePacketBuffer::default();
+    let (size, _) = socket.recv_from(&mut res_buffer.buf).await?;
+
+    tracing::debug!("received {size} bytes from server");
+
+    Ok(DnsPacket::try_from(res_buffer)?)
+}
+
+/// Sends a query prefixed by its length, over TCP or TLS, and reads the response
+async fn exchange_stream<S>(stream: &mut S, packet: &DnsPacket) -> Result<DnsPacket>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let buffer = packet.create_buffer()?;
+    let mut output = Vec::with_capacity(buffer.pos + 2);
+    output.extend_from_slice(&(buffer.pos as u16).to_be_bytes());
+    output.extend_from_slice(&buffer.buf[..buffer.pos]);
+    stream.write_all(&output).await?;
+
+    let size = stream.read_u16().await? as usize;
+    let mut response = vec![0; size];
+    stream.read_exact(&mut response).await?;
+
+    tracing::debug!("received {size} bytes from server");
+
+    Ok(DnsPacket::try_from(BytePacketBuffer::new(response))?)
+}
+
+/// Identifies the response of a query: its id and its question
+type PendingKey = (u16, String, QueryType);
+
+/// Where a query was sent from and to, its response being expected the other way
+type Route = (SocketAddr, SocketAddr);
+
+/// Queries waiting for their response, with the socket they were sent from and their server
+type PendingQueries = Mutex<HashMap<PendingKey, (Route, oneshot::Sender<DnsPacket>)>>;
+
+fn pending_key(packet: &DnsPacket) -> Option<PendingKey> {
+    let question = packet.questions.first()?;
+    Some((
+        packet.header.id,
+        question.name.to_lowercase(),
+        question.qtype,
+    ))
+}
+
+/// Forgets the query when its response is not awaited anymore, like after a timeout
+struct PendingGuard<'a> {
+    pending: &'a PendingQueries,
+    key: PendingKey,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Receives the responses of the upstream servers and hands them to the queries
+/// waiting for them, until the service is dropped
+async fn dispatch(
+    socket: Arc<UdpSocket>,
+    pending: Weak<PendingQueries>,
+    mut stopped: watch::Receiver<()>,
+) {
+    let Ok(local) = socket.local_addr() else {
+        return;
+    };
+    let mut buffer = BytePacketBuffer::with_size(EDNS_PACKET_SIZE);
+    let mut failures = 0;
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer.buf) => received,
+            // the sender is dropped with the service
+            _ = stopped.changed() => break,
+        };
+        let (size, origin) = match received {
+            Ok(found) => {
+                failures = 0;
+                found
+            }
+            Err(error) => {
+                // the pause doubles with each failure, for a broken socket not to spin
+                failures += 1;
+                let backoff = Duration::from_millis(1 << failures.min(10));
+                let backoff = backoff.min(MAX_RECEIVE_BACKOFF);
+                tracing::debug!(
+                    "unable to receive upstream response, retrying in {backoff:?}: {error}"
+                );
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+        let Some(pending) = pending.upgrade() else {
+            break;
+        };
+        tracing::debug!("received {size} bytes from server");
+        let packet = match DnsPacket::try_from(BytePacketBuffer::new(&buffer.buf[..size])) {
+            Ok(found) => found,
+            Err(error) => {
+                tracing::debug!("unable to read upstream response: {error:?}");
+                continue;
+            }
+        };
+        let sender = pending_key(&packet).and_then(|key| {
+            let mut pending = pending.lock().unwrap();
+            // a response from another address, or to another socket, could be spoofed,
+            // the query keeps waiting
+            match pending.get(&key) {
+                Some(((sent_from, server), _))
+                    if *sent_from == local
+                        && socket::canonical(*server) == socket::canonical(origin) =>
+                {
+                    pending.remove(&key).map(|(_, sender)| sender)
+                }
+                _ => None,
+            }
+        });
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(packet);
+            }
+            None => tracing::debug!(
+                "dropping unexpected upstream response {} from {origin}",
+                packet.header.id
+            ),
         }
     }
 }
 
 impl Config {
     pub fn default_address() -> SocketAddr {
-        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 43210))
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0))
+    }
+
+    pub fn default_servers() -> Vec<ServerConfig> {
+        vec!["1.1.1.1".into(), "1.0.0.1".into()]
     }
 
-    pub fn default_servers() -> Vec<String> {
-        vec!["1.1.1.1".to_string(), "1.0.0.1".to_string()]
+    pub fn default_dnssec() -> bool {
+        true
     }
 }
 
 impl Config {
-    pub async fn build(self) -> Result<RemoteLookupService> {
+    pub async fn build(&self) -> Result<RemoteLookupService> {
         RemoteLookupService::new(self).await
     }
 }
 
 #[async_trait::async_trait]
 pub trait LookupService {
-    async fn lookup(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
+    async fn lookup(&self, qname: &str, qtype: QueryType, source: QuerySource)
+        -> Result<DnsPacket>;
 }
 
 pub struct RemoteLookupService {
-    socket: UdpSocket,
-    servers: Vec<(String, u16)>,
-    index: AtomicU16,
+    /// Sockets of the udp queries, a single one when the port is configured
+    sockets: Vec<Arc<UdpSocket>>,
+    /// Stops the dispatchers of the sockets once dropped with the service
+    _stopped: watch::Sender<()>,
+    /// Queries waiting for their response
+    pending: Arc<PendingQueries>,
+    /// Servers ordered by preference, updated by the probes
+    servers: RwLock<Vec<Upstream>>,
+    /// Source of the query ids, unpredictable so that the responses can't be spoofed
+    random: SystemRandom,
+    strategy: Strategy,
+    retry: RetryConfig,
+    /// Number of queries sent, used by the round robin strategy
+    turn: AtomicUsize,
+    health: Mutex<HashMap<String, ServerHealth>>,
+    probe: ProbeConfig,
+    ranking: RwLock<Vec<UpstreamStatus>>,
+    dnssec: bool,
+    tls: TlsConnector,
 }
 
 impl RemoteLookupService {
-    async fn new(config: Config) -> Result<Self> {
-        let socket = UdpSocket::bind(config.address).await?;
+    async fn new(config: &Config) -> Result<Self> {
+        let servers = resolve_servers(&config.servers).await;
+        let first = bind(config.address, &servers)?;
+        // the other sockets get their own random port, on the address the first one ended up with
+        let address = SocketAddr::new(first.local_addr()?.ip(), 0);
+        let count = if config.address.port() == 0 {
+            UDP_SOCKETS
+        } else {
+            1
+        };
+        let mut sockets = vec![Arc::new(UdpSocket::from_std(first)?)];
+        for _ in 1..count {
+            let socket = bind_udp(address, BindOptions::default())?;
+            sockets.push(Arc::new(UdpSocket::from_std(socket)?));
+        }
+        let pending = Arc::new(PendingQueries::default());
+        let (stopped, receiver) = watch::channel(());
+        for socket in sockets.iter() {
+            tokio::spawn(dispatch(
+                socket.clone(),
+                Arc::downgrade(&pending),
+                receiver.clone(),
+            ));
+        }
 
         Ok(Self {
-            socket,
-            servers: config.servers.into_iter().map(|item| (item, 53)).collect(),
-            index: AtomicU16::new(0),
+            sockets,
+            _stopped: stopped,
+            pending,
+            servers: RwLock::new(servers),
+            random: SystemRandom::new(),
+            strategy: config.strategy,
+            retry: config.retry.clone(),
+            turn: AtomicUsize::new(0),
+            health: Default::default(),
+            probe: config.probe.clone(),
+            ranking: Default::default(),
+            dnssec: config.dnssec,
+            tls: tls_connector(),
         })
     }
-}
 
-#[async_trait::async_trait]
-impl LookupService for RemoteLookupService {
-    #[tracing::instrument(skip(self))]
-    async fn lookup(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-        let mut packet = DnsPacket::default();
+    /// Servers, in the order they're used
+    pub fn upstreams(&self) -> Vec<String> {
+        self.servers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|server| server.name.clone())
+            .collect()
+    }
+
+    /// Protocols used to reach the servers
+    pub fn protocols(&self) -> Vec<&'static str> {
+        let mut result: Vec<_> = self
+            .servers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|server| server.transport)
+            .collect();
+        result.sort();
+        result.dedup();
+        result.into_iter().map(|item| item.as_str()).collect()
+    }
+
+    /// Replaces the servers with the ones of a reloaded configuration, until the next probe
+    pub async fn set_servers(&self, servers: Vec<ServerConfig>) {
+        let servers = resolve_servers(&servers).await;
+        self.health
+            .lock()
+            .unwrap()
+            .retain(|name, _| servers.iter().any(|server| &server.name == name));
+        self.ranking.write().unwrap().clear();
+        *self.servers.write().unwrap() = servers;
+    }
 
-        packet.header.id = self.index.fetch_add(1, Ordering::SeqCst);
-        packet.header.recursion_desired = true;
-        packet
-            .questions
-            .push(Question::new(qname.to_string(), qtype));
+    pub fn probe_config(&self) -> &ProbeConfig {
+        &self.probe
+    }
 
-        let req_buffer = packet.create_buffer()?;
-        self.socket
-            .send_to(&req_buffer.buf[0..req_buffer.pos], &self.servers[0])
-            .await?;
+    /// Health of the servers that have been queried
+    pub fn health(&self) -> HashMap<String, ServerHealth> {
+        self.health.lock().unwrap().clone()
+    }
 
-        let mut res_buffer = BytePacketBuffer::default();
-        let (size, _) = self.socket.recv_from(&mut res_buffer.buf).await?;
+    fn record(&self, server: &str, result: Option<Duration>) {
+        let mut health = self.health.lock().unwrap();
+        let entry = health.entry(server.to_string()).or_default();
+        match result {
+            Some(elapsed) => entry.record_success(elapsed),
+            None => entry.record_failure(),
+        }
+    }
 
-        tracing::debug!("received {size} bytes from server");
+    /// Sends a query to the server with its transport and waits for the response
+    async fn exchange(&self, server: &Upstream, packet: &DnsPacket) -> Result<DnsPacket> {
+        match server.transport {
+            UpstreamTransport::Udp => {
+                let response = self.exchange_udp(server, packet).await?;
+                if !response.header.truncated_message {
+                    return Ok(response);
+                }
+                // the response didn't fit in a datagram, it's sent whole over tcp
+                tracing::debug!("truncated response from {}, retrying over tcp", server.name);
+                let mut stream = TcpStream::connect(server.address).await?;
+                exchange_stream(&mut stream, packet).await
+            }
+            UpstreamTransport::Tcp => {
+                let mut stream = TcpStream::connect(server.address).await?;
+                exchange_stream(&mut stream, packet).await
+            }
+            UpstreamTransport::Tls => {
+                let name = ServerName::try_from(server.tls_name.as_str())
+                    .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+                let stream = TcpStream::connect(server.address).await?;
+                let mut stream = self.tls.connect(name, stream).await?;
+                exchange_stream(&mut stream, packet).await
+            }
+        }
+    }
 
-        Ok(DnsPacket::try_from(res_buffer)?)
+    /// Socket of a query, picked at random
+    fn pick_socket(&self) -> Result<&UdpSocket> {
+        let index = ring::rand::generate::<[u8; 4]>(&self.random)
+            .map(|index| u32::from_be_bytes(index.expose()) as usize)
+            .map_err(|_| Error::other("unable to pick a socket"))?;
+        Ok(&self.sockets[index % self.sockets.len()])
+    }
+
+    /// Sends a query to the server and waits for the dispatcher to get its response
+    async fn exchange_udp(&self, server: &Upstream, packet: &DnsPacket) -> Result<DnsPacket> {
+        let key = pending_key(packet)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "query without question"))?;
+        let socket = self.pick_socket()?;
+        let local = socket.local_addr()?;
+        let target = socket::destination(server.address, local);
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(key.clone(), ((local, target), sender));
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            key,
+        };
+
+        let buffer = packet.create_buffer()?;
+        socket.send_to(&buffer.buf[0..buffer.pos], target).await?;
+        receiver
+            .await
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "upstream responses not received"))
+    }
+
+    /// Tries each server once, in the order of the strategy, until one of them answers
+    async fn try_servers(&self, packet: &DnsPacket, source: QuerySource) -> Result<DnsPacket> {
+        let servers = {
+            let servers = self.servers.read().unwrap();
+            let health = self.health.lock().unwrap();
+            let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+            attempt_order(&servers, &health, self.strategy, turn)
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // the last failure is returned when no server answers properly
+        let mut last: Result<DnsPacket> =
+            Err(Error::new(ErrorKind::NotFound, "no upstream server"));
+        for server in servers.iter() {
+            tracing::debug!("forwarding {source} query to {}", server.name);
+            let started = Instant::now();
+            last = match tokio::time::timeout(self.retry.timeout(), self.exchange(server, packet))
+                .await
+            {
+                Ok(Ok(response))
+                    if response.header.response_code != ResponseCode::ServerFailure =>
+                {
+                    self.record(&server.name, Some(started.elapsed()));
+                    return Ok(response);
+                }
+                Ok(Ok(response)) => {
+                    tracing::warn!("upstream {} answered SERVFAIL", server.name);
+                    Ok(response)
+                }
+                Ok(Err(error)) => {
+                    tracing::warn!("upstream {} failed: {error}", server.name);
+                    Err(error)
+                }
+                Err(_) => {
+                    tracing::warn!("upstream {} timed out", server.name);
+                    Err(Error::new(ErrorKind::TimedOut, "upstream timed out"))
+                }
+            };
+            self.record(&server.name, None);
+        }
+        last
+    }
+
+    /// Last ranking of the upstream servers, empty until probed
+    pub fn ranking(&self) -> Vec<UpstreamStatus> {
+        self.ranking.read().unwrap().clone()
+    }
+
+    async fn probe_server(&self, server: &Upstream, bind: SocketAddr) -> UpstreamStatus {
+        let mut answered = 0;
+        let mut total = Duration::ZERO;
+        // a dedicated socket so that the responses don't get mixed with the client ones
+        let socket = (server.transport == UpstreamTransport::Udp).then(|| {
+            bind_udp(SocketAddr::new(bind.ip(), 0), BindOptions::default())
+                .and_then(UdpSocket::from_std)
+        });
+        for index in 0..self.probe.queries {
+            if let Some(Err(_)) = socket {
+                break;
+            }
+            let packet = DnsPacket::query(
+                index as u16,
+                Question::new(self.probe.domain.clone(), QueryType::A),
+            );
+            let exchanged = async {
+                match socket {
+                    Some(Ok(ref socket)) => exchange(socket, server, &packet).await,
+                    _ => self.exchange(server, &packet).await,
+                }
+            };
+            let started = Instant::now();
+            match tokio::time::timeout(PROBE_TIMEOUT, exchanged).await {
+                Ok(Ok(_)) => {
+                    answered += 1;
+                    total += started.elapsed();
+                }
+                Ok(Err(error)) => tracing::debug!("probe of {} failed: {error}", server.name),
+                Err(_) => tracing::debug!("probe of {} timed out", server.name),
+            }
+        }
+        UpstreamStatus {
+            server: server.name.clone(),
+            queries: self.probe.queries,
+            answered,
+            average_latency: (answered > 0).then(|| total / answered),
+        }
+    }
+
+    /// Sends test queries to every server and uses them by order of reliability and latency
+    pub async fn probe(&self) -> Vec<UpstreamStatus> {
+        let servers = self.servers.read().unwrap().clone();
+        let bind = self.sockets[0]
+            .local_addr()
+            .unwrap_or(Config::default_address());
+        let mut statuses =
+            futures::future::join_all(servers.iter().map(|server| self.probe_server(server, bind)))
+                .await;
+        rank(&mut statuses);
+        // servers not answering at all keep their configuration order, at the end
+        *self.servers.write().unwrap() = statuses
+            .iter()
+            .filter_map(|status| servers.iter().find(|server| server.name == status.server))
+            .cloned()
+            .collect();
+        *self.ranking.write().unwrap() = statuses.clone();
+        statuses
+    }
+}
+
+#[async_trait::async_trait]
+impl LookupService for RemoteLookupService {
+    #[tracing::instrument(skip(self))]
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        source: QuerySource,
+    ) -> Result<DnsPacket> {
+        let id = ring::rand::generate::<[u8; 2]>(&self.random)
+            .map(|id| u16::from_be_bytes(id.expose()))
+            .map_err(|_| Error::other("unable to generate a query id"))?;
+        let mut packet = DnsPacket::query(id, Question::new(qname.to_string(), qtype));
+        if self.dnssec {
+            // the signatures are kept for the clients asking for them, the larger
+            // responses needing EDNS to fit in a datagram
+            packet.resources.push(Record::OPT {
+                payload_size: EDNS_PACKET_SIZE as u16,
+                flags: EDNS_DNSSEC_OK,
+                data: Vec::new(),
+            });
+        }
+
+        let mut backoff = self.retry.backoff();
+        let mut result = self.try_servers(&packet, source).await;
+        for attempt in 1..=self.retry.retries {
+            let failed = match result {
+                Ok(ref response) => response.header.response_code == ResponseCode::ServerFailure,
+                Err(_) => true,
+            };
+            if !failed {
+                break;
+            }
+            tracing::debug!(
+                "all upstream servers failed, retrying in {backoff:?} ({attempt}/{})",
+                self.retry.retries
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            result = self.try_servers(&packet, source).await;
+        }
+        // the AD bit of the upstream servers isn't trusted, only the validation sets it
+        result.map(|mut response| {
+            response.header.authed_data = false;
+            response
+        })
     }
 }
 
@@ -110,7 +1005,12 @@ impl MockLookupService {
 #[cfg(test)]
 #[async_trait::async_trait]
 impl LookupService for MockLookupService {
-    async fn lookup(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        _source: QuerySource,
+    ) -> Result<DnsPacket> {
         if let Some(found) = self.inner.get(&(qname, qtype)) {
             Ok(found.clone())
         } else {
@@ -120,3 +1020,549 @@ impl LookupService for MockLookupService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerHealth, Strategy, UpstreamStatus};
+    use crate::common::source::QuerySource;
+    use crate::repository::lookup::LookupService;
+    use donos_parser::buffer::BytePacketBuffer;
+    use donos_parser::packet::header::ResponseCode;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    fn status(server: &str, answered: u32, latency: Option<u64>) -> UpstreamStatus {
+        UpstreamStatus {
+            server: server.into(),
+            queries: 3,
+            answered,
+            average_latency: latency.map(Duration::from_millis),
+        }
+    }
+
+    #[test]
+    fn should_rank_by_reliability_then_latency() {
+        let mut statuses = vec![
+            status("down", 0, None),
+            status("slow", 3, Some(80)),
+            status("flaky", 2, Some(5)),
+            status("fast", 3, Some(10)),
+            status("down-too", 0, None),
+        ];
+        super::rank(&mut statuses);
+        let servers: Vec<&str> = statuses.iter().map(|item| item.server.as_str()).collect();
+        assert_eq!(servers, vec!["fast", "slow", "flaky", "down", "down-too"]);
+    }
+
+    #[test]
+    fn should_order_attempts_following_strategy() {
+        let servers: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| server(name, 53))
+            .collect();
+        let names = |order: Vec<&super::Upstream>| {
+            order
+                .into_iter()
+                .map(|server| server.name.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut health = HashMap::new();
+        assert_eq!(
+            names(super::attempt_order(
+                &servers,
+                &health,
+                Strategy::Ordered,
+                4
+            )),
+            "a,b,c"
+        );
+        assert_eq!(
+            names(super::attempt_order(
+                &servers,
+                &health,
+                Strategy::RoundRobin,
+                4
+            )),
+            "b,c,a"
+        );
+
+        let latency = |millis| ServerHealth {
+            consecutive_failures: 0,
+            latency: Some(Duration::from_millis(millis)),
+        };
+        health.insert("a".to_string(), latency(50));
+        health.insert("b".to_string(), latency(10));
+        assert_eq!(
+            names(super::attempt_order(
+                &servers,
+                &health,
+                Strategy::Fastest,
+                0
+            )),
+            "b,a,c"
+        );
+
+        // the failing servers are tried last
+        health.insert(
+            "b".to_string(),
+            ServerHealth {
+                consecutive_failures: super::MAX_FAILURES,
+                latency: None,
+            },
+        );
+        assert_eq!(
+            names(super::attempt_order(
+                &servers,
+                &health,
+                Strategy::Ordered,
+                0
+            )),
+            "a,c,b"
+        );
+    }
+
+    fn server(name: &str, port: u16) -> super::Upstream {
+        super::Upstream {
+            name: name.to_string(),
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            transport: super::UpstreamTransport::Udp,
+            tls_name: name.to_string(),
+        }
+    }
+
+    /// Fake upstream answering every query with the given code
+    async fn upstream(code: ResponseCode) -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        serve(socket, code)
+    }
+
+    fn serve(socket: UdpSocket, code: ResponseCode) -> u16 {
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = BytePacketBuffer::default();
+            while let Ok((_, origin)) = socket.recv_from(&mut buffer.buf).await {
+                let request = DnsPacket::try_from(BytePacketBuffer::new(buffer.buf.clone()));
+                let Ok(request) = request else {
+                    continue;
+                };
+                let response = DnsPacket::response_from(&request)
+                    .with_response_code(code)
+                    .create_buffer()
+                    .unwrap();
+                let _ = socket.send_to(&response.buf[..response.pos], origin).await;
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn should_split_addresses_with_ports() {
+        let split = |value| super::split_address(value, 53);
+        assert_eq!(split("1.1.1.1"), ("1.1.1.1".into(), 53));
+        assert_eq!(split("1.1.1.1:5353"), ("1.1.1.1".into(), 5353));
+        assert_eq!(split("2606:4700::1111"), ("2606:4700::1111".into(), 53));
+        assert_eq!(
+            split("[2606:4700::1111]:5353"),
+            ("2606:4700::1111".into(), 5353)
+        );
+        assert_eq!(split("[::1]"), ("::1".into(), 53));
+        assert_eq!(split("dns.quad9.net"), ("dns.quad9.net".into(), 53));
+        assert_eq!(split("dns.quad9.net:853"), ("dns.quad9.net".into(), 853));
+    }
+
+    #[test]
+    fn should_parse_detailed_servers() {
+        #[derive(serde::Deserialize)]
+        struct Root {
+            lookup: super::Config,
+        }
+
+        let config = ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                r#"
+[lookup]
+servers = [
+    "1.1.1.1",
+    { address = "9.9.9.9", transport = "tls", tls_name = "dns.quad9.net" },
+    { address = "dns.google:53", transport = "tcp" },
+]
+"#,
+                ::config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize::<Root>()
+            .unwrap()
+            .lookup;
+        assert_eq!(
+            config.servers,
+            vec![
+                super::ServerConfig::Address("1.1.1.1".into()),
+                super::ServerConfig::Detailed {
+                    address: "9.9.9.9".into(),
+                    transport: super::UpstreamTransport::Tls,
+                    tls_name: Some("dns.quad9.net".into()),
+                },
+                super::ServerConfig::Detailed {
+                    address: "dns.google:53".into(),
+                    transport: super::UpstreamTransport::Tcp,
+                    tls_name: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_resolve_servers_with_default_ports() {
+        let server = super::ServerConfig::Detailed {
+            address: "9.9.9.9".into(),
+            transport: super::UpstreamTransport::Tls,
+            tls_name: Some("dns.quad9.net".into()),
+        };
+        let upstream = server.resolve().await.unwrap();
+        assert_eq!(upstream.address, "9.9.9.9:853".parse().unwrap());
+        assert_eq!(upstream.tls_name, "dns.quad9.net");
+
+        let upstream = super::ServerConfig::from("localhost:5353")
+            .resolve()
+            .await
+            .unwrap();
+        assert_eq!(upstream.name, "localhost:5353");
+        assert_eq!(upstream.address.port(), 5353);
+        assert!(upstream.address.ip().is_loopback());
+    }
+
+    /// Fake upstream answering a single query over tcp with the given code
+    fn serve_tcp(listener: tokio::net::TcpListener, code: ResponseCode) {
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let size = stream.read_u16().await.unwrap() as usize;
+            let mut buffer = vec![0; size];
+            stream.read_exact(&mut buffer).await.unwrap();
+            let request = DnsPacket::try_from(BytePacketBuffer::new(buffer)).unwrap();
+            let response = DnsPacket::response_from(&request)
+                .with_response_code(code)
+                .create_buffer()
+                .unwrap();
+            stream.write_u16(response.pos as u16).await.unwrap();
+            stream
+                .write_all(&response.buf[..response.pos])
+                .await
+                .unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn should_forward_over_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        serve_tcp(listener, ResponseCode::NameError);
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            servers: vec![super::ServerConfig::Detailed {
+                address: format!("127.0.0.1:{port}"),
+                transport: super::UpstreamTransport::Tcp,
+                tls_name: None,
+            }],
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        assert_eq!(service.protocols(), vec!["tcp"]);
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NameError);
+    }
+
+    #[tokio::test]
+    async fn should_retry_truncated_responses_over_tcp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        serve_tcp(listener, ResponseCode::NameError);
+        // the same server over udp only answers with the TC bit
+        let socket = UdpSocket::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(async move {
+            let mut buffer = BytePacketBuffer::default();
+            while let Ok((_, origin)) = socket.recv_from(&mut buffer.buf).await {
+                let request = DnsPacket::try_from(BytePacketBuffer::new(buffer.buf.clone()));
+                let Ok(request) = request else {
+                    continue;
+                };
+                let mut response = DnsPacket::response_from(&request);
+                response.header = response.header.with_truncated_message(true);
+                let response = response.create_buffer().unwrap();
+                let _ = socket.send_to(&response.buf[..response.pos], origin).await;
+            }
+        });
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            servers: vec![format!("127.0.0.1:{port}").as_str().into()],
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NameError);
+        assert!(!response.header.truncated_message);
+    }
+
+    #[tokio::test]
+    async fn should_ignore_responses_from_other_addresses() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buffer = BytePacketBuffer::default();
+            let (size, origin) = socket.recv_from(&mut buffer.buf).await.unwrap();
+            let request = DnsPacket::try_from(BytePacketBuffer::new(&buffer.buf[..size])).unwrap();
+            // another host answers first, with the right id and question
+            let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let spoofed = DnsPacket::response_from(&request)
+                .with_response_code(ResponseCode::NameError)
+                .create_buffer()
+                .unwrap();
+            spoofer
+                .send_to(&spoofed.buf[..spoofed.pos], origin)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let response = DnsPacket::response_from(&request).create_buffer().unwrap();
+            socket
+                .send_to(&response.buf[..response.pos], origin)
+                .await
+                .unwrap();
+        });
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            servers: vec![format!("127.0.0.1:{port}").as_str().into()],
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn should_reach_ipv4_and_ipv6_servers() {
+        let Ok(socket) = UdpSocket::bind("[::1]:0").await else {
+            // ipv6 is disabled on this machine
+            return;
+        };
+        let ipv6 = serve(socket, ResponseCode::NameError);
+        let ipv4 = upstream(ResponseCode::ServerFailure).await;
+        let service = super::Config {
+            address: "0.0.0.0:0".parse().unwrap(),
+            servers: vec![
+                format!("127.0.0.1:{ipv4}").as_str().into(),
+                format!("[::1]:{ipv6}").as_str().into(),
+            ],
+            retry: super::RetryConfig {
+                retries: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        assert!(service
+            .sockets
+            .iter()
+            .all(|socket| socket.local_addr().unwrap().is_ipv6()));
+        assert_eq!(
+            service.upstreams(),
+            vec![format!("127.0.0.1:{ipv4}"), format!("[::1]:{ipv6}")]
+        );
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        // the ipv4 server answered through the dual-stack socket before the ipv6 one
+        assert_eq!(response.header.response_code, ResponseCode::NameError);
+        let health = service.health();
+        assert_eq!(health[&format!("127.0.0.1:{ipv4}")].consecutive_failures, 1);
+        assert_eq!(health[&format!("[::1]:{ipv6}")].consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn should_fail_over_on_servfail() {
+        let failing = upstream(ResponseCode::ServerFailure).await;
+        let working = upstream(ResponseCode::NameError).await;
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        *service.servers.write().unwrap() =
+            vec![server("127.0.0.1", failing), server("localhost", working)];
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NameError);
+        let health = service.health();
+        assert_eq!(health["127.0.0.1"].consecutive_failures, 1);
+        assert_eq!(health["localhost"].consecutive_failures, 0);
+        assert!(health["localhost"].latency.is_some());
+    }
+
+    #[tokio::test]
+    async fn should_retry_when_all_servers_fail() {
+        let failing = upstream(ResponseCode::ServerFailure).await;
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            retry: super::RetryConfig {
+                timeout: 500,
+                retries: 2,
+                backoff: 1,
+            },
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        *service.servers.write().unwrap() = vec![server("127.0.0.1", failing)];
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let response = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::ServerFailure);
+        assert_eq!(service.health()["127.0.0.1"].consecutive_failures, 3);
+    }
+
+    #[tokio::test]
+    async fn should_send_the_queries_from_random_ports() {
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        let mut ports: Vec<u16> = service
+            .sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap().port())
+            .collect();
+        ports.sort();
+        ports.dedup();
+        assert_eq!(ports.len(), super::UDP_SOCKETS);
+
+        // the configured port is the only one used
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let service = super::Config {
+            address: SocketAddr::from(([127, 0, 0, 1], port)),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        assert_eq!(service.sockets.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_stop_dispatching_once_dropped() {
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        let sockets: Vec<_> = service.sockets.iter().map(Arc::downgrade).collect();
+        drop(service);
+
+        // the dispatchers stop and release their socket
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while sockets.iter().any(|socket| socket.upgrade().is_some()) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn should_match_responses_received_out_of_order() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = socket.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            // waits for both queries and answers the last one first
+            let mut queries = Vec::new();
+            let mut buffer = BytePacketBuffer::default();
+            while queries.len() < 2 {
+                let (size, origin) = socket.recv_from(&mut buffer.buf).await.unwrap();
+                let request = DnsPacket::try_from(BytePacketBuffer::new(&buffer.buf[..size]));
+                queries.push((request.unwrap(), origin));
+            }
+            for (request, origin) in queries.into_iter().rev() {
+                let response = DnsPacket::response_from(&request)
+                    .with_answer(donos_parser::packet::record::Record::A {
+                        domain: request.questions[0].name.clone(),
+                        addr: std::net::Ipv4Addr::new(1, 2, 3, 4),
+                        ttl: 60,
+                    })
+                    .create_buffer()
+                    .unwrap();
+                socket
+                    .send_to(&response.buf[..response.pos], origin)
+                    .await
+                    .unwrap();
+            }
+        });
+        let service = super::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        }
+        .build()
+        .await
+        .unwrap();
+        *service.servers.write().unwrap() = vec![server("127.0.0.1", port)];
+
+        let source = QuerySource::Internal(crate::common::source::InternalReason::HealthCheck);
+        let (first, second) = tokio::join!(
+            service.lookup("first.com", QueryType::A, source),
+            service.lookup("second.com", QueryType::A, source),
+        );
+        assert_eq!(first.unwrap().answers[0].domain(), "first.com");
+        assert_eq!(second.unwrap().answers[0].domain(), "second.com");
+        assert!(service.pending.lock().unwrap().is_empty());
+    }
+}
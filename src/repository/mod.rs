@@ -1,3 +1,8 @@
 pub mod blocklist;
 pub mod cache;
+pub mod client;
 pub mod lookup;
+pub mod query;
+pub mod recursive;
+pub mod routing;
+pub mod validator;
@@ -0,0 +1,400 @@
+use donos_parser::packet::QueryType;
+use sqlx::{Pool, QueryBuilder, Sqlite};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// Number of queries waiting to be written before the next ones get dropped
+const QUEUE_SIZE: usize = 4096;
+/// Number of queries written at once
+const BATCH_SIZE: usize = 256;
+/// Delay between two removals of the outdated queries
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// History of the queries received from the clients, used by the `stats` command
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default = "Config::default_enabled")]
+    pub enabled: bool,
+    /// Number of days the queries are kept
+    #[serde(default = "Config::default_retention")]
+    pub retention: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            retention: Self::default_retention(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn default_retention() -> u64 {
+        7
+    }
+
+    pub fn retention(&self) -> Duration {
+        Duration::from_secs(self.retention * 86400)
+    }
+
+    /// Spawns the task writing the queries in database, returns `None` when disabled
+    pub fn build(&self, database: Pool<Sqlite>) -> Option<QueryLogger> {
+        if !self.enabled {
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let service = DatabaseQueryLogService::new(database);
+        tokio::spawn(service.run(receiver, self.retention()));
+        Some(QueryLogger { sender })
+    }
+}
+
+/// Query received from a client, with where its answer came from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoggedQuery {
+    pub client: IpAddr,
+    /// Name of the client when known, like its DHCP hostname
+    pub client_name: Option<String>,
+    pub domain: String,
+    pub qtype: QueryType,
+    pub provenance: &'static str,
+    pub created_at: u64,
+}
+
+impl LoggedQuery {
+    pub fn new(client: IpAddr, domain: String, qtype: QueryType, provenance: &'static str) -> Self {
+        Self {
+            client,
+            client_name: None,
+            domain,
+            qtype,
+            provenance,
+            created_at: unix_now(),
+        }
+    }
+
+    pub fn with_client_name(mut self, client_name: Option<&str>) -> Self {
+        self.client_name = client_name.map(String::from);
+        self
+    }
+}
+
+/// Sends the queries to be written in database, without waiting for it
+#[derive(Clone, Debug)]
+pub struct QueryLogger {
+    sender: mpsc::Sender<LoggedQuery>,
+}
+
+impl QueryLogger {
+    pub fn log(&self, query: LoggedQuery) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(query) {
+            tracing::debug!("query log queue is full, dropping query");
+        }
+    }
+}
+
+/// Query read back from the log
+#[derive(Debug, serde::Serialize)]
+pub struct QueryEntry {
+    pub client: String,
+    pub client_name: Option<String>,
+    pub domain: String,
+    pub qtype: String,
+    pub provenance: String,
+    pub created_at: i64,
+}
+
+/// Totals of the queries received since a given time
+#[derive(Debug, Default, serde::Serialize)]
+pub struct Stats {
+    pub queries: u64,
+    /// Number of queries by provenance of their answer
+    pub provenances: Vec<(String, u64)>,
+    pub top_blocked: Vec<(String, u64)>,
+    /// Number of queries by client name, or address for the unnamed ones
+    pub top_clients: Vec<(String, u64)>,
+}
+
+impl Stats {
+    pub fn count(&self, provenance: &str) -> u64 {
+        self.provenances
+            .iter()
+            .find(|(name, _)| name == provenance)
+            .map(|(_, count)| *count)
+            .unwrap_or(0)
+    }
+}
+
+type QueryRow = (String, Option<String>, String, u16, String, i64);
+
+#[derive(Debug, Clone)]
+pub struct DatabaseQueryLogService {
+    database: Pool<Sqlite>,
+}
+
+impl DatabaseQueryLogService {
+    pub fn new(database: Pool<Sqlite>) -> Self {
+        Self { database }
+    }
+
+    pub async fn insert(&self, queries: &[LoggedQuery]) -> Result<(), sqlx::Error> {
+        if queries.is_empty() {
+            return Ok(());
+        }
+        let mut builder = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO queries (client, client_name, domain, qtype, provenance, created_at) ",
+        );
+        builder.push_values(queries, |mut row, query| {
+            row.push_bind(query.client.to_string())
+                .push_bind(query.client_name.as_deref())
+                .push_bind(query.domain.as_str())
+                .push_bind(query.qtype.into_num())
+                .push_bind(query.provenance)
+                .push_bind(query.created_at as i64);
+        });
+        builder.build().execute(&self.database).await?;
+        Ok(())
+    }
+
+    /// Removes the queries received before the given time, returns how many were removed
+    pub async fn prune(&self, before: u64) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM queries WHERE created_at < $1")
+            .bind(before as i64)
+            .execute(&self.database)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn stats(&self, since: u64, limit: u32) -> Result<Stats, sqlx::Error> {
+        let since = since as i64;
+        let provenances: Vec<(String, i64)> = sqlx::query_as(
+            r#"SELECT provenance, COUNT(*) FROM queries
+WHERE created_at >= $1
+GROUP BY provenance
+ORDER BY provenance"#,
+        )
+        .bind(since)
+        .fetch_all(&self.database)
+        .await?;
+        let top_blocked: Vec<(String, i64)> = sqlx::query_as(
+            r#"SELECT domain, COUNT(*) AS total FROM queries
+WHERE created_at >= $1 AND provenance = 'blocked'
+GROUP BY domain
+ORDER BY total DESC, domain
+LIMIT $2"#,
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.database)
+        .await?;
+        let top_clients: Vec<(String, i64)> = sqlx::query_as(
+            r#"SELECT COALESCE(client_name, client) AS name, COUNT(*) AS total FROM queries
+WHERE created_at >= $1
+GROUP BY name
+ORDER BY total DESC, name
+LIMIT $2"#,
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.database)
+        .await?;
+
+        let convert = |rows: Vec<(String, i64)>| -> Vec<(String, u64)> {
+            rows.into_iter()
+                .map(|(name, count)| (name, count as u64))
+                .collect()
+        };
+        let provenances = convert(provenances);
+        Ok(Stats {
+            queries: provenances.iter().map(|(_, count)| count).sum(),
+            provenances,
+            top_blocked: convert(top_blocked),
+            top_clients: convert(top_clients),
+        })
+    }
+
+    /// Domains the most queried by a client, given by its name or its address,
+    /// with how many of their queries were blocked
+    pub async fn client_domains(
+        &self,
+        client: &str,
+        since: u64,
+        limit: u32,
+    ) -> Result<Vec<(String, u64, u64)>, sqlx::Error> {
+        let rows: Vec<(String, i64, i64)> = sqlx::query_as(
+            r#"SELECT domain, COUNT(*) AS total, SUM(provenance = 'blocked') FROM queries
+WHERE created_at >= $1 AND (client = $2 OR client_name = $2)
+GROUP BY domain
+ORDER BY total DESC, domain
+LIMIT $3"#,
+        )
+        .bind(since as i64)
+        .bind(client)
+        .bind(limit)
+        .fetch_all(&self.database)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(domain, total, blocked)| (domain, total as u64, blocked as u64))
+            .collect())
+    }
+
+    /// Last queries received, the most recent first
+    pub async fn recent(&self, limit: u32) -> Result<Vec<QueryEntry>, sqlx::Error> {
+        let rows: Vec<QueryRow> = sqlx::query_as(
+            r#"SELECT client, client_name, domain, qtype, provenance, created_at FROM queries
+ORDER BY id DESC
+LIMIT $1"#,
+        )
+        .bind(limit)
+        .fetch_all(&self.database)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(client, client_name, domain, qtype, provenance, created_at)| QueryEntry {
+                    client,
+                    client_name,
+                    domain,
+                    qtype: format!("{:?}", QueryType::from_num(qtype)),
+                    provenance,
+                    created_at,
+                },
+            )
+            .collect())
+    }
+
+    /// Writes the received queries by batches and removes the outdated ones,
+    /// until all the loggers are dropped.
+    async fn run(self, mut receiver: mpsc::Receiver<LoggedQuery>, retention: Duration) {
+        let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            tokio::select! {
+                received = receiver.recv_many(&mut batch, BATCH_SIZE) => {
+                    if received == 0 {
+                        return;
+                    }
+                    if let Err(error) = self.insert(&batch).await {
+                        tracing::warn!("couldn't write {} queries in the log: {error:?}", batch.len());
+                    }
+                    batch.clear();
+                }
+                _ = prune.tick() => {
+                    let before = unix_now().saturating_sub(retention.as_secs());
+                    match self.prune(before).await {
+                        Ok(0) => {}
+                        Ok(count) => tracing::debug!("removed {count} outdated queries from the log"),
+                        Err(error) => tracing::warn!("couldn't remove outdated queries: {error:?}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatabaseQueryLogService, LoggedQuery};
+    use donos_parser::packet::QueryType;
+    use std::net::IpAddr;
+
+    fn query(client: &str, domain: &str, provenance: &'static str, created_at: u64) -> LoggedQuery {
+        LoggedQuery {
+            client: client.parse::<IpAddr>().unwrap(),
+            client_name: None,
+            domain: domain.to_string(),
+            qtype: QueryType::A,
+            provenance,
+            created_at,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_aggregate_queries() {
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+        let service = DatabaseQueryLogService::new(database);
+        service
+            .insert(&[
+                query("192.168.1.10", "ads.com", "blocked", 100),
+                query("192.168.1.10", "ads.com", "blocked", 100),
+                query("192.168.1.11", "track.com", "blocked", 100),
+                query("192.168.1.10", "perdu.com", "upstream", 100),
+                query("192.168.1.10", "perdu.com", "cache", 100),
+                query("192.168.1.12", "old.com", "upstream", 10),
+            ])
+            .await
+            .unwrap();
+
+        let stats = service.stats(50, 10).await.unwrap();
+        assert_eq!(stats.queries, 5);
+        assert_eq!(stats.count("blocked"), 3);
+        assert_eq!(stats.count("cache"), 1);
+        assert_eq!(stats.count("synthesized"), 0);
+        assert_eq!(
+            stats.top_blocked,
+            vec![("ads.com".into(), 2), ("track.com".into(), 1)]
+        );
+        assert_eq!(stats.top_clients[0], ("192.168.1.10".into(), 4));
+
+        assert_eq!(service.prune(50).await.unwrap(), 1);
+        assert_eq!(service.stats(0, 10).await.unwrap().queries, 5);
+    }
+
+    #[tokio::test]
+    async fn should_aggregate_queries_by_client_name() {
+        let database = crate::service::database::Config::test_env()
+            .build()
+            .await
+            .unwrap();
+        crate::service::database::migrate(&database).await.unwrap();
+        let service = DatabaseQueryLogService::new(database);
+        let named = |client: &str, domain: &str, provenance: &'static str| {
+            query(client, domain, provenance, 100).with_client_name(Some("tv"))
+        };
+        service
+            .insert(&[
+                // the address of the tv changed, its name didn't
+                named("192.168.1.10", "ads.com", "blocked"),
+                named("192.168.1.11", "ads.com", "blocked"),
+                named("192.168.1.11", "ads.com", "upstream"),
+                named("192.168.1.11", "perdu.com", "cache"),
+                query("192.168.1.12", "perdu.com", "cache", 100),
+            ])
+            .await
+            .unwrap();
+
+        let stats = service.stats(0, 10).await.unwrap();
+        assert_eq!(
+            stats.top_clients,
+            vec![("tv".into(), 4), ("192.168.1.12".into(), 1)]
+        );
+        assert_eq!(
+            service.client_domains("tv", 0, 10).await.unwrap(),
+            vec![("ads.com".into(), 3, 2), ("perdu.com".into(), 1, 0)]
+        );
+        assert_eq!(
+            service.client_domains("192.168.1.12", 0, 10).await.unwrap(),
+            vec![("perdu.com".into(), 1, 0)]
+        );
+        let recent = service.recent(1).await.unwrap();
+        assert_eq!(recent[0].client_name, None);
+    }
+}
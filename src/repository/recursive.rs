@@ -0,0 +1,51 @@
+use super::lookup::LookupService;
+use crate::common::source::QuerySource;
+use donos_parser::packet::{DnsPacket, QueryType};
+use donos_resolver::prelude::{Resolver, ResolverError};
+use donos_resolver::recursive::{RecursiveResolver, UdpTransport};
+use std::io::{Error, ErrorKind, Result};
+
+/// Resolves the queries from the root servers, instead of forwarding them
+#[derive(Debug)]
+pub struct RecursiveLookupService {
+    resolver: RecursiveResolver,
+}
+
+impl RecursiveLookupService {
+    pub fn new(config: &super::lookup::Config) -> Self {
+        let transport = UdpTransport::new(config.retry.timeout());
+        Self {
+            resolver: RecursiveResolver::new(Box::new(transport)).with_dnssec(config.dnssec),
+        }
+    }
+}
+
+fn into_error(error: ResolverError) -> Error {
+    match error {
+        ResolverError::Network(kind) => Error::new(kind, "unable to reach the name servers"),
+        ResolverError::Timeout => Error::new(ErrorKind::TimedOut, "name servers timed out"),
+        other => Error::other(format!("recursive resolution failed: {other:?}")),
+    }
+}
+
+#[async_trait::async_trait]
+impl LookupService for RecursiveLookupService {
+    #[tracing::instrument(skip(self))]
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        source: QuerySource,
+    ) -> Result<DnsPacket> {
+        tracing::debug!("resolving {source} query from the root servers");
+        // the AD bit of the name servers isn't trusted, only the validation sets it
+        self.resolver
+            .resolve(qtype, qname)
+            .await
+            .map(|mut response| {
+                response.header.authed_data = false;
+                response
+            })
+            .map_err(into_error)
+    }
+}
@@ -0,0 +1,200 @@
+use super::lookup::{LookupService, ServerConfig};
+use crate::common::domain::{matches_suffix, normalize};
+use crate::common::source::QuerySource;
+use donos_parser::packet::{DnsPacket, QueryType};
+use std::io::Result;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+/// Domain whose queries are sent to dedicated servers, like the ones of a corporate network
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ForwardConfig {
+    /// The domain and its subdomains
+    pub domain: String,
+    pub servers: Vec<ServerConfig>,
+}
+
+struct Route {
+    domain: String,
+    config: Option<ForwardConfig>,
+    service: Arc<dyn LookupService + Send + Sync>,
+}
+
+/// Sends the queries to the servers of the longest matching forwarded domain,
+/// the other ones to the default lookup service.
+pub struct RoutingLookupService {
+    routes: RwLock<Arc<Vec<Route>>>,
+    default: Arc<dyn LookupService + Send + Sync>,
+}
+
+impl RoutingLookupService {
+    pub fn new(default: Arc<dyn LookupService + Send + Sync>) -> Self {
+        Self {
+            routes: Default::default(),
+            default,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_route(mut self, domain: &str, service: Arc<dyn LookupService + Send + Sync>) -> Self {
+        let routes = Arc::get_mut(self.routes.get_mut().unwrap()).unwrap();
+        routes.push(Route {
+            domain: normalize(domain).into_owned(),
+            config: None,
+            service,
+        });
+        self
+    }
+
+    /// Replaces the forwarded domains with the ones of the configuration.
+    ///
+    /// Their servers are reached with the options of the default upstream servers, and
+    /// the domains whose servers didn't change keep their socket and the health of the servers.
+    pub async fn set_zones(&self, config: &super::lookup::Config) {
+        let previous = self.routes.read().unwrap().clone();
+        let mut routes = Vec::with_capacity(config.forward.len());
+        for zone in config.forward.iter() {
+            let domain = normalize(&zone.domain).into_owned();
+            let existing = previous
+                .iter()
+                .find(|route| route.config.as_ref() == Some(zone));
+            if let Some(route) = existing {
+                routes.push(Route {
+                    domain,
+                    config: Some(zone.clone()),
+                    service: route.service.clone(),
+                });
+                continue;
+            }
+            let zone_config = super::lookup::Config {
+                address: SocketAddr::new(config.address.ip(), 0),
+                servers: zone.servers.clone(),
+                strategy: config.strategy,
+                retry: config.retry.clone(),
+                probe: super::lookup::ProbeConfig {
+                    enabled: false,
+                    ..Default::default()
+                },
+                dnssec: config.dnssec,
+                ..Default::default()
+            };
+            match zone_config.build().await {
+                Ok(service) => {
+                    tracing::info!("forwarding {domain} to {}", service.upstreams().join(", "));
+                    routes.push(Route {
+                        domain,
+                        config: Some(zone.clone()),
+                        service: Arc::new(service),
+                    });
+                }
+                Err(error) => {
+                    tracing::warn!("unable to forward {domain}, it will be resolved like the other domains: {error}")
+                }
+            }
+        }
+        *self.routes.write().unwrap() = Arc::new(routes);
+    }
+
+    fn route(&self, qname: &str) -> Arc<dyn LookupService + Send + Sync> {
+        let qname = normalize(qname);
+        let routes = self.routes.read().unwrap();
+        routes
+            .iter()
+            .filter(|route| matches_suffix(&qname, &route.domain))
+            .max_by_key(|route| route.domain.len())
+            .map(|route| route.service.clone())
+            .unwrap_or_else(|| self.default.clone())
+    }
+}
+
+#[async_trait::async_trait]
+impl LookupService for RoutingLookupService {
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        source: QuerySource,
+    ) -> Result<DnsPacket> {
+        self.route(qname).lookup(qname, qtype, source).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoutingLookupService;
+    use crate::common::source::{InternalReason, QuerySource};
+    use crate::repository::lookup::{LookupService, MockLookupService};
+    use donos_parser::packet::header::Header;
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    fn answer(domain: &str, ip: u8) -> DnsPacket {
+        DnsPacket::new(Header::response(0)).with_answer(Record::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(10, 0, 0, ip),
+            ttl: 60,
+        })
+    }
+
+    #[tokio::test]
+    async fn should_route_by_longest_suffix() {
+        let default = MockLookupService::default().with_query(
+            "perdu.com",
+            QueryType::A,
+            answer("perdu.com", 1),
+        );
+        let corp = MockLookupService::default().with_query(
+            "wiki.corp.example",
+            QueryType::A,
+            answer("wiki.corp.example", 2),
+        );
+        let lab = MockLookupService::default().with_query(
+            "ci.lab.corp.example",
+            QueryType::A,
+            answer("ci.lab.corp.example", 3),
+        );
+        let service = RoutingLookupService::new(Arc::new(default))
+            .with_route("corp.example", Arc::new(corp))
+            .with_route("Lab.Corp.Example.", Arc::new(lab));
+
+        let source = QuerySource::Internal(InternalReason::HealthCheck);
+        for (qname, ip) in [
+            ("perdu.com", 1),
+            ("wiki.corp.example", 2),
+            ("ci.lab.corp.example", 3),
+        ] {
+            let response = service.lookup(qname, QueryType::A, source).await.unwrap();
+            assert_eq!(response, answer(qname, ip));
+        }
+        // the other domains of the corporate network are not resolved publicly
+        assert!(service
+            .lookup("mail.corp.example", QueryType::A, source)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn should_keep_unchanged_zones_on_reload() {
+        let zone = |domain: &str| super::ForwardConfig {
+            domain: domain.into(),
+            servers: vec!["127.0.0.1:5353".into()],
+        };
+        let mut config = crate::repository::lookup::Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            forward: vec![zone("corp.example"), zone("home.lan")],
+            ..Default::default()
+        };
+        let service = RoutingLookupService::new(Arc::new(MockLookupService::default()));
+        service.set_zones(&config).await;
+        let corp = service.route("wiki.corp.example");
+        let home = service.route("router.home.lan");
+        assert!(!Arc::ptr_eq(&corp, &home));
+
+        config.forward[1].servers = vec!["127.0.0.1:53".into()];
+        service.set_zones(&config).await;
+        assert!(Arc::ptr_eq(&corp, &service.route("wiki.corp.example")));
+        assert!(!Arc::ptr_eq(&home, &service.route("router.home.lan")));
+    }
+}
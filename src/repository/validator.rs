@@ -0,0 +1,1232 @@
+//! Validation of the upstream answers with DNSSEC (RFC 4035), following the keys
+//! from the trust anchors of the root zone down to the signatures of the records.
+//!
+//! The answers without any signature are only insecure below a delegation whose
+//! absence of DS records is proven by the NSEC or NSEC3 records of the parent zone,
+//! they are bogus otherwise. The same goes for the negative answers of a signed zone,
+//! whose NSEC or NSEC3 records must prove the absence of the name or of the type.
+use crate::common::domain::{equals, matches_suffix, normalize};
+use crate::common::source::{InternalReason, QuerySource};
+use crate::repository::lookup::LookupService;
+use donos_parser::packet::header::{Header, ResponseCode};
+use donos_parser::packet::question::Question;
+use donos_parser::packet::record::Record;
+use donos_parser::packet::{DnsPacket, QueryType};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use moka::future::Cache;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// DS records of the key signing keys of the root zone, published by IANA
+const ROOT_ANCHORS: [&str; 2] = [
+    "20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    "38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// How long a zone without trusted keys is remembered
+const UNTRUSTED_TTL: u32 = 60;
+
+/// Flag of the DNSKEY records holding a key of the zone (RFC 4034 section 2.1.1)
+const ZONE_KEY_FLAG: u16 = 0x0100;
+
+/// Iterations of the NSEC3 hashes over which the proofs are ignored (RFC 9276 section 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub enabled: bool,
+    /// DS records of the root keys, as `<key tag> <algorithm> <digest type> <digest>`
+    #[serde(default = "Config::default_trust_anchors")]
+    pub trust_anchors: Vec<String>,
+    /// Number of zones whose keys are kept
+    #[serde(default = "Config::default_cache_size")]
+    pub cache_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trust_anchors: Self::default_trust_anchors(),
+            cache_size: Self::default_cache_size(),
+        }
+    }
+}
+
+impl Config {
+    pub fn default_trust_anchors() -> Vec<String> {
+        ROOT_ANCHORS.iter().map(|item| item.to_string()).collect()
+    }
+
+    pub fn default_cache_size() -> u64 {
+        1000
+    }
+
+    pub fn build(
+        self,
+        inner: Arc<dyn LookupService + Send + Sync>,
+    ) -> Result<ValidatingLookupService> {
+        let anchors = self
+            .trust_anchors
+            .iter()
+            .map(|value| {
+                parse_anchor(value).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("invalid trust anchor {value:?}"),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ValidatingLookupService {
+            inner,
+            anchors,
+            keys: Cache::new(self.cache_size),
+        })
+    }
+}
+
+/// Reads a DS record of the root zone, like `20326 8 2 E06D44B8...`
+pub(crate) fn parse_anchor(value: &str) -> Option<Record> {
+    let mut parts = value.split_whitespace();
+    let key_tag = parts.next()?.parse().ok()?;
+    let algorithm = parts.next()?.parse().ok()?;
+    let digest_type = parts.next()?.parse().ok()?;
+    let digest: String = parts.collect();
+    if digest.is_empty() || !digest.len().is_multiple_of(2) {
+        return None;
+    }
+    let digest = (0..digest.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(digest.get(idx..idx + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    Some(Record::DS {
+        domain: String::new(),
+        key_tag,
+        algorithm,
+        digest_type,
+        digest,
+        ttl: 0,
+    })
+}
+
+/// Result of the validation of a set of records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Security {
+    /// Signed by keys following from the trust anchors
+    Secure,
+    /// Not signed, or signed with algorithms that aren't supported
+    Insecure,
+    /// The signatures are missing, expired or don't match the records
+    Bogus,
+}
+
+/// Keys of a zone, once followed from the trust anchors
+#[derive(Clone, Debug)]
+enum Keys {
+    Secure(Arc<Vec<Record>>),
+    Insecure,
+    Bogus,
+}
+
+/// Lookup service checking the signatures of the answers, setting the AD bit of
+/// the secure ones and answering SERVFAIL instead of the bogus ones
+pub struct ValidatingLookupService {
+    inner: Arc<dyn LookupService + Send + Sync>,
+    anchors: Vec<Record>,
+    /// Keys of the zones already followed, until they expire
+    keys: Cache<String, (Keys, Instant)>,
+}
+
+impl ValidatingLookupService {
+    async fn validate(&self, qname: &str, qtype: QueryType, response: &DnsPacket) -> Security {
+        let now = unix_time();
+        let signed = |records: &[Record]| {
+            records
+                .iter()
+                .any(|record| matches!(record, Record::RRSIG { .. }))
+        };
+        if !signed(&response.answers) && !signed(&response.authorities) {
+            // the signatures could have been stripped on the way
+            return match self.keys(normalize(qname).into_owned()).await {
+                Keys::Insecure => Security::Insecure,
+                Keys::Secure(_) | Keys::Bogus => Security::Bogus,
+            };
+        }
+
+        let mut result = Security::Secure;
+        for (records, required) in [(&response.answers, true), (&response.authorities, false)] {
+            for (rrset, signatures) in rrsets(records) {
+                if signatures.is_empty() && !required {
+                    continue;
+                }
+                result = result.max(self.validate_rrset(&rrset, &signatures, now).await);
+            }
+        }
+        if response.answers.is_empty() {
+            result = result.max(self.validate_denial(qname, qtype, response).await);
+        }
+        result
+    }
+
+    /// Security of an answer without records, which is only secure when the zone is
+    /// signed and its NSEC or NSEC3 records prove that the name or the type doesn't exist
+    async fn validate_denial(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+    ) -> Security {
+        let qname = normalize(qname);
+        // the SOA of a negative answer is the one of the zone of the name (RFC 2308 section 3)
+        let zone = response
+            .authorities
+            .iter()
+            .find(|record| {
+                matches!(record, Record::SOA { .. }) && is_subdomain(&qname, record.domain())
+            })
+            .map_or_else(
+                || qname.to_string(),
+                |soa| normalize(soa.domain()).into_owned(),
+            );
+        match self.keys(zone).await {
+            Keys::Secure(_) => {}
+            Keys::Insecure => return Security::Insecure,
+            Keys::Bogus => return Security::Bogus,
+        }
+        let now = unix_time();
+        let mut proofs = Vec::new();
+        for (rrset, signatures) in rrsets(&response.authorities) {
+            if matches!(rrset[0], Record::NSEC { .. } | Record::NSEC3 { .. })
+                && self.validate_rrset(&rrset, &signatures, now).await == Security::Secure
+            {
+                proofs.extend(rrset);
+            }
+        }
+        let proven = if response.header.response_code == ResponseCode::NameError {
+            proves_nxdomain(&proofs, &qname)
+        } else {
+            proves_nodata(&proofs, &qname, qtype)
+        };
+        if proven {
+            Security::Secure
+        } else {
+            tracing::debug!("absence of {qname:?} {qtype} not proven");
+            Security::Bogus
+        }
+    }
+
+    async fn validate_rrset(
+        &self,
+        rrset: &[&Record],
+        signatures: &[&Record],
+        now: u32,
+    ) -> Security {
+        let mut result = Security::Bogus;
+        for signature in signatures {
+            let Record::RRSIG {
+                signer,
+                key_tag,
+                algorithm,
+                ..
+            } = signature
+            else {
+                continue;
+            };
+            if !is_current(signature, now) || !is_subdomain(rrset[0].domain(), signer) {
+                continue;
+            }
+            let keys = match self.keys(normalize(signer).into_owned()).await {
+                Keys::Secure(keys) => keys,
+                Keys::Insecure => {
+                    result = Security::Insecure;
+                    continue;
+                }
+                Keys::Bogus => continue,
+            };
+            for key in keys
+                .iter()
+                .filter(|key| matches_key(key, *key_tag, *algorithm))
+            {
+                match verify(key, signature, rrset) {
+                    Some(true) => return Security::Secure,
+                    Some(false) => {}
+                    None => result = Security::Insecure,
+                }
+            }
+        }
+        result
+    }
+
+    /// Keys of the zone, looked up then followed from the trust anchors when not known yet
+    fn keys(&self, zone: String) -> BoxFuture<'_, Keys> {
+        async move {
+            if let Some((keys, until)) = self.keys.get(&zone) {
+                if until > Instant::now() {
+                    return keys;
+                }
+            }
+            match self.fetch_keys(&zone).await {
+                Ok((keys, ttl)) => {
+                    let until = Instant::now() + Duration::from_secs(ttl as u64);
+                    self.keys.insert(zone, (keys.clone(), until)).await;
+                    keys
+                }
+                Err(error) => {
+                    tracing::debug!("unable to look up the keys of {zone:?}: {error}");
+                    Keys::Bogus
+                }
+            }
+        }
+        .boxed()
+    }
+
+    async fn lookup_internal(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.inner
+            .lookup(
+                qname,
+                qtype,
+                QuerySource::Internal(InternalReason::Validation),
+            )
+            .await
+    }
+
+    /// Looks up the keys of the zone and checks them against its DS records, signed
+    /// by the keys of the parent zone, or against the trust anchors for the root zone.
+    ///
+    /// A name that isn't a zone cut gets the keys of its parent.
+    async fn fetch_keys(&self, zone: &str) -> Result<(Keys, u32)> {
+        let delegation = if zone.is_empty() {
+            self.anchors.clone()
+        } else {
+            let parent_keys = self.keys(parent(zone).to_string()).await;
+            if !matches!(parent_keys, Keys::Secure(_)) {
+                return Ok((parent_keys, UNTRUSTED_TTL));
+            }
+            let response = self.lookup_internal(zone, QueryType::DS).await?;
+            let rrsets = rrsets(&response.answers);
+            let Some((ds, signatures)) = rrsets.iter().find(|(rrset, _)| {
+                rrset[0].qtype() == QueryType::DS && equals(rrset[0].domain(), zone)
+            }) else {
+                return Ok(self.check_denial(zone, &response, parent_keys).await);
+            };
+            // the DS records are signed by the parent zone
+            let signed_by_parent = signatures.iter().all(|signature| {
+                matches!(signature, Record::RRSIG { signer, .. } if signer.len() < zone.len())
+            });
+            if !signed_by_parent {
+                return Ok((Keys::Bogus, UNTRUSTED_TTL));
+            }
+            match self.validate_rrset(ds, signatures, unix_time()).await {
+                Security::Secure => ds.iter().map(|record| (*record).clone()).collect(),
+                Security::Insecure => return Ok((Keys::Insecure, UNTRUSTED_TTL)),
+                Security::Bogus => return Ok((Keys::Bogus, UNTRUSTED_TTL)),
+            }
+        };
+        let delegation: Vec<_> = delegation
+            .iter()
+            .filter(|record| {
+                matches!(record, Record::DS { algorithm, digest_type, .. }
+                    if is_supported(*algorithm) && digest_algorithm(*digest_type).is_some())
+            })
+            .collect();
+        if delegation.is_empty() {
+            return Ok((Keys::Insecure, UNTRUSTED_TTL));
+        }
+
+        let response = self.lookup_internal(zone, QueryType::DNSKEY).await?;
+        let Some((keys, signatures)) = rrsets(&response.answers).into_iter().find(|(rrset, _)| {
+            rrset[0].qtype() == QueryType::DNSKEY && equals(rrset[0].domain(), zone)
+        }) else {
+            return Ok((Keys::Bogus, UNTRUSTED_TTL));
+        };
+        let entry_keys: Vec<&Record> = keys
+            .iter()
+            .copied()
+            .filter(|key| delegation.iter().any(|ds| matches_digest(ds, zone, key)))
+            .collect();
+        let now = unix_time();
+        let trusted = signatures.iter().any(|signature| {
+            let Record::RRSIG {
+                key_tag, algorithm, ..
+            } = signature
+            else {
+                return false;
+            };
+            is_current(signature, now)
+                && entry_keys
+                    .iter()
+                    .filter(|key| matches_key(key, *key_tag, *algorithm))
+                    .any(|key| verify(key, signature, &keys) == Some(true))
+        });
+        if !trusted {
+            tracing::debug!("keys of {zone:?} not signed by a trusted key");
+            return Ok((Keys::Bogus, UNTRUSTED_TTL));
+        }
+        let ttl = keys.iter().map(|key| key.ttl()).min().unwrap_or_default();
+        let keys = keys.into_iter().cloned().collect();
+        Ok((Keys::Secure(Arc::new(keys)), ttl))
+    }
+
+    /// Keys of a name without DS records, once their absence is proven by the signed
+    /// NSEC or NSEC3 record of the name: insecure for a delegation, the ones of the
+    /// parent otherwise
+    async fn check_denial(
+        &self,
+        zone: &str,
+        response: &DnsPacket,
+        parent_keys: Keys,
+    ) -> (Keys, u32) {
+        let rrsets = rrsets(&response.authorities);
+        let Some((proof, signatures, types)) = rrsets.iter().find_map(|(rrset, signatures)| {
+            proven_types(rrset[0], zone).map(|types| (rrset, signatures, types))
+        }) else {
+            tracing::debug!("absence of DS records for {zone:?} not proven");
+            return (Keys::Bogus, UNTRUSTED_TTL);
+        };
+        if self.validate_rrset(proof, signatures, unix_time()).await != Security::Secure
+            || types.contains(&QueryType::DS.into_num())
+        {
+            return (Keys::Bogus, UNTRUSTED_TTL);
+        }
+        let ttl = proof[0].ttl();
+        if types.contains(&QueryType::NS.into_num()) {
+            tracing::debug!("no DS record for {zone:?}, considered as unsigned");
+            (Keys::Insecure, ttl)
+        } else {
+            (parent_keys, ttl)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl LookupService for ValidatingLookupService {
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        source: QuerySource,
+    ) -> Result<DnsPacket> {
+        let mut response = self.inner.lookup(qname, qtype, source).await?;
+        if !matches!(
+            response.header.response_code,
+            ResponseCode::NoError | ResponseCode::NameError
+        ) {
+            return Ok(response);
+        }
+        match self.validate(qname, qtype, &response).await {
+            Security::Secure => response.header.authed_data = true,
+            Security::Insecure => response.header.authed_data = false,
+            Security::Bogus => {
+                tracing::warn!("bogus answer for {qname:?} {qtype}");
+                return Ok(DnsPacket::new(Header::response(response.header.id))
+                    .with_question(Question::new(qname.to_string(), qtype))
+                    .with_response_code(ResponseCode::ServerFailure));
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Seconds since the epoch, modulo 2^32 like the validity of the signatures
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as u32)
+        .unwrap_or_default()
+}
+
+/// Whether the signature is valid at the given time, using the serial number
+/// arithmetic of RFC 1982 so that the validity can span over 2106
+fn is_current(signature: &Record, now: u32) -> bool {
+    match signature {
+        Record::RRSIG {
+            inception,
+            expiration,
+            ..
+        } => now.wrapping_sub(*inception) as i32 >= 0 && expiration.wrapping_sub(now) as i32 >= 0,
+        _ => false,
+    }
+}
+
+/// Name without its first label, the root zone being its own parent
+fn parent(name: &str) -> &str {
+    name.split_once('.').map_or("", |(_, parent)| parent)
+}
+
+/// Types of the name listed by the NSEC or NSEC3 record when it's the one of the name
+fn proven_types<'a>(record: &'a Record, name: &str) -> Option<&'a [u16]> {
+    match record {
+        Record::NSEC { domain, types, .. } if equals(domain, name) => Some(types),
+        Record::NSEC3 {
+            domain,
+            hash_algorithm: 1,
+            iterations,
+            salt,
+            types,
+            ..
+        } if *iterations <= MAX_NSEC3_ITERATIONS => {
+            let (label, zone) = domain.split_once('.')?;
+            let hashed = base32hex(&nsec3_hash(name, salt, *iterations));
+            (label.eq_ignore_ascii_case(&hashed) && is_subdomain(name, zone)).then_some(types)
+        }
+        _ => None,
+    }
+}
+
+/// Whether the name falls between the owner and the next name of the NSEC or NSEC3
+/// record, proving that it doesn't exist
+fn covers(record: &Record, name: &str) -> bool {
+    match record {
+        Record::NSEC { domain, next, .. } => {
+            canonical_cmp(domain, name).is_lt()
+                // the last record of the zone points back to its apex
+                && (canonical_cmp(name, next).is_lt() || canonical_cmp(next, domain).is_le())
+        }
+        Record::NSEC3 {
+            domain,
+            hash_algorithm: 1,
+            iterations,
+            salt,
+            next_hashed,
+            ..
+        } if *iterations <= MAX_NSEC3_ITERATIONS => {
+            let Some((label, zone)) = domain.split_once('.') else {
+                return false;
+            };
+            if !is_subdomain(name, zone) {
+                return false;
+            }
+            // the extended hex alphabet keeps the order of the hashes
+            let owner = label.to_ascii_lowercase();
+            let hashed = base32hex(&nsec3_hash(name, salt, *iterations));
+            let next = base32hex(next_hashed);
+            if owner < next {
+                owner < hashed && hashed < next
+            } else {
+                owner < hashed || hashed < next
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Whether the records prove that the name has no record of the type, the name
+/// existing without it or being an empty non-terminal (RFC 4035 section 3.1.3)
+fn proves_nodata(proofs: &[&Record], name: &str, qtype: QueryType) -> bool {
+    proofs.iter().any(|proof| match proven_types(proof, name) {
+        Some(types) => {
+            !types.contains(&qtype.into_num()) && !types.contains(&QueryType::CNAME.into_num())
+        }
+        None => {
+            matches!(proof, Record::NSEC { next, .. } if covers(proof, name) && is_subdomain(next, name))
+        }
+    })
+}
+
+/// Whether the records prove that the name doesn't exist, nor the wildcard of its
+/// closest encloser that could have answered it (RFC 4035 section 5.4, RFC 5155 section 8.4)
+fn proves_nxdomain(proofs: &[&Record], name: &str) -> bool {
+    let covered = |name: &str| proofs.iter().any(|proof| covers(proof, name));
+    let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+    // the closest encloser is the longest ancestor that exists, the name below it being covered
+    let encloser = (1..=labels.len()).find_map(|skipped| {
+        let next_closer = labels[skipped - 1..].join(".");
+        let encloser = labels[skipped..].join(".");
+        let exists = proofs.iter().any(|proof| match proof {
+            // the owner and the next name exist, and so do their ancestors
+            Record::NSEC { domain, next, .. } => {
+                covers(proof, &next_closer)
+                    && (is_subdomain(domain, &encloser) || is_subdomain(next, &encloser))
+            }
+            _ => proven_types(proof, &encloser).is_some() && covered(&next_closer),
+        });
+        exists.then_some(encloser)
+    });
+    encloser.is_some_and(|encloser| match encloser.as_str() {
+        "" => covered("*"),
+        encloser => covered(&format!("*.{encloser}")),
+    })
+}
+
+/// Canonical order of the names, comparing their labels in lowercase from the
+/// rightmost one (RFC 4034 section 6.1)
+fn canonical_cmp(left: &str, right: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase().into_bytes())
+            .collect()
+    };
+    labels(left).cmp(&labels(right))
+}
+
+/// Hashed owner name of the NSEC3 records (RFC 5155 section 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let algorithm = &ring::digest::SHA1_FOR_LEGACY_USE_ONLY;
+    let mut hash = canonical_name(name);
+    for _ in 0..=iterations {
+        let mut context = ring::digest::Context::new(algorithm);
+        context.update(&hash);
+        context.update(salt);
+        hash = context.finish().as_ref().to_vec();
+    }
+    hash
+}
+
+/// Encoding of the hashed names, with the extended hex alphabet (RFC 4648 section 7)
+fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut result = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8 | *byte as u32) & 0xFFFF;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    result
+}
+
+fn is_subdomain(name: &str, zone: &str) -> bool {
+    let zone = normalize(zone);
+    zone.is_empty() || matches_suffix(&normalize(name), &zone)
+}
+
+/// Records of the same owner and type, with the signatures covering them
+type SignedSet<'a> = (Vec<&'a Record>, Vec<&'a Record>);
+
+/// Groups the records by owner, whatever its case, and type, with the signatures covering them
+fn rrsets(records: &[Record]) -> Vec<SignedSet<'_>> {
+    let mut sets: BTreeMap<(Cow<'_, str>, u16), SignedSet<'_>> = BTreeMap::new();
+    for record in records {
+        match record {
+            Record::RRSIG {
+                domain,
+                type_covered,
+                ..
+            } => sets
+                .entry((normalize(domain), *type_covered))
+                .or_default()
+                .1
+                .push(record),
+            Record::OPT { .. } => {}
+            _ => sets
+                .entry((normalize(record.domain()), record.qtype().into_num()))
+                .or_default()
+                .0
+                .push(record),
+        }
+    }
+    sets.into_values()
+        .filter(|(rrset, _)| !rrset.is_empty())
+        .collect()
+}
+
+/// Name in its canonical wire format, in lowercase and without compression
+fn canonical_name(name: &str) -> Vec<u8> {
+    let mut result = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        result.push(label.len() as u8);
+        result.extend(label.to_ascii_lowercase().bytes());
+    }
+    result.push(0);
+    result
+}
+
+/// Data covered by the signature: its own fields then the records in canonical
+/// form and order (RFC 4034 section 3.1.8.1)
+fn signed_data(signature: &Record, rrset: &[&Record]) -> Option<Vec<u8>> {
+    let Record::RRSIG {
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer,
+        ..
+    } = signature
+    else {
+        return None;
+    };
+    let mut data = Vec::new();
+    data.extend(type_covered.to_be_bytes());
+    data.push(*algorithm);
+    data.push(*labels);
+    data.extend(original_ttl.to_be_bytes());
+    data.extend(expiration.to_be_bytes());
+    data.extend(inception.to_be_bytes());
+    data.extend(key_tag.to_be_bytes());
+    data.extend(canonical_name(signer));
+
+    // the owner of a record expanded from a wildcard is signed as the wildcard
+    let domain = rrset.first()?.domain();
+    let owner_labels: Vec<&str> = domain
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .collect();
+    let owner = match owner_labels.len().checked_sub(*labels as usize)? {
+        0 => canonical_name(domain),
+        skipped => canonical_name(&format!("*.{}", owner_labels[skipped..].join("."))),
+    };
+
+    let mut rdatas = rrset
+        .iter()
+        .map(|record| record.canonical_data().ok())
+        .collect::<Option<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+    for rdata in rdatas {
+        data.extend(&owner);
+        data.extend(type_covered.to_be_bytes());
+        data.extend(1u16.to_be_bytes());
+        data.extend(original_ttl.to_be_bytes());
+        data.extend((rdata.len() as u16).to_be_bytes());
+        data.extend(rdata);
+    }
+    Some(data)
+}
+
+/// Algorithms of the keys that can be verified
+fn is_supported(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+/// Tag of a key, used by the signatures and DS records to point to it (RFC 4034 appendix B)
+fn key_tag(key: &Record) -> Option<u16> {
+    let data = key.canonical_data().ok()?;
+    let mut sum: u32 = 0;
+    for (idx, byte) in data.iter().enumerate() {
+        sum += if idx % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+    Some((sum & 0xFFFF) as u16)
+}
+
+fn matches_key(key: &Record, tag: u16, expected: u8) -> bool {
+    matches!(key, Record::DNSKEY { flags, algorithm, .. }
+        if flags & ZONE_KEY_FLAG != 0 && *algorithm == expected)
+        && key_tag(key) == Some(tag)
+}
+
+fn digest_algorithm(digest_type: u8) -> Option<&'static ring::digest::Algorithm> {
+    match digest_type {
+        1 => Some(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&ring::digest::SHA256),
+        4 => Some(&ring::digest::SHA384),
+        _ => None,
+    }
+}
+
+/// Whether the DS record holds the digest of the key
+fn matches_digest(ds: &Record, zone: &str, key: &Record) -> bool {
+    let Record::DS {
+        key_tag: tag,
+        algorithm,
+        digest_type,
+        digest,
+        ..
+    } = ds
+    else {
+        return false;
+    };
+    let (Some(digest_algorithm), Ok(data)) = (digest_algorithm(*digest_type), key.canonical_data())
+    else {
+        return false;
+    };
+    if !matches_key(key, *tag, *algorithm) {
+        return false;
+    }
+    let mut context = ring::digest::Context::new(digest_algorithm);
+    context.update(&canonical_name(zone));
+    context.update(&data);
+    context.finish().as_ref() == digest.as_slice()
+}
+
+/// Checks the signature of the records with the key, `None` meaning its algorithm isn't supported
+fn verify(key: &Record, signature: &Record, rrset: &[&Record]) -> Option<bool> {
+    use ring::signature;
+
+    let (
+        Record::DNSKEY {
+            algorithm,
+            public_key,
+            ..
+        },
+        Record::RRSIG {
+            signature: value, ..
+        },
+    ) = (key, signature)
+    else {
+        return Some(false);
+    };
+    if !is_supported(*algorithm) {
+        return None;
+    }
+    let Some(message) = signed_data(signature, rrset) else {
+        return Some(false);
+    };
+    let rsa = |parameters: &'static signature::RsaParameters| {
+        rsa_components(public_key).is_some_and(|(n, e)| {
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(parameters, &message, value)
+                .is_ok()
+        })
+    };
+    // the ECDSA keys are the uncompressed points without their prefix (RFC 6605)
+    let ecdsa = |algorithm: &'static signature::EcdsaVerificationAlgorithm| {
+        let point = [&[0x04], public_key.as_slice()].concat();
+        signature::UnparsedPublicKey::new(algorithm, point)
+            .verify(&message, value)
+            .is_ok()
+    };
+    Some(match algorithm {
+        5 | 7 => rsa(&signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY),
+        8 => rsa(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY),
+        10 => rsa(&signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY),
+        13 => ecdsa(&signature::ECDSA_P256_SHA256_FIXED),
+        14 => ecdsa(&signature::ECDSA_P384_SHA384_FIXED),
+        _ => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(&message, value)
+            .is_ok(),
+    })
+}
+
+/// Modulus and exponent of a RSA key (RFC 3110 section 2)
+fn rsa_components(key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (first, rest) = key.split_first()?;
+    let (size, rest) = match first {
+        0 => {
+            let (size, rest) = rest.split_first_chunk::<2>()?;
+            (u16::from_be_bytes(*size) as usize, rest)
+        }
+        size => (*size as usize, rest),
+    };
+    if size == 0 || size >= rest.len() {
+        return None;
+    }
+    let (exponent, modulus) = rest.split_at(size);
+    Some((modulus, exponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base32hex, key_tag, nsec3_hash, parse_anchor, signed_data, unix_time, Config};
+    use crate::common::source::{InternalReason, QuerySource};
+    use crate::repository::lookup::{LookupService, MockLookupService};
+    use donos_parser::packet::header::{Header, ResponseCode};
+    use donos_parser::packet::record::Record;
+    use donos_parser::packet::{DnsPacket, QueryType};
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    struct Zone {
+        name: &'static str,
+        pair: Ed25519KeyPair,
+        key: Record,
+    }
+
+    impl Zone {
+        fn new(name: &'static str) -> Self {
+            let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(document.as_ref()).unwrap();
+            let key = Record::DNSKEY {
+                domain: name.into(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 15,
+                public_key: pair.public_key().as_ref().to_vec(),
+                ttl: 3600,
+            };
+            Self { name, pair, key }
+        }
+
+        fn ds(&self) -> Record {
+            let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+            context.update(&super::canonical_name(self.name));
+            context.update(&self.key.canonical_data().unwrap());
+            Record::DS {
+                domain: self.name.into(),
+                key_tag: key_tag(&self.key).unwrap(),
+                algorithm: 15,
+                digest_type: 2,
+                digest: context.finish().as_ref().to_vec(),
+                ttl: 3600,
+            }
+        }
+
+        fn sign(&self, rrset: &[Record]) -> Record {
+            let now = unix_time();
+            let mut signature = Record::RRSIG {
+                domain: rrset[0].domain().into(),
+                type_covered: rrset[0].qtype().into_num(),
+                algorithm: 15,
+                labels: rrset[0]
+                    .domain()
+                    .split('.')
+                    .filter(|l| !l.is_empty())
+                    .count() as u8,
+                original_ttl: rrset[0].ttl(),
+                expiration: now + 3600,
+                inception: now - 3600,
+                key_tag: key_tag(&self.key).unwrap(),
+                signer: self.name.into(),
+                signature: Vec::new(),
+                ttl: rrset[0].ttl(),
+            };
+            let rrset: Vec<&Record> = rrset.iter().collect();
+            let data = signed_data(&signature, &rrset).unwrap();
+            if let Record::RRSIG {
+                signature: ref mut value,
+                ..
+            } = signature
+            {
+                *value = self.pair.sign(&data).as_ref().to_vec();
+            }
+            signature
+        }
+
+        fn keys(&self) -> DnsPacket {
+            DnsPacket::new(Header::response(1))
+                .with_answer(self.key.clone())
+                .with_answer(self.sign(std::slice::from_ref(&self.key)))
+        }
+    }
+
+    fn answer(addr: Ipv4Addr) -> Record {
+        Record::A {
+            domain: "perdu.com".into(),
+            addr,
+            ttl: 300,
+        }
+    }
+
+    fn unsigned_answer(domain: &str) -> DnsPacket {
+        DnsPacket::new(Header::response(1)).with_answer(Record::A {
+            domain: domain.into(),
+            addr: Ipv4Addr::new(1, 1, 1, 1),
+            ttl: 300,
+        })
+    }
+
+    /// Signed root, com and perdu.com zones, perdu.com having two different answers
+    fn signed_lookup(root: &Zone, tld: &Zone, zone: &Zone) -> MockLookupService {
+        let record = answer(Ipv4Addr::new(1, 2, 3, 4));
+        let signature = zone.sign(std::slice::from_ref(&record));
+        // the signature of another address
+        let tampered = answer(Ipv4Addr::new(6, 6, 6, 6));
+        // unsigned.com is delegated without DS records
+        let denial = Record::NSEC {
+            domain: "unsigned.com".into(),
+            next: "unsigned0.com".into(),
+            types: vec![
+                QueryType::NS.into_num(),
+                QueryType::RRSIG.into_num(),
+                QueryType::NSEC.into_num(),
+            ],
+            ttl: 300,
+        };
+        MockLookupService::default()
+            .with_query("", QueryType::DNSKEY, root.keys())
+            .with_query(
+                "com",
+                QueryType::DS,
+                DnsPacket::new(Header::response(1))
+                    .with_answer(tld.ds())
+                    .with_answer(root.sign(&[tld.ds()])),
+            )
+            .with_query("com", QueryType::DNSKEY, tld.keys())
+            .with_query(
+                "perdu.com",
+                QueryType::DS,
+                DnsPacket::new(Header::response(1))
+                    .with_answer(zone.ds())
+                    .with_answer(tld.sign(&[zone.ds()])),
+            )
+            .with_query("perdu.com", QueryType::DNSKEY, zone.keys())
+            .with_query(
+                "perdu.com",
+                QueryType::A,
+                DnsPacket::new(Header::response(1))
+                    .with_answer(record)
+                    .with_answer(signature.clone()),
+            )
+            .with_query(
+                "perdu.com",
+                QueryType::AAAA,
+                DnsPacket::new(Header::response(1))
+                    .with_answer(tampered)
+                    .with_answer(signature),
+            )
+            .with_query(
+                "unsigned.com",
+                QueryType::DS,
+                DnsPacket::new(Header::response(1))
+                    .with_authority(denial.clone())
+                    .with_authority(tld.sign(&[denial])),
+            )
+            .with_query(
+                "unsigned.com",
+                QueryType::A,
+                unsigned_answer("unsigned.com"),
+            )
+            // the absence of DS records isn't proven
+            .with_query(
+                "unproven.com",
+                QueryType::DS,
+                DnsPacket::new(Header::response(1)),
+            )
+            .with_query(
+                "unproven.com",
+                QueryType::A,
+                unsigned_answer("unproven.com"),
+            )
+    }
+
+    fn anchor(zone: &Zone) -> String {
+        let Record::DS {
+            key_tag, digest, ..
+        } = zone.ds()
+        else {
+            unreachable!()
+        };
+        let digest: String = digest.iter().map(|b| format!("{b:02X}")).collect();
+        format!("{key_tag} 15 2 {digest}")
+    }
+
+    #[test]
+    fn should_parse_root_anchors() {
+        let anchors = Config::default_trust_anchors();
+        assert!(matches!(
+            parse_anchor(&anchors[0]),
+            Some(Record::DS {
+                key_tag: 20326,
+                algorithm: 8,
+                digest_type: 2,
+                ref digest,
+                ..
+            }) if digest.len() == 32
+        ));
+        assert!(parse_anchor("20326 8 2 E06").is_none());
+        assert!(parse_anchor("20326 8").is_none());
+    }
+
+    #[tokio::test]
+    async fn should_validate_signed_answers() {
+        crate::init_logs();
+
+        let root = Zone::new("");
+        let tld = Zone::new("com");
+        let zone = Zone::new("perdu.com");
+        let lookup = Arc::new(signed_lookup(&root, &tld, &zone));
+        let source = QuerySource::Internal(InternalReason::HealthCheck);
+
+        let service = Config {
+            enabled: true,
+            trust_anchors: vec![anchor(&root)],
+            cache_size: 10,
+        }
+        .build(lookup.clone())
+        .unwrap();
+
+        let result = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::NoError);
+        assert!(result.header.authed_data);
+
+        // the signature doesn't match the record
+        let result = service
+            .lookup("perdu.com", QueryType::AAAA, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+        assert!(result.answers.is_empty());
+
+        let result = service
+            .lookup("unsigned.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::NoError);
+        assert!(!result.header.authed_data);
+
+        let result = service
+            .lookup("unproven.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+
+        // the keys of the root zone aren't the trusted ones
+        let service = Config {
+            enabled: true,
+            trust_anchors: vec![anchor(&Zone::new(""))],
+            cache_size: 10,
+        }
+        .build(lookup)
+        .unwrap();
+        let result = service
+            .lookup("perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+    }
+
+    #[tokio::test]
+    async fn should_reject_stripped_signatures() {
+        crate::init_logs();
+
+        let root = Zone::new("");
+        let tld = Zone::new("com");
+        let zone = Zone::new("perdu.com");
+        // the answer of a signed zone, without its signature
+        let lookup = signed_lookup(&root, &tld, &zone).with_query(
+            "perdu.com",
+            QueryType::A,
+            DnsPacket::new(Header::response(1)).with_answer(answer(Ipv4Addr::new(6, 6, 6, 6))),
+        );
+        let service = Config {
+            enabled: true,
+            trust_anchors: vec![anchor(&root)],
+            cache_size: 10,
+        }
+        .build(Arc::new(lookup))
+        .unwrap();
+
+        let result = service
+            .lookup(
+                "perdu.com",
+                QueryType::A,
+                QuerySource::Internal(InternalReason::HealthCheck),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+        assert!(result.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_prove_negative_answers() {
+        crate::init_logs();
+
+        let root = Zone::new("");
+        let tld = Zone::new("com");
+        let zone = Zone::new("perdu.com");
+        let soa = Record::SOA {
+            domain: "perdu.com".into(),
+            mname: "ns.perdu.com".into(),
+            rname: "admin.perdu.com".into(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: 300,
+        };
+        // nothing between perdu.com and www.perdu.com, and only these types for perdu.com
+        let nsec = Record::NSEC {
+            domain: "perdu.com".into(),
+            next: "www.perdu.com".into(),
+            types: vec![
+                QueryType::A.into_num(),
+                QueryType::NS.into_num(),
+                QueryType::SOA.into_num(),
+                QueryType::RRSIG.into_num(),
+                QueryType::NSEC.into_num(),
+            ],
+            ttl: 300,
+        };
+        let negative = |code: ResponseCode| {
+            DnsPacket::new(Header::response(1))
+                .with_response_code(code)
+                .with_authority(soa.clone())
+                .with_authority(zone.sign(std::slice::from_ref(&soa)))
+                .with_authority(nsec.clone())
+        };
+        let signed_nsec = zone.sign(std::slice::from_ref(&nsec));
+        let lookup = signed_lookup(&root, &tld, &zone)
+            .with_query(
+                "nope.perdu.com",
+                QueryType::A,
+                negative(ResponseCode::NameError).with_authority(signed_nsec.clone()),
+            )
+            // the signed SOA of the zone, with a denial that isn't signed
+            .with_query(
+                "forged.perdu.com",
+                QueryType::A,
+                negative(ResponseCode::NameError),
+            )
+            .with_query(
+                "perdu.com",
+                QueryType::TXT,
+                negative(ResponseCode::NoError).with_authority(signed_nsec.clone()),
+            )
+            // the types of the name include the one of the query
+            .with_query(
+                "perdu.com",
+                QueryType::NS,
+                negative(ResponseCode::NoError).with_authority(signed_nsec),
+            );
+        let service = Config {
+            enabled: true,
+            trust_anchors: vec![anchor(&root)],
+            cache_size: 10,
+        }
+        .build(Arc::new(lookup))
+        .unwrap();
+        let source = QuerySource::Internal(InternalReason::HealthCheck);
+
+        let result = service
+            .lookup("nope.perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::NameError);
+        assert!(result.header.authed_data);
+
+        let result = service
+            .lookup("forged.perdu.com", QueryType::A, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+
+        let result = service
+            .lookup("perdu.com", QueryType::TXT, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::NoError);
+        assert!(result.header.authed_data);
+
+        let result = service
+            .lookup("perdu.com", QueryType::NS, source)
+            .await
+            .unwrap();
+        assert_eq!(result.header.response_code, ResponseCode::ServerFailure);
+    }
+
+    #[test]
+    fn should_hash_nsec3_names() {
+        // example of RFC 5155 appendix A
+        let hash = nsec3_hash("example", &[0xaa, 0xbb, 0xcc, 0xdd], 12);
+        assert_eq!(base32hex(&hash), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+    }
+}
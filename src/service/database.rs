@@ -6,10 +6,10 @@ pub type Error = sqlx::Error;
 
 static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!();
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Config {
     #[serde(default = "Config::default_url")]
-    url: String,
+    pub url: String,
 }
 
 impl Default for Config {
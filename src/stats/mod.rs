@@ -0,0 +1,106 @@
+use clap::Args;
+
+use crate::dns::metrics::Provenance;
+use crate::repository::client::DatabaseClientService;
+use crate::repository::query::{unix_now, DatabaseQueryLogService};
+
+/// Number of domains and clients listed in the tops
+const TOP_SIZE: u32 = 10;
+
+/// Print what the server has been doing, from the query log
+#[derive(Args, Debug)]
+pub struct Command {
+    /// Number of hours to look back
+    #[arg(long, default_value_t = 24)]
+    hours: u64,
+    /// Only print the domains queried by this client, given by its name or address
+    #[arg(long)]
+    client: Option<String>,
+}
+
+fn percent(value: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        value as f64 * 100.0 / total as f64
+    }
+}
+
+impl Command {
+    pub async fn run(self, config: crate::config::Config) {
+        if !config.query_log.enabled {
+            tracing::warn!("the query log is disabled, no new query is recorded");
+        }
+        let database = config
+            .database
+            .build()
+            .await
+            .expect("unable to connect to database");
+        crate::service::database::migrate(&database)
+            .await
+            .expect("unable to migrate the database");
+
+        let names = match DatabaseClientService::new(database.clone()).names().await {
+            Ok(found) => found,
+            Err(err) => {
+                tracing::error!("couldn't load client names: {err:?}");
+                return;
+            }
+        };
+        let since = unix_now().saturating_sub(self.hours * 3600);
+        let queries = DatabaseQueryLogService::new(database);
+        if let Some(client) = self.client.as_deref() {
+            let domains = match queries.client_domains(client, since, TOP_SIZE).await {
+                Ok(found) => found,
+                Err(err) => {
+                    tracing::error!("couldn't compute stats: {err:?}");
+                    return;
+                }
+            };
+            println!("over the last {} hours", self.hours);
+            println!("top domains of {client}");
+            for (domain, count, blocked) in domains {
+                if blocked > 0 {
+                    println!("{count}\t{domain} ({blocked} blocked)");
+                } else {
+                    println!("{count}\t{domain}");
+                }
+            }
+            return;
+        }
+        let stats = match queries.stats(since, TOP_SIZE).await {
+            Ok(found) => found,
+            Err(err) => {
+                tracing::error!("couldn't compute stats: {err:?}");
+                return;
+            }
+        };
+
+        let blocked = stats.count(Provenance::Blocked.as_str());
+        let cache = stats.count(Provenance::Cache.as_str());
+        // the stale answers are served when the upstream servers fail, not as cache hits
+        let resolved = cache
+            + stats.count(Provenance::Upstream.as_str())
+            + stats.count(Provenance::Stale.as_str());
+        println!("over the last {} hours", self.hours);
+        println!("queries served\t{}", stats.queries);
+        println!(
+            "blocked\t\t{blocked} ({:.1}%)",
+            percent(blocked, stats.queries)
+        );
+        println!("cache hit ratio\t{:.1}%", percent(cache, resolved));
+        println!();
+        println!("top blocked domains");
+        for (domain, count) in stats.top_blocked {
+            println!("{count}\t{domain}");
+        }
+        println!();
+        println!("top clients");
+        for (client, count) in stats.top_clients {
+            match client.parse().ok().and_then(|ip| names.get(&ip)) {
+                Some(name) => println!("{count}\t{client} ({name})"),
+                None => println!("{count}\t{client}"),
+            }
+        }
+    }
+}